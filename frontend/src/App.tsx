@@ -1,5 +1,6 @@
-import { useEffect, useRef } from "react";
+import { useEffect, useRef, useState } from "react";
 import {
+	AuthForm,
 	ChatInput,
 	EmptyState,
 	ErrorBanner,
@@ -10,8 +11,48 @@ import {
 	ModelSelector,
 } from "./components";
 import { useWebSocket } from "./hooks";
+import {
+	checkAuthToken,
+	clearAuthToken,
+	clearMessages,
+	getAuthToken,
+} from "./utils";
 
 function App() {
+	const [token, setToken] = useState<string | null>(() => getAuthToken());
+
+	// 退出时一并清除本地缓存的消息，避免下一个账号看到
+	const logout = () => {
+		clearAuthToken();
+		clearMessages();
+		setToken(null);
+	};
+
+	// 令牌过期或失效时回到登录页
+	useEffect(() => {
+		if (!token) return;
+		checkAuthToken(token).then((valid) => {
+			if (!valid) {
+				clearAuthToken();
+				setToken(null);
+			}
+		});
+	}, [token]);
+
+	if (!token) {
+		return <AuthForm onAuthenticated={setToken} />;
+	}
+
+	// 切换账号时重新建立连接
+	return <ChatApp key={token} token={token} onLogout={logout} />;
+}
+
+interface ChatAppProps {
+	token: string;
+	onLogout: () => void;
+}
+
+function ChatApp({ token, onLogout }: ChatAppProps) {
 	const {
 		connectionStatus,
 		messages,
@@ -26,7 +67,7 @@ function App() {
 		clearError,
 		clearChat,
 		isConnected,
-	} = useWebSocket();
+	} = useWebSocket(token);
 
 	const messagesEndRef = useRef<HTMLDivElement>(null);
 
@@ -52,6 +93,7 @@ function App() {
 				connectionStatus={connectionStatus}
 				onClearChat={clearChat}
 				hasMessages={messages.length > 0}
+				onLogout={onLogout}
 			/>
 
 			{/* 错误提示 */}
@@ -0,0 +1,91 @@
+import { Loader2, Sparkles } from "lucide-react";
+import type { FormEvent } from "react";
+import { useState } from "react";
+import { authenticate, saveAuthToken } from "../utils";
+
+interface AuthFormProps {
+	onAuthenticated: (token: string) => void;
+}
+
+export function AuthForm({ onAuthenticated }: AuthFormProps) {
+	const [mode, setMode] = useState<"login" | "signup">("login");
+	const [username, setUsername] = useState("");
+	const [password, setPassword] = useState("");
+	const [error, setError] = useState("");
+	const [isSubmitting, setIsSubmitting] = useState(false);
+
+	const handleSubmit = async (e: FormEvent) => {
+		e.preventDefault();
+		if (!username.trim() || !password || isSubmitting) return;
+
+		setIsSubmitting(true);
+		setError("");
+
+		const result = await authenticate(mode, username, password);
+
+		setIsSubmitting(false);
+		if (result.success && result.token) {
+			saveAuthToken(result.token);
+			onAuthenticated(result.token);
+		} else {
+			setError(result.error || "请求失败");
+		}
+	};
+
+	return (
+		<div className="h-screen w-full flex items-center justify-center bg-gradient-to-br from-slate-50 via-white to-violet-50">
+			<form
+				onSubmit={handleSubmit}
+				className="w-full max-w-sm bg-white rounded-2xl shadow-xl shadow-gray-100 border border-gray-100 p-8 flex flex-col gap-4"
+			>
+				<div className="flex items-center gap-3 mb-2">
+					<div className="w-9 h-9 bg-gradient-to-br from-violet-500 to-indigo-600 rounded-xl flex items-center justify-center shadow-lg shadow-violet-200">
+						<Sparkles className="w-5 h-5 text-white" />
+					</div>
+					<h1 className="text-lg font-semibold text-gray-800">
+						{mode === "login" ? "登录 Web Chat" : "注册 Web Chat"}
+					</h1>
+				</div>
+
+				<input
+					type="text"
+					value={username}
+					onChange={(e) => setUsername(e.target.value)}
+					placeholder="用户名"
+					autoComplete="username"
+					className="px-3 py-2 text-sm border border-gray-200 rounded-lg focus:outline-none focus:border-violet-400"
+				/>
+				<input
+					type="password"
+					value={password}
+					onChange={(e) => setPassword(e.target.value)}
+					placeholder="密码（至少 8 位）"
+					autoComplete={mode === "login" ? "current-password" : "new-password"}
+					className="px-3 py-2 text-sm border border-gray-200 rounded-lg focus:outline-none focus:border-violet-400"
+				/>
+
+				{error && <p className="text-sm text-red-600">{error}</p>}
+
+				<button
+					type="submit"
+					disabled={isSubmitting}
+					className="flex items-center justify-center gap-2 px-4 py-2 text-sm text-white bg-gradient-to-r from-violet-500 to-indigo-600 rounded-lg disabled:opacity-60"
+				>
+					{isSubmitting && <Loader2 className="w-4 h-4 animate-spin" />}
+					{mode === "login" ? "登录" : "注册"}
+				</button>
+
+				<button
+					type="button"
+					onClick={() => {
+						setMode(mode === "login" ? "signup" : "login");
+						setError("");
+					}}
+					className="text-xs text-gray-500 hover:text-violet-600"
+				>
+					{mode === "login" ? "没有账号？注册" : "已有账号？登录"}
+				</button>
+			</form>
+		</div>
+	);
+}
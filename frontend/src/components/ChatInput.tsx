@@ -42,13 +42,11 @@ export function ChatInput({
 		const result = await uploadFiles(files);
 
 		if (result.success && result.files) {
+			// 相同内容的文件在文件库中只保存一份，按 id 去重
+			const uploadedIds = new Set(result.files.map((file) => file.id));
 			onFilesUploaded([
-				...fileContexts,
-				...result.files.map((f) => ({
-					name: f.name,
-					content: f.content,
-					size: f.size,
-				})),
+				...fileContexts.filter((file) => !uploadedIds.has(file.id)),
+				...result.files,
 			]);
 		} else if (result.error) {
 			onError(result.error);
@@ -70,7 +68,7 @@ export function ChatInput({
 				onChange={handleFileChange}
 				className="hidden"
 				multiple
-				accept=".txt,.md,.json,.js,.ts,.jsx,.tsx,.py,.rs,.go,.java,.c,.cpp,.h,.hpp,.css,.html,.xml,.yaml,.yml,.toml,.ini,.cfg,.conf,.sh,.bat,.ps1,.sql"
+				accept=".txt,.md,.json,.js,.ts,.jsx,.tsx,.py,.rs,.go,.java,.c,.cpp,.h,.hpp,.css,.html,.xml,.yaml,.yml,.toml,.ini,.cfg,.conf,.sh,.bat,.ps1,.sql,.png,.jpg,.jpeg,.webp,.heic,.heif,.pdf,.mp3,.wav,.aiff,.aac,.m4a,.ogg,.flac"
 			/>
 
 			{/* 上传按钮 */}
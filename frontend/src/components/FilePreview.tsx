@@ -1,4 +1,4 @@
-import { File, X } from "lucide-react";
+import { File, FileAudio, FileText, Image, X } from "lucide-react";
 import type { FileContext } from "../types";
 import { formatFileSize } from "../utils";
 
@@ -8,6 +8,14 @@ interface FilePreviewProps {
 	onClear: () => void;
 }
 
+function FileIcon({ mimeType }: { mimeType?: string }) {
+	const className = "w-3.5 h-3.5 text-violet-500";
+	if (mimeType?.startsWith("image/")) return <Image className={className} />;
+	if (mimeType?.startsWith("audio/")) return <FileAudio className={className} />;
+	if (mimeType === "application/pdf") return <FileText className={className} />;
+	return <File className={className} />;
+}
+
 export function FilePreview({ files, onRemove, onClear }: FilePreviewProps) {
 	if (files.length === 0) return null;
 
@@ -28,10 +36,10 @@ export function FilePreview({ files, onRemove, onClear }: FilePreviewProps) {
 			<div className="flex flex-wrap gap-2">
 				{files.map((file, index) => (
 					<div
-						key={`${file.name}-${index}`}
+						key={file.id}
 						className="flex items-center gap-2 bg-white rounded-lg px-2.5 py-1.5 border border-gray-200 shadow-sm group hover:border-violet-200 transition-colors"
 					>
-						<File className="w-3.5 h-3.5 text-violet-500" />
+						<FileIcon mimeType={file.mime_type} />
 						<span className="text-xs text-gray-700 max-w-24 truncate font-medium">
 							{file.name}
 						</span>
@@ -1,16 +1,18 @@
-import { Sparkles, Trash2 } from "lucide-react";
+import { LogOut, Sparkles, Trash2 } from "lucide-react";
 import type { ConnectionStatus } from "../types";
 
 interface HeaderProps {
 	connectionStatus: ConnectionStatus;
 	onClearChat: () => void;
 	hasMessages: boolean;
+	onLogout: () => void;
 }
 
 export function Header({
 	connectionStatus,
 	onClearChat,
 	hasMessages,
+	onLogout,
 }: HeaderProps) {
 	return (
 		<header className="bg-white/80 backdrop-blur-md border-b border-gray-100 sticky top-0 z-10">
@@ -38,6 +40,17 @@ export function Header({
 						</button>
 					)}
 
+					{/* Logout Button */}
+					<button
+						type="button"
+						onClick={onLogout}
+						className="flex items-center gap-1.5 px-2.5 py-1.5 text-xs text-gray-500 hover:text-violet-600 hover:bg-violet-50 rounded-lg transition-colors"
+						title="退出登录"
+					>
+						<LogOut className="w-3.5 h-3.5" />
+						<span className="hidden sm:inline">退出</span>
+					</button>
+
 					{/* Connection Status */}
 					<div className="flex items-center gap-2">
 						<div
@@ -2,6 +2,8 @@ import { Brain, Sparkles, User } from "lucide-react";
 import ReactMarkdown, { type Components } from "react-markdown";
 import remarkGfm from "remark-gfm";
 import type { Message } from "../types";
+import { SourceList } from "./SourceList";
+import { ToolCallList } from "./ToolCallList";
 
 interface MessageBubbleProps {
 	message: Message;
@@ -102,7 +104,7 @@ const markdownComponents: Partial<Components> = {
 };
 
 export function MessageBubble({ message }: MessageBubbleProps) {
-	const { type, content, model } = message;
+	const { type, content, model, usage, sources, toolCalls } = message;
 
 	// 系统消息
 	if (type === "system") {
@@ -161,6 +163,11 @@ export function MessageBubble({ message }: MessageBubbleProps) {
 						<span className="text-xs text-gray-400 mb-1 ml-1">{model}</span>
 					)}
 
+					{/* 工具调用 */}
+					{!isUser && toolCalls && toolCalls.length > 0 && (
+						<ToolCallList calls={toolCalls} />
+					)}
+
 					{/* 消息气泡 */}
 					<div
 						className={`px-4 py-3 ${
@@ -184,6 +191,19 @@ export function MessageBubble({ message }: MessageBubbleProps) {
 							</div>
 						)}
 					</div>
+
+					{/* 引用来源 */}
+					{!isUser && sources && sources.length > 0 && (
+						<SourceList sources={sources} />
+					)}
+
+					{/* token 用量 */}
+					{!isUser && usage && (
+						<span className="text-xs text-gray-400 mt-1 ml-1">
+							输入 {usage.prompt_tokens} · 输出{" "}
+							{usage.candidate_tokens + usage.thought_tokens} tokens
+						</span>
+					)}
 				</div>
 
 				{/* 用户头像 */}
@@ -0,0 +1,48 @@
+import { FileText, MessageSquare } from "lucide-react";
+import type { Source } from "../types";
+import { sourceUrl } from "../utils";
+
+interface SourceListProps {
+	sources: Source[];
+}
+
+function sourceTitle(source: Source): string {
+	if (source.kind === "file_chunk") {
+		return `${source.file_name} 第 ${source.start_line}-${source.end_line} 行`;
+	}
+	return source.role === "user" ? "历史提问" : "历史回答";
+}
+
+export function SourceList({ sources }: SourceListProps) {
+	return (
+		<div className="mt-2 ml-1 flex flex-col gap-1">
+			<span className="text-xs font-medium text-gray-400">引用来源</span>
+			{sources.map((source) => (
+				<a
+					key={source.index}
+					href={sourceUrl(source)}
+					target="_blank"
+					rel="noopener noreferrer"
+					title={source.snippet}
+					className="flex items-center gap-1.5 text-xs text-gray-500 hover:text-violet-600 transition-colors max-w-md"
+				>
+					<span className="font-mono text-violet-500">[{source.index}]</span>
+					{source.kind === "file_chunk" ? (
+						<FileText className="w-3 h-3 flex-shrink-0" />
+					) : (
+						<MessageSquare className="w-3 h-3 flex-shrink-0" />
+					)}
+					<span className="font-medium flex-shrink-0">
+						{sourceTitle(source)}
+					</span>
+					{source.score !== null && (
+						<span className="text-gray-400 flex-shrink-0">
+							{Math.round(source.score * 100)}%
+						</span>
+					)}
+					<span className="truncate text-gray-400">{source.snippet}</span>
+				</a>
+			))}
+		</div>
+	);
+}
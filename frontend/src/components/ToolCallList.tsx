@@ -0,0 +1,50 @@
+import { CheckCircle2, Loader2, Wrench, XCircle } from "lucide-react";
+import type { ToolInvocation } from "../types";
+
+interface ToolCallListProps {
+	calls: ToolInvocation[];
+}
+
+function formatJson(value: unknown): string {
+	return JSON.stringify(value, null, 2);
+}
+
+function StatusIcon({ call }: { call: ToolInvocation }) {
+	if (!call.done) {
+		return <Loader2 className="w-3 h-3 animate-spin text-violet-500" />;
+	}
+	if (call.error) {
+		return <XCircle className="w-3 h-3 text-red-500" />;
+	}
+	return <CheckCircle2 className="w-3 h-3 text-emerald-500" />;
+}
+
+export function ToolCallList({ calls }: ToolCallListProps) {
+	return (
+		<div className="mb-2 ml-1 flex flex-col gap-1">
+			{calls.map((call) => (
+				<details key={call.id} className="text-xs text-gray-500 max-w-md">
+					<summary className="flex items-center gap-1.5 cursor-pointer hover:text-violet-600 transition-colors">
+						<Wrench className="w-3 h-3 flex-shrink-0" />
+						<span className="font-mono font-medium">{call.name}</span>
+						<StatusIcon call={call} />
+					</summary>
+					<div className="mt-1 ml-4 flex flex-col gap-1">
+						<pre className="bg-gray-50 rounded p-2 overflow-x-auto font-mono">
+							{formatJson(call.args)}
+						</pre>
+						{call.done && (
+							<pre
+								className={`rounded p-2 overflow-x-auto font-mono ${
+									call.error ? "bg-red-50 text-red-600" : "bg-gray-50"
+								}`}
+							>
+								{call.error ?? formatJson(call.result)}
+							</pre>
+						)}
+					</div>
+				</details>
+			))}
+		</div>
+	);
+}
@@ -1,3 +1,4 @@
+export { AuthForm } from "./AuthForm";
 export { ChatInput } from "./ChatInput";
 export { EmptyState } from "./EmptyState";
 export { ErrorBanner } from "./ErrorBanner";
@@ -6,3 +7,5 @@ export { Header } from "./Header";
 export { LoadingIndicator } from "./LoadingIndicator";
 export { MessageBubble } from "./MessageBubble";
 export { ModelSelector } from "./ModelSelector";
+export { SourceList } from "./SourceList";
+export { ToolCallList } from "./ToolCallList";
@@ -5,18 +5,20 @@ import type {
 	Message,
 	ModelType,
 	ServerMessage,
+	Source,
+	TokenUsage,
+	ToolInvocation,
 	WsOutgoingMessage,
 } from "../types";
 import {
 	API_CONFIG,
 	checkBackendHealth,
 	generateId,
-	getUserId,
 	loadMessages,
 	saveMessages,
 } from "../utils";
 
-export function useWebSocket() {
+export function useWebSocket(token: string) {
 	const [socket, setSocket] = useState<WebSocket | null>(null);
 	const [connectionStatus, setConnectionStatus] =
 		useState<ConnectionStatus>("connecting");
@@ -29,14 +31,14 @@ export function useWebSocket() {
 	const isConnectingRef = useRef(false);
 	const reconnectTimeoutRef = useRef<number | undefined>(undefined);
 	const socketRef = useRef<WebSocket | null>(null);
-	const userIdRef = useRef<string>(getUserId());
+	// 正在流式接收的回复 / 思考消息 ID
+	const streamingIdRef = useRef<string | null>(null);
+	const thinkingIdRef = useRef<string | null>(null);
 
 	// 发送消息到 WebSocket
 	const sendMessage = useCallback((message: WsOutgoingMessage) => {
 		if (socketRef.current?.readyState === WebSocket.OPEN) {
-			// 添加用户 ID 到消息
-			const messageWithUserId = { ...message, user_id: userIdRef.current };
-			socketRef.current.send(JSON.stringify(messageWithUserId));
+			socketRef.current.send(JSON.stringify(message));
 		}
 	}, []);
 
@@ -55,6 +57,100 @@ export function useWebSocket() {
 		[],
 	);
 
+	// 追加流式片段到指定消息，不存在时创建
+	const appendDelta = useCallback(
+		(
+			idRef: { current: string | null },
+			type: "model" | "thinking",
+			delta: string,
+		) => {
+			setMessages((prev) => {
+				if (idRef.current) {
+					return prev.map((msg) =>
+						msg.id === idRef.current
+							? { ...msg, content: msg.content + delta }
+							: msg,
+					);
+				}
+				const id = generateId();
+				idRef.current = id;
+				return [...prev, { id, type, content: delta, timestamp: Date.now() }];
+			});
+		},
+		[],
+	);
+
+	// 更新正在流式接收的回复中的工具调用，回复消息不存在时创建
+	const updateToolCalls = useCallback(
+		(update: (calls: ToolInvocation[]) => ToolInvocation[]) => {
+			setMessages((prev) => {
+				if (streamingIdRef.current) {
+					return prev.map((msg) =>
+						msg.id === streamingIdRef.current
+							? { ...msg, toolCalls: update(msg.toolCalls ?? []) }
+							: msg,
+					);
+				}
+				const id = generateId();
+				streamingIdRef.current = id;
+				return [
+					...prev,
+					{
+						id,
+						type: "model",
+						content: "",
+						toolCalls: update([]),
+						timestamp: Date.now(),
+					},
+				];
+			});
+		},
+		[],
+	);
+
+	// 流式回复结束，写入完整内容
+	const finishStream = useCallback(
+		(
+			content: string,
+			thinking: string | null,
+			model: string,
+			usage?: TokenUsage,
+			sources?: Source[],
+		) => {
+			const streamingId = streamingIdRef.current;
+			const thinkingId = thinkingIdRef.current;
+			streamingIdRef.current = null;
+			thinkingIdRef.current = null;
+
+			setMessages((prev) => {
+				let newMessages: Message[] = prev.map((msg) => {
+					if (msg.id === streamingId)
+						return { ...msg, content, model, usage, sources };
+					if (msg.id === thinkingId && thinking)
+						return { ...msg, content: thinking };
+					return msg;
+				});
+				if (!streamingId) {
+					newMessages = [
+						...newMessages,
+						{
+							id: generateId(),
+							type: "model",
+							content,
+							model,
+							usage,
+							sources,
+							timestamp: Date.now(),
+						},
+					];
+				}
+				saveMessages(newMessages);
+				return newMessages;
+			});
+		},
+		[],
+	);
+
 	// 清除聊天记录
 	const clearChat = useCallback(() => {
 		setMessages([]);
@@ -69,7 +165,10 @@ export function useWebSocket() {
 		setConnectionStatus("connecting");
 		setError("");
 
-		const ws = new WebSocket(API_CONFIG.WS_URL);
+		// 浏览器无法为 WebSocket 设置请求头，令牌放在查询参数中
+		const ws = new WebSocket(
+			`${API_CONFIG.WS_URL}?token=${encodeURIComponent(token)}`,
+		);
 		socketRef.current = ws;
 
 		ws.onopen = () => {
@@ -89,6 +188,8 @@ export function useWebSocket() {
 							type: "model",
 							content: serverMsg.data.content,
 							model: serverMsg.data.model,
+							usage: serverMsg.data.usage,
+							sources: serverMsg.data.sources,
 						});
 						break;
 					case "thinking":
@@ -104,11 +205,48 @@ export function useWebSocket() {
 						});
 						break;
 					case "error":
+						streamingIdRef.current = null;
+						thinkingIdRef.current = null;
 						setError(serverMsg.data.content);
 						break;
 					case "loading":
 						setIsLoading(serverMsg.data.is_loading);
 						break;
+					case "response_delta":
+						appendDelta(streamingIdRef, "model", serverMsg.data.content);
+						break;
+					case "thinking_delta":
+						appendDelta(thinkingIdRef, "thinking", serverMsg.data.content);
+						break;
+					case "context":
+						setFileContexts(serverMsg.data.files);
+						break;
+					case "tool_call": {
+						const { id, name, args } = serverMsg.data;
+						updateToolCalls((calls) => [
+							...calls,
+							{ id, name, args, done: false },
+						]);
+						break;
+					}
+					case "tool_result": {
+						const { id, result, error } = serverMsg.data;
+						updateToolCalls((calls) =>
+							calls.map((call) =>
+								call.id === id ? { ...call, done: true, result, error } : call,
+							),
+						);
+						break;
+					}
+					case "response_done":
+						finishStream(
+							serverMsg.data.content,
+							serverMsg.data.thinking,
+							serverMsg.data.model,
+							serverMsg.data.usage,
+							serverMsg.data.sources,
+						);
+						break;
 				}
 			} catch {
 				// 兼容旧格式
@@ -145,7 +283,7 @@ export function useWebSocket() {
 				});
 			}, 3000);
 		};
-	}, [addMessage]);
+	}, [token, addMessage, appendDelta, updateToolCalls, finishStream]);
 
 	// 发送聊天消息
 	const sendChat = useCallback(
@@ -153,14 +291,9 @@ export function useWebSocket() {
 			if (!content.trim()) return;
 
 			addMessage({ type: "user", content });
-			sendMessage({ type: "chat", data: { content } });
-
-			// 发送消息后清除文件上下文
-			if (fileContexts.length > 0) {
-				setFileContexts([]);
-			}
+			sendMessage({ type: "chat", data: { content, stream: true } });
 		},
-		[addMessage, sendMessage, fileContexts.length],
+		[addMessage, sendMessage],
 	);
 
 	// 设置文件上下文
@@ -170,9 +303,7 @@ export function useWebSocket() {
 			if (files.length > 0) {
 				sendMessage({
 					type: "set_context",
-					data: {
-						files: files.map((f) => ({ name: f.name, content: f.content })),
-					},
+					data: { file_ids: files.map((file) => file.id) },
 				});
 			}
 		},
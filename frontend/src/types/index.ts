@@ -4,14 +4,58 @@ export interface Message {
 	type: "user" | "model" | "system" | "thinking";
 	content: string;
 	model?: string;
+	usage?: TokenUsage;
+	sources?: Source[];
+	toolCalls?: ToolInvocation[];
 	timestamp: number;
 }
 
-// 文件上下文
+// 回复过程中模型调用的服务端工具，result / error 在执行完成后填入
+export interface ToolInvocation {
+	id: string;
+	name: string;
+	args: unknown;
+	done: boolean;
+	result?: unknown;
+	error?: string;
+}
+
+// 回复引用的检索结果，index 与回复中的 [1] 对应
+export type Source = {
+	index: number;
+	score: number | null;
+	snippet: string;
+} & (
+	| {
+			kind: "message";
+			message_id: number;
+			conversation_id: number;
+			role: string;
+	  }
+	| {
+			kind: "file_chunk";
+			file_id: number;
+			file_name: string;
+			start_line: number;
+			end_line: number;
+	  }
+);
+
+// 模型回复的 token 用量
+export interface TokenUsage {
+	prompt_tokens: number;
+	candidate_tokens: number;
+	thought_tokens: number;
+}
+
+// 文件上下文：服务器文件库中的文件，通过 id 引用
 export interface FileContext {
+	id: number;
 	name: string;
-	content: string;
+	mime_type: string;
 	size: number;
+	sha256: string;
+	created_at: string;
 }
 
 // 连接状态
@@ -51,12 +95,12 @@ export const MODELS: ModelInfo[] = [
 // WebSocket 消息类型
 export interface WsChatMessage {
 	type: "chat";
-	data: { content: string };
+	data: { content: string; stream?: boolean };
 }
 
 export interface WsSetContextMessage {
 	type: "set_context";
-	data: { files: { name: string; content: string }[] };
+	data: { file_ids: number[] };
 }
 
 export interface WsSwitchModelMessage {
@@ -77,7 +121,12 @@ export type WsOutgoingMessage =
 // 服务器响应消息
 export interface ServerResponseMessage {
 	type: "response";
-	data: { content: string; model: string };
+	data: {
+		content: string;
+		model: string;
+		usage?: TokenUsage;
+		sources?: Source[];
+	};
 }
 
 export interface ServerThinkingMessage {
@@ -92,7 +141,13 @@ export interface ServerSystemMessage {
 
 export interface ServerErrorMessage {
 	type: "error";
-	data: { content: string };
+	data: { content: string; retry_after?: number }; // retry_after: 被限流时建议等待的秒数
+}
+
+// 当前会话保存的文件上下文（设置上下文、切换会话后发送）
+export interface ServerContextMessage {
+	type: "context";
+	data: { conversation_id: number | null; files: FileContext[] };
 }
 
 export interface ServerLoadingMessage {
@@ -100,9 +155,46 @@ export interface ServerLoadingMessage {
 	data: { is_loading: boolean };
 }
 
+export interface ServerResponseDeltaMessage {
+	type: "response_delta";
+	data: { content: string };
+}
+
+export interface ServerThinkingDeltaMessage {
+	type: "thinking_delta";
+	data: { content: string };
+}
+
+export interface ServerResponseDoneMessage {
+	type: "response_done";
+	data: {
+		content: string;
+		thinking: string | null;
+		model: string;
+		usage?: TokenUsage;
+		sources?: Source[];
+	};
+}
+
+export interface ServerToolCallMessage {
+	type: "tool_call";
+	data: { id: string; name: string; args: unknown };
+}
+
+export interface ServerToolResultMessage {
+	type: "tool_result";
+	data: { id: string; name: string; result?: unknown; error?: string };
+}
+
 export type ServerMessage =
 	| ServerResponseMessage
 	| ServerThinkingMessage
 	| ServerSystemMessage
 	| ServerErrorMessage
-	| ServerLoadingMessage;
+	| ServerLoadingMessage
+	| ServerContextMessage
+	| ServerResponseDeltaMessage
+	| ServerThinkingDeltaMessage
+	| ServerResponseDoneMessage
+	| ServerToolCallMessage
+	| ServerToolResultMessage;
@@ -1,3 +1,6 @@
+import type { FileContext, Source } from "../types";
+import { getAuthToken } from "./storage";
+
 // API 配置
 const getApiConfig = () => {
 	// 判断是否为开发环境
@@ -15,11 +18,22 @@ const getApiConfig = () => {
 		API_URL: `${baseUrl}/api`,
 		HEALTH_URL: `${baseUrl}/api/health`,
 		UPLOAD_URL: `${baseUrl}/api/upload`,
+		AUTH_URL: `${baseUrl}/api/auth`,
 	};
 };
 
 export const API_CONFIG = getApiConfig();
 
+// 引用来源的原文地址：历史消息定位到所在会话的该条消息，文件片段下载原文件
+// 浏览器打开链接时无法带请求头，令牌放在查询参数中
+export function sourceUrl(source: Source): string {
+	const token = encodeURIComponent(getAuthToken() ?? "");
+	if (source.kind === "message") {
+		return `${API_CONFIG.API_URL}/conversations/${source.conversation_id}/messages?before_id=${source.message_id + 1}&limit=1&token=${token}`;
+	}
+	return `${API_CONFIG.API_URL}/files/${source.file_id}?token=${token}`;
+}
+
 // 生成唯一 ID
 export function generateId(): string {
 	return `${Date.now()}-${Math.random().toString(36).substring(2, 9)}`;
@@ -45,7 +59,7 @@ export async function checkBackendHealth(): Promise<boolean> {
 // 上传文件
 export async function uploadFiles(files: FileList): Promise<{
 	success: boolean;
-	files?: { name: string; content: string; size: number }[];
+	files?: FileContext[];
 	error?: string;
 }> {
 	const formData = new FormData();
@@ -54,8 +68,10 @@ export async function uploadFiles(files: FileList): Promise<{
 	}
 
 	try {
+		const token = getAuthToken();
 		const response = await fetch(API_CONFIG.UPLOAD_URL, {
 			method: "POST",
+			headers: token ? { Authorization: `Bearer ${token}` } : undefined,
 			body: formData,
 		});
 		const result = await response.json();
@@ -73,10 +89,57 @@ export async function uploadFiles(files: FileList): Promise<{
 	}
 }
 
+// 注册或登录，成功时返回会话令牌
+export async function authenticate(
+	mode: "login" | "signup",
+	username: string,
+	password: string,
+): Promise<{ success: boolean; token?: string; error?: string }> {
+	const body = { username, password };
+
+	try {
+		const response = await fetch(`${API_CONFIG.AUTH_URL}/${mode}`, {
+			method: "POST",
+			headers: { "Content-Type": "application/json" },
+			body: JSON.stringify(body),
+		});
+		const result = await response.json();
+
+		if (!response.ok) {
+			return {
+				success: false,
+				error: result.error || (mode === "login" ? "登录失败" : "注册失败"),
+			};
+		}
+
+		return { success: true, token: result.token };
+	} catch (err) {
+		return {
+			success: false,
+			error: err instanceof Error ? err.message : "请求失败",
+		};
+	}
+}
+
+// 检查会话令牌是否仍然有效
+export async function checkAuthToken(token: string): Promise<boolean> {
+	try {
+		const response = await fetch(`${API_CONFIG.AUTH_URL}/me`, {
+			headers: { Authorization: `Bearer ${token}` },
+		});
+		return response.status !== 401;
+	} catch {
+		// 网络错误时交给 WebSocket 重连逻辑处理
+		return true;
+	}
+}
+
 // 导出存储相关函数
 export {
+	clearAuthToken,
 	clearMessages,
-	getUserId,
+	getAuthToken,
 	loadMessages,
+	saveAuthToken,
 	saveMessages,
 } from "./storage";
@@ -2,30 +2,33 @@ import type { Message } from "../types";
 
 const STORAGE_KEYS = {
 	MESSAGES: "web_chat_messages",
-	USER_ID: "web_chat_user_id",
+	AUTH_TOKEN: "web_chat_auth_token",
 } as const;
 
-// 生成 UUID v4
-function generateUUID(): string {
-	return "xxxxxxxx-xxxx-4xxx-yxxx-xxxxxxxxxxxx".replace(/[xy]/g, (c) => {
-		const r = (Math.random() * 16) | 0;
-		const v = c === "x" ? r : (r & 0x3) | 0x8;
-		return v.toString(16);
-	});
+// 获取会话令牌
+export function getAuthToken(): string | null {
+	try {
+		return localStorage.getItem(STORAGE_KEYS.AUTH_TOKEN);
+	} catch {
+		return null;
+	}
 }
 
-// 获取或创建用户 ID
-export function getUserId(): string {
+// 保存会话令牌
+export function saveAuthToken(token: string): void {
 	try {
-		let userId = localStorage.getItem(STORAGE_KEYS.USER_ID);
-		if (!userId) {
-			userId = generateUUID();
-			localStorage.setItem(STORAGE_KEYS.USER_ID, userId);
-		}
-		return userId;
+		localStorage.setItem(STORAGE_KEYS.AUTH_TOKEN, token);
+	} catch (e) {
+		console.error("保存登录状态失败:", e);
+	}
+}
+
+// 清除会话令牌
+export function clearAuthToken(): void {
+	try {
+		localStorage.removeItem(STORAGE_KEYS.AUTH_TOKEN);
 	} catch (e) {
-		console.error("获取用户ID失败:", e);
-		return generateUUID();
+		console.error("清除登录状态失败:", e);
 	}
 }
 
@@ -0,0 +1,191 @@
+use actix_web::dev::Payload;
+use actix_web::error::InternalError;
+use actix_web::http::header;
+use actix_web::{Error, FromRequest, HttpRequest, HttpResponse, Responder, delete, get, post, web};
+use serde_json::json;
+use std::future::{Ready, ready};
+use std::sync::Arc;
+
+use super::rate_limit::{client_key, too_many_requests};
+use crate::models::auth::{CreateApiTokenRequest, Credentials, Scope, SignupRequest, UserInfo};
+use crate::services::auth::{self, SessionManager};
+use crate::services::memory::ChatMemory;
+use crate::services::rate_limit::{RateAction, RateLimiter};
+
+/// 已认证的用户（从会话令牌或 API 令牌中解析）
+///
+/// 令牌优先从 `Authorization: Bearer` 请求头读取；浏览器建立 WebSocket
+/// 连接时无法设置请求头，因此也接受 `token` 查询参数。
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub user_id: String,
+    /// 令牌的权限范围（登录会话拥有全部权限）
+    pub scopes: Vec<Scope>,
+}
+
+impl AuthUser {
+    fn from_request_sync(req: &HttpRequest) -> Result<Self, String> {
+        let sessions = req
+            .app_data::<web::Data<Arc<SessionManager>>>()
+            .ok_or("服务器未配置会话")?;
+        let memory = req
+            .app_data::<web::Data<Arc<ChatMemory>>>()
+            .ok_or("服务器未配置数据库")?;
+        let token = request_token(req).ok_or("未登录")?;
+        let (user_id, scopes) = auth::authenticate(memory, sessions, &token)?;
+        Ok(Self { user_id, scopes })
+    }
+
+    /// 检查权限，不足时返回 403 响应
+    pub fn require(&self, scope: Scope) -> Result<(), HttpResponse> {
+        if scope.granted_by(&self.scopes) {
+            Ok(())
+        } else {
+            Err(HttpResponse::Forbidden().json(json!({
+                "error": format!("令牌缺少 {} 权限", scope.as_str())
+            })))
+        }
+    }
+}
+
+impl FromRequest for AuthUser {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Self::from_request_sync(req).map_err(|e| {
+            InternalError::from_response(
+                e.clone(),
+                HttpResponse::Unauthorized().json(json!({ "error": e })),
+            )
+            .into()
+        }))
+    }
+}
+
+/// 从请求头或查询参数中取出令牌
+fn request_token(req: &HttpRequest) -> Option<String> {
+    if let Some(value) = req.headers().get(header::AUTHORIZATION)
+        && let Ok(value) = value.to_str()
+        && let Some(token) = value.strip_prefix("Bearer ")
+    {
+        return Some(token.trim().to_string());
+    }
+
+    web::Query::<std::collections::HashMap<String, String>>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.get("token").cloned())
+}
+
+/// 注册新用户
+#[post("/api/auth/signup")]
+pub async fn signup(
+    req: HttpRequest,
+    request: web::Json<SignupRequest>,
+    memory: web::Data<Arc<ChatMemory>>,
+    sessions: web::Data<Arc<SessionManager>>,
+    limiter: web::Data<Arc<RateLimiter>>,
+) -> impl Responder {
+    if let Err(limited) = limiter.check(RateAction::Auth, &client_key(&req, None)) {
+        return too_many_requests(limited);
+    }
+
+    let memory = memory.get_ref().clone();
+    let sessions = sessions.get_ref().clone();
+    // argon2 计算较慢，放到阻塞线程池执行
+    let result = web::block(move || auth::signup(&memory, &sessions, request.into_inner())).await;
+
+    match result {
+        Ok(Ok(response)) => HttpResponse::Ok().json(response),
+        Ok(Err(e)) => HttpResponse::BadRequest().json(json!({ "error": e })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "error": e.to_string() })),
+    }
+}
+
+/// 用户名密码登录
+#[post("/api/auth/login")]
+pub async fn login(
+    req: HttpRequest,
+    request: web::Json<Credentials>,
+    memory: web::Data<Arc<ChatMemory>>,
+    sessions: web::Data<Arc<SessionManager>>,
+    limiter: web::Data<Arc<RateLimiter>>,
+) -> impl Responder {
+    // 按 IP 限流，减缓密码暴力破解
+    if let Err(limited) = limiter.check(RateAction::Auth, &client_key(&req, None)) {
+        return too_many_requests(limited);
+    }
+
+    let memory = memory.get_ref().clone();
+    let sessions = sessions.get_ref().clone();
+    let result = web::block(move || auth::login(&memory, &sessions, request.into_inner())).await;
+
+    match result {
+        Ok(Ok(response)) => HttpResponse::Ok().json(response),
+        Ok(Err(e)) => HttpResponse::Unauthorized().json(json!({ "error": e })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "error": e.to_string() })),
+    }
+}
+
+/// 获取当前登录用户
+#[get("/api/auth/me")]
+pub async fn me(user: AuthUser, memory: web::Data<Arc<ChatMemory>>) -> impl Responder {
+    match memory.get_user(&user.user_id) {
+        Ok(Some(u)) => HttpResponse::Ok().json(UserInfo {
+            id: u.id,
+            username: u.username,
+        }),
+        Ok(None) => HttpResponse::Unauthorized().json(json!({ "error": "用户不存在" })),
+        Err(e) => HttpResponse::InternalServerError()
+            .json(json!({ "error": format!("查询用户失败: {}", e) })),
+    }
+}
+
+/// 创建 API 令牌（明文令牌只在响应中返回一次）
+#[post("/api/tokens")]
+pub async fn create_token(
+    user: AuthUser,
+    request: web::Json<CreateApiTokenRequest>,
+    memory: web::Data<Arc<ChatMemory>>,
+) -> impl Responder {
+    if let Err(response) = user.require(Scope::Admin) {
+        return response;
+    }
+
+    match auth::create_api_token(memory.get_ref(), &user.user_id, request.into_inner()) {
+        Ok(created) => HttpResponse::Ok().json(created),
+        Err(e) => HttpResponse::BadRequest().json(json!({ "error": e })),
+    }
+}
+
+/// 列出当前用户的 API 令牌
+#[get("/api/tokens")]
+pub async fn list_tokens(user: AuthUser, memory: web::Data<Arc<ChatMemory>>) -> impl Responder {
+    if let Err(response) = user.require(Scope::Admin) {
+        return response;
+    }
+
+    match auth::list_api_tokens(memory.get_ref(), &user.user_id) {
+        Ok(tokens) => HttpResponse::Ok().json(json!({ "tokens": tokens })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "error": e })),
+    }
+}
+
+/// 吊销 API 令牌
+#[delete("/api/tokens/{token_id}")]
+pub async fn revoke_token(
+    path: web::Path<i64>,
+    user: AuthUser,
+    memory: web::Data<Arc<ChatMemory>>,
+) -> impl Responder {
+    if let Err(response) = user.require(Scope::Admin) {
+        return response;
+    }
+
+    match memory.delete_api_token(&user.user_id, path.into_inner()) {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().json(json!({ "error": "令牌不存在" })),
+        Err(e) => HttpResponse::InternalServerError()
+            .json(json!({ "error": format!("吊销令牌失败: {}", e) })),
+    }
+}
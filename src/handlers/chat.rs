@@ -0,0 +1,235 @@
+use actix_web::http::header;
+use actix_web::web::Bytes;
+use actix_web::{HttpRequest, HttpResponse, Responder, post, web};
+use serde::Serialize;
+use serde_json::json;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+use super::auth::AuthUser;
+use super::rate_limit::{client_key, too_many_requests};
+use crate::models::auth::Scope;
+use crate::models::gemini::GeminiModel;
+use crate::models::messages::{
+    ChatApiRequest, ChatApiResponse, ToolCallMessage, ToolResultMessage,
+};
+use crate::services::budget::BudgetConfig;
+use crate::services::chat::{self, ChatInput, ChatOutcome};
+use crate::services::embedding::EmbeddingProvider;
+use crate::services::files;
+use crate::services::memory::ChatMemory;
+use crate::services::provider::{ChatProvider, StreamDelta};
+use crate::services::rate_limit::RateLimiter;
+use crate::services::tools::ToolRegistry;
+
+/// 无状态的聊天接口
+///
+/// 与 WebSocket 共用检索 → 构建对话 → 调用模型 → 保存回复的流程。
+/// 请求体中 `stream` 为 true 或 `Accept: text/event-stream` 时以 SSE 返回：
+/// 依次发送 `conversation`、若干 `thinking` / `delta` / `tool_call` / `tool_result`，
+/// 最后是 `done` 或 `error` 事件。
+#[post("/api/chat")]
+#[allow(clippy::too_many_arguments)]
+pub async fn send_chat(
+    req: HttpRequest,
+    user: AuthUser,
+    request: web::Json<ChatApiRequest>,
+    memory: web::Data<Arc<ChatMemory>>,
+    provider: web::Data<Arc<dyn ChatProvider>>,
+    embedder: web::Data<Arc<dyn EmbeddingProvider>>,
+    limiter: web::Data<Arc<RateLimiter>>,
+    budget: web::Data<Arc<BudgetConfig>>,
+    tools: web::Data<Arc<ToolRegistry>>,
+) -> impl Responder {
+    if let Err(response) = user.require(Scope::Chat) {
+        return response;
+    }
+
+    let request = request.into_inner();
+    if request.message.trim().is_empty() {
+        return HttpResponse::BadRequest().json(json!({ "error": "消息不能为空" }));
+    }
+
+    let rate_key = client_key(&req, Some(&user));
+    if let Err(limited) = limiter.check_chat(&rate_key) {
+        return too_many_requests(limited);
+    }
+
+    // 继续已有会话时沿用其模型，否则新建会话
+    let (conversation_id, model) = match request.conversation_id {
+        Some(conversation_id) => match memory.get_conversation(&user.user_id, conversation_id) {
+            Ok(Some(conversation)) => {
+                let model = request
+                    .model
+                    .as_deref()
+                    .or(conversation.model.as_deref())
+                    .map(GeminiModel::from_str)
+                    .unwrap_or(GeminiModel::Flash);
+                (conversation.id, model)
+            }
+            Ok(None) => return HttpResponse::NotFound().json(json!({ "error": "会话不存在" })),
+            Err(e) => {
+                return HttpResponse::InternalServerError()
+                    .json(json!({ "error": format!("获取会话失败: {}", e) }));
+            }
+        },
+        None => {
+            let model = request
+                .model
+                .as_deref()
+                .map(GeminiModel::from_str)
+                .unwrap_or(GeminiModel::Flash);
+            match chat::start_conversation(&memory, &user.user_id, &request.message, model) {
+                Ok(conversation) => (conversation.id, model),
+                Err(e) => return HttpResponse::InternalServerError().json(json!({ "error": e })),
+            }
+        }
+    };
+
+    // 指定的文件库文件保存为会话的上下文，未指定时沿用会话已保存的文件
+    let file_ids =
+        match files::conversation_files(&memory, &user.user_id, conversation_id, &request.file_ids)
+        {
+            Ok(stored) => stored.iter().map(|f| f.id).collect(),
+            Err(e) => return HttpResponse::InternalServerError().json(json!({ "error": e })),
+        };
+
+    let stream = request.stream || accepts_event_stream(&req);
+    let display_name = provider.display_name(model);
+    let input = ChatInput {
+        user_id: user.user_id,
+        conversation_id,
+        search_all_conversations: request.search_all_conversations,
+        content: request.message,
+        file_contexts: request.files,
+        file_ids,
+        model,
+        scopes: user.scopes,
+    };
+    let memory = memory.get_ref().clone();
+    let provider = provider.get_ref().clone();
+    let embedder = embedder.get_ref().clone();
+    let limiter = limiter.get_ref().clone();
+    let budget = budget.get_ref().clone();
+    let tools = tools.get_ref().clone();
+
+    if !stream {
+        let outcome = chat::run_chat(
+            memory,
+            provider.as_ref(),
+            embedder,
+            &budget,
+            &tools,
+            input,
+            None,
+        )
+        .await;
+        return match outcome {
+            Ok(outcome) => {
+                limiter.record_tokens(&rate_key, outcome.tokens);
+                HttpResponse::Ok().json(chat_response(conversation_id, outcome, display_name))
+            }
+            Err(e) => HttpResponse::BadGateway().json(json!({ "error": e })),
+        };
+    }
+
+    // 流式模式：后台任务把事件写入通道，响应体从通道读取
+    // 客户端中途断开时任务继续运行，保证回复仍被保存
+    let (tx, rx) = mpsc::unbounded_channel::<Bytes>();
+    let _ = tx.send(sse_event(
+        "conversation",
+        &json!({ "conversation_id": conversation_id }),
+    ));
+
+    actix_web::rt::spawn(async move {
+        let delta_tx = tx.clone();
+        let mut on_delta = |delta| {
+            let event = match delta {
+                StreamDelta::Response(content) => {
+                    sse_event("delta", &json!({ "content": content }))
+                }
+                StreamDelta::Thinking(content) => {
+                    sse_event("thinking", &json!({ "content": content }))
+                }
+                StreamDelta::ToolCall { id, call } => sse_event(
+                    "tool_call",
+                    &ToolCallMessage {
+                        id,
+                        name: call.name,
+                        args: call.args,
+                    },
+                ),
+                StreamDelta::ToolResult { id, result } => sse_event(
+                    "tool_result",
+                    &ToolResultMessage::new(id, result.name, result.output),
+                ),
+            };
+            let _ = delta_tx.send(event);
+        };
+        let outcome = chat::run_chat(
+            memory,
+            provider.as_ref(),
+            embedder,
+            &budget,
+            &tools,
+            input,
+            Some(&mut on_delta),
+        )
+        .await;
+
+        let event = match outcome {
+            Ok(outcome) => {
+                limiter.record_tokens(&rate_key, outcome.tokens);
+                sse_event(
+                    "done",
+                    &chat_response(conversation_id, outcome, display_name),
+                )
+            }
+            Err(e) => sse_event("error", &json!({ "error": e })),
+        };
+        let _ = tx.send(event);
+    });
+
+    event_stream_response(rx)
+}
+
+/// 以 `text/event-stream` 返回通道中的事件，发送端全部关闭时结束
+pub(super) fn event_stream_response(rx: mpsc::UnboundedReceiver<Bytes>) -> HttpResponse {
+    let body = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv()
+            .await
+            .map(|event| (Ok::<_, Infallible>(event), rx))
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(body)
+}
+
+fn accepts_event_stream(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("text/event-stream"))
+}
+
+fn chat_response(conversation_id: i64, outcome: ChatOutcome, model: String) -> ChatApiResponse {
+    ChatApiResponse {
+        conversation_id,
+        user_message_id: outcome.user_message_id,
+        message_id: outcome.reply_message_id,
+        content: outcome.result.response,
+        thinking: outcome.result.thinking,
+        model,
+        usage: outcome.result.usage,
+        sources: outcome.sources,
+    }
+}
+
+/// 编码一条 SSE 事件
+fn sse_event(event: &str, data: &impl Serialize) -> Bytes {
+    let data = serde_json::to_string(data).unwrap_or_default();
+    Bytes::from(format!("event: {}\ndata: {}\n\n", event, data))
+}
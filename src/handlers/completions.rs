@@ -0,0 +1,205 @@
+use actix_web::http::header;
+use actix_web::web::Bytes;
+use actix_web::{HttpRequest, HttpResponse, Responder, get, post, web};
+use serde::Serialize;
+use serde_json::json;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+use super::auth::AuthUser;
+use super::chat::event_stream_response;
+use super::rate_limit::client_key;
+use crate::models::auth::Scope;
+use crate::models::completions::{CompletionDelta, CompletionRequest};
+use crate::models::gemini::GeminiModel;
+use crate::services::budget::{BudgetConfig, estimate_prompt_tokens};
+use crate::services::chat;
+use crate::services::completions;
+use crate::services::embedding::EmbeddingProvider;
+use crate::services::memory::ChatMemory;
+use crate::services::provider::{ChatProvider, ChatRequest, ChatResult, StreamDelta};
+use crate::services::rate_limit::{RateLimiter, result_tokens};
+
+/// OpenAI 格式的错误响应
+fn openai_error(status: actix_web::http::StatusCode, kind: &str, message: String) -> HttpResponse {
+    HttpResponse::build(status).json(json!({
+        "error": { "message": message, "type": kind, "param": null, "code": null }
+    }))
+}
+
+/// OpenAI 兼容的模型列表
+#[get("/v1/models")]
+pub async fn list_models(_user: AuthUser) -> impl Responder {
+    HttpResponse::Ok().json(json!({
+        "object": "list",
+        "data": completions::model_list(),
+    }))
+}
+
+/// OpenAI 兼容的对话补全接口
+///
+/// 使用 API 令牌作为 `Authorization: Bearer` 密钥，需要 chat 权限；
+/// 扩展字段 `memory: true` 会检索用户的聊天记忆（还需要 read-history 权限）。
+/// 该接口不保存消息，对话历史由客户端在 `messages` 中提供；token 用量单独记录到用量报告。
+#[post("/v1/chat/completions")]
+#[allow(clippy::too_many_arguments)]
+pub async fn chat_completions(
+    req: HttpRequest,
+    user: AuthUser,
+    request: web::Json<CompletionRequest>,
+    memory: web::Data<Arc<ChatMemory>>,
+    provider: web::Data<Arc<dyn ChatProvider>>,
+    embedder: web::Data<Arc<dyn EmbeddingProvider>>,
+    limiter: web::Data<Arc<RateLimiter>>,
+    budget: web::Data<Arc<BudgetConfig>>,
+) -> impl Responder {
+    use actix_web::http::StatusCode;
+
+    if let Err(response) = user.require(Scope::Chat) {
+        return response;
+    }
+    if request.memory
+        && let Err(response) = user.require(Scope::ReadHistory)
+    {
+        return response;
+    }
+
+    let Some(model) = GeminiModel::parse(&request.model) else {
+        return openai_error(
+            StatusCode::NOT_FOUND,
+            "invalid_request_error",
+            format!("模型 {} 不存在", request.model),
+        );
+    };
+
+    let mut prompt = match completions::build_prompt(&request.messages) {
+        Ok(prompt) => prompt,
+        Err(e) => return openai_error(StatusCode::BAD_REQUEST, "invalid_request_error", e),
+    };
+
+    // 历史由客户端提供，超出上下文预算时直接拒绝而不是静默截断
+    let limit = budget.prompt_budget(model);
+    let estimated = estimate_prompt_tokens(&prompt);
+    if estimated > limit {
+        return openai_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!(
+                "消息约 {} tokens，超出模型的上下文预算 {} tokens",
+                estimated, limit
+            ),
+        );
+    }
+
+    let rate_key = client_key(&req, Some(&user));
+    if let Err(limited) = limiter.check_chat(&rate_key) {
+        let mut response = openai_error(
+            StatusCode::TOO_MANY_REQUESTS,
+            "rate_limit_exceeded",
+            limited.message,
+        );
+        if let Ok(value) = header::HeaderValue::from_str(&limited.retry_after.to_string()) {
+            response.headers_mut().insert(header::RETRY_AFTER, value);
+        }
+        return response;
+    }
+
+    // 用最后一条用户消息检索记忆，放在客户端系统指令之前
+    if request.memory
+        && let Some(query) = prompt.turns.last().map(|t| t.content.clone())
+        && let Some(context) =
+            chat::recall_context(&memory, embedder.get_ref().as_ref(), &user.user_id, &query).await
+    {
+        prompt.system_instruction = Some(match prompt.system_instruction.take() {
+            Some(system) => format!("{}{}", context, system),
+            None => context,
+        });
+    }
+
+    let id = completions::completion_id();
+    let prompt_tokens = estimate_prompt_tokens(&prompt);
+    let chat_request = ChatRequest { prompt, model };
+    let limiter = limiter.get_ref().clone();
+    let memory = memory.get_ref().clone();
+    let user_id = user.user_id.clone();
+
+    if !request.stream {
+        return match provider.chat(chat_request).await {
+            Ok(result) => {
+                limiter.record_tokens(&rate_key, result_tokens(prompt_tokens, &result));
+                record_usage(&memory, &user_id, model, &result);
+                HttpResponse::Ok().json(completions::completion_response(id, model, result))
+            }
+            Err(e) => openai_error(StatusCode::BAD_GATEWAY, "api_error", e),
+        };
+    }
+
+    let (tx, rx) = mpsc::unbounded_channel::<Bytes>();
+    let _ = tx.send(sse_data(&completions::completion_chunk(
+        &id,
+        model,
+        CompletionDelta {
+            role: Some("assistant"),
+            ..CompletionDelta::default()
+        },
+        None,
+    )));
+
+    let provider = provider.get_ref().clone();
+    actix_web::rt::spawn(async move {
+        let delta_tx = tx.clone();
+        let delta_id = id.clone();
+        let mut on_delta = |delta| {
+            let delta = match delta {
+                StreamDelta::Response(content) => CompletionDelta {
+                    content: Some(content),
+                    ..CompletionDelta::default()
+                },
+                StreamDelta::Thinking(content) => CompletionDelta {
+                    reasoning_content: Some(content),
+                    ..CompletionDelta::default()
+                },
+                // 这里直接调用模型，不会执行工具
+                StreamDelta::ToolCall { .. } | StreamDelta::ToolResult { .. } => return,
+            };
+            let _ = delta_tx.send(sse_data(&completions::completion_chunk(
+                &delta_id, model, delta, None,
+            )));
+        };
+
+        let event = match provider.chat_stream(chat_request, &mut on_delta).await {
+            Ok(result) => {
+                limiter.record_tokens(&rate_key, result_tokens(prompt_tokens, &result));
+                record_usage(&memory, &user_id, model, &result);
+                let mut chunk = completions::completion_chunk(
+                    &id,
+                    model,
+                    CompletionDelta::default(),
+                    Some("stop"),
+                );
+                chunk.usage = result.usage.map(completions::completion_usage);
+                sse_data(&chunk)
+            }
+            Err(e) => sse_data(&json!({ "error": { "message": e, "type": "api_error" } })),
+        };
+        let _ = tx.send(event);
+        let _ = tx.send(Bytes::from_static(b"data: [DONE]\n\n"));
+    });
+
+    event_stream_response(rx)
+}
+
+/// 记录本次调用的 token 用量（后端没有返回用量时跳过）
+fn record_usage(memory: &ChatMemory, user_id: &str, model: GeminiModel, result: &ChatResult) {
+    if let Some(usage) = &result.usage
+        && let Err(e) = memory.add_usage_record(user_id, Some(model.as_str()), usage)
+    {
+        eprintln!("保存 token 用量失败: {}", e);
+    }
+}
+
+/// 编码一条只有 data 字段的 SSE 事件
+fn sse_data(data: &impl Serialize) -> Bytes {
+    let data = serde_json::to_string(data).unwrap_or_default();
+    Bytes::from(format!("data: {}\n\n", data))
+}
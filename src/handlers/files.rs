@@ -0,0 +1,98 @@
+use actix_web::http::header::{
+    Charset, ContentDisposition, DispositionParam, DispositionType, ExtendedValue,
+};
+use actix_web::{HttpResponse, Responder, delete, get, web};
+use serde_json::json;
+use std::sync::Arc;
+
+use super::auth::AuthUser;
+use crate::models::auth::Scope;
+use crate::services::files;
+use crate::services::memory::ChatMemory;
+
+/// 列出当前用户文件库中的文件
+#[get("/api/files")]
+pub async fn list_files(user: AuthUser, memory: web::Data<Arc<ChatMemory>>) -> impl Responder {
+    if let Err(response) = user.require(Scope::ReadHistory) {
+        return response;
+    }
+
+    match memory.list_files(&user.user_id) {
+        Ok(stored) => {
+            let files: Vec<_> = stored.iter().map(files::file_info).collect();
+            HttpResponse::Ok().json(json!({ "files": files }))
+        }
+        Err(e) => HttpResponse::InternalServerError()
+            .json(json!({ "error": format!("获取文件列表失败: {}", e) })),
+    }
+}
+
+/// 下载文件原始内容
+#[get("/api/files/{file_id}")]
+pub async fn download_file(
+    path: web::Path<i64>,
+    user: AuthUser,
+    memory: web::Data<Arc<ChatMemory>>,
+) -> impl Responder {
+    if let Err(response) = user.require(Scope::ReadHistory) {
+        return response;
+    }
+
+    match memory.load_files(&user.user_id, &[path.into_inner()]) {
+        Ok(mut loaded) => match loaded.pop() {
+            Some((file, data)) => HttpResponse::Ok()
+                .content_type(file.mime_type.as_str())
+                .insert_header(attachment_disposition(&file.name))
+                .body(data),
+            None => HttpResponse::NotFound().json(json!({ "error": "文件不存在" })),
+        },
+        Err(e) => HttpResponse::InternalServerError()
+            .json(json!({ "error": format!("读取文件失败: {}", e) })),
+    }
+}
+
+/// 删除文件，同时从所有会话的上下文中移除
+#[delete("/api/files/{file_id}")]
+pub async fn delete_file(
+    path: web::Path<i64>,
+    user: AuthUser,
+    memory: web::Data<Arc<ChatMemory>>,
+) -> impl Responder {
+    if let Err(response) = user.require(Scope::Chat) {
+        return response;
+    }
+
+    match memory.delete_file(&user.user_id, path.into_inner()) {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().json(json!({ "error": "文件不存在" })),
+        Err(e) => HttpResponse::InternalServerError()
+            .json(json!({ "error": format!("删除文件失败: {}", e) })),
+    }
+}
+
+/// 以附件形式下载，非 ASCII 文件名通过 `filename*` 以 UTF-8 编码
+fn attachment_disposition(name: &str) -> ContentDisposition {
+    let ascii_name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_graphic() || c == ' ' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .filter(|c| *c != '"' && *c != '\\')
+        .collect();
+
+    ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![
+            DispositionParam::Filename(ascii_name),
+            DispositionParam::FilenameExt(ExtendedValue {
+                charset: Charset::Ext("UTF-8".to_string()),
+                language_tag: None,
+                value: name.as_bytes().to_vec(),
+            }),
+        ],
+    }
+}
@@ -1,11 +1,15 @@
-use actix_web::{HttpResponse, Responder, get};
+use actix_web::{HttpResponse, Responder, get, web};
 use serde_json::json;
+use std::sync::Arc;
+
+use crate::services::backfill::BackfillStatus;
 
 #[get("/api/health")]
-pub async fn health_check() -> impl Responder {
+pub async fn health_check(backfill: web::Data<Arc<BackfillStatus>>) -> impl Responder {
     HttpResponse::Ok().json(json!({
         "status": "ok",
         "message": "Backend server is running",
-        "models": ["flash", "pro"]
+        "models": ["flash", "pro"],
+        "embedding_backfill": backfill.public_snapshot()
     }))
 }
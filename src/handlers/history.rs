@@ -0,0 +1,68 @@
+use actix_web::{HttpResponse, Responder, get, web};
+use serde_json::json;
+use std::sync::Arc;
+
+use super::auth::AuthUser;
+use crate::models::auth::Scope;
+use crate::models::messages::{GetHistoryMessage, SearchHistoryMessage};
+use crate::services::embedding::EmbeddingProvider;
+use crate::services::history;
+use crate::services::memory::ChatMemory;
+
+/// 全文搜索历史消息
+///
+/// 查询参数与 WebSocket 的 `search_history` 消息相同，需要登录
+#[get("/api/history/search")]
+pub async fn search_history(
+    user: AuthUser,
+    request: web::Query<SearchHistoryMessage>,
+    memory: web::Data<Arc<ChatMemory>>,
+    embedder: web::Data<Arc<dyn EmbeddingProvider>>,
+) -> impl Responder {
+    if let Err(response) = user.require(Scope::ReadHistory) {
+        return response;
+    }
+
+    match history::search_history(
+        memory.get_ref(),
+        embedder.get_ref().as_ref(),
+        &user.user_id,
+        request.into_inner(),
+    )
+    .await
+    {
+        Ok(results) => HttpResponse::Ok().json(results),
+        Err(e) => HttpResponse::BadRequest().json(json!({ "error": e })),
+    }
+}
+
+/// 分页获取会话的历史消息
+///
+/// 查询参数：可选的 `before_id` 和 `limit`，需要登录
+#[get("/api/conversations/{conversation_id}/messages")]
+pub async fn conversation_messages(
+    path: web::Path<i64>,
+    user: AuthUser,
+    request: web::Query<GetHistoryMessage>,
+    memory: web::Data<Arc<ChatMemory>>,
+) -> impl Responder {
+    if let Err(response) = user.require(Scope::ReadHistory) {
+        return response;
+    }
+
+    let conversation_id = path.into_inner();
+
+    match memory.get_conversation(&user.user_id, conversation_id) {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::NotFound().json(json!({ "error": "会话不存在" })),
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .json(json!({ "error": format!("获取会话失败: {}", e) }));
+        }
+    }
+
+    match history::load_history_page(memory.get_ref(), &user.user_id, conversation_id, &request) {
+        Ok(page) => HttpResponse::Ok().json(page),
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "error": e })),
+    }
+}
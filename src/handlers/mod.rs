@@ -1,3 +1,10 @@
+pub mod auth;
+pub mod chat;
+pub mod completions;
+pub mod files;
 pub mod health;
+pub mod history;
+pub mod rate_limit;
 pub mod upload;
+pub mod usage;
 pub mod websocket;
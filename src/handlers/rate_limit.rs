@@ -0,0 +1,32 @@
+use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse};
+use serde_json::json;
+
+use super::auth::AuthUser;
+use crate::services::rate_limit::{self, RateLimited};
+
+/// 限流键：已登录用户按用户 ID，否则按连接的对端 IP
+///
+/// 不信任 `X-Forwarded-For`，部署在反向代理之后时所有未登录请求共用代理的 IP。
+pub fn client_key(req: &HttpRequest, user: Option<&AuthUser>) -> String {
+    match user {
+        Some(user) => rate_limit::user_key(&user.user_id),
+        None => {
+            let ip = req
+                .peer_addr()
+                .map(|addr| addr.ip().to_string())
+                .unwrap_or_else(|| "unknown".to_string());
+            rate_limit::ip_key(&ip)
+        }
+    }
+}
+
+/// 429 响应，附带 `Retry-After` 请求头
+pub fn too_many_requests(limited: RateLimited) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header((header::RETRY_AFTER, limited.retry_after.to_string()))
+        .json(json!({
+            "error": limited.message,
+            "retry_after": limited.retry_after,
+        }))
+}
@@ -1,13 +1,41 @@
 use actix_multipart::Multipart;
-use actix_web::{post, Error, HttpResponse, Responder};
+use actix_web::{post, web, Error, HttpRequest, HttpResponse, Responder};
 use futures_util::stream::StreamExt;
-use std::str;
+use std::sync::Arc;
 
-use crate::models::messages::{UploadResponse, UploadedFile};
+use super::auth::AuthUser;
+use super::rate_limit::{client_key, too_many_requests};
+use crate::models::auth::Scope;
+use crate::models::files::FileInfo;
+use crate::models::messages::UploadResponse;
+use crate::services::attachment::MAX_UPLOAD_BYTES;
+use crate::services::files::{self, UploadError};
+use crate::services::memory::ChatMemory;
+use crate::services::provider::ChatProvider;
+use crate::services::rate_limit::{RateAction, RateLimiter};
 
+/// 上传文件到用户的文件库（需要 chat 权限），返回文件 ID 供 `set_context` 引用
+///
+/// 支持文本文件、图片、PDF 和音频，相同内容重复上传时返回已有文件。
+/// 超过内联上限的二进制文件同时上传到模型后端的文件存储（Gemini Files API），
+/// 文本文件切分为片段，嵌入由后台任务限速生成，聊天时只检索相关片段。
 #[post("/api/upload")]
-pub async fn upload_file(mut payload: Multipart) -> Result<impl Responder, Error> {
-    let mut uploaded_files: Vec<UploadedFile> = Vec::new();
+pub async fn upload_file(
+    req: HttpRequest,
+    user: AuthUser,
+    mut payload: Multipart,
+    limiter: web::Data<Arc<RateLimiter>>,
+    memory: web::Data<Arc<ChatMemory>>,
+    provider: web::Data<Arc<dyn ChatProvider>>,
+) -> Result<impl Responder, Error> {
+    if let Err(response) = user.require(Scope::Chat) {
+        return Ok(response);
+    }
+    if let Err(limited) = limiter.check(RateAction::Upload, &client_key(&req, Some(&user))) {
+        return Ok(too_many_requests(limited));
+    }
+
+    let mut uploaded_files: Vec<FileInfo> = Vec::new();
 
     while let Some(item) = payload.next().await {
         let mut field = item?;
@@ -22,33 +50,41 @@ pub async fn upload_file(mut payload: Multipart) -> Result<impl Responder, Error
             
             while let Some(chunk) = field.next().await {
                 file_content.extend_from_slice(&chunk?);
+                if file_content.len() > MAX_UPLOAD_BYTES {
+                    return Ok(upload_error(
+                        HttpResponse::PayloadTooLarge(),
+                        format!("文件 {} 超过 {} MB 的上限", filename, MAX_UPLOAD_BYTES / 1024 / 1024),
+                    ));
+                }
             }
 
-            match str::from_utf8(&file_content) {
-                Ok(text) => {
-                    uploaded_files.push(UploadedFile {
-                        name: filename.clone(),
-                        content: text.to_string(),
-                        size: file_content.len(),
-                    });
-                }
+            match files::store_upload(
+                memory.get_ref(),
+                provider.get_ref().as_ref(),
+                &user.user_id,
+                &filename,
+                &file_content,
+            )
+            .await
+            {
+                Ok(file) => uploaded_files.push(files::file_info(&file)),
                 Err(e) => {
-                    return Ok(HttpResponse::BadRequest().json(UploadResponse {
-                        status: "error".to_string(),
-                        files: None,
-                        error: Some(format!("文件 {} 不是有效的 UTF-8 编码: {}", filename, e)),
-                    }));
+                    let builder = match e {
+                        UploadError::Unsupported(_) => HttpResponse::UnsupportedMediaType(),
+                        UploadError::TooLarge(_) => HttpResponse::PayloadTooLarge(),
+                        UploadError::Failed(_) => HttpResponse::BadGateway(),
+                    };
+                    return Ok(upload_error(builder, e.message().to_string()));
                 }
             }
         }
     }
 
     if uploaded_files.is_empty() {
-        return Ok(HttpResponse::BadRequest().json(UploadResponse {
-            status: "error".to_string(),
-            files: None,
-            error: Some("没有上传任何文件".to_string()),
-        }));
+        return Ok(upload_error(
+            HttpResponse::BadRequest(),
+            "没有上传任何文件".to_string(),
+        ));
     }
 
     Ok(HttpResponse::Ok().json(UploadResponse {
@@ -57,3 +93,11 @@ pub async fn upload_file(mut payload: Multipart) -> Result<impl Responder, Error
         error: None,
     }))
 }
+
+fn upload_error(mut builder: actix_web::HttpResponseBuilder, error: String) -> HttpResponse {
+    builder.json(UploadResponse {
+        status: "error".to_string(),
+        files: None,
+        error: Some(error),
+    })
+}
@@ -0,0 +1,47 @@
+use actix_web::{HttpResponse, Responder, get, web};
+use serde_json::json;
+use std::sync::Arc;
+
+use super::auth::AuthUser;
+use crate::models::auth::Scope;
+use crate::models::usage::UsageQuery;
+use crate::services::memory::ChatMemory;
+use crate::services::usage::{self, DEFAULT_USAGE_DAYS, UsageConfig};
+
+/// token 用量报告：按用户、模型和日期聚合，并按配置的价格估算花费
+///
+/// 默认只统计当前用户；`all_users=true` 统计所有用户，仅限 USAGE_ADMINS 中的用户。
+#[get("/api/usage")]
+pub async fn usage_report(
+    user: AuthUser,
+    query: web::Query<UsageQuery>,
+    memory: web::Data<Arc<ChatMemory>>,
+    config: web::Data<Arc<UsageConfig>>,
+) -> impl Responder {
+    if let Err(response) = user.require(Scope::ReadHistory) {
+        return response;
+    }
+
+    let user_filter = if query.all_users {
+        if let Err(response) = user.require(Scope::Admin) {
+            return response;
+        }
+        let is_admin = matches!(
+            memory.get_user(&user.user_id),
+            Ok(Some(u)) if config.is_admin(&u.username)
+        );
+        if !is_admin {
+            return HttpResponse::Forbidden()
+                .json(json!({ "error": "没有查看所有用户用量的权限" }));
+        }
+        None
+    } else {
+        Some(user.user_id.as_str())
+    };
+
+    let days = query.days.unwrap_or(DEFAULT_USAGE_DAYS);
+    match usage::usage_report(&memory, &config, user_filter, days) {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "error": e })),
+    }
+}
@@ -1,43 +1,74 @@
 use actix::prelude::*;
 use actix_web::{Error, HttpRequest, HttpResponse, get, web};
 use actix_web_actors::ws;
-use std::env;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+use super::auth::AuthUser;
+use crate::models::auth::Scope;
 use crate::models::gemini::GeminiModel;
 use crate::models::messages::{
-    ErrorMessage, FileContext, HistoryItem, HistoryMessage, LoadingMessage, ResponseMessage,
-    ServerMessage, SystemMessage, ThinkingMessage, WsMessage, WsMessageWrapper,
+    ChatMessage, ContextMessage, ConversationItem, ConversationListMessage, DeltaMessage,
+    ErrorMessage, FileContext, GetHistoryMessage, HistoryMessage, LoadingMessage,
+    ResponseDoneMessage, ResponseMessage, SearchHistoryMessage, ServerMessage, SystemMessage,
+    ThinkingMessage, ToolCallMessage, ToolResultMessage, WsMessage,
 };
-use crate::services::embedding::{generate_embedding, generate_query_embedding};
-use crate::services::gemini::call_gemini_api;
-use crate::services::memory::{ChatMemory, format_recent_context, format_retrieved_context};
+use crate::services::budget::BudgetConfig;
+use crate::services::chat::{self, ChatInput};
+use crate::services::embedding::EmbeddingProvider;
+use crate::services::files;
+use crate::services::history;
+use crate::services::memory::{ChatMemory, StoredFile};
+use crate::services::provider::{ChatProvider, StreamDelta};
+use crate::services::rate_limit::{self, RateLimiter};
+use crate::services::tools::ToolRegistry;
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
-const MAX_RECENT_MESSAGES: usize = 4; // 最近的对话数量（保持连贯性）
-const MAX_SIMILAR_MESSAGES: usize = 5; // 相似消息检索数量
-const MIN_SIMILARITY: f32 = 0.5; // 最小相似度阈值
-const MAX_CONTEXT_CHARS: usize = 4000; // 最大上下文字符数
+/// 单个帧的上限，`set_context` 可能带有 base64 编码的图片和 PDF
+const MAX_FRAME_SIZE: usize = 32 * 1024 * 1024;
 
 /// WebSocket Actor
 pub struct ChatWebSocket {
     hb: Instant,
     file_contexts: Vec<FileContext>,
+    file_ids: Vec<i64>, // 文件库中作为上下文的文件，随会话保存
     current_model: GeminiModel,
     memory: Arc<ChatMemory>,
-    user_id: String, // 当前用户 ID
+    provider: Arc<dyn ChatProvider>,
+    embedder: Arc<dyn EmbeddingProvider>,
+    limiter: Arc<RateLimiter>,
+    budget: Arc<BudgetConfig>,
+    tools: Arc<ToolRegistry>,
+    user_id: String,              // 当前用户 ID（来自会话令牌或 API 令牌）
+    scopes: Vec<Scope>,           // 令牌的权限范围
+    conversation_id: Option<i64>, // 当前会话 ID
 }
 
 impl ChatWebSocket {
-    pub fn new(memory: Arc<ChatMemory>) -> Self {
+    pub fn new(
+        memory: Arc<ChatMemory>,
+        provider: Arc<dyn ChatProvider>,
+        embedder: Arc<dyn EmbeddingProvider>,
+        limiter: Arc<RateLimiter>,
+        budget: Arc<BudgetConfig>,
+        tools: Arc<ToolRegistry>,
+        user: AuthUser,
+    ) -> Self {
         Self {
             hb: Instant::now(),
             file_contexts: Vec::new(),
+            file_ids: Vec::new(),
             current_model: GeminiModel::Flash,
             memory,
-            user_id: String::new(), // 将在收到消息时设置
+            provider,
+            embedder,
+            limiter,
+            budget,
+            tools,
+            user_id: user.user_id,
+            scopes: user.scopes,
+            conversation_id: None, // 首次聊天或切换会话时设置
         }
     }
 
@@ -58,46 +89,335 @@ impl ChatWebSocket {
         }
     }
 
-    fn send_history(&self, ctx: &mut ws::WebsocketContext<Self>) {
-        if self.user_id.is_empty() {
+    fn send_history(&self, ctx: &mut ws::WebsocketContext<Self>, request: GetHistoryMessage) {
+        // 还没有会话时历史为空
+        let Some(conversation_id) = self.conversation_id else {
             self.send_message(
                 ctx,
-                ServerMessage::Error(ErrorMessage {
-                    content: "用户未识别".to_string(),
+                ServerMessage::History(HistoryMessage {
+                    conversation_id: None,
+                    before_id: request.before_id,
+                    messages: Vec::new(),
+                    has_more: false,
                 }),
             );
             return;
+        };
+
+        match history::load_history_page(&self.memory, &self.user_id, conversation_id, &request) {
+            Ok(page) => self.send_message(ctx, ServerMessage::History(page)),
+            Err(e) => self.send_message(ctx, ServerMessage::Error(ErrorMessage::new(e))),
         }
+    }
 
-        match self.memory.get_all_messages(&self.user_id) {
-            Ok(messages) => {
-                let history_items: Vec<HistoryItem> = messages
+    fn send_conversations(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        match self.memory.list_conversations(&self.user_id) {
+            Ok(conversations) => {
+                let conversations = conversations
                     .into_iter()
-                    .map(|msg| HistoryItem {
-                        role: msg.role,
-                        content: msg.content,
-                        model: msg.model,
-                        timestamp: msg.created_at.to_rfc3339(),
+                    .map(|c| ConversationItem {
+                        id: c.id,
+                        title: c.title,
+                        model: c.model,
+                        created_at: c.created_at.to_rfc3339(),
+                        updated_at: c.updated_at.to_rfc3339(),
                     })
                     .collect();
 
                 self.send_message(
                     ctx,
-                    ServerMessage::History(HistoryMessage {
-                        messages: history_items,
+                    ServerMessage::Conversations(ConversationListMessage {
+                        conversations,
+                        active_id: self.conversation_id,
                     }),
                 );
             }
             Err(e) => {
                 self.send_message(
                     ctx,
-                    ServerMessage::Error(ErrorMessage {
-                        content: format!("获取历史记录失败: {}", e),
-                    }),
+                    ServerMessage::Error(ErrorMessage::new(format!("获取会话列表失败: {}", e))),
                 );
             }
         }
     }
+
+    /// 在后台搜索历史消息，完成后发送结果
+    fn search_history(
+        &self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        user_id: String,
+        request: SearchHistoryMessage,
+    ) {
+        let memory = self.memory.clone();
+        let embedder = self.embedder.clone();
+        let fut = async move {
+            history::search_history(&memory, embedder.as_ref(), &user_id, request).await
+        };
+
+        ctx.spawn(fut.into_actor(self).map(|result, act, ctx| {
+            let msg = match result {
+                Ok(results) => ServerMessage::SearchResults(results),
+                Err(e) => ServerMessage::Error(ErrorMessage::new(e)),
+            };
+            act.send_message(ctx, msg);
+        }));
+    }
+
+    /// 新建会话并设为当前会话
+    fn create_conversation(&mut self, title: &str) -> Result<i64, String> {
+        let conversation = self
+            .memory
+            .create_conversation(&self.user_id, title, Some(self.current_model.as_str()))
+            .map_err(|e| format!("创建会话失败: {}", e))?;
+        self.conversation_id = Some(conversation.id);
+        Ok(conversation.id)
+    }
+
+    /// 设置文件库中的上下文文件，有当前会话时保存到会话
+    fn set_file_ids(&mut self, file_ids: &[i64]) -> Result<Vec<StoredFile>, String> {
+        let stored = match self.conversation_id {
+            Some(conversation_id) => self
+                .memory
+                .set_conversation_files(&self.user_id, conversation_id, file_ids)
+                .map_err(|e| format!("保存会话文件失败: {}", e)),
+            None => self
+                .memory
+                .list_files(&self.user_id)
+                .map(|all| {
+                    all.into_iter()
+                        .filter(|f| file_ids.contains(&f.id))
+                        .collect()
+                })
+                .map_err(|e| format!("读取文件失败: {}", e)),
+        }?;
+        self.file_ids = stored.iter().map(|f| f.id).collect();
+        Ok(stored)
+    }
+
+    /// 把当前会话保存的文件上下文发送给客户端
+    fn send_context(&self, ctx: &mut ws::WebsocketContext<Self>, stored: &[StoredFile]) {
+        self.send_message(
+            ctx,
+            ServerMessage::Context(ContextMessage {
+                conversation_id: self.conversation_id,
+                files: stored.iter().map(files::file_info).collect(),
+            }),
+        );
+    }
+
+    fn handle_chat(
+        &mut self,
+        chat_msg: ChatMessage,
+        user_id: String,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        let rate_key = rate_limit::user_key(&user_id);
+        if let Err(limited) = self.limiter.check_chat(&rate_key) {
+            self.send_message(
+                ctx,
+                ServerMessage::Error(ErrorMessage {
+                    content: limited.message,
+                    retry_after: Some(limited.retry_after),
+                }),
+            );
+            return;
+        }
+
+        // 没有当前会话时，以第一条消息作为标题新建会话
+        let conversation_id = match self.conversation_id {
+            Some(id) => id,
+            None => match chat::start_conversation(
+                &self.memory,
+                &user_id,
+                &chat_msg.content,
+                self.current_model,
+            ) {
+                Ok(conversation) => {
+                    self.conversation_id = Some(conversation.id);
+                    if let Err(e) = self.memory.set_conversation_files(
+                        &user_id,
+                        conversation.id,
+                        &self.file_ids,
+                    ) {
+                        eprintln!("保存会话文件失败: {}", e);
+                    }
+                    self.send_conversations(ctx);
+                    conversation.id
+                }
+                Err(e) => {
+                    self.send_message(ctx, ServerMessage::Error(ErrorMessage::new(e)));
+                    return;
+                }
+            },
+        };
+
+        // 发送加载状态
+        self.send_message(
+            ctx,
+            ServerMessage::Loading(LoadingMessage { is_loading: true }),
+        );
+
+        let memory = self.memory.clone();
+        let provider = self.provider.clone();
+        let embedder = self.embedder.clone();
+        let limiter = self.limiter.clone();
+        let budget = self.budget.clone();
+        let tools = self.tools.clone();
+        let display_name = provider.display_name(self.current_model);
+        let input = ChatInput {
+            user_id,
+            conversation_id,
+            search_all_conversations: chat_msg.search_all_conversations,
+            content: chat_msg.content,
+            file_contexts: self.file_contexts.clone(),
+            file_ids: self.file_ids.clone(),
+            model: self.current_model,
+            scopes: self.scopes.clone(),
+        };
+
+        if chat_msg.stream {
+            // 流式模式：所有输出都经由 actor 邮箱发送，保证片段与结束消息的顺序
+            let addr = ctx.address();
+            let fut = async move {
+                let delta_addr = addr.clone();
+                let mut on_delta = |delta| {
+                    let msg = match delta {
+                        StreamDelta::Response(content) => {
+                            ServerMessage::ResponseDelta(DeltaMessage { content })
+                        }
+                        StreamDelta::Thinking(content) => {
+                            ServerMessage::ThinkingDelta(DeltaMessage { content })
+                        }
+                        StreamDelta::ToolCall { id, call } => {
+                            ServerMessage::ToolCall(ToolCallMessage {
+                                id,
+                                name: call.name,
+                                args: call.args,
+                            })
+                        }
+                        StreamDelta::ToolResult { id, result } => ServerMessage::ToolResult(
+                            ToolResultMessage::new(id, result.name, result.output),
+                        ),
+                    };
+                    delta_addr.do_send(StreamEvent(msg));
+                };
+                let outcome = chat::run_chat(
+                    memory,
+                    provider.as_ref(),
+                    embedder,
+                    &budget,
+                    &tools,
+                    input,
+                    Some(&mut on_delta),
+                )
+                .await;
+
+                addr.do_send(StreamEvent(ServerMessage::Loading(LoadingMessage {
+                    is_loading: false,
+                })));
+
+                let msg = match outcome {
+                    Ok(outcome) => {
+                        limiter.record_tokens(&rate_key, outcome.tokens);
+                        ServerMessage::ResponseDone(ResponseDoneMessage {
+                            content: outcome.result.response,
+                            thinking: outcome.result.thinking,
+                            model: display_name,
+                            usage: outcome.result.usage,
+                            sources: outcome.sources,
+                        })
+                    }
+                    Err(e) => ServerMessage::Error(ErrorMessage::new(e)),
+                };
+                addr.do_send(StreamEvent(msg));
+            };
+
+            ctx.spawn(fut.into_actor(self));
+            return;
+        }
+
+        // 异步处理：生成嵌入 -> 检索相关历史 -> 调用模型 -> 保存回复
+        let fut = async move {
+            let result = chat::run_chat(
+                memory,
+                provider.as_ref(),
+                embedder,
+                &budget,
+                &tools,
+                input,
+                None,
+            )
+            .await;
+            if let Ok(outcome) = &result {
+                limiter.record_tokens(&rate_key, outcome.tokens);
+            }
+            result
+        };
+
+        ctx.wait(fut.into_actor(self).map(move |result, act, ctx| {
+            // 发送加载完成
+            act.send_message(
+                ctx,
+                ServerMessage::Loading(LoadingMessage { is_loading: false }),
+            );
+
+            match result {
+                Ok(outcome) => {
+                    // 如果有思考过程，先发送思考消息
+                    if let Some(thinking) = outcome.result.thinking {
+                        act.send_message(
+                            ctx,
+                            ServerMessage::Thinking(ThinkingMessage { content: thinking }),
+                        );
+                    }
+
+                    // 发送回复
+                    act.send_message(
+                        ctx,
+                        ServerMessage::Response(ResponseMessage {
+                            content: outcome.result.response,
+                            model: display_name,
+                            usage: outcome.result.usage,
+                            sources: outcome.sources,
+                        }),
+                    );
+                }
+                Err(e) => {
+                    act.send_message(ctx, ServerMessage::Error(ErrorMessage::new(e)));
+                }
+            }
+        }));
+    }
+}
+
+/// 流式回复中由异步任务转发给 actor 的服务器消息
+#[derive(Message)]
+#[rtype(result = "()")]
+struct StreamEvent(ServerMessage);
+
+impl Handler<StreamEvent> for ChatWebSocket {
+    type Result = ();
+
+    fn handle(&mut self, msg: StreamEvent, ctx: &mut Self::Context) {
+        self.send_message(ctx, msg.0);
+    }
+}
+
+/// 处理 WebSocket 消息所需的权限
+fn required_scope(message: &WsMessage) -> Scope {
+    match message {
+        WsMessage::GetHistory(_)
+        | WsMessage::ListConversations
+        | WsMessage::SwitchConversation(_)
+        | WsMessage::SearchHistory(_) => Scope::ReadHistory,
+        WsMessage::ClearHistory | WsMessage::DeleteConversation(_) => Scope::Admin,
+        WsMessage::Chat(_)
+        | WsMessage::SetContext(_)
+        | WsMessage::SwitchModel(_)
+        | WsMessage::ClearContext
+        | WsMessage::CreateConversation(_)
+        | WsMessage::RenameConversation(_) => Scope::Chat,
+    }
 }
 
 impl Actor for ChatWebSocket {
@@ -105,6 +425,7 @@ impl Actor for ChatWebSocket {
 
     fn started(&mut self, ctx: &mut Self::Context) {
         self.hb(ctx);
+        println!("👤 用户连接: {}", self.user_id);
 
         // 发送欢迎消息
         self.send_message(
@@ -112,7 +433,7 @@ impl Actor for ChatWebSocket {
             ServerMessage::System(SystemMessage {
                 content: format!(
                     "已连接到服务器，当前使用 {} 模型",
-                    self.current_model.display_name()
+                    self.provider.display_name(self.current_model)
                 ),
             }),
         );
@@ -132,225 +453,42 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ChatWebSocket {
             Ok(ws::Message::Text(text)) => {
                 let text_str = text.to_string();
 
-                // 解析带用户 ID 的 JSON 消息
-                match serde_json::from_str::<WsMessageWrapper>(&text_str) {
-                    Ok(wrapper) => {
-                        // 设置用户 ID
-                        if let Some(ref uid) = wrapper.user_id
-                            && self.user_id.is_empty()
-                        {
-                            self.user_id = uid.clone();
-                            println!("👤 用户连接: {}", uid);
-                        }
-
-                        // 如果没有用户 ID，使用默认值
-                        if self.user_id.is_empty() {
-                            self.user_id = "anonymous".to_string();
+                // 用户 ID 来自连接时校验的会话令牌，消息中的 user_id 字段会被忽略
+                match serde_json::from_str::<WsMessage>(&text_str) {
+                    Ok(message) => {
+                        let scope = required_scope(&message);
+                        if !scope.granted_by(&self.scopes) {
+                            self.send_message(
+                                ctx,
+                                ServerMessage::Error(ErrorMessage::new(format!(
+                                    "令牌缺少 {} 权限",
+                                    scope.as_str()
+                                ))),
+                            );
+                            return;
                         }
 
                         let user_id = self.user_id.clone();
 
-                        match wrapper.message {
+                        match message {
                             WsMessage::Chat(chat_msg) => {
-                                // 获取 API key
-                                let api_key = match env::var("GEMINI_API_KEY") {
-                                    Ok(key) => key,
-                                    Err(_) => {
+                                self.handle_chat(chat_msg, user_id, ctx);
+                            }
+                            WsMessage::SetContext(context_msg) => {
+                                // 空的 file_ids 也会清空会话保存的文件
+                                let stored = match self.set_file_ids(&context_msg.file_ids) {
+                                    Ok(stored) => stored,
+                                    Err(e) => {
                                         self.send_message(
                                             ctx,
-                                            ServerMessage::Error(ErrorMessage {
-                                                content: "未设置 GEMINI_API_KEY 环境变量"
-                                                    .to_string(),
-                                            }),
+                                            ServerMessage::Error(ErrorMessage::new(e)),
                                         );
                                         return;
                                     }
                                 };
-
-                                // 发送加载状态
-                                self.send_message(
-                                    ctx,
-                                    ServerMessage::Loading(LoadingMessage { is_loading: true }),
-                                );
-
-                                let user_content = chat_msg.content.clone();
-                                let model = self.current_model;
-                                let memory = self.memory.clone();
-                                let file_contexts = self.file_contexts.clone();
-                                let api_key_clone = api_key.clone();
-                                let user_id_clone = user_id.clone();
-
-                                // 异步处理：生成嵌入 -> 检索相关历史 -> 调用 Gemini API
-                                let fut = async move {
-                                    // 1. 先保存用户消息
-                                    let user_msg_id = memory
-                                        .add_message(
-                                            &user_id_clone,
-                                            "user",
-                                            &user_content,
-                                            Some(model.as_str()),
-                                        )
-                                        .ok();
-
-                                    // 2. 生成用户消息的嵌入向量（用于检索）
-                                    let query_embedding =
-                                        generate_query_embedding(&user_content, &api_key_clone)
-                                            .await
-                                            .ok();
-
-                                    // 3. 如果有嵌入向量，检索相关历史消息
-                                    let similar_messages =
-                                        if let Some(ref embedding) = query_embedding {
-                                            memory
-                                                .retrieve_similar(
-                                                    &user_id_clone,
-                                                    embedding,
-                                                    MAX_SIMILAR_MESSAGES,
-                                                    MIN_SIMILARITY,
-                                                )
-                                                .unwrap_or_default()
-                                        } else {
-                                            Vec::new()
-                                        };
-
-                                    // 4. 获取最近几条消息（保持对话连贯性）
-                                    let recent_messages = memory
-                                        .get_recent_messages(&user_id_clone, MAX_RECENT_MESSAGES)
-                                        .unwrap_or_default();
-
-                                    // 5. 构建 prompt
-                                    let mut prompt = String::new();
-
-                                    // 添加检索到的相关历史
-                                    if !similar_messages.is_empty() {
-                                        prompt.push_str(&format_retrieved_context(
-                                            &similar_messages,
-                                            MAX_CONTEXT_CHARS,
-                                        ));
-                                    }
-
-                                    // 添加最近对话（如果相关历史不够）
-                                    if similar_messages.len() < 2 && !recent_messages.is_empty() {
-                                        prompt.push_str(&format_recent_context(
-                                            &recent_messages,
-                                            MAX_RECENT_MESSAGES,
-                                        ));
-                                    }
-
-                                    // 添加文件上下文
-                                    if !file_contexts.is_empty() {
-                                        prompt.push_str(
-                                            "以下是用户上传的文件内容作为上下文参考：\n\n",
-                                        );
-                                        for (i, file) in file_contexts.iter().enumerate() {
-                                            prompt.push_str(&format!(
-                                                "--- 文件 {} ({}) ---\n{}\n\n",
-                                                i + 1,
-                                                file.name,
-                                                file.content
-                                            ));
-                                        }
-                                        prompt.push_str("---\n\n");
-                                    }
-
-                                    prompt.push_str(&format!("用户消息：{}", user_content));
-
-                                    // 6. 调用 Gemini API
-                                    let gemini_result =
-                                        call_gemini_api(prompt, &api_key_clone, model).await;
-
-                                    // 7. 更新用户消息的嵌入向量
-                                    if let (Some(msg_id), Some(embedding)) =
-                                        (user_msg_id, query_embedding)
-                                    {
-                                        let _ = memory.update_embedding(msg_id, &embedding);
-                                    }
-
-                                    (gemini_result, user_id_clone)
-                                };
-
-                                ctx.wait(fut.into_actor(self).map(
-                                    move |(result, uid), act, ctx| {
-                                        // 发送加载完成
-                                        act.send_message(
-                                            ctx,
-                                            ServerMessage::Loading(LoadingMessage {
-                                                is_loading: false,
-                                            }),
-                                        );
-
-                                        match result {
-                                            Ok(gemini_result) => {
-                                                // 如果有思考过程，先发送思考消息
-                                                if let Some(thinking) = gemini_result.thinking {
-                                                    act.send_message(
-                                                        ctx,
-                                                        ServerMessage::Thinking(ThinkingMessage {
-                                                            content: thinking,
-                                                        }),
-                                                    );
-                                                }
-
-                                                let response_content =
-                                                    gemini_result.response.clone();
-                                                let memory = act.memory.clone();
-                                                let model_str = act.current_model.as_str();
-                                                let api_key = env::var("GEMINI_API_KEY").ok();
-
-                                                // 保存 AI 回复到记忆
-                                                if let Ok(msg_id) = memory.add_message(
-                                                    &uid,
-                                                    "model",
-                                                    &response_content,
-                                                    Some(model_str),
-                                                ) {
-                                                    // 异步生成回复的嵌入向量
-                                                    if let Some(key) = api_key {
-                                                        let response_for_embed =
-                                                            response_content.clone();
-                                                        actix::spawn(async move {
-                                                            if let Ok(embedding) =
-                                                                generate_embedding(
-                                                                    &response_for_embed,
-                                                                    &key,
-                                                                )
-                                                                .await
-                                                            {
-                                                                let _ = memory.update_embedding(
-                                                                    msg_id, &embedding,
-                                                                );
-                                                            }
-                                                        });
-                                                    }
-                                                }
-
-                                                // 发送回复
-                                                act.send_message(
-                                                    ctx,
-                                                    ServerMessage::Response(ResponseMessage {
-                                                        content: gemini_result.response,
-                                                        model: act
-                                                            .current_model
-                                                            .display_name()
-                                                            .to_string(),
-                                                    }),
-                                                );
-                                            }
-                                            Err(e) => {
-                                                act.send_message(
-                                                    ctx,
-                                                    ServerMessage::Error(ErrorMessage {
-                                                        content: e,
-                                                    }),
-                                                );
-                                            }
-                                        }
-                                    },
-                                ));
-                            }
-                            WsMessage::SetContext(context_msg) => {
                                 self.file_contexts = context_msg.files;
-                                let count = self.file_contexts.len();
+                                let count = self.file_contexts.len() + stored.len();
+                                self.send_context(ctx, &stored);
                                 self.send_message(
                                     ctx,
                                     ServerMessage::System(SystemMessage {
@@ -360,18 +498,33 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ChatWebSocket {
                             }
                             WsMessage::SwitchModel(model_msg) => {
                                 self.current_model = GeminiModel::from_str(&model_msg.model);
+                                if let Some(conversation_id) = self.conversation_id {
+                                    let _ = self.memory.set_conversation_model(
+                                        &user_id,
+                                        conversation_id,
+                                        self.current_model.as_str(),
+                                    );
+                                }
                                 self.send_message(
                                     ctx,
                                     ServerMessage::System(SystemMessage {
                                         content: format!(
                                             "已切换到 {} 模型",
-                                            self.current_model.display_name()
+                                            self.provider.display_name(self.current_model)
                                         ),
                                     }),
                                 );
                             }
                             WsMessage::ClearContext => {
                                 self.file_contexts.clear();
+                                if let Err(e) = self.set_file_ids(&[]) {
+                                    self.send_message(
+                                        ctx,
+                                        ServerMessage::Error(ErrorMessage::new(e)),
+                                    );
+                                    return;
+                                }
+                                self.send_context(ctx, &[]);
                                 self.send_message(
                                     ctx,
                                     ServerMessage::System(SystemMessage {
@@ -382,6 +535,7 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ChatWebSocket {
                             WsMessage::ClearHistory => {
                                 match self.memory.clear_user_messages(&user_id) {
                                     Ok(_) => {
+                                        self.conversation_id = None;
                                         self.send_message(
                                             ctx,
                                             ServerMessage::System(SystemMessage {
@@ -392,15 +546,149 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ChatWebSocket {
                                     Err(e) => {
                                         self.send_message(
                                             ctx,
-                                            ServerMessage::Error(ErrorMessage {
-                                                content: format!("清除记录失败: {}", e),
-                                            }),
+                                            ServerMessage::Error(ErrorMessage::new(format!(
+                                                "清除记录失败: {}",
+                                                e
+                                            ))),
+                                        );
+                                    }
+                                }
+                            }
+                            WsMessage::GetHistory(history_msg) => {
+                                self.send_history(ctx, history_msg.unwrap_or_default());
+                            }
+                            WsMessage::CreateConversation(create_msg) => {
+                                let title =
+                                    create_msg.title.unwrap_or_else(|| "新对话".to_string());
+                                match self.create_conversation(&title) {
+                                    Ok(_) => {
+                                        // 新会话沿用当前的文件上下文
+                                        let file_ids = std::mem::take(&mut self.file_ids);
+                                        match self.set_file_ids(&file_ids) {
+                                            Ok(stored) => self.send_context(ctx, &stored),
+                                            Err(e) => eprintln!("保存会话文件失败: {}", e),
+                                        }
+                                        self.send_conversations(ctx);
+                                        self.send_history(ctx, GetHistoryMessage::default());
+                                    }
+                                    Err(e) => {
+                                        self.send_message(
+                                            ctx,
+                                            ServerMessage::Error(ErrorMessage::new(e)),
+                                        );
+                                    }
+                                }
+                            }
+                            WsMessage::ListConversations => {
+                                self.send_conversations(ctx);
+                            }
+                            WsMessage::SwitchConversation(switch_msg) => {
+                                match self
+                                    .memory
+                                    .get_conversation(&user_id, switch_msg.conversation_id)
+                                {
+                                    Ok(Some(conversation)) => {
+                                        self.conversation_id = Some(conversation.id);
+                                        // 恢复会话使用的模型
+                                        if let Some(ref model) = conversation.model {
+                                            self.current_model = GeminiModel::from_str(model);
+                                        }
+                                        // 恢复会话保存的文件上下文，未保存的临时文件不跨会话
+                                        self.file_contexts.clear();
+                                        match files::conversation_files(
+                                            &self.memory,
+                                            &user_id,
+                                            conversation.id,
+                                            &[],
+                                        ) {
+                                            Ok(stored) => {
+                                                self.file_ids =
+                                                    stored.iter().map(|f| f.id).collect();
+                                                self.send_context(ctx, &stored);
+                                            }
+                                            Err(e) => {
+                                                self.file_ids.clear();
+                                                self.send_message(
+                                                    ctx,
+                                                    ServerMessage::Error(ErrorMessage::new(e)),
+                                                );
+                                            }
+                                        }
+                                        self.send_conversations(ctx);
+                                        self.send_history(ctx, GetHistoryMessage::default());
+                                    }
+                                    Ok(None) => {
+                                        self.send_message(
+                                            ctx,
+                                            ServerMessage::Error(ErrorMessage::new("会话不存在")),
+                                        );
+                                    }
+                                    Err(e) => {
+                                        self.send_message(
+                                            ctx,
+                                            ServerMessage::Error(ErrorMessage::new(format!(
+                                                "切换会话失败: {}",
+                                                e
+                                            ))),
+                                        );
+                                    }
+                                }
+                            }
+                            WsMessage::RenameConversation(rename_msg) => {
+                                match self.memory.rename_conversation(
+                                    &user_id,
+                                    rename_msg.conversation_id,
+                                    &rename_msg.title,
+                                ) {
+                                    Ok(true) => self.send_conversations(ctx),
+                                    Ok(false) => {
+                                        self.send_message(
+                                            ctx,
+                                            ServerMessage::Error(ErrorMessage::new("会话不存在")),
+                                        );
+                                    }
+                                    Err(e) => {
+                                        self.send_message(
+                                            ctx,
+                                            ServerMessage::Error(ErrorMessage::new(format!(
+                                                "重命名会话失败: {}",
+                                                e
+                                            ))),
+                                        );
+                                    }
+                                }
+                            }
+                            WsMessage::DeleteConversation(delete_msg) => {
+                                match self
+                                    .memory
+                                    .delete_conversation(&user_id, delete_msg.conversation_id)
+                                {
+                                    Ok(true) => {
+                                        if self.conversation_id == Some(delete_msg.conversation_id)
+                                        {
+                                            self.conversation_id = None;
+                                        }
+                                        self.send_conversations(ctx);
+                                    }
+                                    Ok(false) => {
+                                        self.send_message(
+                                            ctx,
+                                            ServerMessage::Error(ErrorMessage::new("会话不存在")),
+                                        );
+                                    }
+                                    Err(e) => {
+                                        self.send_message(
+                                            ctx,
+                                            ServerMessage::Error(ErrorMessage::new(format!(
+                                                "删除会话失败: {}",
+                                                e
+                                            ))),
                                         );
                                     }
                                 }
                             }
-                            WsMessage::GetHistory => {
-                                self.send_history(ctx);
+                            WsMessage::SearchHistory(search_msg) => {
+                                self.search_history(ctx, user_id, search_msg);
                             }
                         }
                     }
@@ -408,9 +696,9 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ChatWebSocket {
                         // 兼容旧格式：纯文本消息
                         self.send_message(
                             ctx,
-                            ServerMessage::Error(ErrorMessage {
-                                content: "消息格式错误，请使用 JSON 格式".to_string(),
-                            }),
+                            ServerMessage::Error(ErrorMessage::new(
+                                "消息格式错误，请使用 JSON 格式",
+                            )),
                         );
                     }
                 }
@@ -425,11 +713,33 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ChatWebSocket {
     }
 }
 
+/// WebSocket 入口，升级前校验会话令牌或 API 令牌（`?token=` 或 `Authorization: Bearer`）
 #[get("/ws")]
+#[allow(clippy::too_many_arguments)]
 pub async fn ws_index(
     req: HttpRequest,
+    user: AuthUser,
     stream: web::Payload,
     memory: web::Data<Arc<ChatMemory>>,
+    provider: web::Data<Arc<dyn ChatProvider>>,
+    embedder: web::Data<Arc<dyn EmbeddingProvider>>,
+    limiter: web::Data<Arc<RateLimiter>>,
+    budget: web::Data<Arc<BudgetConfig>>,
+    tools: web::Data<Arc<ToolRegistry>>,
 ) -> Result<HttpResponse, Error> {
-    ws::start(ChatWebSocket::new(memory.get_ref().clone()), &req, stream)
+    ws::WsResponseBuilder::new(
+        ChatWebSocket::new(
+            memory.get_ref().clone(),
+            provider.get_ref().clone(),
+            embedder.get_ref().clone(),
+            limiter.get_ref().clone(),
+            budget.get_ref().clone(),
+            tools.get_ref().clone(),
+            user,
+        ),
+        &req,
+        stream,
+    )
+    .frame_size(MAX_FRAME_SIZE)
+    .start()
 }
@@ -9,15 +9,42 @@ use dotenv::dotenv;
 use std::env;
 use std::sync::Arc;
 
-use handlers::{health::health_check, upload::upload_file, websocket::ws_index};
+use handlers::{
+    auth::{create_token, list_tokens, login, me, revoke_token, signup},
+    chat::send_chat,
+    completions::{chat_completions, list_models},
+    files::{delete_file, download_file, list_files},
+    health::health_check,
+    history::{conversation_messages, search_history},
+    upload::upload_file,
+    usage::usage_report,
+    websocket::ws_index,
+};
+use services::auth::SessionManager;
+use services::backfill::{BackfillConfig, spawn_embedding_backfill};
+use services::budget::BudgetConfig;
+use services::embedding::embedding_provider_from_env;
 use services::memory::ChatMemory;
+use services::provider::chat_provider_from_env;
+use services::rate_limit::{RateLimitConfig, RateLimiter};
+use services::summarizer::{SummarizerConfig, spawn_summary_worker};
+use services::tools::{ToolConfig, ToolRegistry};
+use services::usage::UsageConfig;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     // 加载 .env 文件
     dotenv().ok();
 
-    // 检查 API Key
+    // 初始化聊天模型后端
+    let provider = chat_provider_from_env().expect("无法创建聊天模型后端");
+    println!("🤖 聊天模型后端: {}", provider.name());
+
+    // 初始化嵌入后端
+    let embedder = embedding_provider_from_env().expect("无法创建嵌入后端");
+    println!("🧭 嵌入后端: {}", embedder.model_id());
+
+    // 检查 API Key（Gemini 后端需要）
     match env::var("GEMINI_API_KEY") {
         Ok(_) => println!("✅ GEMINI_API_KEY 加载成功"),
         Err(_) => println!(
@@ -25,6 +52,13 @@ async fn main() -> std::io::Result<()> {
         ),
     }
 
+    // 初始化会话令牌签发
+    let (sessions, random_secret) = SessionManager::from_env();
+    if random_secret {
+        println!("⚠️  警告: SESSION_SECRET 未设置，使用随机密钥，重启后需要重新登录");
+    }
+    let sessions = Arc::new(sessions);
+
     // 初始化聊天记忆数据库
     let db_path = env::var("DATABASE_URL").unwrap_or_else(|_| "data/web_chat.db".to_string());
     
@@ -43,28 +77,97 @@ async fn main() -> std::io::Result<()> {
         println!("📝 已加载 {} 条历史消息", message_count);
     }
 
+    // 启动后台嵌入补全任务
+    let backfill_status = spawn_embedding_backfill(
+        memory.clone(),
+        embedder.clone(),
+        BackfillConfig::from_env(),
+    );
+    println!("🔄 嵌入补全任务已启动");
+
+    // 启动后台摘要任务
+    spawn_summary_worker(memory.clone(), provider.clone(), SummarizerConfig::from_env());
+
+    // 初始化限流与每日配额
+    let rate_config = RateLimitConfig::from_env();
+    println!(
+        "🚦 限流: 聊天 {}/分钟, 上传 {}/分钟, 每日请求上限 {}, 每日 token 上限 {}（0 表示不限）",
+        rate_config.chat.per_minute,
+        rate_config.upload.per_minute,
+        rate_config.daily_requests,
+        rate_config.daily_tokens
+    );
+    let limiter = Arc::new(RateLimiter::new(rate_config, memory.clone()));
+
+    // 提示词的上下文预算
+    let budget = Arc::new(BudgetConfig::from_env());
+    println!(
+        "✂️  上下文预算: 最多 {} tokens（0 表示仅受模型窗口限制），为输出预留 {} tokens，{}计数",
+        budget.max_prompt_tokens,
+        budget.reserved_output_tokens,
+        if budget.provider_counting { "后端" } else { "本地估算" }
+    );
+
+    // 用量统计的模型价格
+    let usage_config = Arc::new(UsageConfig::from_env());
+
+    // 模型可以调用的服务端工具
+    let tools = Arc::new(ToolRegistry::from_config(&ToolConfig::from_env()));
+    if tools.is_empty() {
+        println!("🧰 未启用工具调用");
+    } else {
+        println!("🧰 可用工具: {}", tools.names().join(", "));
+    }
+
     println!("🦀 Rust 后端服务器启动于 http://0.0.0.0:23333");
     println!("📡 支持的模型: Gemini 2.0 Flash (flash), Gemini 2.5 Flash (flash-2.5), Gemini 2.5 Pro (pro-2.5)");
 
     HttpServer::new(move || {
         let cors = Cors::default()
             .allow_any_origin() // 允许所有来源，方便开发和Docker环境
-            .allowed_methods(vec!["GET", "POST", "OPTIONS"])
+            .allowed_methods(vec!["GET", "POST", "DELETE", "OPTIONS"])
             .allowed_headers(vec![
                 actix_web::http::header::CONTENT_TYPE,
+                actix_web::http::header::AUTHORIZATION,
                 actix_web::http::header::UPGRADE,
                 actix_web::http::header::CONNECTION,
                 actix_web::http::header::SEC_WEBSOCKET_VERSION,
                 actix_web::http::header::SEC_WEBSOCKET_KEY,
             ])
+            .expose_headers(vec![actix_web::http::header::RETRY_AFTER])
             .supports_credentials()
             .max_age(3600);
 
         App::new()
             .wrap(cors)
             .app_data(web::Data::new(memory.clone()))
+            .app_data(web::Data::new(backfill_status.clone()))
+            .app_data(web::Data::new(provider.clone()))
+            .app_data(web::Data::new(embedder.clone()))
+            .app_data(web::Data::new(sessions.clone()))
+            .app_data(web::Data::new(limiter.clone()))
+            .app_data(web::Data::new(budget.clone()))
+            .app_data(web::Data::new(tools.clone()))
+            // 聊天请求的文件上下文可能带有 base64 编码的附件
+            .app_data(web::JsonConfig::default().limit(32 * 1024 * 1024))
+            .app_data(web::Data::new(usage_config.clone()))
             .service(health_check)
+            .service(signup)
+            .service(login)
+            .service(me)
+            .service(create_token)
+            .service(list_tokens)
+            .service(revoke_token)
+            .service(send_chat)
+            .service(list_models)
+            .service(chat_completions)
+            .service(search_history)
+            .service(conversation_messages)
             .service(upload_file)
+            .service(list_files)
+            .service(download_file)
+            .service(delete_file)
+            .service(usage_report)
             .service(ws_index)
             .service(Files::new("/", "./static").index_file("index.html"))
     })
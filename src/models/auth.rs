@@ -0,0 +1,93 @@
+use serde::{Deserialize, Serialize};
+
+/// 注册请求
+#[derive(Deserialize, Debug)]
+pub struct SignupRequest {
+    pub username: String,
+    pub password: String,
+}
+
+/// 登录请求
+#[derive(Deserialize, Debug)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// 用户信息
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserInfo {
+    pub id: String,
+    pub username: String,
+}
+
+/// 注册 / 登录成功后返回的会话令牌
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuthResponse {
+    pub token: String,
+    pub expires_at: String,
+    pub user: UserInfo,
+}
+
+/// API 令牌的权限范围
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scope {
+    /// 读取历史记录、搜索、查看会话列表
+    ReadHistory,
+    /// 发送聊天消息、上传文件、管理会话
+    Chat,
+    /// 全部权限，包括清除记录和管理 API 令牌
+    Admin,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 3] = [Scope::ReadHistory, Scope::Chat, Scope::Admin];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::ReadHistory => "read-history",
+            Scope::Chat => "chat",
+            Scope::Admin => "admin",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|s| s.as_str() == value)
+    }
+
+    /// 给定的权限集合是否包含该权限（admin 包含全部权限）
+    pub fn granted_by(self, scopes: &[Scope]) -> bool {
+        scopes.contains(&self) || scopes.contains(&Scope::Admin)
+    }
+}
+
+/// 创建 API 令牌的请求
+#[derive(Deserialize, Debug)]
+pub struct CreateApiTokenRequest {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// 有效天数，不填则永不过期
+    #[serde(default)]
+    pub expires_in_days: Option<u32>,
+}
+
+/// API 令牌信息（不含令牌本身）
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiTokenInfo {
+    pub id: i64,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: String,
+    pub expires_at: Option<String>,
+    pub last_used_at: Option<String>,
+}
+
+/// 新建的 API 令牌（明文令牌只在创建时返回一次）
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CreatedApiToken {
+    pub token: String,
+    #[serde(flatten)]
+    pub info: ApiTokenInfo,
+}
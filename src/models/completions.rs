@@ -0,0 +1,113 @@
+use serde::{Deserialize, Serialize};
+
+/// OpenAI 兼容的 `/v1/chat/completions` 请求
+///
+/// 只使用我们支持的字段，其余字段（temperature 等）会被忽略
+#[derive(Deserialize, Debug)]
+pub struct CompletionRequest {
+    pub model: String,
+    pub messages: Vec<CompletionRequestMessage>,
+    #[serde(default)]
+    pub stream: bool,
+    /// 扩展字段：是否检索用户的聊天记忆并放入系统指令
+    #[serde(default)]
+    pub memory: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CompletionRequestMessage {
+    pub role: String,
+    #[serde(default)]
+    pub content: Option<MessageContent>,
+}
+
+/// 消息内容：纯文本或内容片段数组
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ContentPart {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub text: Option<String>,
+}
+
+/// 非流式响应
+#[derive(Serialize, Debug)]
+pub struct CompletionResponse {
+    pub id: String,
+    pub object: &'static str,
+    pub created: i64,
+    pub model: String,
+    pub choices: Vec<CompletionChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<CompletionUsage>,
+}
+
+/// token 用量（completion_tokens 包含思考过程）
+#[derive(Serialize, Debug, PartialEq)]
+pub struct CompletionUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CompletionChoice {
+    pub index: usize,
+    pub message: CompletionResponseMessage,
+    pub finish_reason: &'static str,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CompletionResponseMessage {
+    pub role: &'static str,
+    pub content: String,
+    /// 思考过程（部分兼容客户端会展示该字段）
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_content: Option<String>,
+}
+
+/// 流式响应中的一个片段
+#[derive(Serialize, Debug)]
+pub struct CompletionChunk {
+    pub id: String,
+    pub object: &'static str,
+    pub created: i64,
+    pub model: String,
+    pub choices: Vec<CompletionChunkChoice>,
+    /// 只在结束片段中出现
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<CompletionUsage>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CompletionChunkChoice {
+    pub index: usize,
+    pub delta: CompletionDelta,
+    pub finish_reason: Option<&'static str>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct CompletionDelta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_content: Option<String>,
+}
+
+/// `/v1/models` 中的一个模型
+#[derive(Serialize, Debug)]
+pub struct ModelObject {
+    pub id: &'static str,
+    pub object: &'static str,
+    pub created: i64,
+    pub owned_by: &'static str,
+}
@@ -0,0 +1,12 @@
+use serde::{Deserialize, Serialize};
+
+/// 文件库中的文件信息（不含内容）
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileInfo {
+    pub id: i64,
+    pub name: String,
+    pub mime_type: String,
+    pub size: u64,
+    pub sha256: String,
+    pub created_at: String,
+}
@@ -5,19 +5,88 @@ use serde::{Deserialize, Serialize};
 pub struct GeminiRequest {
     pub contents: Vec<Content>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_instruction: Option<Content>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub generation_config: Option<GenerationConfig>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<Tool>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// 模型可以调用的一组函数
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Tool {
+    pub function_declarations: Vec<FunctionDeclaration>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FunctionDeclaration {
+    pub name: String,
+    pub description: String,
+    /// 参数的 JSON Schema（OpenAPI 子集）
+    pub parameters: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Content {
     pub parts: Vec<Part>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub role: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Part {
-    pub text: String,
+/// 内容片段：文本、内联的二进制数据（base64）、通过 Files API 上传的文件，
+/// 或者模型的函数调用及其结果
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Part {
+    Text(String),
+    InlineData(Blob),
+    FileData(FileData),
+    FunctionCall(FunctionCall),
+    FunctionResponse(FunctionResponse),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FunctionCall {
+    pub name: String,
+    #[serde(default)]
+    pub args: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FunctionResponse {
+    pub name: String,
+    /// 必须是 JSON 对象
+    pub response: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Blob {
+    pub mime_type: String,
+    /// base64 编码的数据
+    pub data: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileData {
+    pub mime_type: String,
+    pub file_uri: String,
+}
+
+/// Files API 上传完成后返回的文件信息
+#[derive(Deserialize, Debug)]
+pub struct UploadFileResponse {
+    pub file: UploadedFileInfo,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadedFileInfo {
+    /// 形如 `files/abc123`
+    pub name: String,
+    pub uri: String,
+    /// PROCESSING / ACTIVE / FAILED
+    #[serde(default)]
+    pub state: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -32,15 +101,39 @@ pub struct GenerationConfig {
 #[derive(Deserialize, Debug)]
 pub struct GeminiResponse {
     pub candidates: Option<Vec<Candidate>>,
+    #[serde(rename = "usageMetadata")]
+    pub usage_metadata: Option<UsageMetadata>,
+}
+
+/// countTokens 接口的响应
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CountTokensResponse {
+    #[serde(default)]
+    pub total_tokens: u64,
+}
+
+/// token 用量（流式响应中每个块都带有截至当前的累计值）
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageMetadata {
+    #[serde(default)]
+    pub prompt_token_count: u64,
+    #[serde(default)]
+    pub candidates_token_count: u64,
+    #[serde(default)]
+    pub thoughts_token_count: u64,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct Candidate {
+    #[serde(default)]
     pub content: CandidateContent,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Default)]
 pub struct CandidateContent {
+    #[serde(default)]
     pub parts: Vec<PartResponse>,
 }
 
@@ -48,6 +141,8 @@ pub struct CandidateContent {
 pub struct PartResponse {
     pub text: Option<String>,
     pub thought: Option<bool>,
+    #[serde(rename = "functionCall")]
+    pub function_call: Option<FunctionCall>,
 }
 
 /// 支持的模型类型
@@ -59,12 +154,20 @@ pub enum GeminiModel {
 }
 
 impl GeminiModel {
+    pub const ALL: [GeminiModel; 3] =
+        [GeminiModel::Flash, GeminiModel::Flash25, GeminiModel::Pro25];
+
     pub fn from_str(s: &str) -> Self {
+        Self::parse(s).unwrap_or(GeminiModel::Flash)
+    }
+
+    /// 解析档位名或模型 ID，无法识别时返回 None
+    pub fn parse(s: &str) -> Option<Self> {
         match s.to_lowercase().as_str() {
-            "flash" | "gemini-2.0-flash" => GeminiModel::Flash,
-            "flash-2.5" | "gemini-2.5-flash" => GeminiModel::Flash25,
-            "pro-2.5" | "gemini-2.5-pro" => GeminiModel::Pro25,
-            _ => GeminiModel::Flash,
+            "flash" | "gemini-2.0-flash" => Some(GeminiModel::Flash),
+            "flash-2.5" | "gemini-2.5-flash" => Some(GeminiModel::Flash25),
+            "pro-2.5" | "gemini-2.5-pro" => Some(GeminiModel::Pro25),
+            _ => None,
         }
     }
 
@@ -84,6 +187,13 @@ impl GeminiModel {
         }
     }
 
+    /// 上下文窗口大小（token）
+    pub fn context_window(&self) -> u64 {
+        match self {
+            GeminiModel::Flash | GeminiModel::Flash25 | GeminiModel::Pro25 => 1_048_576,
+        }
+    }
+
     pub fn supports_thinking(&self) -> bool {
         matches!(self, GeminiModel::Pro25)
     }
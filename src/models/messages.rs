@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use super::files::FileInfo;
+
 /// WebSocket 消息类型
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type", content = "data")]
@@ -24,33 +26,107 @@ pub enum WsMessage {
     #[serde(rename = "clear_history")]
     ClearHistory,
 
-    /// 获取历史记录
+    /// 获取历史记录（不带参数时返回最新一页）
     #[serde(rename = "get_history")]
-    GetHistory,
+    GetHistory(Option<GetHistoryMessage>),
+
+    /// 新建会话
+    #[serde(rename = "create_conversation")]
+    CreateConversation(CreateConversationMessage),
+
+    /// 获取会话列表
+    #[serde(rename = "list_conversations")]
+    ListConversations,
+
+    /// 切换会话
+    #[serde(rename = "switch_conversation")]
+    SwitchConversation(ConversationIdMessage),
+
+    /// 重命名会话
+    #[serde(rename = "rename_conversation")]
+    RenameConversation(RenameConversationMessage),
+
+    /// 删除会话
+    #[serde(rename = "delete_conversation")]
+    DeleteConversation(ConversationIdMessage),
+
+    /// 全文搜索历史消息
+    #[serde(rename = "search_history")]
+    SearchHistory(SearchHistoryMessage),
 }
 
-/// 带用户 ID 的 WebSocket 消息包装
 #[derive(Serialize, Deserialize, Debug)]
-pub struct WsMessageWrapper {
-    #[serde(flatten)]
-    pub message: WsMessage,
-    pub user_id: Option<String>,
+pub struct ChatMessage {
+    pub content: String,
+    /// 是否以流式方式返回回复
+    #[serde(default)]
+    pub stream: bool,
+    /// 是否在用户的所有会话中检索相关记忆（默认只检索当前会话）
+    #[serde(default)]
+    pub search_all_conversations: bool,
+}
+
+/// REST 聊天接口（`POST /api/chat`）的请求
+#[derive(Deserialize, Debug)]
+pub struct ChatApiRequest {
+    pub message: String,
+    /// 不填时沿用会话上次使用的模型，新会话默认 flash
+    #[serde(default)]
+    pub model: Option<String>,
+    /// 作为上下文的文件（内联内容）
+    #[serde(default)]
+    pub files: Vec<FileContext>,
+    /// 作为上下文的文件库中的文件
+    #[serde(default)]
+    pub file_ids: Vec<i64>,
+    /// 继续已有会话，不填则新建会话
+    #[serde(default)]
+    pub conversation_id: Option<i64>,
+    #[serde(default)]
+    pub search_all_conversations: bool,
+    /// 以 `text/event-stream` 流式返回（也可通过 `Accept` 请求头指定）
+    #[serde(default)]
+    pub stream: bool,
 }
 
+/// REST 聊天接口的响应（流式模式下作为 `done` 事件的数据）
 #[derive(Serialize, Deserialize, Debug)]
-pub struct ChatMessage {
+pub struct ChatApiResponse {
+    pub conversation_id: i64,
+    pub user_message_id: Option<i64>,
+    pub message_id: Option<i64>,
     pub content: String,
+    pub thinking: Option<String>,
+    pub model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<TokenUsage>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<Source>,
 }
 
+/// 设置文件上下文：`file_ids` 引用文件库中的文件，会随会话保存；
+/// `files` 直接携带内容，只在本次连接中有效
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SetContextMessage {
+    #[serde(default)]
+    pub file_ids: Vec<i64>,
+    #[serde(default)]
     pub files: Vec<FileContext>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// 作为上下文的文件：文本文件放在 `content` 中，
+/// 图片、PDF、音频等二进制文件以 `data`（base64）或 `file_uri`（Gemini Files API）附加
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct FileContext {
     pub name: String,
+    #[serde(default)]
     pub content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mime_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_uri: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -58,6 +134,60 @@ pub struct SwitchModelMessage {
     pub model: String,
 }
 
+/// 历史记录分页参数（同时用作 REST 接口的查询参数）
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct GetHistoryMessage {
+    /// 只返回 ID 小于该值的消息，不设置时从最新的消息开始
+    #[serde(default)]
+    pub before_id: Option<i64>,
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CreateConversationMessage {
+    #[serde(default)]
+    pub title: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConversationIdMessage {
+    pub conversation_id: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RenameConversationMessage {
+    pub conversation_id: i64,
+    pub title: String,
+}
+
+/// 历史消息搜索请求（同时用作 `GET /api/history/search` 的查询参数）
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchHistoryMessage {
+    pub query: String,
+    /// 只搜索某个会话
+    #[serde(default)]
+    pub conversation_id: Option<i64>,
+    /// 起始时间（RFC 3339 或 YYYY-MM-DD）
+    #[serde(default)]
+    pub from: Option<String>,
+    /// 结束时间（RFC 3339 或 YYYY-MM-DD）
+    #[serde(default)]
+    pub to: Option<String>,
+    /// "user" 或 "model"
+    #[serde(default)]
+    pub role: Option<String>,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub offset: usize,
+    #[serde(default)]
+    pub limit: Option<usize>,
+    /// 是否按嵌入相似度重排
+    #[serde(default)]
+    pub rerank: bool,
+}
+
 /// 服务器响应消息
 #[derive(Serialize, Debug)]
 #[serde(tag = "type", content = "data")]
@@ -85,25 +215,231 @@ pub enum ServerMessage {
     /// 历史记录
     #[serde(rename = "history")]
     History(HistoryMessage),
+
+    /// 流式回复片段
+    #[serde(rename = "response_delta")]
+    ResponseDelta(DeltaMessage),
+
+    /// 流式思考过程片段
+    #[serde(rename = "thinking_delta")]
+    ThinkingDelta(DeltaMessage),
+
+    /// 流式回复结束（携带完整内容）
+    #[serde(rename = "response_done")]
+    ResponseDone(ResponseDoneMessage),
+
+    /// 会话列表
+    #[serde(rename = "conversations")]
+    Conversations(ConversationListMessage),
+
+    /// 历史消息搜索结果
+    #[serde(rename = "search_results")]
+    SearchResults(SearchResultsMessage),
+
+    /// 当前会话的文件上下文（设置上下文或切换会话后发送）
+    #[serde(rename = "context")]
+    Context(ContextMessage),
+
+    /// 模型开始调用工具（流式回复中发送）
+    #[serde(rename = "tool_call")]
+    ToolCall(ToolCallMessage),
+
+    /// 工具调用的结果
+    #[serde(rename = "tool_result")]
+    ToolResult(ToolResultMessage),
+}
+
+#[derive(Serialize, Debug)]
+pub struct ToolCallMessage {
+    /// 在一次回复内唯一，对应的结果消息带有相同的 ID
+    pub id: String,
+    pub name: String,
+    pub args: serde_json::Value,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ToolResultMessage {
+    pub id: String,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+    /// 执行失败时的错误说明
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ToolResultMessage {
+    pub fn new(id: String, name: String, output: Result<serde_json::Value, String>) -> Self {
+        let (result, error) = match output {
+            Ok(value) => (Some(value), None),
+            Err(e) => (None, Some(e)),
+        };
+        Self {
+            id,
+            name,
+            result,
+            error,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct SearchResultsMessage {
+    pub query: String,
+    pub hits: Vec<SearchHitItem>,
+    pub total: usize,
+    pub offset: usize,
+    pub has_more: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SearchHitItem {
+    pub message_id: i64,
+    pub conversation_id: Option<i64>,
+    pub role: String,
+    pub model: Option<String>,
+    /// HTML 片段，命中部分用 `<mark>` 包裹，其余内容已转义
+    pub snippet: String,
+    pub timestamp: String,
+    pub similarity: Option<f32>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ConversationListMessage {
+    pub conversations: Vec<ConversationItem>,
+    pub active_id: Option<i64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ConversationItem {
+    pub id: i64,
+    pub title: String,
+    pub model: Option<String>,
+    pub created_at: String,
+    pub updated_at: String,
 }
 
 #[derive(Serialize, Debug)]
 pub struct HistoryMessage {
+    pub conversation_id: Option<i64>,
+    /// 请求时的游标，为空表示这是最新一页
+    pub before_id: Option<i64>,
     pub messages: Vec<HistoryItem>,
+    /// 是否还有更早的消息（以 `messages[0].id` 作为下一页的 `before_id`）
+    pub has_more: bool,
 }
 
 #[derive(Serialize, Debug)]
 pub struct HistoryItem {
+    pub id: i64,
     pub role: String,
     pub content: String,
     pub model: Option<String>,
     pub timestamp: String,
+    /// 生成这条回复时执行的工具调用
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCallLog>,
+}
+
+/// 回复过程中执行的一次工具调用，随回复消息保存
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ToolCallLog {
+    pub name: String,
+    pub args: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+    /// 执行失败时的错误说明
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
 }
 
 #[derive(Serialize, Debug)]
 pub struct ResponseMessage {
     pub content: String,
     pub model: String,
+    /// 模型返回的 token 用量（后端未提供时省略）
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<TokenUsage>,
+    /// 放入提示词的检索结果，编号与回复中的 `[1]` 引用对应
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<Source>,
+}
+
+/// 回复可以引用的一条检索结果
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Source {
+    /// 提示词中的引用编号，从 1 开始
+    pub index: usize,
+    #[serde(flatten)]
+    pub origin: SourceOrigin,
+    /// 与问题的向量相似度（关键词命中或没有可比较的嵌入时为空）
+    pub score: Option<f32>,
+    /// 内容开头的片段
+    pub snippet: String,
+}
+
+/// 检索结果的出处
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SourceOrigin {
+    /// 历史消息
+    Message {
+        message_id: i64,
+        conversation_id: i64,
+        role: String,
+    },
+    /// 文件库中文本文件的片段
+    FileChunk {
+        file_id: i64,
+        file_name: String,
+        start_line: usize,
+        end_line: usize,
+    },
+}
+
+/// 一次模型调用的 token 用量
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct TokenUsage {
+    pub prompt_tokens: u64,
+    /// 回复正文的 token 数
+    pub candidate_tokens: u64,
+    /// 思考过程的 token 数
+    pub thought_tokens: u64,
+}
+
+impl TokenUsage {
+    /// 输出 token 数（回复 + 思考，均按输出计费）
+    pub fn output_tokens(&self) -> u64 {
+        self.candidate_tokens + self.thought_tokens
+    }
+
+    pub fn total(&self) -> u64 {
+        self.prompt_tokens + self.output_tokens()
+    }
+}
+
+impl std::ops::AddAssign for TokenUsage {
+    fn add_assign(&mut self, other: Self) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.candidate_tokens += other.candidate_tokens;
+        self.thought_tokens += other.thought_tokens;
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct DeltaMessage {
+    pub content: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ResponseDoneMessage {
+    pub content: String,
+    pub thinking: Option<String>,
+    pub model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<TokenUsage>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<Source>,
 }
 
 #[derive(Serialize, Debug)]
@@ -116,9 +452,27 @@ pub struct SystemMessage {
     pub content: String,
 }
 
+#[derive(Serialize, Debug)]
+pub struct ContextMessage {
+    pub conversation_id: Option<i64>,
+    pub files: Vec<FileInfo>,
+}
+
 #[derive(Serialize, Debug)]
 pub struct ErrorMessage {
     pub content: String,
+    /// 被限流或超出配额时，建议等待的秒数
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+}
+
+impl ErrorMessage {
+    pub fn new(content: impl Into<String>) -> Self {
+        Self {
+            content: content.into(),
+            retry_after: None,
+        }
+    }
 }
 
 #[derive(Serialize, Debug)]
@@ -130,13 +484,6 @@ pub struct LoadingMessage {
 #[derive(Serialize)]
 pub struct UploadResponse {
     pub status: String,
-    pub files: Option<Vec<UploadedFile>>,
+    pub files: Option<Vec<FileInfo>>,
     pub error: Option<String>,
 }
-
-#[derive(Serialize)]
-pub struct UploadedFile {
-    pub name: String,
-    pub content: String,
-    pub size: usize,
-}
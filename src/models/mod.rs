@@ -1,2 +1,6 @@
+pub mod auth;
+pub mod completions;
+pub mod files;
 pub mod gemini;
 pub mod messages;
+pub mod usage;
@@ -0,0 +1,51 @@
+use serde::{Deserialize, Serialize};
+
+use super::messages::TokenUsage;
+
+/// `GET /api/usage` 查询参数
+#[derive(Deserialize, Debug)]
+pub struct UsageQuery {
+    /// 统计最近多少天（含今天）
+    #[serde(default)]
+    pub days: Option<u32>,
+    /// 统计所有用户（仅限 USAGE_ADMINS 中的用户）
+    #[serde(default)]
+    pub all_users: bool,
+}
+
+/// 用量报告
+#[derive(Serialize, Debug)]
+pub struct UsageReport {
+    /// 统计起始日期（UTC，含）
+    pub since: String,
+    /// 价格的货币单位
+    pub currency: &'static str,
+    pub rows: Vec<UsageReportRow>,
+    pub totals: UsageTotals,
+}
+
+/// 按用户、日期和模型聚合的一行
+#[derive(Serialize, Debug)]
+pub struct UsageReportRow {
+    pub user_id: String,
+    pub username: Option<String>,
+    pub day: String,
+    pub model: Option<String>,
+    pub replies: u64,
+    #[serde(flatten)]
+    pub usage: TokenUsage,
+    pub total_tokens: u64,
+    /// 估算花费（该模型未配置价格时为 null）
+    pub cost: Option<f64>,
+}
+
+/// 报告中所有行的合计
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct UsageTotals {
+    pub replies: u64,
+    #[serde(flatten)]
+    pub usage: TokenUsage,
+    pub total_tokens: u64,
+    /// 已配置价格的模型的估算花费之和
+    pub cost: f64,
+}
@@ -0,0 +1,471 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+
+use super::embedding::cosine_similarity;
+
+/// 每层最多连接数（第 0 层为两倍）
+const DEFAULT_M: usize = 16;
+/// 构建时的候选集大小
+const DEFAULT_EF_CONSTRUCTION: usize = 100;
+/// 查询时的最小候选集大小
+const DEFAULT_EF_SEARCH: usize = 64;
+/// 节点数不超过该值时直接精确扫描
+const EXACT_SCAN_THRESHOLD: usize = 256;
+
+/// 图中的一个向量
+struct Node {
+    id: i64,
+    conversation_id: Option<i64>,
+    vector: Vec<f32>,
+    /// 每层的邻居（节点下标）
+    links: Vec<Vec<u32>>,
+    /// 被新嵌入替换后保留在图中用于连通，但不再出现在结果里
+    deleted: bool,
+}
+
+/// 带距离的节点下标，按距离排序
+#[derive(Clone, Copy, PartialEq)]
+struct Scored {
+    distance: f32,
+    index: u32,
+}
+
+impl Eq for Scored {}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance
+            .total_cmp(&other.distance)
+            .then(self.index.cmp(&other.index))
+    }
+}
+
+/// HNSW 近似最近邻索引（余弦相似度，要求向量已归一化）
+pub struct HnswIndex {
+    m: usize,
+    ef_construction: usize,
+    nodes: Vec<Node>,
+    /// 消息 ID -> 当前有效节点
+    ids: HashMap<i64, u32>,
+    entry: Option<u32>,
+    max_level: usize,
+}
+
+impl Default for HnswIndex {
+    fn default() -> Self {
+        Self::new(DEFAULT_M, DEFAULT_EF_CONSTRUCTION)
+    }
+}
+
+impl HnswIndex {
+    pub fn new(m: usize, ef_construction: usize) -> Self {
+        Self {
+            m: m.max(2),
+            ef_construction: ef_construction.max(1),
+            nodes: Vec::new(),
+            ids: HashMap::new(),
+            entry: None,
+            max_level: 0,
+        }
+    }
+
+    /// 有效向量数量
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    /// 插入或替换一条消息的向量
+    pub fn insert(&mut self, id: i64, conversation_id: Option<i64>, vector: Vec<f32>) {
+        if let Some(old) = self.ids.remove(&id) {
+            self.nodes[old as usize].deleted = true;
+        }
+
+        let index = self.nodes.len() as u32;
+        let level = self.random_level(id, index);
+        self.nodes.push(Node {
+            id,
+            conversation_id,
+            vector,
+            links: vec![Vec::new(); level + 1],
+            deleted: false,
+        });
+        self.ids.insert(id, index);
+
+        let Some(entry) = self.entry else {
+            self.entry = Some(index);
+            self.max_level = level;
+            return;
+        };
+
+        let query = self.nodes[index as usize].vector.clone();
+
+        // 在高层贪心下降到新节点所在的最高层
+        let mut entry_points = vec![self.scored(&query, entry)];
+        for layer in (level + 1..=self.max_level).rev() {
+            entry_points = self.search_layer(&query, &entry_points, 1, layer);
+        }
+
+        for layer in (0..=level.min(self.max_level)).rev() {
+            let candidates = self.search_layer(&query, &entry_points, self.ef_construction, layer);
+            let candidates_without_self: Vec<Scored> = candidates
+                .iter()
+                .copied()
+                .filter(|c| c.index != index)
+                .collect();
+            let neighbors = self.select_neighbors(&candidates_without_self, self.m);
+
+            self.nodes[index as usize].links[layer] = neighbors.clone();
+            for neighbor in neighbors {
+                self.connect(neighbor, index, layer);
+            }
+            entry_points = candidates;
+        }
+
+        if level > self.max_level {
+            self.max_level = level;
+            self.entry = Some(index);
+        }
+    }
+
+    /// 计算某条消息与查询的相似度（消息不在索引中时为 None）
+    pub fn similarity(&self, id: i64, query: &[f32]) -> Option<f32> {
+        let index = *self.ids.get(&id)?;
+        Some(cosine_similarity(query, &self.nodes[index as usize].vector))
+    }
+
+    /// 删除一条消息的向量
+    pub fn remove(&mut self, id: i64) {
+        if let Some(index) = self.ids.remove(&id) {
+            self.nodes[index as usize].deleted = true;
+        }
+    }
+
+    /// 检索最相似的 `top_k` 条，返回 (消息 ID, 相似度)，按相似度降序
+    ///
+    /// 指定 `conversation_id` 时只在该会话的向量中精确扫描
+    pub fn search(
+        &self,
+        query: &[f32],
+        conversation_id: Option<i64>,
+        top_k: usize,
+        min_similarity: f32,
+    ) -> Vec<(i64, f32)> {
+        if top_k == 0 || self.ids.is_empty() {
+            return Vec::new();
+        }
+
+        if conversation_id.is_some() || self.len() <= EXACT_SCAN_THRESHOLD {
+            return self.exact_search(query, conversation_id, top_k, min_similarity);
+        }
+
+        let Some(entry) = self.entry else {
+            return Vec::new();
+        };
+
+        let mut entry_points = vec![self.scored(query, entry)];
+        for layer in (1..=self.max_level).rev() {
+            entry_points = self.search_layer(query, &entry_points, 1, layer);
+        }
+
+        let ef = DEFAULT_EF_SEARCH.max(top_k * 2);
+        self.search_layer(query, &entry_points, ef, 0)
+            .into_iter()
+            .filter(|c| !self.nodes[c.index as usize].deleted)
+            .map(|c| (self.nodes[c.index as usize].id, 1.0 - c.distance))
+            .filter(|&(_, similarity)| similarity >= min_similarity)
+            .take(top_k)
+            .collect()
+    }
+
+    /// 线性扫描所有有效向量
+    pub fn exact_search(
+        &self,
+        query: &[f32],
+        conversation_id: Option<i64>,
+        top_k: usize,
+        min_similarity: f32,
+    ) -> Vec<(i64, f32)> {
+        let mut results: Vec<(i64, f32)> = self
+            .ids
+            .values()
+            .map(|&index| &self.nodes[index as usize])
+            .filter(|node| conversation_id.is_none() || node.conversation_id == conversation_id)
+            .map(|node| (node.id, cosine_similarity(query, &node.vector)))
+            .filter(|&(_, similarity)| similarity >= min_similarity)
+            .collect();
+
+        results.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        results.truncate(top_k);
+        results
+    }
+
+    fn scored(&self, query: &[f32], index: u32) -> Scored {
+        Scored {
+            distance: 1.0 - cosine_similarity(query, &self.nodes[index as usize].vector),
+            index,
+        }
+    }
+
+    /// 在某一层做 best-first 搜索，返回按距离升序的最多 `ef` 个节点
+    fn search_layer(
+        &self,
+        query: &[f32],
+        entry_points: &[Scored],
+        ef: usize,
+        layer: usize,
+    ) -> Vec<Scored> {
+        let mut visited = vec![false; self.nodes.len()];
+        for entry in entry_points {
+            visited[entry.index as usize] = true;
+        }
+        // 待扩展的候选（最小堆）
+        let mut candidates: BinaryHeap<std::cmp::Reverse<Scored>> = entry_points
+            .iter()
+            .copied()
+            .map(std::cmp::Reverse)
+            .collect();
+        // 当前最好的 ef 个结果（最大堆，堆顶为最远）
+        let mut results: BinaryHeap<Scored> = entry_points.iter().copied().collect();
+        while results.len() > ef {
+            results.pop();
+        }
+
+        while let Some(std::cmp::Reverse(current)) = candidates.pop() {
+            if let Some(furthest) = results.peek()
+                && current.distance > furthest.distance
+                && results.len() >= ef
+            {
+                break;
+            }
+
+            let Some(links) = self.nodes[current.index as usize].links.get(layer) else {
+                continue;
+            };
+            for &neighbor in links {
+                if std::mem::replace(&mut visited[neighbor as usize], true) {
+                    continue;
+                }
+                let scored = self.scored(query, neighbor);
+                if results.len() < ef || results.peek().is_some_and(|f| scored < *f) {
+                    candidates.push(std::cmp::Reverse(scored));
+                    results.push(scored);
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+
+        results.into_sorted_vec()
+    }
+
+    /// 启发式选择邻居：优先保留彼此不相近的候选，让图在簇之间也保持连通
+    ///
+    /// `candidates` 需按距离升序排列，名额没用完时用被跳过的最近候选补齐
+    fn select_neighbors(&self, candidates: &[Scored], max_links: usize) -> Vec<u32> {
+        let mut selected: Vec<Scored> = Vec::with_capacity(max_links);
+        let mut skipped: Vec<u32> = Vec::new();
+
+        for &candidate in candidates {
+            if selected.len() >= max_links {
+                break;
+            }
+            let vector = &self.nodes[candidate.index as usize].vector;
+            let diverse = selected
+                .iter()
+                .all(|s| self.scored(vector, s.index).distance > candidate.distance);
+            if diverse {
+                selected.push(candidate);
+            } else {
+                skipped.push(candidate.index);
+            }
+        }
+
+        let mut neighbors: Vec<u32> = selected.into_iter().map(|s| s.index).collect();
+        let missing = max_links.saturating_sub(neighbors.len());
+        neighbors.extend(skipped.into_iter().take(missing));
+        neighbors
+    }
+
+    /// 添加一条反向连接，超过上限时重新选择邻居
+    fn connect(&mut self, from: u32, to: u32, layer: usize) {
+        let max_links = if layer == 0 { self.m * 2 } else { self.m };
+
+        let links = &mut self.nodes[from as usize].links[layer];
+        if links.contains(&to) {
+            return;
+        }
+        links.push(to);
+        if links.len() <= max_links {
+            return;
+        }
+
+        let from_vector = &self.nodes[from as usize].vector;
+        let mut scored: Vec<Scored> = self.nodes[from as usize].links[layer]
+            .iter()
+            .map(|&index| self.scored(from_vector, index))
+            .collect();
+        scored.sort();
+        self.nodes[from as usize].links[layer] = self.select_neighbors(&scored, max_links);
+    }
+
+    /// 由消息 ID 确定性地生成节点层数（指数分布）
+    fn random_level(&self, id: i64, index: u32) -> usize {
+        let hash = splitmix64((id as u64) ^ ((index as u64) << 32));
+        // 取 53 位得到 (0, 1] 区间的均匀分布
+        let uniform = ((hash >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+        let level_mult = 1.0 / (self.m as f64).ln();
+        ((-uniform.ln() * level_mult) as usize).min(16)
+    }
+}
+
+fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::time::Instant;
+
+    /// 生成确定性的归一化向量
+    ///
+    /// 真实的文本嵌入按话题成簇，这里围绕若干随机中心加噪声来模拟
+    fn random_vectors(count: usize, dimension: usize, seed: u64) -> Vec<Vec<f32>> {
+        let mut state = seed;
+        let mut next = || {
+            state = splitmix64(state);
+            (state >> 40) as f32 / (1u64 << 24) as f32 - 0.5
+        };
+        let centers: Vec<Vec<f32>> = (0..64)
+            .map(|_| (0..dimension).map(|_| next()).collect())
+            .collect();
+
+        (0..count)
+            .map(|i| {
+                let center = &centers[i * 7919 % centers.len()];
+                let v: Vec<f32> = center.iter().map(|c| c + next() * 0.8).collect();
+                let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+                v.into_iter().map(|x| x / norm).collect()
+            })
+            .collect()
+    }
+
+    fn build_index(vectors: &[Vec<f32>]) -> HnswIndex {
+        let mut index = HnswIndex::default();
+        for (i, v) in vectors.iter().enumerate() {
+            index.insert(i as i64, Some(i as i64 % 10), v.clone());
+        }
+        index
+    }
+
+    fn recall(index: &HnswIndex, queries: &[Vec<f32>], top_k: usize) -> f32 {
+        let mut hits = 0;
+        for query in queries {
+            let expected: HashSet<i64> = index
+                .exact_search(query, None, top_k, -1.0)
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect();
+            hits += index
+                .search(query, None, top_k, -1.0)
+                .iter()
+                .filter(|(id, _)| expected.contains(id))
+                .count();
+        }
+        hits as f32 / (queries.len() * top_k) as f32
+    }
+
+    #[test]
+    fn test_hnsw_recall() {
+        let mut vectors = random_vectors(2050, 32, 1);
+        let queries = vectors.split_off(2000);
+        let index = build_index(&vectors);
+
+        assert_eq!(index.len(), 2000);
+        assert!(recall(&index, &queries, 5) >= 0.9);
+
+        // 向量本身一定能找回
+        let results = index.search(&vectors[42], None, 1, 0.0);
+        assert_eq!(results[0].0, 42);
+        assert!((results[0].1 - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_replace_remove_and_filters() {
+        let vectors = random_vectors(300, 16, 3);
+        let mut index = build_index(&vectors);
+
+        // 替换后旧向量不再出现
+        index.insert(7, Some(7), vectors[8].clone());
+        let results = index.search(&vectors[8], None, 2, 0.0);
+        let ids: Vec<i64> = results.iter().map(|(id, _)| *id).collect();
+        assert!(ids.contains(&7) && ids.contains(&8));
+        assert_eq!(index.len(), 300);
+
+        index.remove(8);
+        assert!(
+            index
+                .search(&vectors[8], None, 5, 0.0)
+                .iter()
+                .all(|(id, _)| *id != 8)
+        );
+
+        // 会话过滤和相似度阈值
+        let scoped = index.search(&vectors[3], Some(3), 50, -1.0);
+        assert!(!scoped.is_empty());
+        assert!(scoped.iter().all(|(id, _)| id % 10 == 3 || *id == 7));
+        assert!(index.search(&vectors[3], None, 50, 0.99).len() == 1);
+    }
+
+    /// HNSW 与线性扫描的性能对比
+    ///
+    /// 运行：`cargo test --release bench_hnsw_vs_linear_scan -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_hnsw_vs_linear_scan() {
+        const DIMENSION: usize = 768;
+        const TOP_K: usize = 5;
+
+        for count in [1_000, 10_000, 20_000] {
+            let mut vectors = random_vectors(count + 200, DIMENSION, 7);
+            let queries = vectors.split_off(count);
+
+            let start = Instant::now();
+            let index = build_index(&vectors);
+            let build = start.elapsed();
+
+            let start = Instant::now();
+            for query in &queries {
+                std::hint::black_box(index.exact_search(query, None, TOP_K, -1.0));
+            }
+            let linear = start.elapsed() / queries.len() as u32;
+
+            let start = Instant::now();
+            for query in &queries {
+                std::hint::black_box(index.search(query, None, TOP_K, -1.0));
+            }
+            let hnsw = start.elapsed() / queries.len() as u32;
+
+            println!(
+                "{:>6} 条: 构建 {:?}, 线性扫描 {:?}/次, HNSW {:?}/次, recall@{} = {:.3}",
+                count,
+                build,
+                linear,
+                hnsw,
+                TOP_K,
+                recall(&index, &queries, TOP_K)
+            );
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::path::Path;
+
+/// 单个上传文件的大小上限
+pub const MAX_UPLOAD_BYTES: usize = 50 * 1024 * 1024;
+
+/// 不超过该大小的二进制文件以 base64 内联发送，更大的文件通过后端的文件存储上传
+///
+/// Gemini 限制单个请求不超过 20 MB，内联数据还要经过客户端往返，因此取得较小
+pub const MAX_INLINE_BYTES: usize = 4 * 1024 * 1024;
+
+/// 按文件头识别的格式：(魔数偏移, 魔数, MIME 类型)
+const SIGNATURES: &[(usize, &[u8], &str)] = &[
+    (0, b"\x89PNG\r\n\x1a\n", "image/png"),
+    (0, b"\xff\xd8\xff", "image/jpeg"),
+    (0, b"GIF87a", "image/gif"),
+    (0, b"GIF89a", "image/gif"),
+    (0, b"%PDF-", "application/pdf"),
+    (0, b"ID3", "audio/mp3"),
+    (0, b"OggS", "audio/ogg"),
+    (0, b"fLaC", "audio/flac"),
+    (8, b"WEBP", "image/webp"),
+    (8, b"WAVE", "audio/wav"),
+    (8, b"AIFF", "audio/aiff"),
+    (4, b"ftypheic", "image/heic"),
+    (4, b"ftypheix", "image/heic"),
+    (4, b"ftypmif1", "image/heif"),
+    (4, b"ftypM4A", "audio/aac"),
+];
+
+/// 文件头无法识别时按扩展名判断
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("webp", "image/webp"),
+    ("heic", "image/heic"),
+    ("heif", "image/heif"),
+    ("gif", "image/gif"),
+    ("pdf", "application/pdf"),
+    ("mp3", "audio/mp3"),
+    ("wav", "audio/wav"),
+    ("aiff", "audio/aiff"),
+    ("aac", "audio/aac"),
+    ("m4a", "audio/aac"),
+    ("ogg", "audio/ogg"),
+    ("flac", "audio/flac"),
+];
+
+/// 模型可以直接理解的二进制格式（Gemini 支持的图片、PDF 和音频）
+const SUPPORTED_MIME_TYPES: &[&str] = &[
+    "image/png",
+    "image/jpeg",
+    "image/webp",
+    "image/heic",
+    "image/heif",
+    "application/pdf",
+    "audio/mp3",
+    "audio/wav",
+    "audio/aiff",
+    "audio/aac",
+    "audio/ogg",
+    "audio/flac",
+];
+
+/// 识别二进制文件的 MIME 类型：优先检查文件头，其次看扩展名
+///
+/// 返回 None 表示不是已知的二进制格式（可能是文本文件）
+pub fn detect_mime(name: &str, data: &[u8]) -> Option<&'static str> {
+    let by_signature = SIGNATURES.iter().find_map(|(offset, magic, mime)| {
+        data.get(*offset..offset + magic.len())
+            .filter(|bytes| bytes == magic)
+            .map(|_| *mime)
+    });
+    // MP3 没有 ID3 标签时以 MPEG 层 III 的帧同步字开头（排除 UTF-16 的 BOM `FF FE`）
+    let by_frame_sync =
+        (data.len() >= 2 && data[0] == 0xff && data[1] & 0xe6 == 0xe2).then_some("audio/mp3");
+
+    by_signature.or(by_frame_sync).or_else(|| {
+        let extension = Path::new(name).extension()?.to_str()?.to_lowercase();
+        EXTENSIONS
+            .iter()
+            .find(|(ext, _)| *ext == extension)
+            .map(|(_, mime)| *mime)
+    })
+}
+
+/// 该 MIME 类型能否作为附件发送给模型
+pub fn is_supported(mime_type: &str) -> bool {
+    SUPPORTED_MIME_TYPES.contains(&mime_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_mime_by_signature() {
+        assert_eq!(
+            detect_mime("x.bin", b"\x89PNG\r\n\x1a\n\0\0"),
+            Some("image/png")
+        );
+        assert_eq!(
+            detect_mime("spec", b"%PDF-1.7\n..."),
+            Some("application/pdf")
+        );
+        assert_eq!(
+            detect_mime("a", b"RIFF\x10\0\0\0WEBPVP8 "),
+            Some("image/webp")
+        );
+        assert_eq!(
+            detect_mime("a", b"RIFF\x10\0\0\0WAVEfmt "),
+            Some("audio/wav")
+        );
+        // 文件头优先于扩展名
+        assert_eq!(
+            detect_mime("photo.png", b"\xff\xd8\xff\xe0"),
+            Some("image/jpeg")
+        );
+    }
+
+    #[test]
+    fn test_detect_mime_falls_back_to_extension() {
+        assert_eq!(detect_mime("Voice.M4A", b"\0\0\0\0"), Some("audio/aac"));
+        assert_eq!(detect_mime("notes.md", "# 标题".as_bytes()), None);
+        assert_eq!(detect_mime("main.rs", b"fn main() {}"), None);
+        assert_eq!(detect_mime("utf16.txt", b"\xff\xfeh\0i\0"), None);
+        assert_eq!(detect_mime("song", b"\xff\xfb\x90\x64"), Some("audio/mp3"));
+    }
+
+    #[test]
+    fn test_is_supported() {
+        assert!(is_supported("application/pdf"));
+        assert!(!is_supported("image/gif"));
+    }
+}
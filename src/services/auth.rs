@@ -0,0 +1,492 @@
+use argon2::Argon2;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::time::Duration;
+use uuid::Uuid;
+
+use super::memory::{ApiToken, ChatMemory, User};
+use crate::models::auth::{
+    ApiTokenInfo, AuthResponse, CreateApiTokenRequest, CreatedApiToken, Credentials, Scope,
+    SignupRequest, UserInfo,
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// 用户名长度范围（字符数）
+const USERNAME_CHARS: std::ops::RangeInclusive<usize> = 3..=32;
+
+/// 密码长度范围（字符数）
+const PASSWORD_CHARS: std::ops::RangeInclusive<usize> = 8..=128;
+
+/// API 令牌前缀（会话令牌是 base64 编码的 UUID，不会以此开头）
+const API_TOKEN_PREFIX: &str = "wc_";
+
+/// 列表中展示的令牌前缀长度
+const API_TOKEN_DISPLAY_CHARS: usize = 10;
+
+/// API 令牌名称的最大长度（字符数）
+const MAX_TOKEN_NAME_CHARS: usize = 64;
+
+/// API 令牌的最长有效天数
+const MAX_TOKEN_EXPIRY_DAYS: u32 = 3650;
+
+/// 会话令牌的签发与校验
+///
+/// 令牌格式为 `base64url(用户 ID.过期时间戳).base64url(HMAC-SHA256 签名)`，
+/// 服务端不保存会话状态，更换密钥即可让所有令牌失效。
+pub struct SessionManager {
+    secret: Vec<u8>,
+    ttl: Duration,
+}
+
+/// 签发的会话令牌
+#[derive(Debug, Clone)]
+pub struct SessionToken {
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl SessionManager {
+    pub fn new(secret: impl Into<Vec<u8>>, ttl: Duration) -> Self {
+        Self {
+            secret: secret.into(),
+            ttl,
+        }
+    }
+
+    /// 从环境变量读取配置
+    ///
+    /// 未设置 `SESSION_SECRET` 时使用随机密钥（重启后所有令牌失效），
+    /// 返回值的第二项表示是否使用了随机密钥。
+    pub fn from_env() -> (Self, bool) {
+        let ttl = env::var("SESSION_TTL_HOURS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .map(|hours| Duration::from_secs(hours * 3600))
+            .unwrap_or(Duration::from_secs(7 * 24 * 3600));
+
+        match env::var("SESSION_SECRET") {
+            Ok(secret) if !secret.is_empty() => (Self::new(secret, ttl), false),
+            _ => {
+                let mut secret = Uuid::new_v4().as_bytes().to_vec();
+                secret.extend_from_slice(Uuid::new_v4().as_bytes());
+                (Self::new(secret, ttl), true)
+            }
+        }
+    }
+
+    /// 为用户签发新令牌
+    pub fn issue(&self, user_id: &str) -> SessionToken {
+        let expires_at = Utc::now() + chrono::Duration::from_std(self.ttl).unwrap_or_default();
+        let token = self.sign(user_id, expires_at.timestamp());
+        SessionToken { token, expires_at }
+    }
+
+    /// 校验令牌，返回其中的用户 ID
+    pub fn verify(&self, token: &str) -> Result<String, String> {
+        let (payload, signature) = token.split_once('.').ok_or("令牌格式错误")?;
+        let payload = URL_SAFE_NO_PAD
+            .decode(payload)
+            .map_err(|_| "令牌格式错误".to_string())?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| "令牌格式错误".to_string())?;
+
+        let mut mac = self.mac();
+        mac.update(&payload);
+        mac.verify_slice(&signature)
+            .map_err(|_| "令牌签名无效".to_string())?;
+
+        let payload = String::from_utf8(payload).map_err(|_| "令牌格式错误".to_string())?;
+        let (user_id, expires) = payload.rsplit_once('.').ok_or("令牌格式错误")?;
+        let expires: i64 = expires.parse().map_err(|_| "令牌格式错误".to_string())?;
+        if expires <= Utc::now().timestamp() {
+            return Err("登录已过期，请重新登录".to_string());
+        }
+
+        Ok(user_id.to_string())
+    }
+
+    fn sign(&self, user_id: &str, expires: i64) -> String {
+        let payload = format!("{}.{}", user_id, expires);
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(payload),
+            URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+        )
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.secret).expect("HMAC 接受任意长度的密钥")
+    }
+}
+
+/// 使用 argon2 计算密码哈希（PHC 字符串格式）
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::encode_b64(Uuid::new_v4().as_bytes())
+        .map_err(|e| format!("生成盐值失败: {}", e))?;
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| format!("计算密码哈希失败: {}", e))
+}
+
+/// 校验密码是否与哈希匹配
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    PasswordHash::new(password_hash)
+        .map(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+        .unwrap_or(false)
+}
+
+/// 检查用户名和密码格式
+fn validate_credentials(username: &str, password: &str) -> Result<(), String> {
+    let username_chars = username.chars().count();
+    if !USERNAME_CHARS.contains(&username_chars) {
+        return Err(format!(
+            "用户名长度需在 {} 到 {} 个字符之间",
+            USERNAME_CHARS.start(),
+            USERNAME_CHARS.end()
+        ));
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err("用户名只能包含字母、数字、下划线和连字符".to_string());
+    }
+
+    let password_chars = password.chars().count();
+    if !PASSWORD_CHARS.contains(&password_chars) {
+        return Err(format!(
+            "密码长度需在 {} 到 {} 个字符之间",
+            PASSWORD_CHARS.start(),
+            PASSWORD_CHARS.end()
+        ));
+    }
+
+    Ok(())
+}
+
+fn auth_response(sessions: &SessionManager, user: User) -> AuthResponse {
+    let session = sessions.issue(&user.id);
+    AuthResponse {
+        token: session.token,
+        expires_at: session.expires_at.to_rfc3339(),
+        user: UserInfo {
+            id: user.id,
+            username: user.username,
+        },
+    }
+}
+
+/// 注册新用户并签发令牌
+pub fn signup(
+    memory: &ChatMemory,
+    sessions: &SessionManager,
+    request: SignupRequest,
+) -> Result<AuthResponse, String> {
+    let username = request.username.trim();
+    validate_credentials(username, &request.password)?;
+
+    let user_id = Uuid::new_v4().to_string();
+    let password_hash = hash_password(&request.password)?;
+    let user = memory
+        .create_user(&user_id, username, &password_hash)
+        .map_err(|e| format!("创建用户失败: {}", e))?
+        .ok_or("用户名已被占用")?;
+
+    Ok(auth_response(sessions, user))
+}
+
+/// 校验用户名和密码并签发令牌
+pub fn login(
+    memory: &ChatMemory,
+    sessions: &SessionManager,
+    request: Credentials,
+) -> Result<AuthResponse, String> {
+    let user = memory
+        .get_user_by_username(request.username.trim())
+        .map_err(|e| format!("查询用户失败: {}", e))?;
+
+    match user {
+        Some((user, password_hash)) if verify_password(&request.password, &password_hash) => {
+            Ok(auth_response(sessions, user))
+        }
+        _ => Err("用户名或密码错误".to_string()),
+    }
+}
+
+/// 校验请求携带的令牌，返回用户 ID 和权限范围
+///
+/// `wc_` 开头的视为 API 令牌，按哈希在数据库中查找；其余视为登录会话令牌，
+/// 会话拥有该用户的全部权限。
+pub fn authenticate(
+    memory: &ChatMemory,
+    sessions: &SessionManager,
+    token: &str,
+) -> Result<(String, Vec<Scope>), String> {
+    if !token.starts_with(API_TOKEN_PREFIX) {
+        return sessions
+            .verify(token)
+            .map(|user_id| (user_id, Scope::ALL.to_vec()));
+    }
+
+    let api_token = memory
+        .use_api_token(&hash_api_token(token))
+        .map_err(|e| format!("查询令牌失败: {}", e))?
+        .ok_or("API 令牌无效或已吊销")?;
+    if api_token.expires_at.is_some_and(|t| t <= Utc::now()) {
+        return Err("API 令牌已过期".to_string());
+    }
+
+    Ok((api_token.user_id, parse_scopes(&api_token.scopes)))
+}
+
+/// 计算 API 令牌的存储哈希（令牌本身是高熵随机串，无需加盐慢哈希）
+fn hash_api_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn parse_scopes(scopes: &str) -> Vec<Scope> {
+    scopes.split(',').filter_map(Scope::parse).collect()
+}
+
+fn api_token_info(token: ApiToken) -> ApiTokenInfo {
+    ApiTokenInfo {
+        id: token.id,
+        name: token.name,
+        prefix: token.prefix,
+        scopes: parse_scopes(&token.scopes),
+        created_at: token.created_at.to_rfc3339(),
+        expires_at: token.expires_at.map(|t| t.to_rfc3339()),
+        last_used_at: token.last_used_at.map(|t| t.to_rfc3339()),
+    }
+}
+
+/// 为用户创建 API 令牌，明文令牌只在返回值中出现一次
+pub fn create_api_token(
+    memory: &ChatMemory,
+    user_id: &str,
+    request: CreateApiTokenRequest,
+) -> Result<CreatedApiToken, String> {
+    let name = request.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_TOKEN_NAME_CHARS {
+        return Err(format!(
+            "令牌名称不能为空且不超过 {} 个字符",
+            MAX_TOKEN_NAME_CHARS
+        ));
+    }
+
+    let mut scopes = request.scopes;
+    scopes.sort_by_key(|s| s.as_str());
+    scopes.dedup();
+    if scopes.is_empty() {
+        return Err("至少需要一个权限范围".to_string());
+    }
+
+    let expires_at = match request.expires_in_days {
+        Some(days) if !(1..=MAX_TOKEN_EXPIRY_DAYS).contains(&days) => {
+            return Err(format!(
+                "有效天数必须在 1 到 {} 之间",
+                MAX_TOKEN_EXPIRY_DAYS
+            ));
+        }
+        Some(days) => Some(Utc::now() + chrono::Duration::days(days as i64)),
+        None => None,
+    };
+
+    let mut secret = Uuid::new_v4().as_bytes().to_vec();
+    secret.extend_from_slice(Uuid::new_v4().as_bytes());
+    let token = format!("{}{}", API_TOKEN_PREFIX, URL_SAFE_NO_PAD.encode(secret));
+
+    let scopes = scopes
+        .iter()
+        .map(|s| s.as_str())
+        .collect::<Vec<_>>()
+        .join(",");
+    let stored = memory
+        .create_api_token(
+            user_id,
+            name,
+            &hash_api_token(&token),
+            &token[..API_TOKEN_DISPLAY_CHARS],
+            &scopes,
+            expires_at,
+        )
+        .map_err(|e| format!("创建令牌失败: {}", e))?;
+
+    Ok(CreatedApiToken {
+        token,
+        info: api_token_info(stored),
+    })
+}
+
+/// 列出用户的 API 令牌
+pub fn list_api_tokens(memory: &ChatMemory, user_id: &str) -> Result<Vec<ApiTokenInfo>, String> {
+    memory
+        .list_api_tokens(user_id)
+        .map(|tokens| tokens.into_iter().map(api_token_info).collect())
+        .map_err(|e| format!("获取令牌列表失败: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sessions() -> SessionManager {
+        SessionManager::new("test-secret", Duration::from_secs(3600))
+    }
+
+    #[test]
+    fn test_session_token_roundtrip() {
+        let sessions = sessions();
+        let session = sessions.issue("user-1");
+        assert_eq!(sessions.verify(&session.token).unwrap(), "user-1");
+        assert!(session.expires_at > Utc::now());
+
+        // 其他密钥签发的令牌无效
+        let other = SessionManager::new("other-secret", Duration::from_secs(3600));
+        assert!(other.verify(&session.token).is_err());
+    }
+
+    #[test]
+    fn test_session_token_rejects_tampering_and_expiry() {
+        let sessions = sessions();
+        let session = sessions.issue("user-1");
+
+        // 篡改用户 ID 后签名不匹配
+        let (_, signature) = session.token.split_once('.').unwrap();
+        let forged = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(format!("user-2.{}", session.expires_at.timestamp())),
+            signature
+        );
+        assert!(sessions.verify(&forged).is_err());
+        assert!(sessions.verify("garbage").is_err());
+
+        let expired = sessions.sign("user-1", Utc::now().timestamp() - 1);
+        assert!(sessions.verify(&expired).is_err());
+    }
+
+    #[test]
+    fn test_password_hash() {
+        let hash = hash_password("correct horse").unwrap();
+        assert!(hash.starts_with("$argon2"));
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("wrong horse", &hash));
+        assert!(!verify_password("correct horse", "not a hash"));
+    }
+
+    #[test]
+    fn test_signup_and_login() {
+        let memory = ChatMemory::new(":memory:").unwrap();
+        let sessions = sessions();
+
+        let signup_request = |username: &str| SignupRequest {
+            username: username.to_string(),
+            password: "password123".to_string(),
+        };
+
+        let created = signup(&memory, &sessions, signup_request("alice")).unwrap();
+        assert_eq!(sessions.verify(&created.token).unwrap(), created.user.id);
+
+        // 用户名不区分大小写地唯一
+        assert!(signup(&memory, &sessions, signup_request("Alice")).is_err());
+        assert!(signup(&memory, &sessions, signup_request("a")).is_err());
+
+        let login_request = |password: &str| Credentials {
+            username: "ALICE".to_string(),
+            password: password.to_string(),
+        };
+        let logged_in = login(&memory, &sessions, login_request("password123")).unwrap();
+        assert_eq!(logged_in.user.id, created.user.id);
+        assert!(login(&memory, &sessions, login_request("password124")).is_err());
+    }
+
+    #[test]
+    fn test_api_tokens() {
+        let memory = ChatMemory::new(":memory:").unwrap();
+        let sessions = sessions();
+
+        let request = |scopes: Vec<Scope>, expires_in_days| CreateApiTokenRequest {
+            name: "ci".to_string(),
+            scopes,
+            expires_in_days,
+        };
+
+        let created = create_api_token(
+            &memory,
+            "u1",
+            request(vec![Scope::Chat, Scope::ReadHistory, Scope::Chat], Some(30)),
+        )
+        .unwrap();
+        assert!(created.token.starts_with(API_TOKEN_PREFIX));
+        assert!(created.token.starts_with(&created.info.prefix));
+        assert_eq!(created.info.scopes, vec![Scope::Chat, Scope::ReadHistory]);
+
+        // 只保存哈希
+        let stored = memory.list_api_tokens("u1").unwrap();
+        assert_eq!(stored.len(), 1);
+        assert!(stored[0].last_used_at.is_none());
+
+        let (user_id, scopes) = authenticate(&memory, &sessions, &created.token).unwrap();
+        assert_eq!(user_id, "u1");
+        assert_eq!(scopes, vec![Scope::Chat, Scope::ReadHistory]);
+        assert!(
+            list_api_tokens(&memory, "u1").unwrap()[0]
+                .last_used_at
+                .is_some()
+        );
+
+        // 会话令牌拥有全部权限
+        let session = sessions.issue("u1");
+        let (_, scopes) = authenticate(&memory, &sessions, &session.token).unwrap();
+        assert_eq!(scopes, Scope::ALL.to_vec());
+
+        assert!(create_api_token(&memory, "u1", request(Vec::new(), None)).is_err());
+        assert!(create_api_token(&memory, "u1", request(vec![Scope::Chat], Some(0))).is_err());
+        assert!(create_api_token(&memory, "u1", request(vec![Scope::Chat], Some(3651))).is_err());
+        assert!(
+            create_api_token(&memory, "u1", request(vec![Scope::Chat], Some(u32::MAX))).is_err()
+        );
+        let longest = create_api_token(&memory, "u1", request(vec![Scope::Chat], Some(3650)));
+        assert!(longest.unwrap().info.expires_at.is_some());
+
+        // 吊销后不可用，其他用户不能吊销
+        assert!(!memory.delete_api_token("u2", created.info.id).unwrap());
+        assert!(memory.delete_api_token("u1", created.info.id).unwrap());
+        assert!(authenticate(&memory, &sessions, &created.token).is_err());
+        assert!(authenticate(&memory, &sessions, "wc_unknown").is_err());
+    }
+
+    #[test]
+    fn test_expired_api_token() {
+        let memory = ChatMemory::new(":memory:").unwrap();
+        let token = "wc_expired";
+        memory
+            .create_api_token(
+                "u1",
+                "old",
+                &hash_api_token(token),
+                "wc_expired",
+                "chat",
+                Some(Utc::now() - chrono::Duration::days(1)),
+            )
+            .unwrap();
+        assert!(authenticate(&memory, &sessions(), token).is_err());
+    }
+}
@@ -0,0 +1,489 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::env;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::documents;
+use super::embedding::{EmbeddingProvider, EmbeddingTask};
+use super::files::TEXT_MIME_TYPE;
+use super::memory::ChatMemory;
+
+/// 嵌入补全任务配置
+#[derive(Debug, Clone)]
+pub struct BackfillConfig {
+    /// 两轮扫描之间的间隔
+    pub interval: Duration,
+    /// 每轮最多处理的消息数
+    pub batch_size: usize,
+    /// 相邻两次嵌入请求之间的最小间隔（限速）
+    pub request_interval: Duration,
+    /// 只处理创建时间早于该时长的消息，避免和聊天流程抢着生成嵌入
+    pub min_age: Duration,
+    /// 失败后退避的最长时间
+    pub max_backoff: Duration,
+    /// 单条消息最多尝试次数，超过后跳过
+    pub max_attempts: u32,
+}
+
+impl Default for BackfillConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            batch_size: 16,
+            request_interval: Duration::from_millis(500),
+            min_age: Duration::from_secs(60),
+            max_backoff: Duration::from_secs(600),
+            max_attempts: 5,
+        }
+    }
+}
+
+impl BackfillConfig {
+    /// 从环境变量读取配置，未设置的项使用默认值
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let secs = |name: &str, fallback: Duration| {
+            env::var(name)
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(fallback)
+        };
+
+        Self {
+            interval: secs("BACKFILL_INTERVAL_SECS", default.interval),
+            batch_size: env::var("BACKFILL_BATCH_SIZE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default.batch_size),
+            request_interval: env::var("BACKFILL_REQUEST_INTERVAL_MS")
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .map(Duration::from_millis)
+                .unwrap_or(default.request_interval),
+            ..default
+        }
+    }
+}
+
+/// 嵌入补全任务的运行状态（通过健康检查接口上报）
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BackfillSnapshot {
+    pub enabled: bool,
+    pub embedding_model: Option<String>,
+    pub pending: usize,
+    /// 等待生成嵌入的文件片段数
+    pub pending_chunks: usize,
+    pub embedded_total: u64,
+    pub failed_total: u64,
+    pub skipped: usize,
+    pub backoff_secs: u64,
+    pub last_run: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+}
+
+/// 公开状态中代替错误详情的提示
+const PUBLIC_ERROR: &str = "嵌入补全出错，详情见服务端日志";
+
+/// 在后台任务和 HTTP 处理器之间共享的状态
+#[derive(Default)]
+pub struct BackfillStatus {
+    inner: Mutex<BackfillSnapshot>,
+}
+
+impl BackfillStatus {
+    pub fn snapshot(&self) -> BackfillSnapshot {
+        self.inner.lock().unwrap().clone()
+    }
+
+    /// 供公开接口使用的状态：错误详情可能包含上游地址等敏感信息，只写入服务端日志
+    pub fn public_snapshot(&self) -> BackfillSnapshot {
+        let mut snapshot = self.snapshot();
+        if snapshot.last_error.is_some() {
+            snapshot.last_error = Some(PUBLIC_ERROR.to_string());
+        }
+        snapshot
+    }
+
+    fn update(&self, f: impl FnOnce(&mut BackfillSnapshot)) {
+        f(&mut self.inner.lock().unwrap());
+    }
+
+    fn record_error(&self, error: String) {
+        eprintln!("嵌入补全失败: {}", error);
+        self.update(|s| s.last_error = Some(error));
+    }
+}
+
+/// 单轮补全的结果
+#[derive(Debug, Default, PartialEq)]
+struct BatchOutcome {
+    embedded: usize,
+    failed: usize,
+}
+
+/// 启动后台嵌入补全任务
+///
+/// 定期取出没有嵌入的消息和文件片段，限速生成嵌入并写回数据库；
+/// 请求失败时按指数退避，同一条消息或片段多次失败后跳过。
+pub fn spawn_embedding_backfill(
+    memory: Arc<ChatMemory>,
+    embedder: Arc<dyn EmbeddingProvider>,
+    config: BackfillConfig,
+) -> Arc<BackfillStatus> {
+    let status = Arc::new(BackfillStatus::default());
+    let task_status = status.clone();
+
+    actix_web::rt::spawn(async move {
+        let mut attempts: HashMap<i64, u32> = HashMap::new();
+        let mut backoff = Duration::ZERO;
+
+        loop {
+            tokio::time::sleep(config.interval + backoff).await;
+
+            let model_id = embedder.model_id();
+            let embed = |text: String| {
+                let embedder = embedder.clone();
+                async move { embedder.embed(&text, EmbeddingTask::Document).await }
+            };
+            let mut outcome = run_batch(
+                &memory,
+                &task_status,
+                &config,
+                &model_id,
+                &mut attempts,
+                &embed,
+            )
+            .await;
+            // 消息嵌入出错时本轮不再处理片段，交给退避逻辑
+            if outcome.failed == 0 {
+                let chunks =
+                    run_chunk_batch(&memory, &task_status, &config, &model_id, &embed).await;
+                outcome.embedded += chunks.embedded;
+                outcome.failed += chunks.failed;
+            }
+
+            // 整轮都失败时退避，有成功则恢复正常间隔
+            backoff = if outcome.failed > 0 && outcome.embedded == 0 {
+                next_backoff(backoff, config.max_backoff)
+            } else {
+                Duration::ZERO
+            };
+            task_status.update(|s| s.backoff_secs = backoff.as_secs());
+        }
+    });
+
+    status
+}
+
+/// 处理一批没有嵌入的消息
+async fn run_batch<F, Fut>(
+    memory: &ChatMemory,
+    status: &BackfillStatus,
+    config: &BackfillConfig,
+    embedding_model: &str,
+    attempts: &mut HashMap<i64, u32>,
+    embed: F,
+) -> BatchOutcome
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<Vec<f32>, String>>,
+{
+    let skipped = attempts
+        .values()
+        .filter(|&&n| n >= config.max_attempts)
+        .count();
+    let cutoff = Utc::now() - chrono::Duration::from_std(config.min_age).unwrap_or_default();
+
+    let candidates =
+        match memory.get_messages_without_embedding(embedding_model, config.batch_size + skipped) {
+            Ok(records) => records,
+            Err(e) => {
+                status.record_error(format!("读取待补全消息失败: {}", e));
+                return BatchOutcome::default();
+            }
+        };
+
+    let batch: Vec<_> = candidates
+        .into_iter()
+        .filter(|r| attempts.get(&r.id).copied().unwrap_or(0) < config.max_attempts)
+        .filter(|r| r.created_at <= cutoff)
+        .take(config.batch_size)
+        .collect();
+
+    let mut outcome = BatchOutcome::default();
+    for (i, record) in batch.into_iter().enumerate() {
+        if i > 0 {
+            tokio::time::sleep(config.request_interval).await;
+        }
+
+        match embed(record.content).await {
+            Ok(embedding) => {
+                match memory.update_embedding(record.id, &embedding, embedding_model) {
+                    Ok(_) => {
+                        attempts.remove(&record.id);
+                        outcome.embedded += 1;
+                    }
+                    Err(e) => {
+                        outcome.failed += 1;
+                        status.record_error(format!("保存嵌入失败: {}", e));
+                    }
+                }
+            }
+            Err(e) => {
+                *attempts.entry(record.id).or_insert(0) += 1;
+                outcome.failed += 1;
+                status.record_error(e);
+                // 出错时结束本轮，交给退避逻辑
+                break;
+            }
+        }
+    }
+
+    let pending = memory
+        .message_count()
+        .unwrap_or(0)
+        .saturating_sub(memory.embedded_message_count(embedding_model).unwrap_or(0));
+    let skipped = attempts
+        .values()
+        .filter(|&&n| n >= config.max_attempts)
+        .count();
+
+    status.update(|s| {
+        s.enabled = true;
+        s.embedding_model = Some(embedding_model.to_string());
+        s.pending = pending;
+        s.skipped = skipped;
+        s.embedded_total += outcome.embedded as u64;
+        s.failed_total += outcome.failed as u64;
+        s.last_run = Some(Utc::now());
+    });
+
+    outcome
+}
+
+/// 切分尚未切分的文本文件，并为一批文件片段生成嵌入
+///
+/// 与消息补全共用限速；片段的失败次数保存在数据库中，达到上限后跳过
+async fn run_chunk_batch<F, Fut>(
+    memory: &ChatMemory,
+    status: &BackfillStatus,
+    config: &BackfillConfig,
+    embedding_model: &str,
+    embed: F,
+) -> BatchOutcome
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<Vec<f32>, String>>,
+{
+    // 上传时切分失败或更早保存的文本文件
+    match memory.get_unchunked_files(TEXT_MIME_TYPE, config.batch_size) {
+        Ok(files) => {
+            for (file, data) in files {
+                if let Err(e) =
+                    documents::chunk_file(memory, file.id, &String::from_utf8_lossy(&data))
+                {
+                    status.record_error(e);
+                }
+            }
+        }
+        Err(e) => status.record_error(format!("读取待切分文件失败: {}", e)),
+    }
+
+    let chunks = match memory.get_chunks_without_embedding(
+        embedding_model,
+        config.max_attempts,
+        config.batch_size,
+    ) {
+        Ok(chunks) => chunks,
+        Err(e) => {
+            status.record_error(format!("读取待补全片段失败: {}", e));
+            return BatchOutcome::default();
+        }
+    };
+
+    let mut outcome = BatchOutcome::default();
+    for chunk in chunks {
+        tokio::time::sleep(config.request_interval).await;
+
+        let input = documents::chunk_embedding_input(&chunk.file_name, &chunk.content);
+        match embed(input).await {
+            Ok(embedding) => {
+                match memory.update_chunk_embedding(chunk.id, &embedding, embedding_model) {
+                    Ok(_) => outcome.embedded += 1,
+                    Err(e) => {
+                        outcome.failed += 1;
+                        status.record_error(format!("保存片段嵌入失败: {}", e));
+                    }
+                }
+            }
+            Err(e) => {
+                if let Err(e) = memory.record_chunk_embed_failure(chunk.id, embedding_model) {
+                    eprintln!("记录片段嵌入失败次数失败: {}", e);
+                }
+                outcome.failed += 1;
+                status.record_error(e);
+                // 出错时结束本轮，交给退避逻辑
+                break;
+            }
+        }
+    }
+
+    let pending_chunks = memory
+        .pending_chunk_count(embedding_model, config.max_attempts)
+        .unwrap_or(0);
+    status.update(|s| {
+        s.pending_chunks = pending_chunks;
+        s.embedded_total += outcome.embedded as u64;
+        s.failed_total += outcome.failed as u64;
+    });
+
+    outcome
+}
+
+/// 计算下一次退避时间（指数增长，有上限）
+pub(super) fn next_backoff(current: Duration, max: Duration) -> Duration {
+    if current.is_zero() {
+        Duration::from_secs(30).min(max)
+    } else {
+        (current * 2).min(max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::embedding::EMBEDDING_DIMENSION;
+    use super::*;
+
+    const TEST_MODEL: &str = "test/model";
+
+    fn test_config() -> BackfillConfig {
+        BackfillConfig {
+            request_interval: Duration::ZERO,
+            min_age: Duration::ZERO,
+            max_attempts: 2,
+            ..BackfillConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_next_backoff() {
+        let max = Duration::from_secs(100);
+        assert_eq!(next_backoff(Duration::ZERO, max), Duration::from_secs(30));
+        assert_eq!(
+            next_backoff(Duration::from_secs(30), max),
+            Duration::from_secs(60)
+        );
+        assert_eq!(next_backoff(Duration::from_secs(60), max), max);
+    }
+
+    #[tokio::test]
+    async fn test_run_batch_embeds_and_skips_failures() {
+        let memory = ChatMemory::new(":memory:").unwrap();
+        let conversation = memory.create_conversation("u", "测试", None).unwrap();
+        memory
+            .add_message("u", conversation.id, "user", "bad", None)
+            .unwrap();
+        memory
+            .add_message("u", conversation.id, "model", "good", None)
+            .unwrap();
+
+        let status = BackfillStatus::default();
+        let config = test_config();
+        let mut attempts = HashMap::new();
+        let embed = |text: String| async move {
+            if text == "bad" {
+                Err("嵌入失败".to_string())
+            } else {
+                Ok(vec![0.1; EMBEDDING_DIMENSION])
+            }
+        };
+
+        // 第一条失败时结束本轮
+        let outcome = run_batch(&memory, &status, &config, TEST_MODEL, &mut attempts, embed).await;
+        assert_eq!(
+            outcome,
+            BatchOutcome {
+                embedded: 0,
+                failed: 1
+            }
+        );
+
+        // 达到最大尝试次数后跳过失败的消息
+        run_batch(&memory, &status, &config, TEST_MODEL, &mut attempts, embed).await;
+        let outcome = run_batch(&memory, &status, &config, TEST_MODEL, &mut attempts, embed).await;
+        assert_eq!(
+            outcome,
+            BatchOutcome {
+                embedded: 1,
+                failed: 0
+            }
+        );
+
+        let snapshot = status.snapshot();
+        assert_eq!(snapshot.embedded_total, 1);
+        assert_eq!(snapshot.skipped, 1);
+        assert_eq!(snapshot.pending, 1);
+        assert_eq!(memory.embedded_message_count(TEST_MODEL).unwrap(), 1);
+
+        // 公开状态不带错误详情
+        assert_eq!(snapshot.last_error.as_deref(), Some("嵌入失败"));
+        assert_eq!(
+            status.public_snapshot().last_error.as_deref(),
+            Some(PUBLIC_ERROR)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_chunk_batch_chunks_files_and_limits_attempts() {
+        let memory = ChatMemory::new(":memory:").unwrap();
+        let file = memory
+            .save_file("u", "notes.txt", TEXT_MIME_TYPE, "hash", b"good\n")
+            .unwrap();
+        let bad = memory
+            .save_file("u", "bad.txt", TEXT_MIME_TYPE, "hash-bad", b"bad\n")
+            .unwrap();
+        let empty = memory
+            .save_file("u", "empty.txt", TEXT_MIME_TYPE, "hash-empty", b" \n")
+            .unwrap();
+
+        let status = BackfillStatus::default();
+        let config = test_config();
+        let embed = |text: String| async move {
+            if text.ends_with("bad") {
+                Err("嵌入失败".to_string())
+            } else {
+                Ok(vec![0.1; EMBEDDING_DIMENSION])
+            }
+        };
+
+        // 先切分文件，再按顺序生成嵌入，出错时结束本轮
+        let outcome = run_chunk_batch(&memory, &status, &config, TEST_MODEL, embed).await;
+        assert_eq!(
+            outcome,
+            BatchOutcome {
+                embedded: 1,
+                failed: 1
+            }
+        );
+        let files = memory.list_files("u").unwrap();
+        let chunk_count = |id| files.iter().find(|f| f.id == id).unwrap().chunk_count;
+        assert_eq!(chunk_count(file.id), Some(1));
+        assert_eq!(chunk_count(bad.id), Some(1));
+        // 没有内容的文件记为 0 个片段，不会反复切分
+        assert_eq!(chunk_count(empty.id), Some(0));
+
+        // 失败次数达到上限后不再重试
+        run_chunk_batch(&memory, &status, &config, TEST_MODEL, embed).await;
+        let outcome = run_chunk_batch(&memory, &status, &config, TEST_MODEL, embed).await;
+        assert_eq!(outcome, BatchOutcome::default());
+        assert_eq!(status.snapshot().pending_chunks, 0);
+
+        // 更换嵌入来源后重新生成
+        let outcome = run_chunk_batch(&memory, &status, &config, "other/model", embed).await;
+        assert_eq!(outcome.embedded, 1);
+    }
+}
@@ -0,0 +1,756 @@
+use std::collections::HashMap;
+use std::env;
+
+use super::documents::RetrievedChunk;
+use super::memory::{ChatRecord, RetrievedMessage, format_retrieved_context};
+use super::provider::{Attachment, ChatPrompt, ChatRole};
+use crate::models::gemini::GeminiModel;
+use crate::models::messages::{FileContext, Source, SourceOrigin};
+
+/// 各部分在预算不足时保底分到的比例（按优先级从高到低）
+const RECENT_TURNS_SHARE: f64 = 0.35;
+const FILE_CONTEXT_SHARE: f64 = 0.45;
+const MEMORY_SHARE: f64 = 0.20;
+
+/// 使用后端计数时，按实际结果重新分配的安全余量
+const RECOUNT_MARGIN: f64 = 0.95;
+
+/// 每个附件按固定 token 数估算（Gemini 每张图片、每页 PDF 约 258 token）
+const ATTACHMENT_TOKENS: u64 = 258;
+
+/// 截断文本时追加的标记
+const TRUNCATED_MARK: &str = "\n…（内容过长，已截断）";
+
+/// 引用来源中保留的内容长度（字符数）
+const SNIPPET_CHARS: usize = 120;
+
+/// 提示词预算配置
+#[derive(Debug, Clone)]
+pub struct BudgetConfig {
+    /// 按模型档位覆盖上下文窗口大小（本地模型通常远小于 Gemini）
+    pub context_windows: HashMap<String, u64>,
+    /// 提示词的 token 上限（控制花费），0 表示只受上下文窗口限制
+    pub max_prompt_tokens: u64,
+    /// 为模型输出预留的 token 数
+    pub reserved_output_tokens: u64,
+    /// 是否用后端的 token 计数接口（如 Gemini countTokens）校正本地估算
+    pub provider_counting: bool,
+}
+
+impl Default for BudgetConfig {
+    fn default() -> Self {
+        Self {
+            context_windows: HashMap::new(),
+            max_prompt_tokens: 128_000,
+            reserved_output_tokens: 8_192,
+            provider_counting: false,
+        }
+    }
+}
+
+impl BudgetConfig {
+    /// 从环境变量读取配置，未设置的项使用默认值
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let parse = |name: &str, fallback: u64| {
+            env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(fallback)
+        };
+
+        let context_windows = env::var("CONTEXT_WINDOWS")
+            .map(|spec| {
+                spec.split(',')
+                    .filter_map(|entry| entry.split_once('='))
+                    .filter_map(|(tier, tokens)| {
+                        Some((tier.trim().to_string(), tokens.trim().parse().ok()?))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self {
+            context_windows,
+            max_prompt_tokens: parse("CONTEXT_MAX_PROMPT_TOKENS", default.max_prompt_tokens),
+            reserved_output_tokens: parse(
+                "CONTEXT_RESERVED_OUTPUT_TOKENS",
+                default.reserved_output_tokens,
+            ),
+            provider_counting: env::var("TOKEN_COUNTER")
+                .map(|v| v.eq_ignore_ascii_case("provider"))
+                .unwrap_or(default.provider_counting),
+        }
+    }
+
+    /// 该模型的上下文窗口大小
+    pub fn context_window(&self, model: GeminiModel) -> u64 {
+        self.context_windows
+            .get(model.as_str())
+            .copied()
+            .unwrap_or_else(|| model.context_window())
+    }
+
+    /// 该模型可用于提示词的 token 数
+    pub fn prompt_budget(&self, model: GeminiModel) -> u64 {
+        let window = self
+            .context_window(model)
+            .saturating_sub(self.reserved_output_tokens);
+        if self.max_prompt_tokens > 0 {
+            window.min(self.max_prompt_tokens)
+        } else {
+            window
+        }
+    }
+}
+
+/// 待放入提示词的各部分内容
+#[derive(Debug, Clone, Copy)]
+pub struct PromptSections<'a> {
+    /// 固定的系统指令（最高优先级，不会被截断）
+    pub system: Option<&'a str>,
+    /// 最近的对话，按时间顺序
+    pub recent: &'a [ChatRecord],
+    pub files: &'a [FileContext],
+    /// 从文件库检索到的片段，与文件上下文一起分配预算
+    pub chunks: &'a [RetrievedChunk],
+    /// 检索到的记忆，按相关度排序
+    pub memories: &'a [RetrievedMessage],
+    /// 当前用户消息
+    pub user_message: &'a str,
+}
+
+/// 预算分配结果
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BudgetReport {
+    pub budget: u64,
+    pub system_tokens: u64,
+    /// 图片、PDF 等附件的估算值
+    pub attachment_tokens: u64,
+    pub turn_tokens: u64,
+    pub file_tokens: u64,
+    pub memory_tokens: u64,
+    pub user_tokens: u64,
+    /// 因预算不足被丢弃的较早消息数
+    pub dropped_turns: usize,
+    /// 以摘要代替原文的消息数
+    pub summarized_turns: usize,
+    /// 被截断的文件数
+    pub truncated_files: usize,
+    /// 检索到的记忆是否被裁剪
+    pub memories_trimmed: bool,
+    /// 实际放入的记忆条数（按相关度从高到低）
+    pub memories_included: usize,
+}
+
+impl BudgetReport {
+    pub fn total(&self) -> u64 {
+        self.system_tokens
+            + self.attachment_tokens
+            + self.turn_tokens
+            + self.file_tokens
+            + self.memory_tokens
+            + self.user_tokens
+    }
+
+    /// 是否有内容被截断、摘要或丢弃
+    pub fn trimmed(&self) -> bool {
+        self.dropped_turns > 0
+            || self.summarized_turns > 0
+            || self.truncated_files > 0
+            || self.memories_trimmed
+    }
+}
+
+/// 在 token 预算内构建对话
+///
+/// 文件片段和记忆依次编号为 `[1]`、`[2]`…，供模型引用（见 `prompt_sources`）。
+/// 系统指令、附件和当前用户消息必须保留，剩余预算先按保底比例分给最近对话、文件和记忆，
+/// 用不完的部分再按优先级（最近对话 > 文件 > 记忆）补给仍然不够的部分。
+/// 因此预算紧张时，检索到的记忆最先被裁剪，其次是文件，最后才丢弃较早的对话。
+pub fn build_prompt(sections: &PromptSections, budget: u64) -> (ChatPrompt, BudgetReport) {
+    let system = sections.system.map(|s| format!("{}\n\n", s.trim_end()));
+    let attachments: Vec<Attachment> = sections
+        .files
+        .iter()
+        .filter_map(Attachment::from_file)
+        .collect();
+    // 直接携带的文本文件在前，检索到的片段在后并参与编号
+    let mut text_files: Vec<FileContext> = sections
+        .files
+        .iter()
+        .filter(|f| f.data.is_none() && f.file_uri.is_none())
+        .cloned()
+        .collect();
+    let first_chunk = text_files.len();
+    text_files.extend(sections.chunks.iter().map(RetrievedChunk::to_file_context));
+    let first_memory_ref = sections.chunks.len() + 1;
+    let mut report = BudgetReport {
+        budget,
+        system_tokens: system.as_deref().map_or(0, estimate_tokens),
+        attachment_tokens: attachments.len() as u64 * ATTACHMENT_TOKENS,
+        ..BudgetReport::default()
+    };
+
+    let user_message = truncate_to_tokens(
+        sections.user_message,
+        budget.saturating_sub(report.system_tokens + report.attachment_tokens),
+    );
+    report.user_tokens = estimate_tokens(&user_message);
+
+    let available =
+        budget.saturating_sub(report.system_tokens + report.attachment_tokens + report.user_tokens);
+    let needs = [
+        sections
+            .recent
+            .iter()
+            .map(|r| estimate_tokens(&r.content))
+            .sum(),
+        estimate_tokens(&format_files(&text_files, first_chunk)),
+        estimate_tokens(&format_retrieved_context(sections.memories, first_memory_ref, u64::MAX).0),
+    ];
+    let [turn_budget, file_budget, memory_budget] = apportion(available, needs);
+
+    // 最近对话：从最新的消息往前取，放不下原文时尝试使用摘要
+    let mut turns: Vec<(ChatRole, String)> = Vec::new();
+    for (index, record) in sections.recent.iter().enumerate().rev() {
+        let remaining = turn_budget - report.turn_tokens;
+        let content_tokens = estimate_tokens(&record.content);
+        let (content, tokens) = if content_tokens <= remaining {
+            (record.content.clone(), content_tokens)
+        } else if let Some(summary) = &record.summary
+            && estimate_tokens(summary) <= remaining
+        {
+            report.summarized_turns += 1;
+            (summary.clone(), estimate_tokens(summary))
+        } else {
+            report.dropped_turns = index + 1;
+            break;
+        };
+
+        let role = if record.role == "model" {
+            ChatRole::Model
+        } else {
+            ChatRole::User
+        };
+        turns.push((role, content));
+        report.turn_tokens += tokens;
+    }
+    turns.reverse();
+
+    let (memory_context, memories_included) = if needs[2] <= memory_budget {
+        format_retrieved_context(sections.memories, first_memory_ref, u64::MAX)
+    } else {
+        report.memories_trimmed = true;
+        format_retrieved_context(sections.memories, first_memory_ref, memory_budget)
+    };
+    report.memory_tokens = estimate_tokens(&memory_context);
+    report.memories_included = memories_included;
+
+    let files = fit_files(
+        &text_files,
+        first_chunk,
+        file_budget,
+        &mut report.truncated_files,
+    );
+    let file_context = format_files(&files, first_chunk);
+    report.file_tokens = estimate_tokens(&file_context);
+
+    let mut system_instruction = system.unwrap_or_default();
+    // 与编号顺序一致：文件片段在前，记忆在后
+    system_instruction.push_str(&file_context);
+    system_instruction.push_str(&memory_context);
+    let system_instruction = if system_instruction.is_empty() {
+        None
+    } else {
+        Some(system_instruction)
+    };
+
+    let messages = turns
+        .iter()
+        .map(|(role, content)| (*role, content.as_str()))
+        .chain(std::iter::once((ChatRole::User, user_message.as_str())));
+    let mut prompt = ChatPrompt::from_messages(system_instruction, messages);
+    prompt.attachments = attachments;
+    (prompt, report)
+}
+
+/// 提示词中编号的检索结果：先是文件片段，然后是实际放入的记忆
+pub fn prompt_sources(sections: &PromptSections, report: &BudgetReport) -> Vec<Source> {
+    let chunks = sections.chunks.iter().map(|chunk| {
+        (
+            SourceOrigin::FileChunk {
+                file_id: chunk.file_id,
+                file_name: chunk.file_name.clone(),
+                start_line: chunk.chunk.start_line,
+                end_line: chunk.chunk.end_line,
+            },
+            chunk.similarity,
+            chunk.chunk.content.as_str(),
+        )
+    });
+    let memories = sections
+        .memories
+        .iter()
+        .take(report.memories_included)
+        .map(|m| {
+            (
+                SourceOrigin::Message {
+                    message_id: m.record.id,
+                    conversation_id: m.record.conversation_id.unwrap_or_default(),
+                    role: m.record.role.clone(),
+                },
+                m.similarity,
+                m.record.summary.as_deref().unwrap_or(&m.record.content),
+            )
+        });
+
+    chunks
+        .chain(memories)
+        .enumerate()
+        .map(|(i, (origin, score, content))| Source {
+            index: i + 1,
+            origin,
+            score,
+            snippet: snippet(content),
+        })
+        .collect()
+}
+
+/// 压缩空白并截取开头的内容
+fn snippet(content: &str) -> String {
+    let text = content.split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.chars().count() <= SNIPPET_CHARS {
+        return text;
+    }
+    let mut snippet: String = text.chars().take(SNIPPET_CHARS).collect();
+    snippet.push('…');
+    snippet
+}
+
+/// 后端计数超出预算时，按实际与估算的比例缩小预算
+pub fn rescale_budget(budget: u64, estimated: u64, counted: u64) -> u64 {
+    if counted == 0 {
+        return budget;
+    }
+    (budget as f64 * estimated as f64 / counted as f64 * RECOUNT_MARGIN) as u64
+}
+
+/// 分配剩余预算，返回最近对话、文件、记忆各自的额度
+fn apportion(available: u64, needs: [u64; 3]) -> [u64; 3] {
+    let shares = [RECENT_TURNS_SHARE, FILE_CONTEXT_SHARE, MEMORY_SHARE];
+    let mut given = [0u64; 3];
+    for i in 0..3 {
+        given[i] = needs[i].min((available as f64 * shares[i]) as u64);
+    }
+
+    let mut leftover = available - given.iter().sum::<u64>();
+    for i in 0..3 {
+        let extra = (needs[i] - given[i]).min(leftover);
+        given[i] += extra;
+        leftover -= extra;
+    }
+    given
+}
+
+/// 在额度内放入所有文件：小文件完整保留，大文件平分剩余额度后截断
+fn fit_files(
+    files: &[FileContext],
+    first_chunk: usize,
+    budget: u64,
+    truncated: &mut usize,
+) -> Vec<FileContext> {
+    if files.is_empty() || estimate_tokens(&format_files(files, first_chunk)) <= budget {
+        return files.to_vec();
+    }
+
+    // 先扣除标题和分隔符的开销，剩下的按“注水”方式分给各文件内容
+    let overhead = estimate_tokens(&format_files(
+        &files
+            .iter()
+            .map(|f| FileContext {
+                name: f.name.clone(),
+                content: TRUNCATED_MARK.to_string(),
+                ..FileContext::default()
+            })
+            .collect::<Vec<_>>(),
+        first_chunk,
+    ));
+    let mut remaining = budget.saturating_sub(overhead);
+    let mut sizes: Vec<(usize, u64)> = files
+        .iter()
+        .enumerate()
+        .map(|(i, f)| (i, estimate_tokens(&f.content)))
+        .collect();
+    sizes.sort_by_key(|(_, size)| *size);
+
+    let mut allowances = vec![0u64; files.len()];
+    let mut left = files.len() as u64;
+    for (index, size) in sizes {
+        let share = remaining / left;
+        allowances[index] = size.min(share);
+        remaining -= allowances[index];
+        left -= 1;
+    }
+
+    files
+        .iter()
+        .zip(allowances)
+        .map(|(file, allowance)| {
+            let content = truncate_to_tokens(&file.content, allowance);
+            if content.len() < file.content.len() {
+                *truncated += 1;
+            }
+            FileContext {
+                content,
+                ..file.clone()
+            }
+        })
+        .collect()
+}
+
+/// 把上传的文件格式化为系统指令片段，从 `first_chunk` 开始的是检索到的片段，以 `[编号]` 标注
+fn format_files(files: &[FileContext], first_chunk: usize) -> String {
+    if files.is_empty() {
+        return String::new();
+    }
+
+    let mut context = String::from(if first_chunk < files.len() {
+        "以下是用户上传的文件内容作为上下文参考，引用片段时请用 [编号] 标注来源：\n\n"
+    } else {
+        "以下是用户上传的文件内容作为上下文参考：\n\n"
+    });
+    for (i, file) in files.iter().enumerate() {
+        let label = if i < first_chunk {
+            format!("文件 {} ({})", i + 1, file.name)
+        } else {
+            format!("[{}] {}", i - first_chunk + 1, file.name)
+        };
+        context.push_str(&format!("--- {} ---\n{}\n\n", label, file.content));
+    }
+    context.push_str("---\n\n");
+    context
+}
+
+/// 粗略估算文本的 token 数：非 ASCII 字符（如中文）每字约 1 个，ASCII 每 4 个字符约 1 个
+pub fn estimate_tokens(text: &str) -> u64 {
+    let (ascii, other) = text.chars().fold((0u64, 0u64), |(ascii, other), c| {
+        if c.is_ascii() {
+            (ascii + 1, other)
+        } else {
+            (ascii, other + 1)
+        }
+    });
+    other + ascii.div_ceil(4)
+}
+
+/// 估算整个对话（系统指令 + 所有轮次）的 token 数
+pub fn estimate_prompt_tokens(prompt: &ChatPrompt) -> u64 {
+    prompt
+        .system_instruction
+        .iter()
+        .map(|s| s.as_str())
+        .chain(prompt.turns.iter().map(|t| t.content.as_str()))
+        .map(estimate_tokens)
+        .sum::<u64>()
+        + prompt.attachments.len() as u64 * ATTACHMENT_TOKENS
+        + prompt
+            .tool_rounds
+            .iter()
+            .map(|round| {
+                estimate_tokens(&round.text)
+                    + round
+                        .calls
+                        .iter()
+                        .map(|call| estimate_tokens(&call.args.to_string()))
+                        .sum::<u64>()
+                    + round
+                        .results
+                        .iter()
+                        .map(|result| estimate_tokens(&result.response().to_string()))
+                        .sum::<u64>()
+            })
+            .sum::<u64>()
+}
+
+/// 截断文本使其估算 token 数不超过 `max_tokens`，截断时末尾带有标记
+pub fn truncate_to_tokens(text: &str, max_tokens: u64) -> String {
+    if estimate_tokens(text) <= max_tokens {
+        return text.to_string();
+    }
+
+    let limit = max_tokens.saturating_sub(estimate_tokens(TRUNCATED_MARK));
+    let (mut ascii, mut other) = (0u64, 0u64);
+    let mut end = 0;
+    for (index, c) in text.char_indices() {
+        if c.is_ascii() {
+            ascii += 1;
+        } else {
+            other += 1;
+        }
+        if other + ascii.div_ceil(4) > limit {
+            break;
+        }
+        end = index + c.len_utf8();
+    }
+
+    if end == 0 {
+        return String::new();
+    }
+    format!("{}{}", &text[..end], TRUNCATED_MARK)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::documents::TextChunk;
+    use chrono::Utc;
+
+    fn record(id: i64, role: &str, content: &str, summary: Option<&str>) -> ChatRecord {
+        ChatRecord {
+            id,
+            user_id: "u".to_string(),
+            conversation_id: Some(1),
+            role: role.to_string(),
+            content: content.to_string(),
+            summary: summary.map(str::to_string),
+            model: None,
+            created_at: Utc::now(),
+        }
+    }
+
+    fn memory(id: i64, content: &str) -> RetrievedMessage {
+        RetrievedMessage {
+            record: record(id, "user", content, None),
+            similarity: Some(0.9),
+            score: 0.9,
+        }
+    }
+
+    fn file(name: &str, content: &str) -> FileContext {
+        FileContext {
+            name: name.to_string(),
+            content: content.to_string(),
+            ..FileContext::default()
+        }
+    }
+
+    #[test]
+    fn test_estimate_tokens() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("hello world"), 3);
+        assert_eq!(estimate_tokens("你好"), 2);
+    }
+
+    #[test]
+    fn test_truncate_to_tokens() {
+        let text = "字".repeat(100);
+        let truncated = truncate_to_tokens(&text, 30);
+        assert!(estimate_tokens(&truncated) <= 30);
+        assert!(truncated.ends_with(TRUNCATED_MARK));
+        assert_eq!(truncate_to_tokens("短", 30), "短");
+    }
+
+    #[test]
+    fn test_apportion_prefers_higher_priority() {
+        // 都放得下时全部满足
+        assert_eq!(apportion(1000, [100, 200, 300]), [100, 200, 300]);
+        // 记忆最先被压缩到保底比例，剩余额度优先补给对话和文件
+        assert_eq!(apportion(1000, [100, 700, 700]), [100, 700, 200]);
+        assert_eq!(apportion(1000, [1000, 1000, 1000]), [350, 450, 200]);
+    }
+
+    #[test]
+    fn test_build_prompt_within_budget_keeps_everything() {
+        let recent = [
+            record(1, "user", "之前的问题", None),
+            record(2, "model", "之前的回答", None),
+        ];
+        let files = [file("a.txt", "文件内容")];
+        let memories = [memory(3, "相关记忆")];
+        let sections = PromptSections {
+            system: None,
+            recent: &recent,
+            files: &files,
+            chunks: &[],
+            memories: &memories,
+            user_message: "现在的问题",
+        };
+
+        let (prompt, report) = build_prompt(&sections, 10_000);
+        assert!(!report.trimmed());
+        assert_eq!(prompt.turns.len(), 3);
+        let system = prompt.system_instruction.unwrap();
+        assert!(system.contains("相关记忆"));
+        assert!(system.contains("文件内容"));
+        assert!(report.total() <= 10_000);
+    }
+
+    #[test]
+    fn test_build_prompt_sends_binary_files_as_attachments() {
+        let files = [
+            file("a.txt", "文件内容"),
+            FileContext {
+                name: "shot.png".to_string(),
+                mime_type: Some("image/png".to_string()),
+                data: Some("iVBORw0=".to_string()),
+                ..FileContext::default()
+            },
+        ];
+        let sections = PromptSections {
+            system: None,
+            recent: &[],
+            files: &files,
+            chunks: &[],
+            memories: &[],
+            user_message: "图里是什么？",
+        };
+
+        let (prompt, report) = build_prompt(&sections, 10_000);
+        assert_eq!(prompt.attachments.len(), 1);
+        assert_eq!(prompt.attachments[0].mime_type, "image/png");
+        assert_eq!(report.attachment_tokens, ATTACHMENT_TOKENS);
+        assert_eq!(estimate_prompt_tokens(&prompt), report.total());
+        let system = prompt.system_instruction.unwrap();
+        assert!(system.contains("a.txt"));
+        assert!(!system.contains("shot.png"));
+    }
+
+    #[test]
+    fn test_build_prompt_trims_low_priority_sections_first() {
+        let recent = [
+            record(1, "user", &"旧".repeat(300), Some("旧问题摘要")),
+            record(2, "model", &"答".repeat(50), None),
+        ];
+        let files = [
+            file("big.txt", &"文".repeat(2000)),
+            file("small.txt", "小文件"),
+        ];
+        let memories = [memory(3, &"忆".repeat(500))];
+        let sections = PromptSections {
+            system: Some("你是助手。"),
+            recent: &recent,
+            files: &files,
+            chunks: &[],
+            memories: &memories,
+            user_message: "问题",
+        };
+
+        let (prompt, report) = build_prompt(&sections, 1000);
+        assert!(report.total() <= 1000);
+        assert_eq!(report.truncated_files, 1);
+        assert!(report.memories_trimmed);
+        // 较早的长消息以摘要代替
+        assert_eq!(report.summarized_turns, 1);
+        assert_eq!(report.dropped_turns, 0);
+        assert_eq!(prompt.turns[0].content, "旧问题摘要");
+
+        let system = prompt.system_instruction.unwrap();
+        assert!(system.starts_with("你是助手。"));
+        assert!(system.contains("小文件"));
+        assert!(system.contains(TRUNCATED_MARK));
+    }
+
+    #[test]
+    fn test_prompt_sources_number_chunks_then_memories() {
+        let files = [file("notes.txt", "直接携带的文件")];
+        let chunks = [RetrievedChunk {
+            file_id: 7,
+            file_name: "manual.txt".to_string(),
+            chunk: TextChunk {
+                start_line: 10,
+                end_line: 42,
+                content: "片段内容".to_string(),
+            },
+            similarity: Some(0.8),
+        }];
+        let memories = [memory(3, "第一条记忆"), memory(4, &"忆".repeat(500))];
+        let sections = PromptSections {
+            system: None,
+            recent: &[],
+            files: &files,
+            chunks: &chunks,
+            memories: &memories,
+            user_message: "问题",
+        };
+
+        let (prompt, report) = build_prompt(&sections, 300);
+        assert_eq!(report.memories_included, 1);
+        let system = prompt.system_instruction.unwrap();
+        assert!(system.contains("--- 文件 1 (notes.txt) ---"));
+        assert!(system.contains("--- [1] manual.txt 第 10-42 行 ---"));
+        assert!(system.contains("[2] 【用户】"));
+        assert!(!system.contains("[3]"));
+
+        let sources = prompt_sources(&sections, &report);
+        assert_eq!(sources.len(), 2);
+        assert_eq!(
+            sources[0].origin,
+            SourceOrigin::FileChunk {
+                file_id: 7,
+                file_name: "manual.txt".to_string(),
+                start_line: 10,
+                end_line: 42,
+            }
+        );
+        assert_eq!(sources[1].index, 2);
+        assert_eq!(sources[1].snippet, "第一条记忆");
+        assert!(matches!(
+            sources[1].origin,
+            SourceOrigin::Message { message_id: 3, .. }
+        ));
+    }
+
+    #[test]
+    fn test_snippet() {
+        assert_eq!(snippet("  多行\n  内容  "), "多行 内容");
+        let long = snippet(&"字".repeat(200));
+        assert_eq!(long.chars().count(), SNIPPET_CHARS + 1);
+        assert!(long.ends_with('…'));
+    }
+
+    #[test]
+    fn test_build_prompt_drops_oldest_turns() {
+        let recent = [
+            record(1, "user", &"一".repeat(500), None),
+            record(2, "model", &"二".repeat(500), None),
+            record(3, "user", "最近的问题", None),
+            record(4, "model", "最近的回答", None),
+        ];
+        let sections = PromptSections {
+            system: None,
+            recent: &recent,
+            files: &[],
+            chunks: &[],
+            memories: &[],
+            user_message: "问题",
+        };
+
+        let (prompt, report) = build_prompt(&sections, 500);
+        assert_eq!(report.dropped_turns, 2);
+        assert_eq!(prompt.turns.len(), 3);
+        assert_eq!(prompt.turns[0].content, "最近的问题");
+    }
+
+    #[test]
+    fn test_prompt_budget() {
+        let mut config = BudgetConfig::default();
+        assert_eq!(config.prompt_budget(GeminiModel::Flash), 128_000);
+
+        config.context_windows.insert("flash".to_string(), 8_192);
+        config.reserved_output_tokens = 1_024;
+        assert_eq!(config.prompt_budget(GeminiModel::Flash), 7_168);
+
+        config.max_prompt_tokens = 0;
+        assert_eq!(
+            config.prompt_budget(GeminiModel::Pro25),
+            GeminiModel::Pro25.context_window() - 1_024
+        );
+    }
+
+    #[test]
+    fn test_rescale_budget() {
+        assert_eq!(rescale_budget(1000, 1000, 2000), 475);
+        assert_eq!(rescale_budget(1000, 1000, 0), 1000);
+    }
+}
@@ -0,0 +1,922 @@
+use futures_util::future::join_all;
+use std::sync::Arc;
+
+use super::budget::{
+    BudgetConfig, PromptSections, build_prompt, estimate_prompt_tokens, prompt_sources,
+    rescale_budget,
+};
+use super::documents::{self, RetrievedChunk};
+use super::embedding::{EmbeddingProvider, EmbeddingTask};
+use super::files;
+use super::memory::{
+    ChatMemory, ChatRecord, Conversation, RetrievedMessage, format_retrieved_context,
+};
+use super::provider::{
+    ChatPrompt, ChatProvider, ChatRequest, ChatResult, StreamDelta, ToolCall, ToolResult, ToolRound,
+};
+use super::rate_limit::result_tokens;
+use super::tools::{ToolContext, ToolRegistry};
+use crate::models::auth::Scope;
+use crate::models::gemini::GeminiModel;
+use crate::models::messages::{FileContext, Source, TokenUsage, ToolCallLog};
+
+const MAX_RECENT_MESSAGES: usize = 10; // 作为多轮对话发送的最近消息数量
+const MAX_SIMILAR_MESSAGES: usize = 5; // 相似消息检索数量
+const MIN_SIMILARITY: f32 = 0.5; // 最小相似度阈值
+const MAX_DOCUMENT_CHUNKS: usize = 8; // 从文件库的文本文件中检索的片段数量
+const MAX_RECALL_TOKENS: u64 = 1000; // 不经过预算分配时注入的记忆 token 上限
+const CONVERSATION_TITLE_CHARS: usize = 30; // 自动生成的会话标题长度
+const MAX_TOOL_ROUNDS: usize = 5; // 一次回复中最多执行的工具调用轮数
+
+/// 工具调用轮数用完仍没有得到回复时的占位文本
+const TOOL_LIMIT_REPLY: &str = "工具调用次数超过上限，没有得到最终回复";
+
+/// 模型没有返回任何回复文本时的占位文本
+const EMPTY_REPLY: &str = "模型没有返回回复内容";
+
+/// 超过轮数上限、没有执行的工具调用返回给模型和客户端的错误
+const TOOL_LIMIT_ERROR: &str = "工具调用次数已达上限，本次调用没有执行，请根据已有信息直接回答";
+
+/// 一次聊天请求（WebSocket 和 REST 接口共用）
+#[derive(Debug, Clone)]
+pub struct ChatInput {
+    pub user_id: String,
+    pub conversation_id: i64,
+    /// 是否在用户的所有会话中检索相关记忆
+    pub search_all_conversations: bool,
+    pub content: String,
+    /// 直接携带内容的文件上下文
+    pub file_contexts: Vec<FileContext>,
+    /// 文件库中作为上下文的文件，调用模型前读取
+    pub file_ids: Vec<i64>,
+    pub model: GeminiModel,
+    /// 调用者的权限范围，决定模型可以使用哪些工具
+    pub scopes: Vec<Scope>,
+}
+
+/// 一次聊天的结果
+#[derive(Debug, Clone)]
+pub struct ChatOutcome {
+    pub result: ChatResult,
+    pub user_message_id: Option<i64>,
+    pub reply_message_id: Option<i64>,
+    /// 本次消耗的 token 数，计入每日配额（后端未返回用量时为估算值）
+    pub tokens: u64,
+    /// 放入提示词的检索结果，编号与回复中的引用对应
+    pub sources: Vec<Source>,
+}
+
+/// 以第一条消息作为标题新建会话
+pub fn start_conversation(
+    memory: &ChatMemory,
+    user_id: &str,
+    first_message: &str,
+    model: GeminiModel,
+) -> Result<Conversation, String> {
+    let title: String = first_message
+        .chars()
+        .take(CONVERSATION_TITLE_CHARS)
+        .collect();
+    memory
+        .create_conversation(user_id, &title, Some(model.as_str()))
+        .map_err(|e| format!("创建会话失败: {}", e))
+}
+
+/// 检索到的对话上下文
+struct ChatContext {
+    /// 最近的消息，按时间顺序
+    recent: Vec<ChatRecord>,
+    /// 相关的历史消息，按相关度排序
+    memories: Vec<RetrievedMessage>,
+    /// 文件库中与问题相关的片段
+    chunks: Vec<RetrievedChunk>,
+}
+
+/// 执行一次完整的聊天流程：读取文件 → 检索记忆和文件片段 → 按预算构建对话 → 调用模型（及工具） → 保存回复
+///
+/// 传入 `on_delta` 时以流式方式调用模型，每个片段到达和每次工具调用开始、结束时回调。
+/// 模型调用失败时用户消息仍会保存，但不保存回复；读取文件失败时什么都不保存。
+pub async fn run_chat(
+    memory: Arc<ChatMemory>,
+    provider: &dyn ChatProvider,
+    embedder: Arc<dyn EmbeddingProvider>,
+    budget: &BudgetConfig,
+    tools: &ToolRegistry,
+    mut input: ChatInput,
+    on_delta: Option<&mut (dyn FnMut(StreamDelta) + Send)>,
+) -> Result<ChatOutcome, String> {
+    let stored_files =
+        files::load_file_contexts(&memory, provider, &input.user_id, &input.file_ids).await?;
+    input.file_contexts.extend(stored_files);
+
+    let (mut context, user_message_id, query_embedding) =
+        gather_context(&memory, embedder.as_ref(), &input).await;
+
+    // 文本文件只放入与问题最相关的片段
+    let chunks = documents::retrieve_chunks(
+        &memory,
+        embedder.as_ref(),
+        &input.user_id,
+        &input.file_ids,
+        query_embedding.as_deref(),
+        MAX_DOCUMENT_CHUNKS,
+        MIN_SIMILARITY,
+    );
+    match chunks {
+        Ok(chunks) => context.chunks = chunks,
+        Err(e) => eprintln!("检索文件片段失败: {}", e),
+    }
+    let (prompt, sources) = fit_prompt(provider, budget, &input, &context).await;
+
+    let tool_context = ToolContext {
+        memory: memory.clone(),
+        embedder: embedder.clone(),
+        user_id: input.user_id.clone(),
+        conversation_id: input.conversation_id,
+        user_message_id,
+        scopes: input.scopes.clone(),
+    };
+    let chat_result = call_model(
+        provider,
+        tools,
+        &tool_context,
+        prompt,
+        input.model,
+        on_delta,
+    )
+    .await;
+
+    // 更新用户消息的嵌入向量
+    if let (Some(msg_id), Some(embedding)) = (user_message_id, query_embedding) {
+        let _ = memory.update_embedding(msg_id, &embedding, &embedder.model_id());
+    }
+
+    // 只保存完整的回复
+    let (result, tokens, tool_calls) = chat_result?;
+    let reply_message_id = persist_model_reply(memory, embedder, &input, &result, &tool_calls);
+
+    Ok(ChatOutcome {
+        result,
+        user_message_id,
+        reply_message_id,
+        tokens,
+        sources,
+    })
+}
+
+/// 调用模型；模型请求调用工具时执行工具，把结果加入对话后再次调用，直到得到最终回复
+///
+/// 各轮的回复文本依次拼接（与流式输出一致），用量累加。
+/// 返回 (合并后的结果, 消耗的 token 数, 工具调用记录)。后端不支持工具时只调用一次。
+///
+/// 执行 `MAX_TOOL_ROUNDS` 轮后不再执行工具：之后请求的调用以 `TOOL_LIMIT_ERROR` 作为结果
+/// 告诉模型和客户端，再给模型一次直接回答的机会；仍然请求工具时结束，没有回复文本时使用
+/// `TOOL_LIMIT_REPLY`。其他情况下没有回复文本时使用 `EMPTY_REPLY`。
+/// 没有执行的调用只推送给客户端，不写入工具调用记录。
+async fn call_model(
+    provider: &dyn ChatProvider,
+    tools: &ToolRegistry,
+    tool_context: &ToolContext,
+    mut prompt: ChatPrompt,
+    model: GeminiModel,
+    mut on_delta: Option<&mut (dyn FnMut(StreamDelta) + Send)>,
+) -> Result<(ChatResult, u64, Vec<ToolCallLog>), String> {
+    if provider.supports_tools() {
+        prompt.tools = tools.declarations(tool_context);
+    }
+
+    let mut response = String::new();
+    let mut thinking = String::new();
+    let mut usage: Option<TokenUsage> = None;
+    let mut tokens = 0;
+
+    loop {
+        let prompt_tokens = estimate_prompt_tokens(&prompt);
+        let request = ChatRequest {
+            prompt: prompt.clone(),
+            model,
+        };
+        let result = match on_delta.as_deref_mut() {
+            Some(on_delta) => provider.chat_stream(request, on_delta).await,
+            None => provider.chat(request).await,
+        }?;
+
+        tokens += result_tokens(prompt_tokens, &result);
+        if let Some(round_usage) = result.usage {
+            *usage.get_or_insert_default() += round_usage;
+        }
+        if let Some(text) = &result.thinking {
+            thinking.push_str(text);
+        }
+        response.push_str(&result.response);
+
+        let limit_reached = prompt.tool_rounds.len() >= MAX_TOOL_ROUNDS;
+        let exhausted = prompt.tool_rounds.len() > MAX_TOOL_ROUNDS;
+        let calls_pending = !result.tool_calls.is_empty();
+        if calls_pending {
+            let results = run_tool_round(
+                tools,
+                tool_context,
+                prompt.tool_rounds.len() + 1,
+                &result.tool_calls,
+                limit_reached,
+                &mut on_delta,
+            )
+            .await;
+            prompt.tool_rounds.push(ToolRound {
+                text: result.response,
+                calls: result.tool_calls,
+                results,
+            });
+            if !exhausted {
+                continue;
+            }
+        }
+        if response.is_empty() {
+            response = if calls_pending {
+                TOOL_LIMIT_REPLY
+            } else {
+                EMPTY_REPLY
+            }
+            .to_string();
+        }
+
+        let result = ChatResult {
+            response,
+            thinking: Some(thinking).filter(|t| !t.is_empty()),
+            usage,
+            tool_calls: Vec::new(),
+        };
+        return Ok((result, tokens, tool_call_logs(prompt.tool_rounds)));
+    }
+}
+
+/// 执行一轮工具调用并推送调用和结果；`skip` 为 true 时不执行，结果为 `TOOL_LIMIT_ERROR`
+async fn run_tool_round(
+    tools: &ToolRegistry,
+    tool_context: &ToolContext,
+    round: usize,
+    calls: &[ToolCall],
+    skip: bool,
+    on_delta: &mut Option<&mut (dyn FnMut(StreamDelta) + Send)>,
+) -> Vec<ToolResult> {
+    let ids: Vec<String> = (1..=calls.len())
+        .map(|index| format!("call_{}_{}", round, index))
+        .collect();
+    if let Some(on_delta) = on_delta.as_deref_mut() {
+        for (id, call) in ids.iter().zip(calls) {
+            on_delta(StreamDelta::ToolCall {
+                id: id.clone(),
+                call: call.clone(),
+            });
+        }
+    }
+
+    let results = if skip {
+        calls
+            .iter()
+            .map(|call| ToolResult {
+                name: call.name.clone(),
+                output: Err(TOOL_LIMIT_ERROR.to_string()),
+            })
+            .collect()
+    } else {
+        join_all(calls.iter().map(|call| tools.execute(call, tool_context))).await
+    };
+    if let Some(on_delta) = on_delta.as_deref_mut() {
+        for (id, result) in ids.into_iter().zip(&results) {
+            on_delta(StreamDelta::ToolResult {
+                id,
+                result: result.clone(),
+            });
+        }
+    }
+    results
+}
+
+/// 把实际执行的各轮工具调用及其结果展开为按执行顺序排列的记录
+fn tool_call_logs(rounds: Vec<ToolRound>) -> Vec<ToolCallLog> {
+    rounds
+        .into_iter()
+        .take(MAX_TOOL_ROUNDS)
+        .flat_map(|round| round.calls.into_iter().zip(round.results))
+        .map(|(call, result)| {
+            let (result, error) = match result.output {
+                Ok(value) => (Some(value), None),
+                Err(e) => (None, Some(e)),
+            };
+            ToolCallLog {
+                name: call.name,
+                args: call.args,
+                result,
+                error,
+            }
+        })
+        .collect()
+}
+
+/// 检索最近消息和相关历史，并保存用户消息
+///
+/// 返回 (上下文, 用户消息 ID, 查询嵌入)
+async fn gather_context(
+    memory: &ChatMemory,
+    embedder: &dyn EmbeddingProvider,
+    input: &ChatInput,
+) -> (ChatContext, Option<i64>, Option<Vec<f32>>) {
+    let user_id = input.user_id.as_str();
+    let user_content = input.content.as_str();
+
+    // 1. 获取最近几条消息（作为真实的多轮对话发送）
+    let recent_messages = memory
+        .get_recent_messages(user_id, input.conversation_id, MAX_RECENT_MESSAGES)
+        .unwrap_or_default();
+
+    // 2. 混合检索相关历史消息（关键词 + 向量，排除已在最近对话中的消息）
+    //    在保存用户消息之前检索，避免关键词检索命中当前消息本身
+    let search_conversation = if input.search_all_conversations {
+        None
+    } else {
+        Some(input.conversation_id)
+    };
+    let (similar_messages, query_embedding) =
+        retrieve_memories(memory, embedder, user_id, search_conversation, user_content).await;
+    let similar_messages: Vec<_> = similar_messages
+        .into_iter()
+        .filter(|m| !recent_messages.iter().any(|r| r.id == m.record.id))
+        .collect();
+
+    // 3. 保存用户消息
+    let user_msg_id = memory
+        .add_message(
+            user_id,
+            input.conversation_id,
+            "user",
+            user_content,
+            Some(input.model.as_str()),
+        )
+        .ok();
+
+    let context = ChatContext {
+        recent: recent_messages,
+        memories: similar_messages,
+        chunks: Vec::new(),
+    };
+    (context, user_msg_id, query_embedding)
+}
+
+/// 在模型的上下文预算内构建对话，返回对话及其中编号的检索结果
+///
+/// 检索到的记忆和文件上下文放入系统指令。开启后端计数时，
+/// 若实际 token 数超出预算，按实际与估算的比例缩小预算重新分配一次。
+async fn fit_prompt(
+    provider: &dyn ChatProvider,
+    budget: &BudgetConfig,
+    input: &ChatInput,
+    context: &ChatContext,
+) -> (ChatPrompt, Vec<Source>) {
+    let sections = PromptSections {
+        system: None,
+        recent: &context.recent,
+        files: &input.file_contexts,
+        chunks: &context.chunks,
+        memories: &context.memories,
+        user_message: &input.content,
+    };
+    let limit = budget.prompt_budget(input.model);
+    let (mut prompt, mut report) = build_prompt(&sections, limit);
+
+    if budget.provider_counting {
+        let request = ChatRequest {
+            prompt: prompt.clone(),
+            model: input.model,
+        };
+        if let Some(counted) = provider.count_tokens(&request).await
+            && counted > limit
+        {
+            (prompt, report) =
+                build_prompt(&sections, rescale_budget(limit, report.total(), counted));
+        }
+    }
+
+    if report.trimmed() {
+        println!(
+            "✂️  上下文超出预算 ({} tokens)：丢弃 {} 条较早消息，{} 条改用摘要，截断 {} 个文件{}",
+            report.budget,
+            report.dropped_turns,
+            report.summarized_turns,
+            report.truncated_files,
+            if report.memories_trimmed {
+                "，裁剪检索记忆"
+            } else {
+                ""
+            }
+        );
+    }
+
+    let sources = prompt_sources(&sections, &report);
+    (prompt, sources)
+}
+
+/// 生成查询嵌入并混合检索相关历史消息
+///
+/// 返回 (检索结果, 查询嵌入)，嵌入失败时只使用关键词检索
+async fn retrieve_memories(
+    memory: &ChatMemory,
+    embedder: &dyn EmbeddingProvider,
+    user_id: &str,
+    conversation_id: Option<i64>,
+    query: &str,
+) -> (Vec<RetrievedMessage>, Option<Vec<f32>>) {
+    let query_embedding = embedder.embed(query, EmbeddingTask::Query).await.ok();
+    let memories = memory
+        .retrieve_hybrid(
+            user_id,
+            conversation_id,
+            query,
+            query_embedding.as_deref(),
+            &embedder.model_id(),
+            MAX_SIMILAR_MESSAGES,
+            MIN_SIMILARITY,
+        )
+        .unwrap_or_default();
+    (memories, query_embedding)
+}
+
+/// 在用户的所有会话中检索与查询相关的记忆，格式化为系统指令片段
+///
+/// 供不保存消息的调用方（如 OpenAI 兼容接口）使用，没有相关记忆时返回 None
+pub async fn recall_context(
+    memory: &ChatMemory,
+    embedder: &dyn EmbeddingProvider,
+    user_id: &str,
+    query: &str,
+) -> Option<String> {
+    let (memories, _) = retrieve_memories(memory, embedder, user_id, None, query).await;
+    if memories.is_empty() {
+        None
+    } else {
+        Some(format_retrieved_context(&memories, 1, MAX_RECALL_TOKENS).0)
+    }
+}
+
+/// 保存模型回复及其 token 用量和工具调用记录，并在后台生成其嵌入向量
+///
+/// 返回回复消息的 ID（保存失败时为 None）
+fn persist_model_reply(
+    memory: Arc<ChatMemory>,
+    embedder: Arc<dyn EmbeddingProvider>,
+    input: &ChatInput,
+    result: &ChatResult,
+    tool_calls: &[ToolCallLog],
+) -> Option<i64> {
+    let msg_id = memory
+        .add_message(
+            &input.user_id,
+            input.conversation_id,
+            "model",
+            &result.response,
+            Some(input.model.as_str()),
+        )
+        .ok()?;
+
+    if let Some(usage) = &result.usage
+        && let Err(e) = memory.set_message_usage(msg_id, usage)
+    {
+        eprintln!("保存 token 用量失败: {}", e);
+    }
+    if !tool_calls.is_empty()
+        && let Err(e) = memory.save_tool_calls(msg_id, tool_calls)
+    {
+        eprintln!("保存工具调用记录失败: {}", e);
+    }
+
+    // 异步生成回复的嵌入向量
+    let response_for_embed = result.response.clone();
+    actix::spawn(async move {
+        if let Ok(embedding) = embedder
+            .embed(&response_for_embed, EmbeddingTask::Document)
+            .await
+        {
+            let _ = memory.update_embedding(msg_id, &embedding, &embedder.model_id());
+        }
+    });
+
+    Some(msg_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::embedding::LocalEmbedding;
+    use crate::services::provider::{ToolCall, ToolResult};
+    use crate::services::tools::Tool;
+    use async_trait::async_trait;
+    use serde_json::json;
+    use std::sync::Mutex;
+
+    /// 把最后一轮用户消息原样返回，并记录收到的系统指令
+    #[derive(Default)]
+    struct EchoProvider {
+        fail: bool,
+        system_instructions: Mutex<Vec<Option<String>>>,
+    }
+
+    #[async_trait]
+    impl ChatProvider for EchoProvider {
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+
+        fn model_name(&self, model: GeminiModel) -> String {
+            model.as_str().to_string()
+        }
+
+        async fn chat(&self, request: ChatRequest) -> Result<ChatResult, String> {
+            self.chat_stream(request, &mut |_| {}).await
+        }
+
+        async fn chat_stream(
+            &self,
+            request: ChatRequest,
+            on_delta: &mut (dyn FnMut(StreamDelta) + Send),
+        ) -> Result<ChatResult, String> {
+            if self.fail {
+                return Err("模型调用失败".to_string());
+            }
+            self.system_instructions
+                .lock()
+                .unwrap()
+                .push(request.prompt.system_instruction.clone());
+
+            let content = request.prompt.turns.last().unwrap().content.clone();
+            on_delta(StreamDelta::Response("回声：".to_string()));
+            on_delta(StreamDelta::Response(content.clone()));
+            Ok(ChatResult {
+                response: format!("回声：{}", content),
+                thinking: None,
+                usage: Some(TokenUsage {
+                    prompt_tokens: 10,
+                    candidate_tokens: 5,
+                    thought_tokens: 0,
+                }),
+                tool_calls: Vec::new(),
+            })
+        }
+    }
+
+    /// 每轮都请求调用 `lookup` 工具（`call_tools` 为 false 时返回空回复），记录收到的请求
+    #[derive(Default)]
+    struct LoopingProvider {
+        call_tools: bool,
+        requests: Mutex<Vec<ChatPrompt>>,
+    }
+
+    #[async_trait]
+    impl ChatProvider for LoopingProvider {
+        fn name(&self) -> &'static str {
+            "looping"
+        }
+
+        fn model_name(&self, model: GeminiModel) -> String {
+            model.as_str().to_string()
+        }
+
+        fn supports_tools(&self) -> bool {
+            true
+        }
+
+        async fn chat(&self, request: ChatRequest) -> Result<ChatResult, String> {
+            self.chat_stream(request, &mut |_| {}).await
+        }
+
+        async fn chat_stream(
+            &self,
+            request: ChatRequest,
+            _on_delta: &mut (dyn FnMut(StreamDelta) + Send),
+        ) -> Result<ChatResult, String> {
+            self.requests.lock().unwrap().push(request.prompt);
+            let tool_calls = if self.call_tools {
+                vec![ToolCall {
+                    name: "lookup".to_string(),
+                    args: json!({ "key": "port" }),
+                }]
+            } else {
+                Vec::new()
+            };
+            Ok(ChatResult {
+                response: String::new(),
+                thinking: None,
+                usage: None,
+                tool_calls,
+            })
+        }
+    }
+
+    /// 第一轮请求调用 `lookup` 工具，拿到结果后据此回答
+    struct ToolCallingProvider;
+
+    #[async_trait]
+    impl ChatProvider for ToolCallingProvider {
+        fn name(&self) -> &'static str {
+            "tool-calling"
+        }
+
+        fn model_name(&self, model: GeminiModel) -> String {
+            model.as_str().to_string()
+        }
+
+        fn supports_tools(&self) -> bool {
+            true
+        }
+
+        async fn chat(&self, request: ChatRequest) -> Result<ChatResult, String> {
+            self.chat_stream(request, &mut |_| {}).await
+        }
+
+        async fn chat_stream(
+            &self,
+            request: ChatRequest,
+            on_delta: &mut (dyn FnMut(StreamDelta) + Send),
+        ) -> Result<ChatResult, String> {
+            assert_eq!(request.prompt.tools[0].name, "lookup");
+            let usage = Some(TokenUsage {
+                prompt_tokens: 10,
+                candidate_tokens: 1,
+                thought_tokens: 0,
+            });
+            let Some(round) = request.prompt.tool_rounds.last() else {
+                on_delta(StreamDelta::Response("查询中。".to_string()));
+                return Ok(ChatResult {
+                    response: "查询中。".to_string(),
+                    thinking: None,
+                    usage,
+                    tool_calls: vec![ToolCall {
+                        name: "lookup".to_string(),
+                        args: json!({ "key": "port" }),
+                    }],
+                });
+            };
+
+            let answer = format!("端口是 {}", round.results[0].response()["result"]);
+            on_delta(StreamDelta::Response(answer.clone()));
+            Ok(ChatResult {
+                response: answer,
+                thinking: None,
+                usage,
+                tool_calls: Vec::new(),
+            })
+        }
+    }
+
+    struct LookupTool;
+
+    #[async_trait]
+    impl Tool for LookupTool {
+        fn name(&self) -> &str {
+            "lookup"
+        }
+
+        fn description(&self) -> &str {
+            "查询配置项"
+        }
+
+        fn parameters(&self) -> serde_json::Value {
+            json!({ "type": "object", "properties": { "key": { "type": "string" } } })
+        }
+
+        async fn execute(
+            &self,
+            args: serde_json::Value,
+            context: &ToolContext,
+        ) -> Result<serde_json::Value, String> {
+            assert_eq!(context.user_id, "u");
+            assert_eq!(args["key"], "port");
+            Ok(json!(23333))
+        }
+    }
+
+    fn input(conversation_id: i64, content: &str) -> ChatInput {
+        ChatInput {
+            user_id: "u".to_string(),
+            conversation_id,
+            search_all_conversations: false,
+            content: content.to_string(),
+            file_contexts: vec![FileContext {
+                name: "notes.txt".to_string(),
+                content: "文件内容".to_string(),
+                ..FileContext::default()
+            }],
+            file_ids: Vec::new(),
+            model: GeminiModel::Flash,
+            scopes: Scope::ALL.to_vec(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_run_chat_persists_and_streams() {
+        let memory = Arc::new(ChatMemory::new(":memory:").unwrap());
+        let embedder: Arc<dyn EmbeddingProvider> = Arc::new(LocalEmbedding::new(64));
+        let provider = EchoProvider::default();
+        let conversation =
+            start_conversation(&memory, "u", "一个很长很长的第一条消息", GeminiModel::Flash)
+                .unwrap();
+
+        let mut deltas = Vec::new();
+        let mut on_delta = |delta| {
+            if let StreamDelta::Response(content) = delta {
+                deltas.push(content);
+            }
+        };
+        let outcome = run_chat(
+            memory.clone(),
+            &provider,
+            embedder.clone(),
+            &BudgetConfig::default(),
+            &ToolRegistry::default(),
+            input(conversation.id, "你好"),
+            Some(&mut on_delta),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(deltas.concat(), "回声：你好");
+        assert_eq!(outcome.result.response, "回声：你好");
+        let messages = memory.get_all_messages("u", conversation.id).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(outcome.user_message_id, Some(messages[0].id));
+        assert_eq!(outcome.reply_message_id, Some(messages[1].id));
+        assert_eq!(messages[1].role, "model");
+
+        // 回复的 token 用量随消息保存，并计入每日配额
+        assert_eq!(outcome.tokens, 15);
+        let usage = memory.usage_summary(Some("u"), "2000-01-01").unwrap();
+        assert_eq!(usage.len(), 1);
+        assert_eq!(usage[0].usage.total(), 15);
+
+        // 文件上下文放入系统指令
+        let instructions = provider.system_instructions.lock().unwrap();
+        assert!(instructions[0].as_deref().unwrap().contains("文件内容"));
+    }
+
+    #[actix_web::test]
+    async fn test_run_chat_executes_tool_calls() {
+        let memory = Arc::new(ChatMemory::new(":memory:").unwrap());
+        let embedder: Arc<dyn EmbeddingProvider> = Arc::new(LocalEmbedding::new(64));
+        let mut tools = ToolRegistry::default();
+        tools.register(Arc::new(LookupTool));
+        let conversation = start_conversation(&memory, "u", "端口", GeminiModel::Flash).unwrap();
+
+        let mut events = Vec::new();
+        let mut on_delta = |delta| events.push(delta);
+        let outcome = run_chat(
+            memory.clone(),
+            &ToolCallingProvider,
+            embedder,
+            &BudgetConfig::default(),
+            &tools,
+            input(conversation.id, "服务端口是多少？"),
+            Some(&mut on_delta),
+        )
+        .await
+        .unwrap();
+
+        let call = ToolCall {
+            name: "lookup".to_string(),
+            args: json!({ "key": "port" }),
+        };
+        assert_eq!(
+            events,
+            vec![
+                StreamDelta::Response("查询中。".to_string()),
+                StreamDelta::ToolCall {
+                    id: "call_1_1".to_string(),
+                    call,
+                },
+                StreamDelta::ToolResult {
+                    id: "call_1_1".to_string(),
+                    result: ToolResult {
+                        name: "lookup".to_string(),
+                        output: Ok(json!(23333)),
+                    },
+                },
+                StreamDelta::Response("端口是 23333".to_string()),
+            ]
+        );
+
+        // 各轮回复拼接保存，用量累加
+        assert_eq!(outcome.result.response, "查询中。端口是 23333");
+        assert_eq!(outcome.tokens, 22);
+        let messages = memory.get_all_messages("u", conversation.id).unwrap();
+        assert_eq!(messages[1].content, "查询中。端口是 23333");
+
+        // 工具调用记录随回复消息保存
+        let reply_id = outcome.reply_message_id.unwrap();
+        let logs = memory.get_tool_calls(&[reply_id]).unwrap();
+        assert_eq!(
+            logs[&reply_id],
+            vec![ToolCallLog {
+                name: "lookup".to_string(),
+                args: json!({ "key": "port" }),
+                result: Some(json!(23333)),
+                error: None,
+            }]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_call_model_stops_at_tool_round_limit() {
+        let memory = Arc::new(ChatMemory::new(":memory:").unwrap());
+        let mut tools = ToolRegistry::default();
+        tools.register(Arc::new(LookupTool));
+        let context = ToolContext {
+            memory,
+            embedder: Arc::new(LocalEmbedding::new(64)),
+            user_id: "u".to_string(),
+            conversation_id: 1,
+            user_message_id: None,
+            scopes: Scope::ALL.to_vec(),
+        };
+        let prompt = ChatPrompt::from_history(None, &[], "端口是多少？");
+
+        let provider = LoopingProvider {
+            call_tools: true,
+            ..LoopingProvider::default()
+        };
+        let mut events = Vec::new();
+        let mut on_delta = |delta| events.push(delta);
+        let (result, _, logs) = call_model(
+            &provider,
+            &tools,
+            &context,
+            prompt.clone(),
+            GeminiModel::Flash,
+            Some(&mut on_delta),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.response, TOOL_LIMIT_REPLY);
+
+        // 执行 MAX_TOOL_ROUNDS 轮后，再给模型一次回答的机会
+        let requests = provider.requests.lock().unwrap().clone();
+        assert_eq!(requests.len(), MAX_TOOL_ROUNDS + 2);
+        let dropped = Err(TOOL_LIMIT_ERROR.to_string());
+        let last_round = requests.last().unwrap().tool_rounds.last().unwrap();
+        assert_eq!(last_round.results[0].output, dropped);
+
+        // 没有执行的调用推送给客户端，但不写入记录
+        assert_eq!(logs.len(), MAX_TOOL_ROUNDS);
+        assert!(logs.iter().all(|log| log.error.is_none()));
+        assert_eq!(
+            events.last(),
+            Some(&StreamDelta::ToolResult {
+                id: format!("call_{}_1", MAX_TOOL_ROUNDS + 2),
+                result: ToolResult {
+                    name: "lookup".to_string(),
+                    output: dropped,
+                },
+            })
+        );
+
+        // 没有达到上限时不使用上限提示，空回复使用占位文本
+        let provider = LoopingProvider::default();
+        let (result, _, logs) = call_model(
+            &provider,
+            &tools,
+            &context,
+            prompt,
+            GeminiModel::Flash,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.response, EMPTY_REPLY);
+        assert!(logs.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_run_chat_failure_keeps_user_message_only() {
+        let memory = Arc::new(ChatMemory::new(":memory:").unwrap());
+        let embedder: Arc<dyn EmbeddingProvider> = Arc::new(LocalEmbedding::new(64));
+        let provider = EchoProvider {
+            fail: true,
+            ..EchoProvider::default()
+        };
+        let conversation = start_conversation(&memory, "u", "你好", GeminiModel::Flash).unwrap();
+
+        let result = run_chat(
+            memory.clone(),
+            &provider,
+            embedder,
+            &BudgetConfig::default(),
+            &ToolRegistry::default(),
+            input(conversation.id, "你好"),
+            None,
+        )
+        .await;
+
+        assert!(result.is_err());
+        let messages = memory.get_all_messages("u", conversation.id).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].role, "user");
+    }
+}
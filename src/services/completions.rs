@@ -0,0 +1,198 @@
+use chrono::Utc;
+use uuid::Uuid;
+
+use super::provider::{ChatPrompt, ChatResult, ChatRole};
+use crate::models::completions::{
+    CompletionChoice, CompletionChunk, CompletionChunkChoice, CompletionDelta,
+    CompletionRequestMessage, CompletionResponse, CompletionResponseMessage, CompletionUsage,
+    MessageContent, ModelObject,
+};
+use crate::models::gemini::GeminiModel;
+use crate::models::messages::TokenUsage;
+
+/// `/v1/models` 返回的模型列表（模型 ID 即 Gemini 的 API 名称）
+pub fn model_list() -> Vec<ModelObject> {
+    GeminiModel::ALL
+        .into_iter()
+        .map(|model| ModelObject {
+            id: model.api_name(),
+            object: "model",
+            created: 0,
+            owned_by: "web-chat",
+        })
+        .collect()
+}
+
+/// 把 OpenAI 格式的消息转换为对话
+///
+/// system / developer 消息合并为系统指令，user / assistant 对应 user / model 角色；
+/// 最后一条对话消息必须来自 user。
+pub fn build_prompt(messages: &[CompletionRequestMessage]) -> Result<ChatPrompt, String> {
+    let mut system_parts = Vec::new();
+    let mut turns = Vec::new();
+
+    for message in messages {
+        let text = message_text(message.content.as_ref())?;
+        match message.role.as_str() {
+            "system" | "developer" => system_parts.push(text),
+            "user" => turns.push((ChatRole::User, text)),
+            "assistant" => turns.push((ChatRole::Model, text)),
+            other => return Err(format!("不支持的消息角色: {}", other)),
+        }
+    }
+
+    match turns.last() {
+        Some((ChatRole::User, _)) => {}
+        _ => return Err("最后一条消息必须来自 user".to_string()),
+    }
+
+    let system_instruction = if system_parts.is_empty() {
+        None
+    } else {
+        Some(system_parts.join("\n\n"))
+    };
+
+    Ok(ChatPrompt::from_messages(
+        system_instruction,
+        turns.iter().map(|(role, text)| (*role, text.as_str())),
+    ))
+}
+
+/// 取出消息中的文本（内容片段数组中只支持 text 类型）
+fn message_text(content: Option<&MessageContent>) -> Result<String, String> {
+    match content {
+        None => Ok(String::new()),
+        Some(MessageContent::Text(text)) => Ok(text.clone()),
+        Some(MessageContent::Parts(parts)) => parts
+            .iter()
+            .map(|part| match (part.kind.as_str(), &part.text) {
+                ("text", Some(text)) => Ok(text.as_str()),
+                (kind, _) => Err(format!("暂不支持 {} 类型的消息内容", kind)),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(|texts| texts.join("\n")),
+    }
+}
+
+/// 生成一次补全的 ID
+pub fn completion_id() -> String {
+    format!("chatcmpl-{}", Uuid::new_v4().simple())
+}
+
+/// 构建非流式响应
+pub fn completion_response(
+    id: String,
+    model: GeminiModel,
+    result: ChatResult,
+) -> CompletionResponse {
+    CompletionResponse {
+        id,
+        object: "chat.completion",
+        created: Utc::now().timestamp(),
+        model: model.api_name().to_string(),
+        choices: vec![CompletionChoice {
+            index: 0,
+            message: CompletionResponseMessage {
+                role: "assistant",
+                content: result.response,
+                reasoning_content: result.thinking,
+            },
+            finish_reason: "stop",
+        }],
+        usage: result.usage.map(completion_usage),
+    }
+}
+
+/// 转换为 OpenAI 格式的用量
+pub fn completion_usage(usage: TokenUsage) -> CompletionUsage {
+    CompletionUsage {
+        prompt_tokens: usage.prompt_tokens,
+        completion_tokens: usage.output_tokens(),
+        total_tokens: usage.total(),
+    }
+}
+
+/// 构建流式响应的一个片段
+pub fn completion_chunk(
+    id: &str,
+    model: GeminiModel,
+    delta: CompletionDelta,
+    finish_reason: Option<&'static str>,
+) -> CompletionChunk {
+    CompletionChunk {
+        id: id.to_string(),
+        object: "chat.completion.chunk",
+        created: Utc::now().timestamp(),
+        model: model.api_name().to_string(),
+        choices: vec![CompletionChunkChoice {
+            index: 0,
+            delta,
+            finish_reason,
+        }],
+        usage: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(json: &str) -> Vec<CompletionRequestMessage> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_build_prompt() {
+        let prompt = build_prompt(&messages(
+            r#"[
+                {"role": "system", "content": "你是助手"},
+                {"role": "assistant", "content": "开场白"},
+                {"role": "user", "content": "问题一"},
+                {"role": "assistant", "content": "回答一"},
+                {"role": "user", "content": [{"type": "text", "text": "问题二"}, {"type": "text", "text": "补充"}]}
+            ]"#,
+        ))
+        .unwrap();
+
+        assert_eq!(prompt.system_instruction.as_deref(), Some("你是助手"));
+        // 开头的 assistant 消息被丢弃
+        let turns: Vec<_> = prompt
+            .turns
+            .iter()
+            .map(|t| (t.role, t.content.as_str()))
+            .collect();
+        assert_eq!(
+            turns,
+            vec![
+                (ChatRole::User, "问题一"),
+                (ChatRole::Model, "回答一"),
+                (ChatRole::User, "问题二\n补充"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_prompt_rejects_invalid_messages() {
+        assert!(build_prompt(&messages(r#"[{"role": "system", "content": "只有系统"}]"#)).is_err());
+        assert!(
+            build_prompt(&messages(
+                r#"[{"role": "user", "content": "问"}, {"role": "assistant", "content": "答"}]"#
+            ))
+            .is_err()
+        );
+        assert!(build_prompt(&messages(r#"[{"role": "tool", "content": "x"}]"#)).is_err());
+        assert!(
+            build_prompt(&messages(
+                r#"[{"role": "user", "content": [{"type": "image_url"}]}]"#
+            ))
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_model_list_ids_parse_back() {
+        for model in model_list() {
+            assert_eq!(GeminiModel::parse(model.id).unwrap().api_name(), model.id);
+        }
+    }
+}
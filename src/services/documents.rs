@@ -0,0 +1,299 @@
+use super::embedding::{EmbeddingProvider, cosine_similarity};
+use super::files::TEXT_MIME_TYPE;
+use super::memory::{ChatMemory, DocumentChunk};
+use crate::models::messages::FileContext;
+
+/// 每个片段的字符数上限
+const CHUNK_MAX_CHARS: usize = 2000;
+/// 每个片段的行数上限
+const CHUNK_MAX_LINES: usize = 60;
+/// 相邻片段重叠的行数，避免切分处的内容丢失上下文
+const CHUNK_OVERLAP_LINES: usize = 8;
+/// 每个文件最多保存的片段数，上传更长的文本文件时拒绝
+pub const MAX_FILE_CHUNKS: usize = 1000;
+
+/// 文本文件中的一段连续内容（行号从 1 开始，包含首尾两行）
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextChunk {
+    pub start_line: usize,
+    pub end_line: usize,
+    pub content: String,
+}
+
+/// 检索到的文件片段
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetrievedChunk {
+    pub file_id: i64,
+    pub file_name: String,
+    pub chunk: TextChunk,
+    /// 与问题的向量相似度（没有可比较的嵌入时为 None）
+    pub similarity: Option<f32>,
+}
+
+impl RetrievedChunk {
+    /// 作为文件上下文放入提示词，名称形如 `main.rs 第 10-42 行`
+    pub fn to_file_context(&self) -> FileContext {
+        FileContext {
+            name: format!(
+                "{} 第 {}-{} 行",
+                self.file_name, self.chunk.start_line, self.chunk.end_line
+            ),
+            content: self.chunk.content.clone(),
+            ..FileContext::default()
+        }
+    }
+}
+
+/// 按行把文本切分为相互重叠的片段
+///
+/// 每个片段不超过 `CHUNK_MAX_LINES` 行和 `CHUNK_MAX_CHARS` 个字符，
+/// 超长的单行（如压缩后的代码）按字符拆开，拆出的部分共用同一行号。
+pub fn chunk_text(text: &str) -> Vec<TextChunk> {
+    let mut segments: Vec<(usize, String)> = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let chars: Vec<char> = line.chars().collect();
+        if chars.is_empty() {
+            segments.push((index + 1, String::new()));
+        }
+        for piece in chars.chunks(CHUNK_MAX_CHARS) {
+            segments.push((index + 1, piece.iter().collect()));
+        }
+    }
+    if segments.iter().all(|(_, s)| s.trim().is_empty()) {
+        return Vec::new();
+    }
+
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    loop {
+        let mut end = start;
+        let mut chars = 0;
+        while end < segments.len() && end - start < CHUNK_MAX_LINES {
+            let len = segments[end].1.chars().count() + 1;
+            if end > start && chars + len > CHUNK_MAX_CHARS {
+                break;
+            }
+            chars += len;
+            end += 1;
+        }
+
+        let lines: Vec<&str> = segments[start..end]
+            .iter()
+            .map(|(_, s)| s.as_str())
+            .collect();
+        chunks.push(TextChunk {
+            start_line: segments[start].0,
+            end_line: segments[end - 1].0,
+            content: lines.join("\n"),
+        });
+
+        if end == segments.len() {
+            return chunks;
+        }
+        start = end.saturating_sub(CHUNK_OVERLAP_LINES).max(start + 1);
+    }
+}
+
+/// 切分文本文件并保存片段，超过 `MAX_FILE_CHUNKS` 的部分不保存，返回片段数
+///
+/// 片段的嵌入由后台补全任务限速生成（见 `backfill`），生成之前按原文顺序参与检索。
+pub fn chunk_file(memory: &ChatMemory, file_id: i64, text: &str) -> Result<usize, String> {
+    let mut chunks = chunk_text(text);
+    chunks.truncate(MAX_FILE_CHUNKS);
+    memory
+        .replace_document_chunks(file_id, &chunks)
+        .map_err(|e| format!("保存文件片段失败: {}", e))?;
+    Ok(chunks.len())
+}
+
+/// 生成片段嵌入时的输入：带上文件名，让“某个文件里的…”这类问题也能命中
+pub fn chunk_embedding_input(file_name: &str, content: &str) -> String {
+    format!("{}\n{}", file_name, content)
+}
+
+/// 从文件库的文本文件中检索与问题最相关的片段
+///
+/// 只读取已保存的片段，不在请求中生成嵌入。相似度低于 `min_similarity` 的片段被丢弃，
+/// 还没有可比较嵌入的片段排在所有有相似度的片段之后、按文件顺序补足 `top_k` 个。
+/// 结果按文件和行号排序。
+pub fn retrieve_chunks(
+    memory: &ChatMemory,
+    embedder: &dyn EmbeddingProvider,
+    user_id: &str,
+    file_ids: &[i64],
+    query_embedding: Option<&[f32]>,
+    top_k: usize,
+    min_similarity: f32,
+) -> Result<Vec<RetrievedChunk>, String> {
+    let library = memory
+        .list_files(user_id)
+        .map_err(|e| format!("读取文件失败: {}", e))?;
+    let text_ids: Vec<i64> = file_ids
+        .iter()
+        .copied()
+        .filter(|id| {
+            library
+                .iter()
+                .any(|f| f.id == *id && f.mime_type == TEXT_MIME_TYPE)
+        })
+        .collect();
+    if text_ids.is_empty() || top_k == 0 {
+        return Ok(Vec::new());
+    }
+
+    let model = embedder.model_id();
+    let chunks = memory
+        .get_document_chunks(user_id, &text_ids)
+        .map_err(|e| format!("读取文件片段失败: {}", e))?;
+
+    let mut ranked: Vec<(usize, Option<f32>)> = chunks
+        .iter()
+        .enumerate()
+        .map(|(i, chunk)| (i, chunk_similarity(chunk, &model, query_embedding)))
+        .filter(|(_, similarity)| similarity.is_none_or(|s| s >= min_similarity))
+        .collect();
+    // 稳定排序：有相似度的按相似度降序在前，没有的保持文件顺序在后
+    ranked.sort_by(|a, b| match (a.1, b.1) {
+        (Some(a), Some(b)) => b.total_cmp(&a),
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (None, None) => std::cmp::Ordering::Equal,
+    });
+    ranked.truncate(top_k);
+    // 片段已按文件顺序和行号读取，恢复原文顺序便于阅读
+    ranked.sort_unstable_by_key(|(i, _)| *i);
+
+    Ok(ranked
+        .into_iter()
+        .map(|(i, similarity)| {
+            let chunk = &chunks[i];
+            RetrievedChunk {
+                file_id: chunk.file_id,
+                file_name: chunk.file_name.clone(),
+                chunk: chunk.chunk.clone(),
+                similarity,
+            }
+        })
+        .collect())
+}
+
+/// 片段与问题的相似度，不可比较（没有嵌入或来源不同）时为 None
+fn chunk_similarity(
+    chunk: &DocumentChunk,
+    model: &str,
+    query_embedding: Option<&[f32]>,
+) -> Option<f32> {
+    match (query_embedding, &chunk.embedding) {
+        (Some(query), Some(embedding))
+            if chunk.embedding_model.as_deref() == Some(model)
+                && query.len() == embedding.len() =>
+        {
+            Some(cosine_similarity(query, embedding))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::embedding::LocalEmbedding;
+
+    #[test]
+    fn test_chunk_text_overlaps_and_tracks_lines() {
+        let text: String = (1..=130).map(|i| format!("line {}\n", i)).collect();
+        let chunks = chunk_text(&text);
+
+        assert_eq!(chunks.len(), 3);
+        assert_eq!((chunks[0].start_line, chunks[0].end_line), (1, 60));
+        assert_eq!((chunks[1].start_line, chunks[1].end_line), (53, 112));
+        assert_eq!((chunks[2].start_line, chunks[2].end_line), (105, 130));
+        assert!(chunks[1].content.starts_with("line 53\n"));
+        assert!(chunks[2].content.ends_with("line 130"));
+    }
+
+    #[test]
+    fn test_chunk_text_splits_long_lines() {
+        let text = format!("head\n{}\ntail", "x".repeat(CHUNK_MAX_CHARS * 2));
+        let chunks = chunk_text(&text);
+
+        assert!(
+            chunks
+                .iter()
+                .all(|c| c.content.chars().count() <= CHUNK_MAX_CHARS + 1)
+        );
+        assert_eq!(chunks.first().unwrap().start_line, 1);
+        assert_eq!(chunks.last().unwrap().end_line, 3);
+        assert!(chunk_text(" \n\n").is_empty());
+    }
+
+    #[test]
+    fn test_retrieve_chunks() {
+        let memory = ChatMemory::new(":memory:").unwrap();
+        let embedder = LocalEmbedding::new(256);
+        let manual: String = (1..=120)
+            .map(|i| match i {
+                90 => "database connection pool timeout settings\n".to_string(),
+                _ => format!("unrelated filler paragraph number {}\n", i),
+            })
+            .collect();
+        let file = memory
+            .save_file("u", "manual.txt", TEXT_MIME_TYPE, "hash", manual.as_bytes())
+            .unwrap();
+        let image = memory
+            .save_file("u", "a.png", "image/png", "hash-png", b"\x89PNG")
+            .unwrap();
+        assert_eq!(chunk_file(&memory, file.id, &manual).unwrap(), 3);
+
+        // 生成嵌入之前按原文顺序返回
+        let query = embedder.embed_text("database connection pool timeout");
+        let ids = [image.id, file.id];
+        let chunks = retrieve_chunks(&memory, &embedder, "u", &ids, Some(&query), 1, 0.5).unwrap();
+        assert_eq!(chunks[0].chunk.start_line, 1);
+        assert_eq!(chunks[0].similarity, None);
+
+        // 只有部分片段有嵌入时，没有嵌入的片段排在负相似度的片段之后
+        let model = embedder.model_id();
+        let first = memory.get_chunks_without_embedding(&model, 1, 1).unwrap();
+        let opposite: Vec<f32> = query.iter().map(|x| -x).collect();
+        memory
+            .update_chunk_embedding(first[0].id, &opposite, &model)
+            .unwrap();
+        let chunks = retrieve_chunks(&memory, &embedder, "u", &ids, Some(&query), 2, -1.0).unwrap();
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks[0].similarity.unwrap() < 0.0);
+        assert_eq!(chunks[1].similarity, None);
+        let chunks = retrieve_chunks(&memory, &embedder, "u", &ids, Some(&query), 3, 0.5).unwrap();
+        assert!(chunks.iter().all(|c| c.similarity.is_none()));
+        assert_eq!(chunks.len(), 2);
+
+        for chunk in memory.get_chunks_without_embedding(&model, 1, 10).unwrap() {
+            let input = chunk_embedding_input(&chunk.file_name, &chunk.content);
+            memory
+                .update_chunk_embedding(chunk.id, &embedder.embed_text(&input), &model)
+                .unwrap();
+        }
+        // 相似度为负的片段低于阈值被丢弃
+        let chunks = retrieve_chunks(&memory, &embedder, "u", &ids, Some(&query), 3, 0.0).unwrap();
+        assert_eq!(chunks.len(), 2);
+        let chunks = retrieve_chunks(&memory, &embedder, "u", &ids, Some(&query), 1, 0.0).unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].file_id, file.id);
+        assert!(chunks[0].similarity.is_some());
+        // 每行约 40 个字符，片段受字符数限制
+        let context = chunks[0].to_file_context();
+        assert_eq!(context.name, "manual.txt 第 47-99 行");
+        assert!(context.content.contains("connection pool"));
+
+        // 不相关的问题低于阈值，不放入任何片段
+        let unrelated = embedder.embed_text("chocolate cake recipe");
+        let chunks =
+            retrieve_chunks(&memory, &embedder, "u", &ids, Some(&unrelated), 3, 0.5).unwrap();
+        assert!(chunks.is_empty());
+
+        // 其他用户无法读取
+        let chunks =
+            retrieve_chunks(&memory, &embedder, "other", &[file.id], None, 5, 0.5).unwrap();
+        assert!(chunks.is_empty());
+    }
+}
@@ -1,5 +1,10 @@
+use async_trait::async_trait;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
+use std::env;
+use std::sync::Arc;
+
+use super::gemini::API_KEY_HEADER;
 
 /// Embedding API 请求结构
 #[derive(Serialize, Debug)]
@@ -36,97 +41,316 @@ pub struct EmbeddingData {
 /// 嵌入维度（使用 768 维以节省存储空间）
 pub const EMBEDDING_DIMENSION: usize = 768;
 
-/// 调用 text-embedding-004 API 生成文本嵌入
-pub async fn generate_embedding(text: &str, api_key: &str) -> Result<Vec<f32>, String> {
-    let client = Client::new();
-    let url = format!(
-        "https://generativelanguage.googleapis.com/v1beta/models/text-embedding-004:embedContent?key={}",
-        api_key
-    );
-
-    let request = EmbeddingRequest {
-        model: "models/text-embedding-004".to_string(),
-        content: EmbeddingContent {
-            parts: vec![EmbeddingPart {
-                text: text.to_string(),
-            }],
-        },
-        task_type: Some("RETRIEVAL_DOCUMENT".to_string()),
-        output_dimensionality: Some(EMBEDDING_DIMENSION as u32),
+/// Gemini 默认嵌入模型
+const DEFAULT_GEMINI_MODEL: &str = "text-embedding-004";
+
+/// Gemini API 默认地址
+const DEFAULT_GEMINI_API_BASE: &str = "https://generativelanguage.googleapis.com/v1beta";
+
+/// 嵌入用途（部分后端对文档和查询使用不同的编码方式）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmbeddingTask {
+    /// 存入记忆的消息
+    Document,
+    /// 用于检索的查询
+    Query,
+}
+
+/// 文本嵌入后端
+#[async_trait]
+pub trait EmbeddingProvider: Send + Sync {
+    /// 写入数据库的嵌入来源标识，只有来源相同的向量才能互相比较
+    fn model_id(&self) -> String;
+
+    /// 生成归一化的嵌入向量
+    async fn embed(&self, text: &str, task: EmbeddingTask) -> Result<Vec<f32>, String>;
+}
+
+/// 根据环境变量创建嵌入后端
+///
+/// `EMBEDDING_PROVIDER` 可选 `gemini`、`openai`、`ollama`、`local`，
+/// 未设置时有 GEMINI_API_KEY 则用 Gemini，否则使用本地哈希嵌入
+pub fn embedding_provider_from_env() -> Result<Arc<dyn EmbeddingProvider>, String> {
+    let gemini_key = env::var("GEMINI_API_KEY").ok();
+    let kind = env::var("EMBEDDING_PROVIDER").unwrap_or_else(|_| {
+        if gemini_key.is_some() {
+            "gemini".to_string()
+        } else {
+            "local".to_string()
+        }
+    });
+    let base_url = env::var("EMBEDDING_BASE_URL").ok();
+    let model = env::var("EMBEDDING_MODEL").ok();
+    let dimension = env::var("EMBEDDING_DIMENSION")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(EMBEDDING_DIMENSION);
+    let required_model = || {
+        model
+            .clone()
+            .ok_or_else(|| "未设置 EMBEDDING_MODEL 环境变量".to_string())
     };
 
-    let response = client
-        .post(&url)
-        .json(&request)
+    match kind.to_lowercase().as_str() {
+        "gemini" => Ok(Arc::new(GeminiEmbedding {
+            base_url: base_url
+                .or_else(|| env::var("GEMINI_API_BASE").ok())
+                .unwrap_or_else(|| DEFAULT_GEMINI_API_BASE.to_string()),
+            api_key: gemini_key.ok_or_else(|| "未设置 GEMINI_API_KEY 环境变量".to_string())?,
+            model: model.unwrap_or_else(|| DEFAULT_GEMINI_MODEL.to_string()),
+            dimension,
+        })),
+        "openai" => Ok(Arc::new(OpenAiEmbedding {
+            base_url: trim_base_url(
+                base_url.unwrap_or_else(|| "http://localhost:8000/v1".to_string()),
+            ),
+            api_key: env::var("EMBEDDING_API_KEY").ok(),
+            model: required_model()?,
+        })),
+        "ollama" => Ok(Arc::new(OllamaEmbedding {
+            base_url: trim_base_url(
+                base_url.unwrap_or_else(|| "http://localhost:11434".to_string()),
+            ),
+            model: required_model()?,
+        })),
+        "local" => Ok(Arc::new(LocalEmbedding::new(dimension))),
+        other => Err(format!("不支持的 EMBEDDING_PROVIDER: {}", other)),
+    }
+}
+
+fn trim_base_url(url: String) -> String {
+    url.trim_end_matches('/').to_string()
+}
+
+/// 发送请求并解析 JSON 响应
+async fn post_json<T, R>(builder: reqwest::RequestBuilder, body: &T) -> Result<R, String>
+where
+    T: Serialize + ?Sized,
+    R: for<'de> Deserialize<'de>,
+{
+    let response = builder
+        .json(body)
         .send()
         .await
-        .map_err(|e| format!("请求嵌入API失败: {}", e))?;
+        .map_err(|e| format!("请求嵌入API失败: {}", e.without_url()))?;
 
     if !response.status().is_success() {
         let error_text = response.text().await.unwrap_or_default();
         return Err(format!("嵌入API返回错误: {}", error_text));
     }
 
-    let embedding_response: EmbeddingResponse = response
+    response
         .json()
         .await
-        .map_err(|e| format!("解析嵌入响应失败: {}", e))?;
+        .map_err(|e| format!("解析嵌入响应失败: {}", e.without_url()))
+}
+
+/// Google Gemini `embedContent` 后端
+pub struct GeminiEmbedding {
+    base_url: String,
+    api_key: String,
+    model: String,
+    dimension: usize,
+}
+
+#[async_trait]
+impl EmbeddingProvider for GeminiEmbedding {
+    fn model_id(&self) -> String {
+        format!("gemini/{}", self.model)
+    }
+
+    async fn embed(&self, text: &str, task: EmbeddingTask) -> Result<Vec<f32>, String> {
+        let url = format!("{}/models/{}:embedContent", self.base_url, self.model);
+
+        let request = EmbeddingRequest {
+            model: format!("models/{}", self.model),
+            content: EmbeddingContent {
+                parts: vec![EmbeddingPart {
+                    text: text.to_string(),
+                }],
+            },
+            task_type: Some(
+                match task {
+                    EmbeddingTask::Document => "RETRIEVAL_DOCUMENT",
+                    EmbeddingTask::Query => "RETRIEVAL_QUERY",
+                }
+                .to_string(),
+            ),
+            output_dimensionality: Some(self.dimension as u32),
+        };
+
+        let response: EmbeddingResponse = post_json(
+            Client::new()
+                .post(&url)
+                .header(API_KEY_HEADER, &self.api_key),
+            &request,
+        )
+        .await?;
 
-    match embedding_response.embedding {
-        Some(data) => {
-            // 归一化嵌入向量
-            let normalized = normalize_embedding(&data.values);
-            Ok(normalized)
+        match response.embedding {
+            Some(data) => Ok(normalize_embedding(&data.values)),
+            None => Err("嵌入响应中没有数据".to_string()),
         }
-        None => Err("嵌入响应中没有数据".to_string()),
-    }
-}
-
-/// 为查询生成嵌入（使用不同的任务类型）
-pub async fn generate_query_embedding(text: &str, api_key: &str) -> Result<Vec<f32>, String> {
-    let client = Client::new();
-    let url = format!(
-        "https://generativelanguage.googleapis.com/v1beta/models/text-embedding-004:embedContent?key={}",
-        api_key
-    );
-
-    let request = EmbeddingRequest {
-        model: "models/text-embedding-004".to_string(),
-        content: EmbeddingContent {
-            parts: vec![EmbeddingPart {
-                text: text.to_string(),
-            }],
-        },
-        task_type: Some("RETRIEVAL_QUERY".to_string()),
-        output_dimensionality: Some(EMBEDDING_DIMENSION as u32),
-    };
+    }
+}
 
-    let response = client
-        .post(&url)
-        .json(&request)
-        .send()
-        .await
-        .map_err(|e| format!("请求嵌入API失败: {}", e))?;
+/// OpenAI 兼容的 `/v1/embeddings` 后端
+pub struct OpenAiEmbedding {
+    base_url: String,
+    api_key: Option<String>,
+    model: String,
+}
 
-    if !response.status().is_success() {
-        let error_text = response.text().await.unwrap_or_default();
-        return Err(format!("嵌入API返回错误: {}", error_text));
+#[derive(Serialize, Debug)]
+struct OpenAiEmbeddingRequest<'a> {
+    model: &'a str,
+    input: &'a str,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAiEmbeddingResponse {
+    #[serde(default)]
+    data: Vec<OpenAiEmbeddingData>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAiEmbeddingData {
+    embedding: Vec<f32>,
+}
+
+#[async_trait]
+impl EmbeddingProvider for OpenAiEmbedding {
+    fn model_id(&self) -> String {
+        format!("openai/{}", self.model)
     }
 
-    let embedding_response: EmbeddingResponse = response
-        .json()
-        .await
-        .map_err(|e| format!("解析嵌入响应失败: {}", e))?;
+    async fn embed(&self, text: &str, _task: EmbeddingTask) -> Result<Vec<f32>, String> {
+        let mut builder = Client::new().post(format!("{}/embeddings", self.base_url));
+        if let Some(ref key) = self.api_key {
+            builder = builder.bearer_auth(key);
+        }
+
+        let response: OpenAiEmbeddingResponse = post_json(
+            builder,
+            &OpenAiEmbeddingRequest {
+                model: &self.model,
+                input: text,
+            },
+        )
+        .await?;
 
-    match embedding_response.embedding {
-        Some(data) => {
-            let normalized = normalize_embedding(&data.values);
-            Ok(normalized)
+        match response.data.into_iter().next() {
+            Some(data) => Ok(normalize_embedding(&data.embedding)),
+            None => Err("嵌入响应中没有数据".to_string()),
         }
-        None => Err("嵌入响应中没有数据".to_string()),
     }
 }
 
+/// Ollama `/api/embed` 后端
+pub struct OllamaEmbedding {
+    base_url: String,
+    model: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct OllamaEmbeddingResponse {
+    #[serde(default)]
+    embeddings: Vec<Vec<f32>>,
+}
+
+#[async_trait]
+impl EmbeddingProvider for OllamaEmbedding {
+    fn model_id(&self) -> String {
+        format!("ollama/{}", self.model)
+    }
+
+    async fn embed(&self, text: &str, _task: EmbeddingTask) -> Result<Vec<f32>, String> {
+        let response: OllamaEmbeddingResponse = post_json(
+            Client::new().post(format!("{}/api/embed", self.base_url)),
+            &OpenAiEmbeddingRequest {
+                model: &self.model,
+                input: text,
+            },
+        )
+        .await?;
+
+        match response.embeddings.into_iter().next() {
+            Some(values) => Ok(normalize_embedding(&values)),
+            None => Err("嵌入响应中没有数据".to_string()),
+        }
+    }
+}
+
+/// 本地哈希 n-gram 嵌入
+///
+/// 不依赖网络，结果确定；把词和字符 n-gram 哈希到固定维度，
+/// 检索效果不如语义模型，但能找到字面相近的历史消息
+pub struct LocalEmbedding {
+    dimension: usize,
+}
+
+impl LocalEmbedding {
+    pub fn new(dimension: usize) -> Self {
+        Self {
+            dimension: dimension.max(1),
+        }
+    }
+
+    /// 同步计算嵌入
+    pub fn embed_text(&self, text: &str) -> Vec<f32> {
+        let mut values = vec![0.0f32; self.dimension];
+        let text = text.to_lowercase();
+
+        let mut add = |feature: &str, weight: f32| {
+            let hash = fnv1a(feature.as_bytes());
+            let index = (hash % self.dimension as u64) as usize;
+            // 用哈希的最高位决定符号，减少碰撞带来的偏差
+            let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+            values[index] += sign * weight;
+        };
+
+        // 词（按非字母数字字符切分，适合英文和代码标识符）
+        for word in text
+            .split(|c: char| !c.is_alphanumeric() && c != '_')
+            .filter(|w| w.chars().count() > 1)
+        {
+            add(word, 1.0);
+        }
+
+        // 字符 2-gram 和 3-gram（适合中文等不以空格分词的语言）
+        let chars: Vec<char> = text.chars().filter(|c| !c.is_whitespace()).collect();
+        for n in 2..=3 {
+            for window in chars.windows(n) {
+                let gram: String = window.iter().collect();
+                add(&gram, 0.5);
+            }
+        }
+        if chars.len() == 1 {
+            add(&chars[0].to_string(), 1.0);
+        }
+
+        normalize_embedding(&values)
+    }
+}
+
+#[async_trait]
+impl EmbeddingProvider for LocalEmbedding {
+    fn model_id(&self) -> String {
+        format!("local/hash-ngram-{}", self.dimension)
+    }
+
+    async fn embed(&self, text: &str, _task: EmbeddingTask) -> Result<Vec<f32>, String> {
+        Ok(self.embed_text(text))
+    }
+}
+
+/// 64 位 FNV-1a 哈希（跨平台、跨版本结果一致）
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
 /// 归一化嵌入向量
 fn normalize_embedding(values: &[f32]) -> Vec<f32> {
     let norm: f32 = values.iter().map(|x| x * x).sum::<f32>().sqrt();
@@ -143,7 +367,21 @@ pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
         return 0.0;
     }
     // 由于向量已归一化，余弦相似度就是点积
-    a.iter().zip(b.iter()).map(|(x, y)| x * y).sum()
+    // 分 8 路累加，便于编译器向量化
+    let mut sums = [0.0f32; 8];
+    let (a_chunks, b_chunks) = (a.chunks_exact(8), b.chunks_exact(8));
+    let tail: f32 = a_chunks
+        .remainder()
+        .iter()
+        .zip(b_chunks.remainder())
+        .map(|(x, y)| x * y)
+        .sum();
+    for (x, y) in a_chunks.zip(b_chunks) {
+        for i in 0..8 {
+            sums[i] += x[i] * y[i];
+        }
+    }
+    sums.iter().sum::<f32>() + tail
 }
 
 /// 将嵌入向量序列化为字节数组（用于存储）
@@ -164,6 +402,7 @@ pub fn bytes_to_embedding(bytes: &[u8]) -> Vec<f32> {
 
 #[cfg(test)]
 mod tests {
+    use super::super::provider::test_support::mock_server;
     use super::*;
 
     #[test]
@@ -184,6 +423,37 @@ mod tests {
         assert_eq!(embedding, restored);
     }
 
+    #[test]
+    fn test_local_embedding() {
+        let local = LocalEmbedding::new(EMBEDDING_DIMENSION);
+        let a = local.embed_text("如何在 Rust 中读取文件");
+        let b = local.embed_text("Rust 怎么读取文件内容");
+        let c = local.embed_text("今天天气不错");
+
+        assert_eq!(a.len(), EMBEDDING_DIMENSION);
+        assert_eq!(a, local.embed_text("如何在 Rust 中读取文件"));
+        assert!(cosine_similarity(&a, &b) > cosine_similarity(&a, &c));
+        assert!(local.embed_text("").iter().all(|&x| x == 0.0));
+    }
+
+    #[tokio::test]
+    async fn test_openai_embedding_with_mock_server() {
+        let body = r#"{"data":[{"embedding":[3.0,4.0],"index":0}]}"#;
+        let (base_url, request_body) = mock_server("application/json", body.to_string()).await;
+
+        let provider = OpenAiEmbedding {
+            base_url,
+            api_key: None,
+            model: "nomic-embed-text".to_string(),
+        };
+        let embedding = provider.embed("你好", EmbeddingTask::Query).await.unwrap();
+        assert_eq!(embedding, vec![0.6, 0.8]);
+        assert_eq!(provider.model_id(), "openai/nomic-embed-text");
+
+        let sent: serde_json::Value = serde_json::from_str(&request_body.await.unwrap()).unwrap();
+        assert_eq!(sent["input"], "你好");
+    }
+
     #[test]
     fn test_normalize() {
         let values = vec![3.0, 4.0];
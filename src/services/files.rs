@@ -0,0 +1,291 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chrono::{Duration, Utc};
+use sha2::{Digest, Sha256};
+
+use super::attachment::{self, MAX_INLINE_BYTES};
+use super::documents::{self, MAX_FILE_CHUNKS};
+use super::memory::{ChatMemory, StoredFile};
+use super::provider::ChatProvider;
+use crate::models::files::FileInfo;
+use crate::models::messages::FileContext;
+
+/// 后端文件存储中的文件在该时长后视为过期（Gemini Files API 保存 48 小时），需要重新上传
+const REMOTE_FILE_TTL_HOURS: i64 = 46;
+
+/// 文本文件的 MIME 类型
+pub const TEXT_MIME_TYPE: &str = "text/plain";
+
+/// 上传失败的原因
+#[derive(Debug, Clone, PartialEq)]
+pub enum UploadError {
+    /// 不支持的文件类型
+    Unsupported(String),
+    /// 文件过大且后端没有文件存储
+    TooLarge(String),
+    /// 保存或上传到后端失败
+    Failed(String),
+}
+
+impl UploadError {
+    pub fn message(&self) -> &str {
+        match self {
+            UploadError::Unsupported(message)
+            | UploadError::TooLarge(message)
+            | UploadError::Failed(message) => message,
+        }
+    }
+}
+
+/// 识别上传文件的类型：支持的图片、PDF、音频返回其 MIME 类型，UTF-8 文本返回 `text/plain`
+pub fn classify(name: &str, data: &[u8]) -> Result<&'static str, String> {
+    match attachment::detect_mime(name, data) {
+        Some(mime_type) if attachment::is_supported(mime_type) => Ok(mime_type),
+        Some(mime_type) => Err(format!("不支持的文件类型: {} ({})", name, mime_type)),
+        None => std::str::from_utf8(data)
+            .map(|_| TEXT_MIME_TYPE)
+            .map_err(|e| {
+                format!(
+                    "文件 {} 既不是支持的图片、PDF、音频，也不是有效的 UTF-8 文本: {}",
+                    name, e
+                )
+            }),
+    }
+}
+
+/// 把上传的文件保存到用户的文件库
+///
+/// 超过内联上限的二进制文件先上传到模型后端的文件存储，后端不支持或上传失败时拒绝；
+/// 文本文件切分为片段保存（超过 `MAX_FILE_CHUNKS` 个片段时拒绝），嵌入由后台任务生成。
+/// 所有检查通过后才写入文件库，失败的上传不会留下数据。
+pub async fn store_upload(
+    memory: &ChatMemory,
+    provider: &dyn ChatProvider,
+    user_id: &str,
+    name: &str,
+    data: &[u8],
+) -> Result<StoredFile, UploadError> {
+    let mime_type = classify(name, data).map_err(UploadError::Unsupported)?;
+    let sha256 = sha256_hex(data);
+
+    let chunks = if mime_type == TEXT_MIME_TYPE {
+        let chunks = documents::chunk_text(&String::from_utf8_lossy(data));
+        if chunks.len() > MAX_FILE_CHUNKS {
+            return Err(UploadError::TooLarge(format!(
+                "文本文件 {} 过长，最多支持 {} 个片段",
+                name, MAX_FILE_CHUNKS
+            )));
+        }
+        Some(chunks)
+    } else {
+        None
+    };
+
+    let mut remote_uri = None;
+    if mime_type != TEXT_MIME_TYPE && data.len() > MAX_INLINE_BYTES {
+        let existing = memory
+            .find_file(user_id, &sha256)
+            .map_err(|e| UploadError::Failed(format!("查询文件失败: {}", e)))?;
+        if !existing.as_ref().is_some_and(is_remote_fresh) {
+            let uri = provider
+                .upload_file(name, mime_type, data.to_vec())
+                .await
+                .map_err(|e| UploadError::Failed(format!("上传文件 {} 失败: {}", name, e)))?
+                .ok_or_else(|| {
+                    UploadError::TooLarge(format!(
+                        "文件 {} 超过 {} MB，当前模型后端不支持上传大文件",
+                        name,
+                        MAX_INLINE_BYTES / 1024 / 1024
+                    ))
+                })?;
+            remote_uri = Some(uri);
+        }
+    }
+
+    let mut file = memory
+        .save_file(user_id, name, mime_type, &sha256, data)
+        .map_err(|e| UploadError::Failed(format!("保存文件失败: {}", e)))?;
+    if let Some(uri) = remote_uri {
+        if let Err(e) = memory.set_file_remote_uri(file.id, &uri) {
+            eprintln!("记录文件 URI 失败: {}", e);
+        }
+        file.remote_uri = Some(uri);
+        file.remote_uploaded_at = Some(Utc::now());
+    }
+    // 重复上传时沿用已有片段；保存失败不影响上传，后台任务会重新切分
+    if let Some(chunks) = chunks
+        && file.chunk_count.is_none()
+    {
+        match memory.replace_document_chunks(file.id, &chunks) {
+            Ok(_) => file.chunk_count = Some(chunks.len()),
+            Err(e) => eprintln!("保存文件 {} 的片段失败: {}", name, e),
+        }
+    }
+    Ok(file)
+}
+
+/// 读取文件库中的图片、PDF、音频作为对话上下文
+///
+/// 较小的文件以 base64 内联，较大的文件引用后端文件存储中的 URI（过期时重新上传）。
+/// 文本文件不在这里读取，而是切分后按问题检索片段（见 `documents::retrieve_chunks`）。
+pub async fn load_file_contexts(
+    memory: &ChatMemory,
+    provider: &dyn ChatProvider,
+    user_id: &str,
+    file_ids: &[i64],
+) -> Result<Vec<FileContext>, String> {
+    if file_ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let files = memory
+        .load_files(user_id, file_ids)
+        .map_err(|e| format!("读取文件失败: {}", e))?;
+
+    let mut contexts = Vec::with_capacity(files.len());
+    for (file, data) in files {
+        if file.mime_type == TEXT_MIME_TYPE {
+            continue;
+        }
+        let mut context = FileContext {
+            name: file.name.clone(),
+            mime_type: Some(file.mime_type.clone()),
+            ..FileContext::default()
+        };
+        if data.len() <= MAX_INLINE_BYTES {
+            context.data = Some(BASE64.encode(&data));
+        } else if is_remote_fresh(&file) {
+            context.file_uri = file.remote_uri.clone();
+        } else {
+            let uri = upload_remote(memory, provider, &file, data)
+                .await
+                .map_err(|e| e.message().to_string())?
+                .ok_or_else(|| format!("文件 {} 过大，当前模型后端无法读取", file.name))?;
+            context.file_uri = Some(uri);
+        }
+        contexts.push(context);
+    }
+    Ok(contexts)
+}
+
+/// 确定会话的文件上下文：指定了文件时替换会话保存的上下文，否则沿用已保存的
+pub fn conversation_files(
+    memory: &ChatMemory,
+    user_id: &str,
+    conversation_id: i64,
+    file_ids: &[i64],
+) -> Result<Vec<StoredFile>, String> {
+    let files = if file_ids.is_empty() {
+        memory.get_conversation_files(user_id, conversation_id)
+    } else {
+        memory.set_conversation_files(user_id, conversation_id, file_ids)
+    };
+    files.map_err(|e| format!("读取会话文件失败: {}", e))
+}
+
+/// 文件库中文件的对外信息
+pub fn file_info(file: &StoredFile) -> FileInfo {
+    FileInfo {
+        id: file.id,
+        name: file.name.clone(),
+        mime_type: file.mime_type.clone(),
+        size: file.size,
+        sha256: file.sha256.clone(),
+        created_at: file.created_at.to_rfc3339(),
+    }
+}
+
+/// 上传到后端文件存储并记录 URI，后端没有文件存储时返回 None
+async fn upload_remote(
+    memory: &ChatMemory,
+    provider: &dyn ChatProvider,
+    file: &StoredFile,
+    data: Vec<u8>,
+) -> Result<Option<String>, UploadError> {
+    let uri = provider
+        .upload_file(&file.name, &file.mime_type, data)
+        .await
+        .map_err(|e| UploadError::Failed(format!("上传文件 {} 失败: {}", file.name, e)))?;
+    if let Some(ref uri) = uri
+        && let Err(e) = memory.set_file_remote_uri(file.id, uri)
+    {
+        eprintln!("记录文件 URI 失败: {}", e);
+    }
+    Ok(uri)
+}
+
+fn is_remote_fresh(file: &StoredFile) -> bool {
+    file.remote_uri.is_some()
+        && file
+            .remote_uploaded_at
+            .is_some_and(|t| Utc::now() - t < Duration::hours(REMOTE_FILE_TTL_HOURS))
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::gemini::GeminiProvider;
+    use crate::services::ollama::OllamaProvider;
+    use crate::services::provider::ModelMap;
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify("a.txt", "你好".as_bytes()), Ok("text/plain"));
+        assert_eq!(classify("a.pdf", b"%PDF-1.7"), Ok("application/pdf"));
+        assert!(classify("a.gif", b"GIF89a").is_err());
+        assert!(classify("a.bin", b"\x00\x9f\x92\x96").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_store_and_load_file_contexts() {
+        let memory = ChatMemory::new(":memory:").unwrap();
+        // 小文件不会调用后端，不需要 API 密钥
+        let provider = GeminiProvider::new("http://127.0.0.1:9".to_string(), None);
+
+        let text = store_upload(&memory, &provider, "u", "a.txt", b"hello")
+            .await
+            .unwrap();
+        let image = store_upload(&memory, &provider, "u", "a.png", b"\x89PNG\r\n\x1a\n")
+            .await
+            .unwrap();
+        assert_eq!(text.sha256.len(), 64);
+        assert_eq!(text.chunk_count, Some(1));
+        assert_eq!(image.mime_type, "image/png");
+
+        let contexts = load_file_contexts(&memory, &provider, "u", &[image.id, text.id])
+            .await
+            .unwrap();
+        // 文本文件通过片段检索读取，这里只返回图片
+        assert_eq!(contexts.len(), 1);
+        assert_eq!(contexts[0].data.as_deref(), Some("iVBORw0KGgo="));
+
+        // 其他用户无法读取
+        let contexts = load_file_contexts(&memory, &provider, "other", &[image.id])
+            .await
+            .unwrap();
+        assert!(contexts.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_rejected_upload_is_not_stored() {
+        let memory = ChatMemory::new(":memory:").unwrap();
+        // Ollama 后端没有文件存储
+        let provider = OllamaProvider::new(
+            "http://127.0.0.1:9".to_string(),
+            ModelMap::new("llama3.1", ""),
+        );
+
+        let mut data = b"%PDF-1.7\n".to_vec();
+        data.resize(MAX_INLINE_BYTES + 1, b' ');
+        let result = store_upload(&memory, &provider, "u", "big.pdf", &data).await;
+        assert!(matches!(result, Err(UploadError::TooLarge(_))));
+        assert!(memory.list_files("u").unwrap().is_empty());
+    }
+}
@@ -1,31 +1,378 @@
+use async_trait::async_trait;
+use serde::Serialize;
+
+use super::provider::{
+    AttachmentSource, ChatPrompt, ChatProvider, ChatRequest, ChatResult, ChatRole, SseBuffer,
+    StreamAccumulator, StreamChunk, StreamDelta, ToolCall, read_text_stream,
+};
 use crate::models::gemini::{
-    Content, GeminiModel, GeminiRequest, GeminiResponse, GenerationConfig, Part,
+    Blob, Content, CountTokensResponse, FileData, FunctionCall, FunctionDeclaration,
+    FunctionResponse, GeminiModel, GeminiRequest, GeminiResponse, GenerationConfig, Part, Tool,
+    UploadFileResponse, UploadedFileInfo, UsageMetadata,
 };
+use crate::models::messages::TokenUsage;
+
+/// Gemini API 默认地址
+const DEFAULT_API_BASE: &str = "https://generativelanguage.googleapis.com/v1beta";
+
+/// 传递 API 密钥的请求头（不放在 URL 里，避免随请求错误信息泄露）
+pub const API_KEY_HEADER: &str = "x-goog-api-key";
 
-/// Gemini API 调用结果
-pub struct GeminiResult {
-    pub response: String,
-    pub thinking: Option<String>,
+/// 没有收到回复时的占位文本
+const EMPTY_REPLY: &str = "没有收到 Gemini 的回复";
+
+/// 等待上传的文件处理完成（PDF 等需要服务端处理）的最长轮询次数，每次间隔 1 秒
+const FILE_ACTIVE_POLLS: u32 = 30;
+
+/// Google Gemini 后端
+pub struct GeminiProvider {
+    base_url: String,
+    api_key: Option<String>,
 }
 
-/// 调用 Gemini API
-pub async fn call_gemini_api(
-    prompt: String,
-    api_key: &str,
-    model: GeminiModel,
-) -> Result<GeminiResult, String> {
-    let url = format!(
-        "https://generativelanguage.googleapis.com/v1beta/models/{}:generateContent?key={}",
-        model.api_name(),
-        api_key
-    );
+impl GeminiProvider {
+    pub fn new(base_url: String, api_key: Option<String>) -> Self {
+        Self { base_url, api_key }
+    }
 
-    let mut request_body = GeminiRequest {
-        contents: vec![Content {
-            parts: vec![Part { text: prompt }],
+    /// 从 GEMINI_API_KEY / GEMINI_API_BASE 环境变量创建
+    pub fn from_env() -> Self {
+        Self::new(
+            std::env::var("GEMINI_API_BASE").unwrap_or_else(|_| DEFAULT_API_BASE.to_string()),
+            std::env::var("GEMINI_API_KEY").ok(),
+        )
+    }
+
+    fn api_key(&self) -> Result<&str, String> {
+        self.api_key
+            .as_deref()
+            .ok_or_else(|| "未设置 GEMINI_API_KEY 环境变量".to_string())
+    }
+
+    async fn post(
+        &self,
+        method: &str,
+        model: GeminiModel,
+        body: &impl Serialize,
+    ) -> Result<reqwest::Response, String> {
+        let url = format!("{}/models/{}:{}", self.base_url, model.api_name(), method);
+
+        let client = reqwest::Client::new();
+        let response = client
+            .post(&url)
+            .header(API_KEY_HEADER, self.api_key()?)
+            .json(body)
+            .send()
+            .await
+            .map_err(|e| format!("发送请求失败: {}", e.without_url()))?;
+
+        if !response.status().is_success() {
+            let error_text = response
+                .text()
+                .await
+                .unwrap_or_else(|_| "未知错误".to_string());
+            return Err(format!("Gemini API 错误: {}", error_text));
+        }
+
+        Ok(response)
+    }
+
+    /// Files API 的上传地址：在 API 路径前加上 `/upload`
+    fn upload_url(&self) -> String {
+        let path_start = self
+            .base_url
+            .find("://")
+            .and_then(|scheme| {
+                self.base_url[scheme + 3..]
+                    .find('/')
+                    .map(|index| scheme + 3 + index)
+            })
+            .unwrap_or(self.base_url.len());
+        let (origin, path) = self.base_url.split_at(path_start);
+        format!("{}/upload{}/files", origin, path)
+    }
+
+    /// 轮询文件状态，直到处理完成
+    async fn wait_until_active(&self, mut file: UploadedFileInfo) -> Result<String, String> {
+        let client = reqwest::Client::new();
+        for _ in 0..FILE_ACTIVE_POLLS {
+            match file.state.as_deref() {
+                None | Some("ACTIVE") => return Ok(file.uri),
+                Some("FAILED") => return Err(format!("Gemini 处理文件 {} 失败", file.name)),
+                _ => {}
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            file = client
+                .get(format!("{}/{}", self.base_url, file.name))
+                .header(API_KEY_HEADER, self.api_key()?)
+                .send()
+                .await
+                .map_err(|e| format!("查询文件状态失败: {}", e.without_url()))?
+                .json()
+                .await
+                .map_err(|e| format!("解析文件状态失败: {}", e.without_url()))?;
+        }
+        Err(format!("等待 Gemini 处理文件 {} 超时", file.name))
+    }
+}
+
+#[async_trait]
+impl ChatProvider for GeminiProvider {
+    fn name(&self) -> &'static str {
+        "gemini"
+    }
+
+    fn model_name(&self, model: GeminiModel) -> String {
+        model.api_name().to_string()
+    }
+
+    fn display_name(&self, model: GeminiModel) -> String {
+        model.display_name().to_string()
+    }
+
+    async fn chat(&self, request: ChatRequest) -> Result<ChatResult, String> {
+        let body = build_request(request.prompt, request.model);
+        let response = self.post("generateContent", request.model, &body).await?;
+
+        let gemini_response: GeminiResponse = response
+            .json()
+            .await
+            .map_err(|e| format!("解析响应失败: {}", e.without_url()))?;
+
+        let usage = gemini_response.usage_metadata.map(token_usage);
+
+        // 解析响应，分离思考过程、最终回复和函数调用
+        let mut thinking = None;
+        let mut response_text = String::new();
+        let mut tool_calls = Vec::new();
+
+        if let Some(candidates) = gemini_response.candidates
+            && let Some(candidate) = candidates.first()
+        {
+            for part in &candidate.content.parts {
+                if let Some(text) = &part.text {
+                    if part.thought.unwrap_or(false) {
+                        // 这是思考过程
+                        thinking = Some(text.clone());
+                    } else {
+                        // 这是最终回复
+                        response_text = text.clone();
+                    }
+                }
+                if let Some(call) = &part.function_call {
+                    tool_calls.push(tool_call(call.clone()));
+                }
+            }
+        }
+
+        if response_text.is_empty() && tool_calls.is_empty() {
+            response_text = EMPTY_REPLY.to_string();
+        }
+
+        Ok(ChatResult {
+            response: response_text,
+            thinking,
+            usage,
+            tool_calls,
+        })
+    }
+
+    async fn chat_stream(
+        &self,
+        request: ChatRequest,
+        on_delta: &mut (dyn FnMut(StreamDelta) + Send),
+    ) -> Result<ChatResult, String> {
+        let body = build_request(request.prompt, request.model);
+        let response = self
+            .post("streamGenerateContent?alt=sse", request.model, &body)
+            .await?;
+
+        let mut sse = SseBuffer::default();
+        let mut result = StreamAccumulator::default();
+
+        read_text_stream(response, |text| {
+            for data in sse.push(text) {
+                result.push_chunk(parse_stream_chunk(&data)?, on_delta);
+            }
+            Ok(())
+        })
+        .await?;
+
+        for data in sse.finish() {
+            result.push_chunk(parse_stream_chunk(&data)?, on_delta);
+        }
+
+        Ok(result.finish(EMPTY_REPLY))
+    }
+
+    fn supports_tools(&self) -> bool {
+        true
+    }
+
+    async fn count_tokens(&self, request: &ChatRequest) -> Option<u64> {
+        // 通过 generateContentRequest 计数，系统指令也会被计入
+        let mut content_request =
+            serde_json::to_value(build_request(request.prompt.clone(), request.model)).ok()?;
+        content_request["model"] = format!("models/{}", request.model.api_name()).into();
+        let body = serde_json::json!({ "generateContentRequest": content_request });
+
+        let result = async {
+            let response = self.post("countTokens", request.model, &body).await?;
+            response
+                .json::<CountTokensResponse>()
+                .await
+                .map_err(|e| format!("解析响应失败: {}", e))
+        }
+        .await;
+
+        match result {
+            Ok(counted) => Some(counted.total_tokens),
+            Err(e) => {
+                eprintln!("Gemini countTokens 失败: {}", e);
+                None
+            }
+        }
+    }
+
+    /// 通过 Files API 的可续传协议上传：先创建上传会话，再一次性发送全部数据
+    async fn upload_file(
+        &self,
+        name: &str,
+        mime_type: &str,
+        data: Vec<u8>,
+    ) -> Result<Option<String>, String> {
+        let client = reqwest::Client::new();
+        let start = client
+            .post(self.upload_url())
+            .header(API_KEY_HEADER, self.api_key()?)
+            .header("X-Goog-Upload-Protocol", "resumable")
+            .header("X-Goog-Upload-Command", "start")
+            .header("X-Goog-Upload-Header-Content-Length", data.len())
+            .header("X-Goog-Upload-Header-Content-Type", mime_type)
+            .json(&serde_json::json!({ "file": { "display_name": name } }))
+            .send()
+            .await
+            .map_err(|e| format!("创建上传会话失败: {}", e.without_url()))?;
+        if !start.status().is_success() {
+            let error_text = start.text().await.unwrap_or_default();
+            return Err(format!("Gemini Files API 错误: {}", error_text));
+        }
+        let upload_url = start
+            .headers()
+            .get("x-goog-upload-url")
+            .and_then(|v| v.to_str().ok())
+            .ok_or_else(|| "Gemini Files API 没有返回上传地址".to_string())?
+            .to_string();
+
+        let response = client
+            .post(&upload_url)
+            .header("X-Goog-Upload-Offset", 0)
+            .header("X-Goog-Upload-Command", "upload, finalize")
+            .body(data)
+            .send()
+            .await
+            .map_err(|e| format!("上传文件失败: {}", e.without_url()))?;
+        if !response.status().is_success() {
+            let error_text = response.text().await.unwrap_or_default();
+            return Err(format!("Gemini Files API 错误: {}", error_text));
+        }
+        let uploaded: UploadFileResponse = response
+            .json()
+            .await
+            .map_err(|e| format!("解析上传结果失败: {}", e.without_url()))?;
+
+        self.wait_until_active(uploaded.file).await.map(Some)
+    }
+}
+
+/// 构建请求体，附件放在最后一条用户消息的文本之前，已完成的工具调用接在其后
+fn build_request(prompt: ChatPrompt, model: GeminiModel) -> GeminiRequest {
+    let mut contents: Vec<Content> = prompt
+        .turns
+        .into_iter()
+        .map(|turn| Content {
+            parts: vec![Part::Text(turn.content)],
+            role: Some(
+                match turn.role {
+                    ChatRole::User => "user",
+                    ChatRole::Model => "model",
+                }
+                .to_string(),
+            ),
+        })
+        .collect();
+
+    if let Some(last) = contents.last_mut() {
+        let attachments =
+            prompt
+                .attachments
+                .into_iter()
+                .map(|attachment| match attachment.source {
+                    AttachmentSource::Inline(data) => Part::InlineData(Blob {
+                        mime_type: attachment.mime_type,
+                        data,
+                    }),
+                    AttachmentSource::Uri(file_uri) => Part::FileData(FileData {
+                        mime_type: attachment.mime_type,
+                        file_uri,
+                    }),
+                });
+        last.parts.splice(0..0, attachments);
+    }
+
+    for round in prompt.tool_rounds {
+        let text = Some(round.text)
+            .filter(|text| !text.is_empty())
+            .map(Part::Text);
+        let calls = round.calls.into_iter().map(|call| {
+            Part::FunctionCall(FunctionCall {
+                name: call.name,
+                args: call.args,
+            })
+        });
+        contents.push(Content {
+            parts: text.into_iter().chain(calls).collect(),
+            role: Some("model".to_string()),
+        });
+        contents.push(Content {
+            parts: round
+                .results
+                .iter()
+                .map(|result| {
+                    Part::FunctionResponse(FunctionResponse {
+                        name: result.name.clone(),
+                        response: result.response(),
+                    })
+                })
+                .collect(),
             role: Some("user".to_string()),
-        }],
+        });
+    }
+
+    let tools = if prompt.tools.is_empty() {
+        Vec::new()
+    } else {
+        vec![Tool {
+            function_declarations: prompt
+                .tools
+                .into_iter()
+                .map(|tool| FunctionDeclaration {
+                    name: tool.name,
+                    description: tool.description,
+                    parameters: tool.parameters,
+                })
+                .collect(),
+        }]
+    };
+
+    let mut request_body = GeminiRequest {
+        contents,
+        system_instruction: prompt.system_instruction.map(|text| Content {
+            parts: vec![Part::Text(text)],
+            role: None,
+        }),
         generation_config: None,
+        tools,
     };
 
     // Pro 模型启用思考功能
@@ -36,53 +383,249 @@ pub async fn call_gemini_api(
         });
     }
 
-    let client = reqwest::Client::new();
-    let response = client
-        .post(&url)
-        .json(&request_body)
-        .send()
-        .await
-        .map_err(|e| format!("发送请求失败: {}", e))?;
+    request_body
+}
 
-    if !response.status().is_success() {
-        let error_text = response
-            .text()
-            .await
-            .unwrap_or_else(|_| "未知错误".to_string());
-        return Err(format!("Gemini API 错误: {}", error_text));
+fn tool_call(call: FunctionCall) -> ToolCall {
+    ToolCall {
+        name: call.name,
+        args: call.args,
     }
+}
 
-    let gemini_response: GeminiResponse = response
-        .json()
-        .await
-        .map_err(|e| format!("解析响应失败: {}", e))?;
+fn token_usage(metadata: UsageMetadata) -> TokenUsage {
+    TokenUsage {
+        prompt_tokens: metadata.prompt_token_count,
+        candidate_tokens: metadata.candidates_token_count,
+        thought_tokens: metadata.thoughts_token_count,
+    }
+}
 
-    // 解析响应，分离思考过程和最终回复
-    let mut thinking = None;
-    let mut response_text = String::new();
+/// 解析单个流式响应块中的增量内容和用量
+fn parse_stream_chunk(data: &str) -> Result<StreamChunk, String> {
+    let chunk: GeminiResponse =
+        serde_json::from_str(data).map_err(|e| format!("解析流式响应失败: {}", e))?;
 
-    if let Some(candidates) = gemini_response.candidates
-        && let Some(candidate) = candidates.first()
+    let mut deltas = Vec::new();
+    let mut tool_calls = Vec::new();
+    if let Some(candidates) = chunk.candidates
+        && let Some(candidate) = candidates.into_iter().next()
     {
-        for part in &candidate.content.parts {
-            if let Some(text) = &part.text {
+        for part in candidate.content.parts {
+            if let Some(text) = part.text {
                 if part.thought.unwrap_or(false) {
-                    // 这是思考过程
-                    thinking = Some(text.clone());
+                    deltas.push(StreamDelta::Thinking(text));
                 } else {
-                    // 这是最终回复
-                    response_text = text.clone();
+                    deltas.push(StreamDelta::Response(text));
                 }
             }
+            if let Some(call) = part.function_call {
+                tool_calls.push(tool_call(call));
+            }
         }
     }
 
-    if response_text.is_empty() {
-        response_text = "没有收到 Gemini 的回复".to_string();
+    Ok(StreamChunk {
+        deltas,
+        usage: chunk.usage_metadata.map(token_usage),
+        tool_calls,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::provider::test_support::mock_server;
+    use super::super::provider::{Attachment, ToolDeclaration, ToolResult, ToolRound};
+    use super::*;
+
+    #[test]
+    fn test_parse_stream_chunk() {
+        let data = r#"{"candidates":[{"content":{"parts":[{"text":"想一想","thought":true},{"text":"你好"}]}}]}"#;
+        let chunk = parse_stream_chunk(data).unwrap();
+        assert_eq!(chunk.usage, None);
+        assert_eq!(
+            chunk.deltas,
+            vec![
+                StreamDelta::Thinking("想一想".to_string()),
+                StreamDelta::Response("你好".to_string()),
+            ]
+        );
     }
 
-    Ok(GeminiResult {
-        response: response_text,
-        thinking,
-    })
+    #[test]
+    fn test_parse_function_call() {
+        let data = r#"{"candidates":[{"content":{"parts":[{"text":"我查一下"},{"functionCall":{"name":"search_memory","args":{"query":"数据库"}}}]}}]}"#;
+        let chunk = parse_stream_chunk(data).unwrap();
+        assert_eq!(
+            chunk.deltas,
+            vec![StreamDelta::Response("我查一下".to_string())]
+        );
+        assert_eq!(
+            chunk.tool_calls,
+            vec![ToolCall {
+                name: "search_memory".to_string(),
+                args: serde_json::json!({ "query": "数据库" }),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_build_request_with_tools() {
+        let mut prompt = ChatPrompt::from_history(None, &[], "上次说的数据库是哪个？");
+        prompt.tools = vec![ToolDeclaration {
+            name: "search_memory".to_string(),
+            description: "检索历史消息".to_string(),
+            parameters: serde_json::json!({ "type": "object", "properties": {} }),
+        }];
+        prompt.tool_rounds = vec![ToolRound {
+            text: String::new(),
+            calls: vec![ToolCall {
+                name: "search_memory".to_string(),
+                args: serde_json::json!({ "query": "数据库" }),
+            }],
+            results: vec![ToolResult {
+                name: "search_memory".to_string(),
+                output: Ok(serde_json::json!(["PostgreSQL"])),
+            }],
+        }];
+
+        let json = serde_json::to_value(build_request(prompt, GeminiModel::Flash)).unwrap();
+        assert_eq!(
+            json["tools"][0]["function_declarations"][0]["name"],
+            "search_memory"
+        );
+        let contents = json["contents"].as_array().unwrap();
+        assert_eq!(contents.len(), 3);
+        assert_eq!(contents[1]["role"], "model");
+        assert_eq!(
+            contents[1]["parts"][0]["function_call"]["args"]["query"],
+            "数据库"
+        );
+        assert_eq!(contents[2]["role"], "user");
+        assert_eq!(
+            contents[2]["parts"][0]["function_response"]["response"]["result"][0],
+            "PostgreSQL"
+        );
+
+        // 没有工具时不发送 tools 字段
+        let prompt = ChatPrompt::from_history(None, &[], "你好");
+        let json = serde_json::to_value(build_request(prompt, GeminiModel::Flash)).unwrap();
+        assert!(json.get("tools").is_none());
+    }
+
+    #[test]
+    fn test_build_request() {
+        let prompt = ChatPrompt::from_history(Some("系统".to_string()), &[], "你好");
+        let request = build_request(prompt, GeminiModel::Flash);
+        let json = serde_json::to_value(&request).unwrap();
+        assert_eq!(json["system_instruction"]["parts"][0]["text"], "系统");
+        assert_eq!(json["contents"][0]["role"], "user");
+        assert_eq!(json["contents"][0]["parts"][0]["text"], "你好");
+    }
+
+    #[test]
+    fn test_build_request_with_attachments() {
+        let mut prompt = ChatPrompt::from_history(None, &[], "图里是什么？");
+        prompt.attachments = vec![
+            Attachment {
+                name: "a.png".to_string(),
+                mime_type: "image/png".to_string(),
+                source: AttachmentSource::Inline("iVBORw0=".to_string()),
+            },
+            Attachment {
+                name: "spec.pdf".to_string(),
+                mime_type: "application/pdf".to_string(),
+                source: AttachmentSource::Uri("https://example.com/files/1".to_string()),
+            },
+        ];
+        let json = serde_json::to_value(build_request(prompt, GeminiModel::Flash)).unwrap();
+        let parts = &json["contents"][0]["parts"];
+        assert_eq!(parts[0]["inline_data"]["mime_type"], "image/png");
+        assert_eq!(parts[0]["inline_data"]["data"], "iVBORw0=");
+        assert_eq!(
+            parts[1]["file_data"]["file_uri"],
+            "https://example.com/files/1"
+        );
+        assert_eq!(parts[2]["text"], "图里是什么？");
+    }
+
+    #[test]
+    fn test_upload_url() {
+        let provider = GeminiProvider::new(DEFAULT_API_BASE.to_string(), None);
+        assert_eq!(
+            provider.upload_url(),
+            "https://generativelanguage.googleapis.com/upload/v1beta/files"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chat_stream_with_mock_server() {
+        let events = [
+            r#"{"candidates":[{"content":{"parts":[{"text":"思考中","thought":true}]}}]}"#,
+            r#"{"candidates":[{"content":{"parts":[{"text":"你好，"}]}}]}"#,
+            r#"{"candidates":[{"content":{"parts":[{"text":"世界"}]}}],"usageMetadata":{"promptTokenCount":12,"candidatesTokenCount":4,"thoughtsTokenCount":3,"totalTokenCount":19}}"#,
+        ];
+        let body: String = events
+            .iter()
+            .map(|e| format!("data: {}\r\n\r\n", e))
+            .collect();
+        let (base_url, _) = mock_server("text/event-stream", body).await;
+
+        let provider = GeminiProvider::new(base_url, Some("test-key".to_string()));
+        let mut deltas = Vec::new();
+        let result = provider
+            .chat_stream(
+                ChatRequest {
+                    prompt: ChatPrompt::from_history(None, &[], "hi"),
+                    model: GeminiModel::Pro25,
+                },
+                &mut |delta| deltas.push(delta),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(result.response, "你好，世界");
+        assert_eq!(result.thinking.as_deref(), Some("思考中"));
+        assert_eq!(
+            result.usage,
+            Some(TokenUsage {
+                prompt_tokens: 12,
+                candidate_tokens: 4,
+                thought_tokens: 3,
+            })
+        );
+        assert_eq!(deltas.len(), 3);
+        assert_eq!(deltas[1], StreamDelta::Response("你好，".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_count_tokens_with_mock_server() {
+        let (base_url, request_body) =
+            mock_server("application/json", r#"{"totalTokens":42}"#.to_string()).await;
+
+        let provider = GeminiProvider::new(base_url, Some("test-key".to_string()));
+        let counted = provider
+            .count_tokens(&ChatRequest {
+                prompt: ChatPrompt::from_history(Some("系统".to_string()), &[], "hi"),
+                model: GeminiModel::Flash,
+            })
+            .await;
+        assert_eq!(counted, Some(42));
+
+        let sent = request_body.await.unwrap();
+        assert!(sent.contains(r#""model":"models/gemini-2.0-flash""#));
+        assert!(sent.contains("系统"));
+    }
+
+    #[tokio::test]
+    async fn test_missing_api_key() {
+        let provider = GeminiProvider::new(DEFAULT_API_BASE.to_string(), None);
+        let result = provider
+            .chat(ChatRequest {
+                prompt: ChatPrompt::from_history(None, &[], "hi"),
+                model: GeminiModel::Flash,
+            })
+            .await;
+        assert!(result.unwrap_err().contains("GEMINI_API_KEY"));
+    }
 }
@@ -0,0 +1,263 @@
+use chrono::{DateTime, NaiveDate, Utc};
+
+use super::embedding::{EmbeddingProvider, EmbeddingTask};
+use super::memory::{ChatMemory, HistoryFilter, HistoryPage};
+use crate::models::messages::{
+    GetHistoryMessage, HistoryItem, HistoryMessage, SearchHistoryMessage, SearchHitItem,
+    SearchResultsMessage,
+};
+
+/// 搜索结果默认每页条数
+const DEFAULT_PAGE_SIZE: usize = 20;
+/// 搜索结果每页最多条数
+const MAX_PAGE_SIZE: usize = 100;
+/// 历史记录默认每页条数
+const DEFAULT_HISTORY_PAGE_SIZE: usize = 50;
+/// 历史记录每页最多条数
+const MAX_HISTORY_PAGE_SIZE: usize = 200;
+
+/// 加载会话的一页历史记录（WebSocket 和 REST 接口共用）
+pub fn load_history_page(
+    memory: &ChatMemory,
+    user_id: &str,
+    conversation_id: i64,
+    request: &GetHistoryMessage,
+) -> Result<HistoryMessage, String> {
+    let limit = request
+        .limit
+        .unwrap_or(DEFAULT_HISTORY_PAGE_SIZE)
+        .clamp(1, MAX_HISTORY_PAGE_SIZE);
+
+    let (messages, has_more) = memory
+        .get_messages_page(user_id, conversation_id, request.before_id, limit)
+        .map_err(|e| format!("获取历史记录失败: {}", e))?;
+    let ids: Vec<i64> = messages.iter().map(|msg| msg.id).collect();
+    let mut tool_calls = memory
+        .get_tool_calls(&ids)
+        .map_err(|e| format!("获取工具调用记录失败: {}", e))?;
+
+    Ok(HistoryMessage {
+        conversation_id: Some(conversation_id),
+        before_id: request.before_id,
+        messages: messages
+            .into_iter()
+            .map(|msg| HistoryItem {
+                id: msg.id,
+                role: msg.role,
+                content: msg.content,
+                model: msg.model,
+                timestamp: msg.created_at.to_rfc3339(),
+                tool_calls: tool_calls.remove(&msg.id).unwrap_or_default(),
+            })
+            .collect(),
+        has_more,
+    })
+}
+
+/// 检索用户的历史消息（WebSocket 和 REST 接口共用）
+pub async fn search_history(
+    memory: &ChatMemory,
+    embedder: &dyn EmbeddingProvider,
+    user_id: &str,
+    request: SearchHistoryMessage,
+) -> Result<SearchResultsMessage, String> {
+    let query = request.query.trim().to_string();
+    let offset = request.offset;
+    let page = search_records(memory, embedder, user_id, request).await?;
+
+    let hits: Vec<SearchHitItem> = page
+        .hits
+        .into_iter()
+        .map(|hit| SearchHitItem {
+            message_id: hit.record.id,
+            conversation_id: hit.record.conversation_id,
+            role: hit.record.role,
+            model: hit.record.model,
+            snippet: hit.snippet,
+            timestamp: hit.record.created_at.to_rfc3339(),
+            similarity: hit.similarity,
+        })
+        .collect();
+
+    Ok(SearchResultsMessage {
+        query,
+        has_more: offset + hits.len() < page.total,
+        hits,
+        total: page.total,
+        offset,
+    })
+}
+
+/// 校验筛选条件并检索一页历史消息（搜索接口和 `search_memory` 工具共用）
+pub async fn search_records(
+    memory: &ChatMemory,
+    embedder: &dyn EmbeddingProvider,
+    user_id: &str,
+    request: SearchHistoryMessage,
+) -> Result<HistoryPage, String> {
+    let query = request.query.trim().to_string();
+    if query.is_empty() {
+        return Err("搜索内容不能为空".to_string());
+    }
+
+    if let Some(ref role) = request.role
+        && role != "user"
+        && role != "model"
+    {
+        return Err(format!("无效的角色: {}", role));
+    }
+
+    let filter = HistoryFilter {
+        query: query.clone(),
+        conversation_id: request.conversation_id,
+        from: request
+            .from
+            .as_deref()
+            .map(|v| parse_date(v, false))
+            .transpose()?,
+        to: request
+            .to
+            .as_deref()
+            .map(|v| parse_date(v, true))
+            .transpose()?,
+        role: request.role,
+        model: request.model,
+    };
+    let limit = request
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    // 重排需要查询嵌入，生成失败时退回纯关键词排序
+    let query_embedding = if request.rerank {
+        embedder.embed(&query, EmbeddingTask::Query).await.ok()
+    } else {
+        None
+    };
+    let embedding_model = embedder.model_id();
+    let rerank = query_embedding
+        .as_deref()
+        .map(|embedding| (embedding, embedding_model.as_str()));
+
+    memory
+        .search_history(user_id, &filter, rerank, request.offset, limit)
+        .map_err(|e| format!("搜索历史记录失败: {}", e))
+}
+
+/// 解析 RFC 3339 时间或 `YYYY-MM-DD` 日期（日期作为结束时间时取当天末尾）
+fn parse_date(value: &str, end_of_day: bool) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|_| format!("无效的日期: {}", value))?;
+    let time = if end_of_day {
+        date.and_hms_milli_opt(23, 59, 59, 999)
+    } else {
+        date.and_hms_opt(0, 0, 0)
+    };
+    Ok(time.unwrap_or_default().and_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::embedding::LocalEmbedding;
+    use super::*;
+
+    fn request(query: &str) -> SearchHistoryMessage {
+        SearchHistoryMessage {
+            query: query.to_string(),
+            conversation_id: None,
+            from: None,
+            to: None,
+            role: None,
+            model: None,
+            offset: 0,
+            limit: None,
+            rerank: false,
+        }
+    }
+
+    #[test]
+    fn test_get_history_message_is_optional() {
+        use crate::models::messages::WsMessage;
+
+        let msg: WsMessage = serde_json::from_str(r#"{"type":"get_history"}"#).unwrap();
+        assert!(matches!(msg, WsMessage::GetHistory(None)));
+
+        let msg: WsMessage =
+            serde_json::from_str(r#"{"type":"get_history","data":{"before_id":10,"limit":5}}"#)
+                .unwrap();
+        let WsMessage::GetHistory(Some(request)) = msg else {
+            panic!("解析失败");
+        };
+        assert_eq!(request.before_id, Some(10));
+        assert_eq!(request.limit, Some(5));
+    }
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(
+            parse_date("2024-05-01", false).unwrap().to_rfc3339(),
+            "2024-05-01T00:00:00+00:00"
+        );
+        assert_eq!(
+            parse_date("2024-05-01", true).unwrap().to_rfc3339(),
+            "2024-05-01T23:59:59.999+00:00"
+        );
+        assert!(parse_date("2024-05-01T08:00:00+08:00", false).is_ok());
+        assert!(parse_date("昨天", false).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_search_history() {
+        let memory = ChatMemory::new(":memory:").unwrap();
+        let embedder = LocalEmbedding::new(64);
+        let conversation = memory.create_conversation("u", "测试", None).unwrap();
+        for i in 0..5 {
+            memory
+                .add_message(
+                    "u",
+                    conversation.id,
+                    if i % 2 == 0 { "user" } else { "model" },
+                    &format!("第 {} 次遇到 <E0382> 错误", i),
+                    Some("flash"),
+                )
+                .unwrap();
+        }
+
+        let mut first = request("E0382");
+        first.limit = Some(2);
+        let page = search_history(&memory, &embedder, "u", first)
+            .await
+            .unwrap();
+        assert_eq!(page.total, 5);
+        assert_eq!(page.hits.len(), 2);
+        assert!(page.has_more);
+        assert!(page.hits[0].snippet.contains("&lt;<mark>E0382</mark>&gt;"));
+
+        let mut models_only = request("E0382");
+        models_only.role = Some("model".to_string());
+        models_only.offset = 1;
+        let page = search_history(&memory, &embedder, "u", models_only)
+            .await
+            .unwrap();
+        assert_eq!(page.total, 2);
+        assert_eq!(page.hits.len(), 1);
+        assert!(!page.has_more);
+
+        let mut future = request("E0382");
+        future.from = Some("2999-01-01".to_string());
+        let page = search_history(&memory, &embedder, "u", future)
+            .await
+            .unwrap();
+        assert_eq!(page.total, 0);
+
+        assert!(
+            search_history(&memory, &embedder, "u", request(" "))
+                .await
+                .is_err()
+        );
+    }
+}
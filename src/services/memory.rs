@@ -1,33 +1,280 @@
 use chrono::{DateTime, Utc};
-use rusqlite::{Connection, Result, params};
+use rusqlite::{Connection, OptionalExtension, Result, Row, params};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::Path;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex, RwLock};
 
-use super::embedding::{bytes_to_embedding, cosine_similarity, embedding_to_bytes};
+use super::ann::HnswIndex;
+use super::budget::estimate_tokens;
+use super::documents::TextChunk;
+use super::embedding::{bytes_to_embedding, embedding_to_bytes};
+use crate::models::messages::{TokenUsage, ToolCallLog};
 
 /// 聊天消息记录（带嵌入向量）
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatRecord {
     pub id: i64,
     pub user_id: String, // 用户 UUID
-    pub role: String,    // "user" 或 "model"
+    pub conversation_id: Option<i64>,
+    pub role: String, // "user" 或 "model"
     pub content: String,
     pub summary: Option<String>, // 对话摘要（用于长对话压缩）
     pub model: Option<String>,
     pub created_at: DateTime<Utc>,
 }
 
+/// 会话（一个用户可以有多个独立的对话）
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Conversation {
+    pub id: i64,
+    pub user_id: String,
+    pub title: String,
+    pub model: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// 注册用户
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    pub id: String,
+    pub username: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// 用户创建的 API 令牌（只保存哈希）
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub id: i64,
+    pub user_id: String,
+    pub name: String,
+    /// 令牌开头几位，用于在列表中辨认
+    pub prefix: String,
+    /// 逗号分隔的权限范围
+    pub scopes: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+/// 用户上传并保存在服务端的文件（不含内容）
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredFile {
+    pub id: i64,
+    pub user_id: String,
+    pub name: String,
+    pub mime_type: String,
+    pub size: u64,
+    /// 内容的 SHA-256（十六进制），同一用户重复上传相同内容时复用已有记录
+    pub sha256: String,
+    /// 上传到模型后端文件存储（Gemini Files API）后的 URI
+    pub remote_uri: Option<String>,
+    pub remote_uploaded_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    /// 文本文件切分出的片段数，尚未切分时为 None
+    pub chunk_count: Option<usize>,
+}
+
+/// 等待生成嵌入的文件片段
+#[derive(Debug, Clone)]
+pub struct PendingChunk {
+    pub id: i64,
+    pub file_name: String,
+    pub content: String,
+}
+
+/// 文本文件切分出的片段及其嵌入
+#[derive(Debug, Clone)]
+pub struct DocumentChunk {
+    pub file_id: i64,
+    pub file_name: String,
+    pub chunk: TextChunk,
+    pub embedding: Option<Vec<f32>>,
+    /// 生成嵌入的来源，嵌入生成失败时为 None
+    pub embedding_model: Option<String>,
+}
+
+/// 按用户、日期和模型聚合的 token 用量
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsageRow {
+    pub user_id: String,
+    pub username: Option<String>,
+    /// UTC 日期（YYYY-MM-DD）
+    pub day: String,
+    pub model: Option<String>,
+    /// 记录了用量的回复数量（含不保存消息的模型调用）
+    pub replies: u64,
+    pub usage: TokenUsage,
+}
+
 /// 带相似度的检索结果
 #[derive(Debug, Clone)]
 pub struct RetrievedMessage {
     pub record: ChatRecord,
-    pub similarity: f32,
+    /// 向量相似度（没有可比较的嵌入时为 None）
+    pub similarity: Option<f32>,
+    /// 排序分数（纯向量检索时等于相似度，混合检索时为 RRF 分数）
+    pub score: f32,
+}
+
+/// 历史消息全文检索的筛选条件
+#[derive(Debug, Clone, Default)]
+pub struct HistoryFilter {
+    pub query: String,
+    pub conversation_id: Option<i64>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub role: Option<String>,
+    pub model: Option<String>,
+}
+
+/// 历史检索命中的消息
+#[derive(Debug, Clone)]
+pub struct HistoryHit {
+    pub record: ChatRecord,
+    /// 已做 HTML 转义、命中部分用 `<mark>` 包裹的片段
+    pub snippet: String,
+    /// 重排时的向量相似度
+    pub similarity: Option<f32>,
+}
+
+/// 一页历史检索结果
+#[derive(Debug, Clone, Default)]
+pub struct HistoryPage {
+    pub hits: Vec<HistoryHit>,
+    /// 符合条件的总数
+    pub total: usize,
+}
+
+/// 查询 ChatRecord 时使用的列（顺序与 `record_from_row` 对应）
+const RECORD_COLUMNS: &str =
+    "id, user_id, conversation_id, role, content, summary, model, created_at";
+
+/// 查询 Conversation 时使用的列（顺序与 `conversation_from_row` 对应）
+const CONVERSATION_COLUMNS: &str = "id, user_id, title, model, created_at, updated_at";
+
+/// 查询 User 时使用的列（顺序与 `user_from_row` 对应）
+const USER_COLUMNS: &str = "id, username, created_at";
+
+/// 查询 ApiToken 时使用的列（顺序与 `api_token_from_row` 对应）
+const API_TOKEN_COLUMNS: &str =
+    "id, user_id, name, prefix, scopes, created_at, expires_at, last_used_at";
+
+/// 查询 StoredFile 时使用的列（顺序与 `file_from_row` 对应）
+const FILE_COLUMNS: &str = "id, user_id, name, mime_type, size, sha256, remote_uri, remote_uploaded_at, created_at, chunk_count";
+
+/// 记录嵌入来源之前写入的向量所属的模型
+const LEGACY_EMBEDDING_MODEL: &str = "gemini/text-embedding-004";
+
+/// RRF 融合常数（排名越靠后贡献越小）
+const RRF_K: f32 = 60.0;
+
+/// 混合检索时每路召回的候选数量倍数
+const HYBRID_CANDIDATE_FACTOR: usize = 4;
+
+/// 关键词查询最多使用的词数
+const MAX_KEYWORD_TERMS: usize = 32;
+
+/// 历史检索重排时最多取的候选数量
+const MAX_RERANK_CANDIDATES: usize = 200;
+
+/// 历史检索片段的长度（trigram 词元数）
+const SNIPPET_TOKENS: usize = 32;
+
+/// FTS 片段中的高亮标记（Unicode 私用区字符，不会出现在正常文本中）
+const HIGHLIGHT_START: char = '\u{e000}';
+const HIGHLIGHT_END: char = '\u{e001}';
+
+fn parse_timestamp(value: &str) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|dt| dt.with_timezone(&Utc))
+        .unwrap_or_else(|_| Utc::now())
+}
+
+fn record_from_row(row: &Row) -> Result<ChatRecord> {
+    let created_at_str: String = row.get(7)?;
+    Ok(ChatRecord {
+        id: row.get(0)?,
+        user_id: row.get(1)?,
+        conversation_id: row.get(2)?,
+        role: row.get(3)?,
+        content: row.get(4)?,
+        summary: row.get(5)?,
+        model: row.get(6)?,
+        created_at: parse_timestamp(&created_at_str),
+    })
+}
+
+fn conversation_from_row(row: &Row) -> Result<Conversation> {
+    let created_at_str: String = row.get(4)?;
+    let updated_at_str: String = row.get(5)?;
+    Ok(Conversation {
+        id: row.get(0)?,
+        user_id: row.get(1)?,
+        title: row.get(2)?,
+        model: row.get(3)?,
+        created_at: parse_timestamp(&created_at_str),
+        updated_at: parse_timestamp(&updated_at_str),
+    })
+}
+
+fn user_from_row(row: &Row) -> Result<User> {
+    let created_at_str: String = row.get(2)?;
+    Ok(User {
+        id: row.get(0)?,
+        username: row.get(1)?,
+        created_at: parse_timestamp(&created_at_str),
+    })
+}
+
+fn api_token_from_row(row: &Row) -> Result<ApiToken> {
+    let created_at_str: String = row.get(5)?;
+    let expires_at_str: Option<String> = row.get(6)?;
+    let last_used_at_str: Option<String> = row.get(7)?;
+    Ok(ApiToken {
+        id: row.get(0)?,
+        user_id: row.get(1)?,
+        name: row.get(2)?,
+        prefix: row.get(3)?,
+        scopes: row.get(4)?,
+        created_at: parse_timestamp(&created_at_str),
+        expires_at: expires_at_str.as_deref().map(parse_timestamp),
+        last_used_at: last_used_at_str.as_deref().map(parse_timestamp),
+    })
+}
+
+fn file_from_row(row: &Row) -> Result<StoredFile> {
+    let remote_uploaded_at_str: Option<String> = row.get(7)?;
+    let created_at_str: String = row.get(8)?;
+    Ok(StoredFile {
+        id: row.get(0)?,
+        user_id: row.get(1)?,
+        name: row.get(2)?,
+        mime_type: row.get(3)?,
+        size: row.get::<_, i64>(4)? as u64,
+        sha256: row.get(5)?,
+        remote_uri: row.get(6)?,
+        remote_uploaded_at: remote_uploaded_at_str.as_deref().map(parse_timestamp),
+        created_at: parse_timestamp(&created_at_str),
+        chunk_count: row.get::<_, Option<i64>>(9)?.map(|n| n as usize),
+    })
+}
+
+/// 单个用户的向量索引（只包含同一来源、同一维度的嵌入）
+struct UserIndex {
+    embedding_model: String,
+    dimension: usize,
+    hnsw: HnswIndex,
 }
 
 /// 聊天记忆数据库（使用向量嵌入）
 pub struct ChatMemory {
     conn: Mutex<Connection>,
+    /// 按用户懒加载的近似最近邻索引
+    ///
+    /// 加锁顺序固定为先 `indexes` 后 `conn`，持有 `conn` 时不要获取 `indexes`
+    indexes: Mutex<HashMap<String, Arc<RwLock<UserIndex>>>>,
 }
 
 impl ChatMemory {
@@ -50,21 +297,794 @@ impl ChatMemory {
             [],
         )?;
 
+        // 创建会话表
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS conversations (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id TEXT NOT NULL,
+                title TEXT NOT NULL,
+                model TEXT,
+                created_at TEXT NOT NULL,
+                updated_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        // 创建用户表（用户 ID 与消息、会话表中的 user_id 对应）
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS users (
+                id TEXT PRIMARY KEY,
+                username TEXT NOT NULL UNIQUE COLLATE NOCASE,
+                password_hash TEXT NOT NULL,
+                created_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        // 创建 API 令牌表（只保存令牌的 SHA-256 哈希）
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS api_tokens (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id TEXT NOT NULL,
+                name TEXT NOT NULL,
+                token_hash TEXT NOT NULL UNIQUE,
+                prefix TEXT NOT NULL,
+                scopes TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                expires_at TEXT,
+                last_used_at TEXT
+            )",
+            [],
+        )?;
+
+        // 创建每日用量表（按用户或 IP 统计请求数和 token 数，用于每日配额）
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS daily_usage (
+                subject TEXT NOT NULL,
+                day TEXT NOT NULL,
+                requests INTEGER NOT NULL DEFAULT 0,
+                tokens INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (subject, day)
+            )",
+            [],
+        )?;
+
+        // 不保存为消息的模型调用（如 /v1/chat/completions）的 token 用量
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS usage_records (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id TEXT NOT NULL,
+                model TEXT,
+                prompt_tokens INTEGER NOT NULL,
+                candidate_tokens INTEGER NOT NULL,
+                thought_tokens INTEGER NOT NULL,
+                created_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        // 创建文件表（上传的文件内容保存在数据库中，同一用户按内容哈希去重）
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS files (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id TEXT NOT NULL,
+                name TEXT NOT NULL,
+                mime_type TEXT NOT NULL,
+                size INTEGER NOT NULL,
+                sha256 TEXT NOT NULL,
+                data BLOB NOT NULL,
+                remote_uri TEXT,
+                remote_uploaded_at TEXT,
+                created_at TEXT NOT NULL,
+                UNIQUE (user_id, sha256)
+            )",
+            [],
+        )?;
+
+        // 会话附加的文件上下文
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS conversation_files (
+                conversation_id INTEGER NOT NULL,
+                file_id INTEGER NOT NULL,
+                PRIMARY KEY (conversation_id, file_id)
+            )",
+            [],
+        )?;
+
+        // 文本文件切分后的片段及其嵌入，用于按问题检索文件内容
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS document_chunks (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                file_id INTEGER NOT NULL,
+                chunk_index INTEGER NOT NULL,
+                start_line INTEGER NOT NULL,
+                end_line INTEGER NOT NULL,
+                content TEXT NOT NULL,
+                embedding BLOB,
+                embedding_model TEXT,
+                UNIQUE (file_id, chunk_index)
+            )",
+            [],
+        )?;
+
+        // 生成回复时执行的工具调用（参数和结果为 JSON 文本）
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS tool_calls (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                message_id INTEGER NOT NULL,
+                call_index INTEGER NOT NULL,
+                name TEXT NOT NULL,
+                args TEXT NOT NULL,
+                result TEXT,
+                error TEXT,
+                created_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        // 旧数据库的消息表没有 conversation_id 列
+        if !has_column(&conn, "messages", "conversation_id")? {
+            conn.execute(
+                "ALTER TABLE messages ADD COLUMN conversation_id INTEGER",
+                [],
+            )?;
+        }
+
+        // 记录每条嵌入的来源和维度，切换嵌入后端后旧向量不会混入检索
+        if !has_column(&conn, "messages", "embedding_model")? {
+            conn.execute("ALTER TABLE messages ADD COLUMN embedding_model TEXT", [])?;
+            conn.execute("ALTER TABLE messages ADD COLUMN embedding_dim INTEGER", [])?;
+            // 此前的嵌入都来自 Gemini text-embedding-004
+            conn.execute(
+                "UPDATE messages SET embedding_model = ?1, embedding_dim = length(embedding) / 4
+                 WHERE embedding IS NOT NULL",
+                [LEGACY_EMBEDDING_MODEL],
+            )?;
+        }
+
+        // 模型回复的 token 用量（旧消息和不提供用量的后端为 NULL）
+        if !has_column(&conn, "messages", "prompt_tokens")? {
+            conn.execute("ALTER TABLE messages ADD COLUMN prompt_tokens INTEGER", [])?;
+            conn.execute(
+                "ALTER TABLE messages ADD COLUMN candidate_tokens INTEGER",
+                [],
+            )?;
+            conn.execute("ALTER TABLE messages ADD COLUMN thought_tokens INTEGER", [])?;
+        }
+
+        // 文本文件的切分状态（NULL 表示尚未切分，0 表示没有内容）和片段嵌入失败的次数
+        if !has_column(&conn, "files", "chunk_count")? {
+            conn.execute("ALTER TABLE files ADD COLUMN chunk_count INTEGER", [])?;
+            conn.execute(
+                "UPDATE files SET chunk_count =
+                    (SELECT COUNT(*) FROM document_chunks c WHERE c.file_id = files.id)
+                 WHERE EXISTS (SELECT 1 FROM document_chunks c WHERE c.file_id = files.id)",
+                [],
+            )?;
+        }
+        if !has_column(&conn, "document_chunks", "embed_attempts")? {
+            conn.execute(
+                "ALTER TABLE document_chunks ADD COLUMN embed_attempts INTEGER NOT NULL DEFAULT 0",
+                [],
+            )?;
+        }
+
+        // 生成摘要失败的次数，达到上限后不再尝试
+        if !has_column(&conn, "messages", "summary_attempts")? {
+            conn.execute(
+                "ALTER TABLE messages ADD COLUMN summary_attempts INTEGER NOT NULL DEFAULT 0",
+                [],
+            )?;
+        }
+
         // 创建索引
         conn.execute(
             "CREATE INDEX IF NOT EXISTS idx_messages_user_created ON messages(user_id, created_at DESC)",
             [],
         )?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_messages_conversation ON messages(conversation_id, id)",
+            [],
+        )?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_conversations_user_updated ON conversations(user_id, updated_at DESC)",
+            [],
+        )?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_tool_calls_message ON tool_calls(message_id, call_index)",
+            [],
+        )?;
+
+        migrate_orphan_messages(&conn)?;
+        create_fts_index(&conn)?;
 
         Ok(Self {
             conn: Mutex::new(conn),
+            indexes: Mutex::new(HashMap::new()),
         })
     }
 
+    /// 创建用户，用户名已被占用时返回 None
+    pub fn create_user(
+        &self,
+        id: &str,
+        username: &str,
+        password_hash: &str,
+    ) -> Result<Option<User>> {
+        let conn = self.conn.lock().unwrap();
+        let inserted = conn.execute(
+            "INSERT OR IGNORE INTO users (id, username, password_hash, created_at) VALUES (?1, ?2, ?3, ?4)",
+            params![id, username, password_hash, Utc::now().to_rfc3339()],
+        )?;
+        if inserted == 0 {
+            return Ok(None);
+        }
+
+        conn.query_row(
+            &format!("SELECT {} FROM users WHERE id = ?1", USER_COLUMNS),
+            [id],
+            user_from_row,
+        )
+        .map(Some)
+    }
+
+    /// 按 ID 获取用户
+    pub fn get_user(&self, id: &str) -> Result<Option<User>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            &format!("SELECT {} FROM users WHERE id = ?1", USER_COLUMNS),
+            [id],
+            user_from_row,
+        )
+        .optional()
+    }
+
+    /// 按用户名（不区分大小写）获取用户及其密码哈希
+    pub fn get_user_by_username(&self, username: &str) -> Result<Option<(User, String)>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            &format!(
+                "SELECT {}, password_hash FROM users WHERE username = ?1",
+                USER_COLUMNS
+            ),
+            [username],
+            |row| Ok((user_from_row(row)?, row.get(3)?)),
+        )
+        .optional()
+    }
+
+    /// 保存新建的 API 令牌
+    pub fn create_api_token(
+        &self,
+        user_id: &str,
+        name: &str,
+        token_hash: &str,
+        prefix: &str,
+        scopes: &str,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<ApiToken> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO api_tokens (user_id, name, token_hash, prefix, scopes, created_at, expires_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                user_id,
+                name,
+                token_hash,
+                prefix,
+                scopes,
+                Utc::now().to_rfc3339(),
+                expires_at.map(|t| t.to_rfc3339())
+            ],
+        )?;
+
+        let id = conn.last_insert_rowid();
+        conn.query_row(
+            &format!("SELECT {} FROM api_tokens WHERE id = ?1", API_TOKEN_COLUMNS),
+            [id],
+            api_token_from_row,
+        )
+    }
+
+    /// 获取用户的所有 API 令牌（按创建时间倒序）
+    pub fn list_api_tokens(&self, user_id: &str) -> Result<Vec<ApiToken>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM api_tokens WHERE user_id = ?1 ORDER BY id DESC",
+            API_TOKEN_COLUMNS
+        ))?;
+        stmt.query_map([user_id], api_token_from_row)?.collect()
+    }
+
+    /// 按哈希查找 API 令牌，找到时记录最近使用时间
+    pub fn use_api_token(&self, token_hash: &str) -> Result<Option<ApiToken>> {
+        let conn = self.conn.lock().unwrap();
+        let token = conn
+            .query_row(
+                &format!(
+                    "SELECT {} FROM api_tokens WHERE token_hash = ?1",
+                    API_TOKEN_COLUMNS
+                ),
+                [token_hash],
+                api_token_from_row,
+            )
+            .optional()?;
+
+        if let Some(ref token) = token {
+            conn.execute(
+                "UPDATE api_tokens SET last_used_at = ?1 WHERE id = ?2",
+                params![Utc::now().to_rfc3339(), token.id],
+            )?;
+        }
+        Ok(token)
+    }
+
+    /// 吊销（删除）用户的 API 令牌，令牌不存在或不属于该用户时返回 false
+    pub fn delete_api_token(&self, user_id: &str, token_id: i64) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let deleted = conn.execute(
+            "DELETE FROM api_tokens WHERE id = ?1 AND user_id = ?2",
+            params![token_id, user_id],
+        )?;
+        Ok(deleted > 0)
+    }
+
+    /// 获取某个主体（用户或 IP）某天的 (请求数, token 数)
+    pub fn get_daily_usage(&self, subject: &str, day: &str) -> Result<(u64, u64)> {
+        let conn = self.conn.lock().unwrap();
+        let usage: Option<(i64, i64)> = conn
+            .query_row(
+                "SELECT requests, tokens FROM daily_usage WHERE subject = ?1 AND day = ?2",
+                params![subject, day],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let (requests, tokens) = usage.unwrap_or_default();
+        Ok((requests.max(0) as u64, tokens.max(0) as u64))
+    }
+
+    /// 累加某个主体某天的请求数和 token 数
+    pub fn add_daily_usage(
+        &self,
+        subject: &str,
+        day: &str,
+        requests: u64,
+        tokens: u64,
+    ) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO daily_usage (subject, day, requests, tokens) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT(subject, day) DO UPDATE SET
+                requests = requests + excluded.requests,
+                tokens = tokens + excluded.tokens",
+            params![subject, day, requests as i64, tokens as i64],
+        )?;
+        Ok(())
+    }
+
+    /// 创建新会话
+    pub fn create_conversation(
+        &self,
+        user_id: &str,
+        title: &str,
+        model: Option<&str>,
+    ) -> Result<Conversation> {
+        let conn = self.conn.lock().unwrap();
+        let now = Utc::now().to_rfc3339();
+
+        conn.execute(
+            "INSERT INTO conversations (user_id, title, model, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?4)",
+            params![user_id, title, model, now],
+        )?;
+
+        let id = conn.last_insert_rowid();
+        conn.query_row(
+            &format!(
+                "SELECT {} FROM conversations WHERE id = ?1",
+                CONVERSATION_COLUMNS
+            ),
+            [id],
+            conversation_from_row,
+        )
+    }
+
+    /// 获取用户的某个会话
+    pub fn get_conversation(
+        &self,
+        user_id: &str,
+        conversation_id: i64,
+    ) -> Result<Option<Conversation>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            &format!(
+                "SELECT {} FROM conversations WHERE id = ?1 AND user_id = ?2",
+                CONVERSATION_COLUMNS
+            ),
+            params![conversation_id, user_id],
+            conversation_from_row,
+        )
+        .optional()
+    }
+
+    /// 获取用户的所有会话（按最近更新排序）
+    pub fn list_conversations(&self, user_id: &str) -> Result<Vec<Conversation>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM conversations WHERE user_id = ?1 ORDER BY updated_at DESC, id DESC",
+            CONVERSATION_COLUMNS
+        ))?;
+
+        let conversations = stmt.query_map([user_id], conversation_from_row)?;
+        Ok(conversations.filter_map(|c| c.ok()).collect())
+    }
+
+    /// 重命名会话，返回是否找到该会话
+    pub fn rename_conversation(
+        &self,
+        user_id: &str,
+        conversation_id: i64,
+        title: &str,
+    ) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let changed = conn.execute(
+            "UPDATE conversations SET title = ?1, updated_at = ?2 WHERE id = ?3 AND user_id = ?4",
+            params![title, Utc::now().to_rfc3339(), conversation_id, user_id],
+        )?;
+        Ok(changed > 0)
+    }
+
+    /// 更新会话使用的模型
+    pub fn set_conversation_model(
+        &self,
+        user_id: &str,
+        conversation_id: i64,
+        model: &str,
+    ) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE conversations SET model = ?1 WHERE id = ?2 AND user_id = ?3",
+            params![model, conversation_id, user_id],
+        )?;
+        Ok(())
+    }
+
+    /// 删除会话及其所有消息，返回是否找到该会话
+    pub fn delete_conversation(&self, user_id: &str, conversation_id: i64) -> Result<bool> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let deleted = tx.execute(
+            "DELETE FROM conversations WHERE id = ?1 AND user_id = ?2",
+            params![conversation_id, user_id],
+        )?;
+        if deleted > 0 {
+            tx.execute(
+                "DELETE FROM tool_calls WHERE message_id IN
+                    (SELECT id FROM messages WHERE conversation_id = ?1 AND user_id = ?2)",
+                params![conversation_id, user_id],
+            )?;
+            tx.execute(
+                "DELETE FROM messages WHERE conversation_id = ?1 AND user_id = ?2",
+                params![conversation_id, user_id],
+            )?;
+            tx.execute(
+                "DELETE FROM conversation_files WHERE conversation_id = ?1",
+                [conversation_id],
+            )?;
+        }
+        tx.commit()?;
+        drop(conn);
+
+        if deleted > 0 {
+            self.invalidate_index(user_id);
+        }
+        Ok(deleted > 0)
+    }
+
+    /// 保存上传的文件，同一用户已有相同内容的文件时返回已有记录
+    pub fn save_file(
+        &self,
+        user_id: &str,
+        name: &str,
+        mime_type: &str,
+        sha256: &str,
+        data: &[u8],
+    ) -> Result<StoredFile> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO files (user_id, name, mime_type, size, sha256, data, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+             ON CONFLICT (user_id, sha256) DO NOTHING",
+            params![
+                user_id,
+                name,
+                mime_type,
+                data.len() as i64,
+                sha256,
+                data,
+                Utc::now().to_rfc3339()
+            ],
+        )?;
+        conn.query_row(
+            &format!(
+                "SELECT {} FROM files WHERE user_id = ?1 AND sha256 = ?2",
+                FILE_COLUMNS
+            ),
+            params![user_id, sha256],
+            file_from_row,
+        )
+    }
+
+    /// 获取用户的所有文件（按上传时间倒序）
+    pub fn list_files(&self, user_id: &str) -> Result<Vec<StoredFile>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM files WHERE user_id = ?1 ORDER BY id DESC",
+            FILE_COLUMNS
+        ))?;
+        stmt.query_map([user_id], file_from_row)?.collect()
+    }
+
+    /// 按 ID 读取用户的文件及其内容，按传入顺序返回，忽略不存在或不属于该用户的 ID
+    pub fn load_files(
+        &self,
+        user_id: &str,
+        file_ids: &[i64],
+    ) -> Result<Vec<(StoredFile, Vec<u8>)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {}, data FROM files WHERE id = ?1 AND user_id = ?2",
+            FILE_COLUMNS
+        ))?;
+        let mut files = Vec::new();
+        for id in file_ids {
+            let file = stmt
+                .query_row(params![id, user_id], |row| {
+                    Ok((file_from_row(row)?, row.get(10)?))
+                })
+                .optional()?;
+            files.extend(file);
+        }
+        Ok(files)
+    }
+
+    /// 按内容哈希查找用户已保存的文件
+    pub fn find_file(&self, user_id: &str, sha256: &str) -> Result<Option<StoredFile>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            &format!(
+                "SELECT {} FROM files WHERE user_id = ?1 AND sha256 = ?2",
+                FILE_COLUMNS
+            ),
+            params![user_id, sha256],
+            file_from_row,
+        )
+        .optional()
+    }
+
+    /// 记录文件在模型后端文件存储中的 URI
+    pub fn set_file_remote_uri(&self, file_id: i64, uri: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE files SET remote_uri = ?1, remote_uploaded_at = ?2 WHERE id = ?3",
+            params![uri, Utc::now().to_rfc3339(), file_id],
+        )?;
+        Ok(())
+    }
+
+    /// 删除用户的文件并从所有会话的上下文中移除，文件不存在或不属于该用户时返回 false
+    pub fn delete_file(&self, user_id: &str, file_id: i64) -> Result<bool> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let deleted = tx.execute(
+            "DELETE FROM files WHERE id = ?1 AND user_id = ?2",
+            params![file_id, user_id],
+        )?;
+        if deleted > 0 {
+            tx.execute(
+                "DELETE FROM conversation_files WHERE file_id = ?1",
+                [file_id],
+            )?;
+            tx.execute("DELETE FROM document_chunks WHERE file_id = ?1", [file_id])?;
+        }
+        tx.commit()?;
+        Ok(deleted > 0)
+    }
+
+    /// 替换会话的文件上下文，只保留属于该用户的文件，返回实际保存的文件
+    pub fn set_conversation_files(
+        &self,
+        user_id: &str,
+        conversation_id: i64,
+        file_ids: &[i64],
+    ) -> Result<Vec<StoredFile>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM conversation_files WHERE conversation_id = ?1",
+            [conversation_id],
+        )?;
+        for file_id in file_ids {
+            tx.execute(
+                "INSERT OR IGNORE INTO conversation_files (conversation_id, file_id)
+                 SELECT ?1, id FROM files WHERE id = ?2 AND user_id = ?3",
+                params![conversation_id, file_id, user_id],
+            )?;
+        }
+        tx.commit()?;
+        drop(conn);
+
+        self.get_conversation_files(user_id, conversation_id)
+    }
+
+    /// 获取会话的文件上下文（按文件 ID 排序）
+    pub fn get_conversation_files(
+        &self,
+        user_id: &str,
+        conversation_id: i64,
+    ) -> Result<Vec<StoredFile>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM files
+             WHERE user_id = ?2
+               AND id IN (SELECT file_id FROM conversation_files WHERE conversation_id = ?1)
+             ORDER BY id",
+            FILE_COLUMNS
+        ))?;
+        stmt.query_map(params![conversation_id, user_id], file_from_row)?
+            .collect()
+    }
+
+    /// 替换文件的所有片段（不带嵌入，由后台任务补全）并记录片段数
+    pub fn replace_document_chunks(&self, file_id: i64, chunks: &[TextChunk]) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM document_chunks WHERE file_id = ?1", [file_id])?;
+        for (index, chunk) in chunks.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO document_chunks
+                    (file_id, chunk_index, start_line, end_line, content)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    file_id,
+                    index as i64,
+                    chunk.start_line as i64,
+                    chunk.end_line as i64,
+                    chunk.content,
+                ],
+            )?;
+        }
+        tx.execute(
+            "UPDATE files SET chunk_count = ?1 WHERE id = ?2",
+            params![chunks.len() as i64, file_id],
+        )?;
+        tx.commit()
+    }
+
+    /// 获取指定类型、尚未切分的文件及其内容
+    pub fn get_unchunked_files(
+        &self,
+        mime_type: &str,
+        limit: usize,
+    ) -> Result<Vec<(StoredFile, Vec<u8>)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {}, data FROM files
+             WHERE mime_type = ?1 AND chunk_count IS NULL ORDER BY id LIMIT ?2",
+            FILE_COLUMNS
+        ))?;
+        stmt.query_map(params![mime_type, limit], |row| {
+            Ok((file_from_row(row)?, row.get(10)?))
+        })?
+        .collect()
+    }
+
+    /// 获取没有当前嵌入来源的向量、且用该来源失败少于 `max_attempts` 次的片段
+    pub fn get_chunks_without_embedding(
+        &self,
+        embedding_model: &str,
+        max_attempts: u32,
+        limit: usize,
+    ) -> Result<Vec<PendingChunk>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT c.id, f.name, c.content
+             FROM document_chunks c JOIN files f ON f.id = c.file_id
+             WHERE c.embedding_model IS NOT ?1 OR (c.embedding IS NULL AND c.embed_attempts < ?2)
+             ORDER BY c.id LIMIT ?3",
+        )?;
+        stmt.query_map(params![embedding_model, max_attempts, limit], |row| {
+            Ok(PendingChunk {
+                id: row.get(0)?,
+                file_name: row.get(1)?,
+                content: row.get(2)?,
+            })
+        })?
+        .collect()
+    }
+
+    /// 统计等待生成嵌入的片段数（含未达到失败上限的片段）
+    pub fn pending_chunk_count(&self, embedding_model: &str, max_attempts: u32) -> Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT COUNT(*) FROM document_chunks
+             WHERE embedding_model IS NOT ?1 OR (embedding IS NULL AND embed_attempts < ?2)",
+            params![embedding_model, max_attempts],
+            |row| row.get::<_, i64>(0),
+        )
+        .map(|n| n as usize)
+    }
+
+    /// 保存片段的嵌入
+    pub fn update_chunk_embedding(
+        &self,
+        chunk_id: i64,
+        embedding: &[f32],
+        embedding_model: &str,
+    ) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE document_chunks SET embedding = ?1, embedding_model = ?2, embed_attempts = 0
+             WHERE id = ?3",
+            params![embedding_to_bytes(embedding), embedding_model, chunk_id],
+        )?;
+        Ok(())
+    }
+
+    /// 记录一次片段嵌入失败（更换嵌入来源后重新计数）
+    pub fn record_chunk_embed_failure(&self, chunk_id: i64, embedding_model: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE document_chunks
+             SET embed_attempts = CASE WHEN embedding_model IS ?1 THEN embed_attempts + 1 ELSE 1 END,
+                 embedding = NULL, embedding_model = ?1
+             WHERE id = ?2",
+            params![embedding_model, chunk_id],
+        )?;
+        Ok(())
+    }
+
+    /// 读取用户文件的所有片段，按传入的文件顺序和片段顺序返回
+    pub fn get_document_chunks(
+        &self,
+        user_id: &str,
+        file_ids: &[i64],
+    ) -> Result<Vec<DocumentChunk>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT c.file_id, f.name, c.start_line, c.end_line, c.content,
+                    c.embedding, c.embedding_model
+             FROM document_chunks c JOIN files f ON f.id = c.file_id
+             WHERE c.file_id = ?1 AND f.user_id = ?2
+             ORDER BY c.chunk_index",
+        )?;
+        let mut chunks = Vec::new();
+        for file_id in file_ids {
+            let rows = stmt.query_map(params![file_id, user_id], |row| {
+                let embedding: Option<Vec<u8>> = row.get(5)?;
+                Ok(DocumentChunk {
+                    file_id: row.get(0)?,
+                    file_name: row.get(1)?,
+                    chunk: TextChunk {
+                        start_line: row.get::<_, i64>(2)? as usize,
+                        end_line: row.get::<_, i64>(3)? as usize,
+                        content: row.get(4)?,
+                    },
+                    embedding: embedding.as_deref().map(bytes_to_embedding),
+                    embedding_model: row.get(6)?,
+                })
+            })?;
+            for chunk in rows {
+                chunks.push(chunk?);
+            }
+        }
+        Ok(chunks)
+    }
+
     /// 添加消息（不带嵌入，稍后异步更新）
     pub fn add_message(
         &self,
         user_id: &str,
+        conversation_id: i64,
         role: &str,
         content: &str,
         model: Option<&str>,
@@ -73,190 +1093,678 @@ impl ChatMemory {
         let now = Utc::now().to_rfc3339();
 
         conn.execute(
-            "INSERT INTO messages (user_id, role, content, model, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
-            params![user_id, role, content, model, now],
+            "INSERT INTO messages (user_id, conversation_id, role, content, model, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![user_id, conversation_id, role, content, model, now],
+        )?;
+        let message_id = conn.last_insert_rowid();
+
+        conn.execute(
+            "UPDATE conversations SET updated_at = ?1 WHERE id = ?2",
+            params![now, conversation_id],
         )?;
 
-        Ok(conn.last_insert_rowid())
+        Ok(message_id)
     }
 
-    /// 更新消息的嵌入向量
-    pub fn update_embedding(&self, message_id: i64, embedding: &[f32]) -> Result<()> {
+    /// 记录模型回复的 token 用量
+    pub fn set_message_usage(&self, message_id: i64, usage: &TokenUsage) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE messages SET prompt_tokens = ?1, candidate_tokens = ?2, thought_tokens = ?3
+             WHERE id = ?4",
+            params![
+                usage.prompt_tokens as i64,
+                usage.candidate_tokens as i64,
+                usage.thought_tokens as i64,
+                message_id
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// 保存生成回复时执行的工具调用
+    pub fn save_tool_calls(&self, message_id: i64, calls: &[ToolCallLog]) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let now = Utc::now().to_rfc3339();
+        for (index, call) in calls.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO tool_calls
+                    (message_id, call_index, name, args, result, error, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    message_id,
+                    index as i64,
+                    call.name,
+                    call.args.to_string(),
+                    call.result.as_ref().map(|r| r.to_string()),
+                    call.error,
+                    now
+                ],
+            )?;
+        }
+        tx.commit()
+    }
+
+    /// 读取消息的工具调用记录，按消息 ID 分组，组内按调用顺序排列
+    pub fn get_tool_calls(&self, message_ids: &[i64]) -> Result<HashMap<i64, Vec<ToolCallLog>>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT name, args, result, error FROM tool_calls
+             WHERE message_id = ?1 ORDER BY call_index",
+        )?;
+        let mut calls = HashMap::new();
+        for message_id in message_ids {
+            let rows = stmt.query_map([message_id], |row| {
+                let args: String = row.get(1)?;
+                let result: Option<String> = row.get(2)?;
+                Ok(ToolCallLog {
+                    name: row.get(0)?,
+                    args: serde_json::from_str(&args).unwrap_or_default(),
+                    result: result.and_then(|r| serde_json::from_str(&r).ok()),
+                    error: row.get(3)?,
+                })
+            })?;
+            let rows = rows.collect::<Result<Vec<_>>>()?;
+            if !rows.is_empty() {
+                calls.insert(*message_id, rows);
+            }
+        }
+        Ok(calls)
+    }
+
+    /// 记录一次不保存为消息的模型调用的 token 用量
+    pub fn add_usage_record(
+        &self,
+        user_id: &str,
+        model: Option<&str>,
+        usage: &TokenUsage,
+    ) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO usage_records
+                (user_id, model, prompt_tokens, candidate_tokens, thought_tokens, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                user_id,
+                model,
+                usage.prompt_tokens as i64,
+                usage.candidate_tokens as i64,
+                usage.thought_tokens as i64,
+                Utc::now().to_rfc3339()
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// 按用户、日期和模型聚合 `since_day`（含）之后的 token 用量
+    ///
+    /// 包括模型回复消息和 `usage_records` 中的调用；
+    /// `user_id` 为 None 时统计所有用户，结果按日期倒序
+    pub fn usage_summary(&self, user_id: Option<&str>, since_day: &str) -> Result<Vec<UsageRow>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT m.user_id, u.username, substr(m.created_at, 1, 10) AS day, m.model,
+                    COUNT(*), SUM(m.prompt_tokens), SUM(COALESCE(m.candidate_tokens, 0)),
+                    SUM(COALESCE(m.thought_tokens, 0))
+             FROM (
+                 SELECT user_id, model, prompt_tokens, candidate_tokens, thought_tokens, created_at
+                 FROM messages WHERE role = 'model' AND prompt_tokens IS NOT NULL
+                 UNION ALL
+                 SELECT user_id, model, prompt_tokens, candidate_tokens, thought_tokens, created_at
+                 FROM usage_records
+             ) m LEFT JOIN users u ON u.id = m.user_id
+             WHERE m.created_at >= ?1 AND (?2 IS NULL OR m.user_id = ?2)
+             GROUP BY m.user_id, day, m.model
+             ORDER BY day DESC, m.user_id, m.model",
+        )?;
+        let rows = stmt.query_map(params![since_day, user_id], |row| {
+            Ok(UsageRow {
+                user_id: row.get(0)?,
+                username: row.get(1)?,
+                day: row.get(2)?,
+                model: row.get(3)?,
+                replies: row.get::<_, i64>(4)? as u64,
+                usage: TokenUsage {
+                    prompt_tokens: row.get::<_, i64>(5)? as u64,
+                    candidate_tokens: row.get::<_, i64>(6)? as u64,
+                    thought_tokens: row.get::<_, i64>(7)? as u64,
+                },
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// 更新消息的嵌入向量，同时记录嵌入来源和维度
+    pub fn update_embedding(
+        &self,
+        message_id: i64,
+        embedding: &[f32],
+        embedding_model: &str,
+    ) -> Result<()> {
         let conn = self.conn.lock().unwrap();
         let embedding_bytes = embedding_to_bytes(embedding);
 
         conn.execute(
-            "UPDATE messages SET embedding = ?1 WHERE id = ?2",
-            params![embedding_bytes, message_id],
+            "UPDATE messages SET embedding = ?1, embedding_model = ?2, embedding_dim = ?3
+             WHERE id = ?4",
+            params![
+                embedding_bytes,
+                embedding_model,
+                embedding.len(),
+                message_id
+            ],
         )?;
 
-        Ok(())
-    }
+        let owner: Option<(String, Option<i64>)> = conn
+            .query_row(
+                "SELECT user_id, conversation_id FROM messages WHERE id = ?1",
+                [message_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        drop(conn);
+
+        // 同步更新已加载的索引
+        if let Some((user_id, conversation_id)) = owner {
+            let index = self.indexes.lock().unwrap().get(&user_id).cloned();
+            if let Some(index) = index {
+                let mut index = index.write().unwrap();
+                if index.embedding_model == embedding_model && index.dimension == embedding.len() {
+                    index
+                        .hnsw
+                        .insert(message_id, conversation_id, embedding.to_vec());
+                } else {
+                    index.hnsw.remove(message_id);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 更新消息的摘要
+    pub fn update_summary(&self, message_id: i64, summary: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute(
+            "UPDATE messages SET summary = ?1 WHERE id = ?2",
+            params![summary, message_id],
+        )?;
+
+        Ok(())
+    }
+
+    /// 累加消息生成摘要失败的次数
+    pub fn add_summary_attempts(&self, message_id: i64, count: u32) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE messages SET summary_attempts = summary_attempts + ?1 WHERE id = ?2",
+            params![count, message_id],
+        )?;
+        Ok(())
+    }
+
+    /// 根据查询嵌入检索用户最相关的消息
+    ///
+    /// `conversation_id` 为 `None` 时在用户的所有会话中检索；
+    /// 只比较由同一个 `embedding_model` 生成、维度相同的向量
+    pub fn retrieve_similar(
+        &self,
+        user_id: &str,
+        conversation_id: Option<i64>,
+        query_embedding: &[f32],
+        embedding_model: &str,
+        top_k: usize,
+        min_similarity: f32,
+    ) -> Result<Vec<RetrievedMessage>> {
+        let index = self.user_index(user_id, embedding_model, query_embedding.len())?;
+        let hits = index.read().unwrap().hnsw.search(
+            query_embedding,
+            conversation_id,
+            top_k,
+            min_similarity,
+        );
+
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT {} FROM messages WHERE id = ?1 AND user_id = ?2",
+            RECORD_COLUMNS
+        ))?;
+
+        let mut results = Vec::with_capacity(hits.len());
+        for (id, similarity) in hits {
+            // 索引和数据库之间可能短暂不一致，找不到的消息直接跳过
+            if let Some(record) = stmt
+                .query_row(params![id, user_id], record_from_row)
+                .optional()?
+            {
+                results.push(RetrievedMessage {
+                    record,
+                    similarity: Some(similarity),
+                    score: similarity,
+                });
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// 混合检索：BM25 关键词检索与向量检索结果按倒数排名融合（RRF）
+    ///
+    /// 没有查询嵌入时只使用关键词检索；`min_similarity` 只作用于向量检索，
+    /// 精确命中的标识符、错误码等即使向量相似度较低也会保留
+    #[allow(clippy::too_many_arguments)]
+    pub fn retrieve_hybrid(
+        &self,
+        user_id: &str,
+        conversation_id: Option<i64>,
+        query_text: &str,
+        query_embedding: Option<&[f32]>,
+        embedding_model: &str,
+        top_k: usize,
+        min_similarity: f32,
+    ) -> Result<Vec<RetrievedMessage>> {
+        let candidates = top_k * HYBRID_CANDIDATE_FACTOR;
+
+        let vector_hits = match query_embedding {
+            Some(embedding) => self.retrieve_similar(
+                user_id,
+                conversation_id,
+                embedding,
+                embedding_model,
+                candidates,
+                min_similarity,
+            )?,
+            None => Vec::new(),
+        };
+        let keyword_hits =
+            self.search_keywords(user_id, conversation_id, query_text, candidates)?;
+
+        let mut fused: Vec<RetrievedMessage> = Vec::new();
+        for (rank, hit) in vector_hits.into_iter().enumerate() {
+            fused.push(RetrievedMessage {
+                score: rrf_score(rank),
+                ..hit
+            });
+        }
+        for (rank, record) in keyword_hits.into_iter().enumerate() {
+            if let Some(existing) = fused.iter_mut().find(|m| m.record.id == record.id) {
+                existing.score += rrf_score(rank);
+                continue;
+            }
+            let similarity = query_embedding.and_then(|embedding| {
+                self.indexed_similarity(user_id, embedding_model, record.id, embedding)
+            });
+            fused.push(RetrievedMessage {
+                record,
+                similarity,
+                score: rrf_score(rank),
+            });
+        }
+
+        fused.sort_by(|a, b| b.score.total_cmp(&a.score));
+        fused.truncate(top_k);
+        Ok(fused)
+    }
+
+    /// 按 BM25 排序的关键词检索
+    ///
+    /// `conversation_id` 为 `None` 时在用户的所有会话中检索
+    pub fn search_keywords(
+        &self,
+        user_id: &str,
+        conversation_id: Option<i64>,
+        query_text: &str,
+        limit: usize,
+    ) -> Result<Vec<ChatRecord>> {
+        let Some(query) = fts_query(query_text) else {
+            return Ok(Vec::new());
+        };
+
+        let conn = self.conn.lock().unwrap();
+        let columns = RECORD_COLUMNS
+            .split(", ")
+            .map(|column| format!("m.{}", column))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM messages_fts f JOIN messages m ON m.id = f.rowid
+             WHERE messages_fts MATCH ?1 AND m.user_id = ?2
+               AND (?3 IS NULL OR m.conversation_id = ?3)
+             ORDER BY bm25(messages_fts) LIMIT ?4",
+            columns
+        ))?;
+
+        let messages = stmt.query_map(
+            params![query, user_id, conversation_id, limit],
+            record_from_row,
+        )?;
+
+        Ok(messages.filter_map(|m| m.ok()).collect())
+    }
+
+    /// 全文检索历史消息，返回带高亮片段的一页结果
+    ///
+    /// 传入 `rerank` (查询嵌入, 嵌入来源) 时，对 BM25 排名靠前的候选
+    /// 再按向量相似度做 RRF 融合后分页
+    pub fn search_history(
+        &self,
+        user_id: &str,
+        filter: &HistoryFilter,
+        rerank: Option<(&[f32], &str)>,
+        offset: usize,
+        limit: usize,
+    ) -> Result<HistoryPage> {
+        let Some(query) = fts_query(&filter.query) else {
+            return Ok(HistoryPage::default());
+        };
+
+        let conditions = "messages_fts MATCH ?1 AND m.user_id = ?2
+               AND (?3 IS NULL OR m.conversation_id = ?3)
+               AND (?4 IS NULL OR julianday(m.created_at) >= julianday(?4))
+               AND (?5 IS NULL OR julianday(m.created_at) <= julianday(?5))
+               AND (?6 IS NULL OR m.role = ?6)
+               AND (?7 IS NULL OR m.model = ?7)";
+        let from = filter.from.map(|t| t.to_rfc3339());
+        let to = filter.to.map(|t| t.to_rfc3339());
+        let filter_params = params![
+            query,
+            user_id,
+            filter.conversation_id,
+            from,
+            to,
+            filter.role,
+            filter.model
+        ];
+
+        let (total, rows) = {
+            let conn = self.conn.lock().unwrap();
+            let total: i64 = conn.query_row(
+                &format!(
+                    "SELECT COUNT(*) FROM messages_fts f JOIN messages m ON m.id = f.rowid
+                     WHERE {}",
+                    conditions
+                ),
+                filter_params,
+                |row| row.get(0),
+            )?;
+
+            // 重排时先取固定数量的候选，融合后再分页
+            let (sql_limit, sql_offset) = match rerank {
+                Some(_) => (MAX_RERANK_CANDIDATES, 0),
+                None => (limit, offset),
+            };
+            let columns = RECORD_COLUMNS
+                .split(", ")
+                .map(|column| format!("m.{}", column))
+                .collect::<Vec<_>>()
+                .join(", ");
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {}, snippet(messages_fts, 0, char({}), char({}), '…', {})
+                 FROM messages_fts f JOIN messages m ON m.id = f.rowid
+                 WHERE {}
+                 ORDER BY bm25(messages_fts) LIMIT {} OFFSET {}",
+                columns,
+                HIGHLIGHT_START as u32,
+                HIGHLIGHT_END as u32,
+                SNIPPET_TOKENS,
+                conditions,
+                sql_limit,
+                sql_offset
+            ))?;
+            let rows = stmt
+                .query_map(filter_params, |row| {
+                    Ok((record_from_row(row)?, row.get::<_, String>(8)?))
+                })?
+                .filter_map(|r| r.ok())
+                .collect::<Vec<_>>();
+            (total as usize, rows)
+        };
+
+        let mut hits: Vec<HistoryHit> = rows
+            .into_iter()
+            .map(|(record, snippet)| HistoryHit {
+                record,
+                snippet: highlight_snippet(&snippet),
+                similarity: None,
+            })
+            .collect();
+
+        let Some((query_embedding, embedding_model)) = rerank else {
+            return Ok(HistoryPage { hits, total });
+        };
+
+        let index = self.user_index(user_id, embedding_model, query_embedding.len())?;
+        {
+            let index = index.read().unwrap();
+            for hit in &mut hits {
+                hit.similarity = index.hnsw.similarity(hit.record.id, query_embedding);
+            }
+        }
 
-    /// 更新消息的摘要
-    #[allow(dead_code)]
-    pub fn update_summary(&self, message_id: i64, summary: &str) -> Result<()> {
-        let conn = self.conn.lock().unwrap();
+        // BM25 排名与相似度排名融合
+        let mut by_similarity: Vec<usize> = (0..hits.len())
+            .filter(|&i| hits[i].similarity.is_some())
+            .collect();
+        by_similarity.sort_by(|&a, &b| {
+            hits[b]
+                .similarity
+                .unwrap_or(0.0)
+                .total_cmp(&hits[a].similarity.unwrap_or(0.0))
+        });
+        let mut scores: Vec<f32> = (0..hits.len()).map(rrf_score).collect();
+        for (rank, &i) in by_similarity.iter().enumerate() {
+            scores[i] += rrf_score(rank);
+        }
 
-        conn.execute(
-            "UPDATE messages SET summary = ?1 WHERE id = ?2",
-            params![summary, message_id],
-        )?;
+        let mut ranked: Vec<(f32, HistoryHit)> = scores.into_iter().zip(hits).collect();
+        ranked.sort_by(|a, b| b.0.total_cmp(&a.0));
+        let hits = ranked
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .map(|(_, hit)| hit)
+            .collect();
 
-        Ok(())
+        Ok(HistoryPage {
+            hits,
+            total: total.min(MAX_RERANK_CANDIDATES),
+        })
     }
 
-    /// 根据查询嵌入检索用户最相关的消息
-    pub fn retrieve_similar(
+    /// 用已加载的索引计算某条消息与查询的相似度
+    fn indexed_similarity(
         &self,
         user_id: &str,
+        embedding_model: &str,
+        message_id: i64,
         query_embedding: &[f32],
-        top_k: usize,
-        min_similarity: f32,
-    ) -> Result<Vec<RetrievedMessage>> {
+    ) -> Option<f32> {
+        let index = self.indexes.lock().unwrap().get(user_id).cloned()?;
+        let index = index.read().unwrap();
+        if index.embedding_model != embedding_model || index.dimension != query_embedding.len() {
+            return None;
+        }
+        index.hnsw.similarity(message_id, query_embedding)
+    }
+
+    /// 获取用户的向量索引，不存在或来源不一致时从数据库重建
+    fn user_index(
+        &self,
+        user_id: &str,
+        embedding_model: &str,
+        dimension: usize,
+    ) -> Result<Arc<RwLock<UserIndex>>> {
+        let mut indexes = self.indexes.lock().unwrap();
+        if let Some(index) = indexes.get(user_id) {
+            let current = index.read().unwrap();
+            if current.embedding_model == embedding_model && current.dimension == dimension {
+                return Ok(index.clone());
+            }
+        }
+
         let conn = self.conn.lock().unwrap();
         let mut stmt = conn.prepare(
-            "SELECT id, user_id, role, content, summary, embedding, model, created_at 
-             FROM messages WHERE user_id = ?1 AND embedding IS NOT NULL",
+            "SELECT id, conversation_id, embedding FROM messages
+             WHERE user_id = ?1 AND embedding IS NOT NULL
+               AND embedding_model = ?2 AND embedding_dim = ?3
+             ORDER BY id ASC",
         )?;
-
-        let messages = stmt.query_map([user_id], |row| {
-            let embedding_bytes: Option<Vec<u8>> = row.get(5)?;
-            let created_at_str: String = row.get(7)?;
-            let created_at = DateTime::parse_from_rfc3339(&created_at_str)
-                .map(|dt| dt.with_timezone(&Utc))
-                .unwrap_or_else(|_| Utc::now());
-
+        let rows = stmt.query_map(params![user_id, embedding_model, dimension], |row| {
             Ok((
-                ChatRecord {
-                    id: row.get(0)?,
-                    user_id: row.get(1)?,
-                    role: row.get(2)?,
-                    content: row.get(3)?,
-                    summary: row.get(4)?,
-                    model: row.get(6)?,
-                    created_at,
-                },
-                embedding_bytes,
+                row.get::<_, i64>(0)?,
+                row.get::<_, Option<i64>>(1)?,
+                row.get::<_, Vec<u8>>(2)?,
             ))
         })?;
 
-        let mut results: Vec<RetrievedMessage> = messages
-            .filter_map(|m| m.ok())
-            .filter_map(|(record, embedding_bytes)| {
-                embedding_bytes.map(|bytes| {
-                    let embedding = bytes_to_embedding(&bytes);
-                    let similarity = cosine_similarity(query_embedding, &embedding);
-                    RetrievedMessage { record, similarity }
-                })
-            })
-            .filter(|m| m.similarity >= min_similarity)
-            .collect();
-
-        // 按相似度降序排序
-        results.sort_by(|a, b| {
-            b.similarity
-                .partial_cmp(&a.similarity)
-                .unwrap_or(std::cmp::Ordering::Equal)
-        });
+        let mut hnsw = HnswIndex::default();
+        for row in rows {
+            let (id, conversation_id, bytes) = row?;
+            hnsw.insert(id, conversation_id, bytes_to_embedding(&bytes));
+        }
 
-        // 只返回前 top_k 个
-        results.truncate(top_k);
+        let index = Arc::new(RwLock::new(UserIndex {
+            embedding_model: embedding_model.to_string(),
+            dimension,
+            hnsw,
+        }));
+        indexes.insert(user_id.to_string(), index.clone());
+        Ok(index)
+    }
 
-        Ok(results)
+    /// 丢弃用户的向量索引，下次检索时重建
+    fn invalidate_index(&self, user_id: &str) {
+        self.indexes.lock().unwrap().remove(user_id);
     }
 
-    /// 获取用户最近的 N 条消息（用于保持对话连贯性）
-    pub fn get_recent_messages(&self, user_id: &str, limit: usize) -> Result<Vec<ChatRecord>> {
+    /// 获取会话最近的 N 条消息（用于保持对话连贯性）
+    pub fn get_recent_messages(
+        &self,
+        user_id: &str,
+        conversation_id: i64,
+        limit: usize,
+    ) -> Result<Vec<ChatRecord>> {
         let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT id, user_id, role, content, summary, model, created_at FROM messages 
-             WHERE user_id = ?1 ORDER BY id DESC LIMIT ?2",
-        )?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM messages
+             WHERE user_id = ?1 AND conversation_id = ?2 ORDER BY id DESC LIMIT ?3",
+            RECORD_COLUMNS
+        ))?;
 
-        let messages = stmt.query_map(params![user_id, limit], |row| {
-            let created_at_str: String = row.get(6)?;
-            let created_at = DateTime::parse_from_rfc3339(&created_at_str)
-                .map(|dt| dt.with_timezone(&Utc))
-                .unwrap_or_else(|_| Utc::now());
-
-            Ok(ChatRecord {
-                id: row.get(0)?,
-                user_id: row.get(1)?,
-                role: row.get(2)?,
-                content: row.get(3)?,
-                summary: row.get(4)?,
-                model: row.get(5)?,
-                created_at,
-            })
-        })?;
+        let messages = stmt.query_map(params![user_id, conversation_id, limit], record_from_row)?;
 
         let mut result: Vec<ChatRecord> = messages.filter_map(|m| m.ok()).collect();
         result.reverse(); // 按时间正序排列
         Ok(result)
     }
 
-    /// 获取没有嵌入的消息（用于批量生成嵌入）
-    #[allow(dead_code)]
-    pub fn get_messages_without_embedding(&self, limit: usize) -> Result<Vec<ChatRecord>> {
+    /// 获取没有当前来源嵌入的消息（用于批量生成嵌入）
+    ///
+    /// 嵌入缺失或由其他后端生成的消息都会返回，切换后端后逐步重建
+    pub fn get_messages_without_embedding(
+        &self,
+        embedding_model: &str,
+        limit: usize,
+    ) -> Result<Vec<ChatRecord>> {
         let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT id, user_id, role, content, summary, model, created_at FROM messages 
-             WHERE embedding IS NULL ORDER BY id ASC LIMIT ?1",
-        )?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM messages
+             WHERE embedding IS NULL OR embedding_model IS NOT ?1 ORDER BY id ASC LIMIT ?2",
+            RECORD_COLUMNS
+        ))?;
 
-        let messages = stmt.query_map([limit], |row| {
-            let created_at_str: String = row.get(6)?;
-            let created_at = DateTime::parse_from_rfc3339(&created_at_str)
-                .map(|dt| dt.with_timezone(&Utc))
-                .unwrap_or_else(|_| Utc::now());
+        let messages = stmt.query_map(params![embedding_model, limit], record_from_row)?;
 
-            Ok(ChatRecord {
-                id: row.get(0)?,
-                user_id: row.get(1)?,
-                role: row.get(2)?,
-                content: row.get(3)?,
-                summary: row.get(4)?,
-                model: row.get(5)?,
-                created_at,
-            })
-        })?;
+        Ok(messages.filter_map(|m| m.ok()).collect())
+    }
+
+    /// 获取超过指定字符数、没有摘要且失败次数少于 `max_attempts` 的消息（用于批量生成摘要）
+    pub fn get_messages_without_summary(
+        &self,
+        min_chars: usize,
+        max_attempts: u32,
+        limit: usize,
+    ) -> Result<Vec<ChatRecord>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM messages
+             WHERE summary IS NULL AND length(content) > ?1 AND summary_attempts < ?2
+             ORDER BY id ASC LIMIT ?3",
+            RECORD_COLUMNS
+        ))?;
+
+        let messages = stmt.query_map(params![min_chars, max_attempts, limit], record_from_row)?;
 
         Ok(messages.filter_map(|m| m.ok()).collect())
     }
 
-    /// 获取用户所有消息
-    pub fn get_all_messages(&self, user_id: &str) -> Result<Vec<ChatRecord>> {
+    /// 按游标分页获取会话消息（从新到旧翻页）
+    ///
+    /// 返回 `before_id` 之前最近的 `limit` 条消息（按时间正序）以及是否还有更早的消息
+    pub fn get_messages_page(
+        &self,
+        user_id: &str,
+        conversation_id: i64,
+        before_id: Option<i64>,
+        limit: usize,
+    ) -> Result<(Vec<ChatRecord>, bool)> {
         let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT id, user_id, role, content, summary, model, created_at FROM messages 
-             WHERE user_id = ?1 ORDER BY id ASC",
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM messages
+             WHERE user_id = ?1 AND conversation_id = ?2 AND (?3 IS NULL OR id < ?3)
+             ORDER BY id DESC LIMIT ?4",
+            RECORD_COLUMNS
+        ))?;
+
+        // 多取一条用来判断是否还有更早的消息
+        let messages = stmt.query_map(
+            params![user_id, conversation_id, before_id, limit + 1],
+            record_from_row,
         )?;
 
-        let messages = stmt.query_map([user_id], |row| {
-            let created_at_str: String = row.get(6)?;
-            let created_at = DateTime::parse_from_rfc3339(&created_at_str)
-                .map(|dt| dt.with_timezone(&Utc))
-                .unwrap_or_else(|_| Utc::now());
+        let mut result: Vec<ChatRecord> = messages.filter_map(|m| m.ok()).collect();
+        let has_more = result.len() > limit;
+        result.truncate(limit);
+        result.reverse(); // 按时间正序排列
+        Ok((result, has_more))
+    }
 
-            Ok(ChatRecord {
-                id: row.get(0)?,
-                user_id: row.get(1)?,
-                role: row.get(2)?,
-                content: row.get(3)?,
-                summary: row.get(4)?,
-                model: row.get(5)?,
-                created_at,
-            })
-        })?;
+    /// 获取会话的所有消息
+    #[allow(dead_code)]
+    pub fn get_all_messages(&self, user_id: &str, conversation_id: i64) -> Result<Vec<ChatRecord>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM messages WHERE user_id = ?1 AND conversation_id = ?2 ORDER BY id ASC",
+            RECORD_COLUMNS
+        ))?;
+
+        let messages = stmt.query_map(params![user_id, conversation_id], record_from_row)?;
 
         Ok(messages.filter_map(|m| m.ok()).collect())
     }
 
-    /// 清除用户所有消息
+    /// 清除用户所有消息和会话
     pub fn clear_user_messages(&self, user_id: &str) -> Result<()> {
-        let conn = self.conn.lock().unwrap();
-        conn.execute("DELETE FROM messages WHERE user_id = ?1", [user_id])?;
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM tool_calls WHERE message_id IN
+                (SELECT id FROM messages WHERE user_id = ?1)",
+            [user_id],
+        )?;
+        tx.execute("DELETE FROM messages WHERE user_id = ?1", [user_id])?;
+        tx.execute(
+            "DELETE FROM conversation_files WHERE conversation_id IN
+                (SELECT id FROM conversations WHERE user_id = ?1)",
+            [user_id],
+        )?;
+        tx.execute("DELETE FROM conversations WHERE user_id = ?1", [user_id])?;
+        tx.commit()?;
+        drop(conn);
+
+        self.invalidate_index(user_id);
         Ok(())
     }
 
@@ -279,27 +1787,191 @@ impl ChatMemory {
         Ok(count as usize)
     }
 
-    /// 获取有嵌入的消息数量
-    #[allow(dead_code)]
-    pub fn embedded_message_count(&self) -> Result<usize> {
+    /// 获取有当前来源嵌入的消息数量
+    pub fn embedded_message_count(&self, embedding_model: &str) -> Result<usize> {
         let conn = self.conn.lock().unwrap();
         let count: i64 = conn.query_row(
-            "SELECT COUNT(*) FROM messages WHERE embedding IS NOT NULL",
-            [],
+            "SELECT COUNT(*) FROM messages WHERE embedding IS NOT NULL AND embedding_model = ?1",
+            [embedding_model],
             |row| row.get(0),
         )?;
         Ok(count as usize)
     }
 }
 
-/// 将检索到的相关消息格式化为上下文
-pub fn format_retrieved_context(messages: &[RetrievedMessage], max_chars: usize) -> String {
-    if messages.is_empty() {
-        return String::new();
+/// 创建镜像 `messages.content` 的 FTS5 全文索引
+///
+/// 使用 trigram 分词，中文和代码标识符都能按子串匹配
+fn create_fts_index(conn: &Connection) -> Result<()> {
+    let exists: bool = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'messages_fts')",
+        [],
+        |row| row.get(0),
+    )?;
+
+    conn.execute_batch(
+        "CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5(
+            content, content = 'messages', content_rowid = 'id', tokenize = 'trigram'
+        );
+        CREATE TRIGGER IF NOT EXISTS messages_fts_insert AFTER INSERT ON messages BEGIN
+            INSERT INTO messages_fts(rowid, content) VALUES (new.id, new.content);
+        END;
+        CREATE TRIGGER IF NOT EXISTS messages_fts_delete AFTER DELETE ON messages BEGIN
+            INSERT INTO messages_fts(messages_fts, rowid, content)
+                VALUES ('delete', old.id, old.content);
+        END;
+        CREATE TRIGGER IF NOT EXISTS messages_fts_update AFTER UPDATE OF content ON messages BEGIN
+            INSERT INTO messages_fts(messages_fts, rowid, content)
+                VALUES ('delete', old.id, old.content);
+            INSERT INTO messages_fts(rowid, content) VALUES (new.id, new.content);
+        END;",
+    )?;
+
+    // 旧数据库首次创建索引时导入已有消息
+    if !exists {
+        conn.execute(
+            "INSERT INTO messages_fts(messages_fts) VALUES ('rebuild')",
+            [],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// 将用户输入转换为 FTS5 查询
+///
+/// 标识符、文件名、错误码等整体作为短语；中文按 3 字滑动窗口切分。
+/// trigram 分词无法匹配少于 3 个字符的词，这些词会被忽略
+fn fts_query(text: &str) -> Option<String> {
+    fn is_cjk(c: char) -> bool {
+        matches!(c, '\u{3040}'..='\u{30ff}' | '\u{3400}'..='\u{4dbf}' | '\u{4e00}'..='\u{9fff}' | '\u{ac00}'..='\u{d7af}')
+    }
+    fn is_word(c: char) -> bool {
+        (c.is_alphanumeric() && !is_cjk(c)) || matches!(c, '_' | '-' | '.' | ':' | '/' | '#')
+    }
+
+    let mut terms: Vec<String> = Vec::new();
+    let mut push = |term: String| {
+        if term.chars().count() >= 3 && !terms.contains(&term) {
+            terms.push(term);
+        }
+    };
+
+    let chars: Vec<char> = text.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let start = i;
+        if is_cjk(chars[i]) {
+            while i < chars.len() && is_cjk(chars[i]) {
+                i += 1;
+            }
+            for window in chars[start..i].windows(3) {
+                push(window.iter().collect());
+            }
+        } else if is_word(chars[i]) {
+            while i < chars.len() && is_word(chars[i]) {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            push(
+                word.trim_matches(|c| matches!(c, '-' | '.' | ':' | '/' | '#'))
+                    .to_string(),
+            );
+        } else {
+            i += 1;
+        }
+    }
+
+    if terms.is_empty() {
+        return None;
+    }
+
+    Some(
+        terms
+            .iter()
+            .take(MAX_KEYWORD_TERMS)
+            .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+            .collect::<Vec<_>>()
+            .join(" OR "),
+    )
+}
+
+/// 将 FTS 片段中的高亮标记转换为 `<mark>`，其余文本做 HTML 转义
+fn highlight_snippet(snippet: &str) -> String {
+    let mut html = String::with_capacity(snippet.len() + 16);
+    for c in snippet.chars() {
+        match c {
+            HIGHLIGHT_START => html.push_str("<mark>"),
+            HIGHLIGHT_END => html.push_str("</mark>"),
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            c => html.push(c),
+        }
+    }
+    html
+}
+
+/// 倒数排名融合分数（`rank` 从 0 开始）
+fn rrf_score(rank: usize) -> f32 {
+    1.0 / (RRF_K + rank as f32 + 1.0)
+}
+
+/// 检查表中是否存在某列
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns = stmt.query_map([], |row| row.get::<_, String>(1))?;
+    for name in columns {
+        if name? == column {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// 将没有会话的旧消息按用户归入一个"历史对话"会话
+fn migrate_orphan_messages(conn: &Connection) -> Result<()> {
+    let mut stmt = conn.prepare(
+        "SELECT user_id, MIN(created_at), MAX(created_at) FROM messages
+         WHERE conversation_id IS NULL GROUP BY user_id",
+    )?;
+    let orphans: Vec<(String, String, String)> = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .filter_map(|r| r.ok())
+        .collect();
+
+    for (user_id, created_at, updated_at) in orphans {
+        conn.execute(
+            "INSERT INTO conversations (user_id, title, model, created_at, updated_at) VALUES (?1, ?2, NULL, ?3, ?4)",
+            params![user_id, "历史对话", created_at, updated_at],
+        )?;
+        let conversation_id = conn.last_insert_rowid();
+        conn.execute(
+            "UPDATE messages SET conversation_id = ?1 WHERE user_id = ?2 AND conversation_id IS NULL",
+            params![conversation_id, user_id],
+        )?;
     }
 
-    let mut context = String::from("以下是与当前问题相关的历史对话记录：\n\n");
-    let mut total_chars = context.len();
+    Ok(())
+}
+
+/// 将检索到的相关消息格式化为上下文，按顺序放入直到超出 token 预算
+///
+/// 每条消息以 `[编号]` 开头（从 `first_ref` 开始），便于模型引用。
+/// 返回 (上下文, 放入的条数)，一条都放不下时上下文为空字符串
+pub fn format_retrieved_context(
+    messages: &[RetrievedMessage],
+    first_ref: usize,
+    max_tokens: u64,
+) -> (String, usize) {
+    const HEADER: &str = "以下是与当前问题相关的历史对话记录，引用时请用 [编号] 标注来源：\n\n";
+    const FOOTER: &str = "---\n\n";
+
+    let mut context = String::from(HEADER);
+    let mut total_tokens = estimate_tokens(HEADER) + estimate_tokens(FOOTER);
+    let mut included = 0;
 
     for msg in messages {
         let role_label = if msg.record.role == "user" {
@@ -309,58 +1981,33 @@ pub fn format_retrieved_context(messages: &[RetrievedMessage], max_chars: usize)
         };
         // 优先使用摘要，如果没有则使用原始内容
         let content = msg.record.summary.as_ref().unwrap_or(&msg.record.content);
+        let relevance = match msg.similarity {
+            Some(similarity) => format!("相关度: {:.0}%", similarity * 100.0),
+            None => "关键词匹配".to_string(),
+        };
         let entry = format!(
-            "【{}】(相关度: {:.0}%): {}\n\n",
+            "[{}] 【{}】({}): {}\n\n",
+            first_ref + included,
             role_label,
-            msg.similarity * 100.0,
+            relevance,
             content
         );
+        let entry_tokens = estimate_tokens(&entry);
 
-        if total_chars + entry.len() > max_chars {
+        if total_tokens + entry_tokens > max_tokens {
             break;
         }
 
         context.push_str(&entry);
-        total_chars += entry.len();
-    }
-
-    context.push_str("---\n\n");
-    context
-}
-
-/// 将最近消息格式化为简短上下文
-pub fn format_recent_context(messages: &[ChatRecord], max_messages: usize) -> String {
-    if messages.is_empty() {
-        return String::new();
+        total_tokens += entry_tokens;
+        included += 1;
     }
 
-    let start_idx = if messages.len() > max_messages {
-        messages.len() - max_messages
-    } else {
-        0
-    };
-
-    let mut context = String::from("最近的对话：\n\n");
-
-    for msg in &messages[start_idx..] {
-        let role_label = if msg.role == "user" {
-            "用户"
-        } else {
-            "助手"
-        };
-        // 优先使用摘要
-        let content = msg.summary.as_ref().unwrap_or(&msg.content);
-        // 截断过长的内容
-        let truncated = if content.len() > 200 {
-            format!("{}...", &content[..200])
-        } else {
-            content.clone()
-        };
-        context.push_str(&format!("【{}】: {}\n\n", role_label, truncated));
+    if included == 0 {
+        return (String::new(), 0);
     }
-
-    context.push_str("---\n\n");
-    context
+    context.push_str(FOOTER);
+    (context, included)
 }
 
 #[cfg(test)]
@@ -368,16 +2015,22 @@ mod tests {
     use super::super::embedding::EMBEDDING_DIMENSION;
     use super::*;
 
+    const TEST_MODEL: &str = "test/model";
+
     #[test]
     fn test_memory_operations() {
         let memory = ChatMemory::new(":memory:").unwrap();
         let user_id = "test-user-123";
+        let conversation = memory.create_conversation(user_id, "测试", None).unwrap();
 
         // 添加消息
-        let id1 = memory.add_message(user_id, "user", "你好", None).unwrap();
+        let id1 = memory
+            .add_message(user_id, conversation.id, "user", "你好", None)
+            .unwrap();
         let id2 = memory
             .add_message(
                 user_id,
+                conversation.id,
                 "model",
                 "你好！有什么可以帮助你的？",
                 Some("flash"),
@@ -386,26 +2039,349 @@ mod tests {
 
         // 更新嵌入
         let fake_embedding = vec![0.1; EMBEDDING_DIMENSION];
-        memory.update_embedding(id1, &fake_embedding).unwrap();
-        memory.update_embedding(id2, &fake_embedding).unwrap();
+        memory
+            .update_embedding(id1, &fake_embedding, TEST_MODEL)
+            .unwrap();
+        memory
+            .update_embedding(id2, &fake_embedding, TEST_MODEL)
+            .unwrap();
 
         // 获取消息
-        let messages = memory.get_all_messages(user_id).unwrap();
+        let messages = memory.get_all_messages(user_id, conversation.id).unwrap();
         assert_eq!(messages.len(), 2);
 
         // 检索相似消息
         let results = memory
-            .retrieve_similar(user_id, &fake_embedding, 10, 0.0)
+            .retrieve_similar(
+                user_id,
+                Some(conversation.id),
+                &fake_embedding,
+                TEST_MODEL,
+                10,
+                0.0,
+            )
             .unwrap();
         assert_eq!(results.len(), 2);
 
         // 测试用户隔离
         let other_user = "other-user-456";
-        let other_messages = memory.get_all_messages(other_user).unwrap();
+        let other_messages = memory
+            .get_all_messages(other_user, conversation.id)
+            .unwrap();
         assert_eq!(other_messages.len(), 0);
 
         // 清除
         memory.clear_user_messages(user_id).unwrap();
         assert_eq!(memory.user_message_count(user_id).unwrap(), 0);
+        assert!(memory.list_conversations(user_id).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_conversation_scoping() {
+        let memory = ChatMemory::new(":memory:").unwrap();
+        let user_id = "test-user-123";
+        let first = memory.create_conversation(user_id, "第一个", None).unwrap();
+        let second = memory
+            .create_conversation(user_id, "第二个", Some("pro-2.5"))
+            .unwrap();
+
+        let fake_embedding = vec![0.1; EMBEDDING_DIMENSION];
+        for conversation_id in [first.id, second.id] {
+            let id = memory
+                .add_message(user_id, conversation_id, "user", "内容", None)
+                .unwrap();
+            memory
+                .update_embedding(id, &fake_embedding, TEST_MODEL)
+                .unwrap();
+        }
+
+        // 最近消息和检索默认只在当前会话内
+        let recent = memory.get_recent_messages(user_id, first.id, 10).unwrap();
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].conversation_id, Some(first.id));
+        let scoped = memory
+            .retrieve_similar(
+                user_id,
+                Some(first.id),
+                &fake_embedding,
+                TEST_MODEL,
+                10,
+                0.0,
+            )
+            .unwrap();
+        assert_eq!(scoped.len(), 1);
+
+        // 跨会话检索
+        let all = memory
+            .retrieve_similar(user_id, None, &fake_embedding, TEST_MODEL, 10, 0.0)
+            .unwrap();
+        assert_eq!(all.len(), 2);
+
+        // 重命名和删除只对自己的会话生效
+        assert!(
+            memory
+                .rename_conversation(user_id, first.id, "新标题")
+                .unwrap()
+        );
+        assert!(!memory.rename_conversation("other", first.id, "x").unwrap());
+        assert_eq!(
+            memory
+                .get_conversation(user_id, first.id)
+                .unwrap()
+                .unwrap()
+                .title,
+            "新标题"
+        );
+
+        assert!(memory.delete_conversation(user_id, second.id).unwrap());
+        assert_eq!(memory.list_conversations(user_id).unwrap().len(), 1);
+        assert_eq!(memory.user_message_count(user_id).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_file_library() {
+        let memory = ChatMemory::new(":memory:").unwrap();
+        let first = memory
+            .save_file("u", "a.txt", "text/plain", "hash-a", b"hello")
+            .unwrap();
+        let second = memory
+            .save_file("u", "b.png", "image/png", "hash-b", b"\x89PNG")
+            .unwrap();
+        // 相同内容再次上传时复用已有记录
+        let again = memory
+            .save_file("u", "copy.txt", "text/plain", "hash-a", b"hello")
+            .unwrap();
+        assert_eq!(again.id, first.id);
+        assert_eq!(again.name, "a.txt");
+        // 其他用户上传相同内容是独立的文件
+        let other = memory
+            .save_file("other", "a.txt", "text/plain", "hash-a", b"hello")
+            .unwrap();
+        assert_ne!(other.id, first.id);
+
+        assert_eq!(memory.list_files("u").unwrap().len(), 2);
+        let loaded = memory
+            .load_files("u", &[second.id, other.id, first.id])
+            .unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[0].0.id, second.id);
+        assert_eq!(loaded[1].1, b"hello");
+
+        // 会话上下文只保存属于该用户的文件
+        let conversation = memory.create_conversation("u", "对话", None).unwrap();
+        let attached = memory
+            .set_conversation_files("u", conversation.id, &[first.id, second.id, other.id])
+            .unwrap();
+        assert_eq!(attached.len(), 2);
+
+        memory
+            .set_file_remote_uri(second.id, "https://example.com/f")
+            .unwrap();
+        assert!(memory.delete_file("u", first.id).unwrap());
+        assert!(!memory.delete_file("u", other.id).unwrap());
+        let attached = memory.get_conversation_files("u", conversation.id).unwrap();
+        assert_eq!(attached.len(), 1);
+        assert_eq!(
+            attached[0].remote_uri.as_deref(),
+            Some("https://example.com/f")
+        );
+    }
+
+    #[test]
+    fn test_messages_page() {
+        let memory = ChatMemory::new(":memory:").unwrap();
+        let conversation = memory.create_conversation("u", "测试", None).unwrap();
+        let ids: Vec<i64> = (0..5)
+            .map(|i| {
+                memory
+                    .add_message("u", conversation.id, "user", &i.to_string(), None)
+                    .unwrap()
+            })
+            .collect();
+
+        let (page, has_more) = memory
+            .get_messages_page("u", conversation.id, None, 2)
+            .unwrap();
+        assert!(has_more);
+        assert_eq!(
+            page.iter().map(|r| r.id).collect::<Vec<_>>(),
+            vec![ids[3], ids[4]]
+        );
+
+        let (page, has_more) = memory
+            .get_messages_page("u", conversation.id, Some(ids[3]), 2)
+            .unwrap();
+        assert!(has_more);
+        assert_eq!(page[0].id, ids[1]);
+
+        let (page, has_more) = memory
+            .get_messages_page("u", conversation.id, Some(ids[1]), 2)
+            .unwrap();
+        assert!(!has_more);
+        assert_eq!(page.len(), 1);
+
+        let (page, _) = memory
+            .get_messages_page("other", conversation.id, None, 2)
+            .unwrap();
+        assert!(page.is_empty());
+    }
+
+    #[test]
+    fn test_embedding_model_isolation() {
+        let memory = ChatMemory::new(":memory:").unwrap();
+        let conversation = memory.create_conversation("u", "测试", None).unwrap();
+        let id1 = memory
+            .add_message("u", conversation.id, "user", "一", None)
+            .unwrap();
+        let id2 = memory
+            .add_message("u", conversation.id, "user", "二", None)
+            .unwrap();
+        memory
+            .update_embedding(id1, &[1.0, 0.0], "local/hash-ngram-2")
+            .unwrap();
+        memory
+            .update_embedding(id2, &[1.0, 0.0], TEST_MODEL)
+            .unwrap();
+
+        // 只检索同一来源的嵌入
+        let results = memory
+            .retrieve_similar("u", None, &[1.0, 0.0], TEST_MODEL, 10, 0.0)
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].record.id, id2);
+
+        // 其他来源的嵌入视为待补全
+        let pending = memory
+            .get_messages_without_embedding(TEST_MODEL, 10)
+            .unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, id1);
+        assert_eq!(memory.embedded_message_count(TEST_MODEL).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_hybrid_retrieval() {
+        let memory = ChatMemory::new(":memory:").unwrap();
+        let conversation = memory.create_conversation("u", "测试", None).unwrap();
+        let error_id = memory
+            .add_message(
+                "u",
+                conversation.id,
+                "user",
+                "编译报错 E0382: borrow of moved value",
+                None,
+            )
+            .unwrap();
+        let file_id = memory
+            .add_message(
+                "u",
+                conversation.id,
+                "model",
+                "请检查 src/main.rs 第 10 行",
+                None,
+            )
+            .unwrap();
+        let other_id = memory
+            .add_message("u", conversation.id, "user", "今天天气怎么样", None)
+            .unwrap();
+        memory
+            .add_message("other", conversation.id, "user", "E0382 又来了", None)
+            .unwrap();
+
+        // 没有嵌入时仍然可以按关键词检索，且只返回自己的消息
+        let results = memory
+            .retrieve_hybrid("u", None, "E0382 是什么错误", None, TEST_MODEL, 5, 0.5)
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].record.id, error_id);
+        assert_eq!(results[0].similarity, None);
+
+        let results = memory
+            .retrieve_hybrid("u", None, "main.rs 怎么改", None, TEST_MODEL, 5, 0.5)
+            .unwrap();
+        assert_eq!(results[0].record.id, file_id);
+
+        // 向量和关键词同时命中的消息排在前面
+        memory
+            .update_embedding(error_id, &[1.0, 0.0], TEST_MODEL)
+            .unwrap();
+        memory
+            .update_embedding(other_id, &[0.8, 0.6], TEST_MODEL)
+            .unwrap();
+        let results = memory
+            .retrieve_hybrid(
+                "u",
+                Some(conversation.id),
+                "E0382",
+                Some(&[1.0, 0.0]),
+                TEST_MODEL,
+                5,
+                0.5,
+            )
+            .unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].record.id, error_id);
+        assert_eq!(results[0].similarity, Some(1.0));
+        assert_eq!(results[1].record.id, other_id);
+    }
+
+    #[test]
+    fn test_fts_query() {
+        assert_eq!(
+            fts_query("E0382 是什么"),
+            Some("\"E0382\" OR \"是什么\"".to_string())
+        );
+        assert_eq!(
+            fts_query("读取文件"),
+            Some("\"读取文\" OR \"取文件\"".to_string())
+        );
+        assert_eq!(fts_query("a \"b\" 你好"), None);
+        assert_eq!(
+            fts_query("打开 `main.rs`."),
+            Some("\"main.rs\"".to_string())
+        );
+    }
+
+    #[test]
+    fn test_migrate_orphan_messages() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE conversations (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id TEXT NOT NULL,
+                title TEXT NOT NULL,
+                model TEXT,
+                created_at TEXT NOT NULL,
+                updated_at TEXT NOT NULL
+            );
+            CREATE TABLE messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id TEXT NOT NULL,
+                conversation_id INTEGER,
+                role TEXT NOT NULL,
+                content TEXT NOT NULL,
+                created_at TEXT NOT NULL
+            );
+            INSERT INTO messages (user_id, role, content, created_at) VALUES
+                ('a', 'user', '1', '2024-01-01T00:00:00+00:00'),
+                ('a', 'model', '2', '2024-01-02T00:00:00+00:00'),
+                ('b', 'user', '3', '2024-01-03T00:00:00+00:00');",
+        )
+        .unwrap();
+
+        migrate_orphan_messages(&conn).unwrap();
+
+        let conversations: i64 = conn
+            .query_row("SELECT COUNT(*) FROM conversations", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(conversations, 2);
+        let orphans: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM messages WHERE conversation_id IS NULL",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(orphans, 0);
     }
 }
@@ -1,3 +1,22 @@
+pub mod ann;
+pub mod attachment;
+pub mod auth;
+pub mod backfill;
+pub mod budget;
+pub mod chat;
+pub mod completions;
+pub mod documents;
 pub mod embedding;
+pub mod files;
 pub mod gemini;
+pub mod history;
 pub mod memory;
+pub mod ollama;
+pub mod openai;
+pub mod provider;
+pub mod rate_limit;
+#[cfg(feature = "code-execution")]
+pub mod sandbox;
+pub mod summarizer;
+pub mod tools;
+pub mod usage;
@@ -0,0 +1,264 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use super::provider::{
+    AttachmentSource, ChatPrompt, ChatProvider, ChatRequest, ChatResult, ChatRole, ModelMap,
+    StreamAccumulator, StreamChunk, StreamDelta, read_text_stream,
+};
+use crate::models::gemini::GeminiModel;
+use crate::models::messages::TokenUsage;
+
+/// 没有收到回复时的占位文本
+const EMPTY_REPLY: &str = "没有收到模型的回复";
+
+/// Ollama `/api/chat` 后端
+pub struct OllamaProvider {
+    base_url: String,
+    models: ModelMap,
+}
+
+#[derive(Serialize, Debug)]
+struct OllamaRequest {
+    model: String,
+    messages: Vec<OllamaChatMessage>,
+    stream: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct OllamaChatMessage {
+    role: &'static str,
+    content: String,
+    /// base64 编码的图片（需要视觉模型，如 llava、qwen2.5vl）
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    images: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OllamaResponse {
+    message: Option<OllamaMessage>,
+    error: Option<String>,
+    /// 只在最后一个响应中出现（思考过程的 token 计入 eval_count）
+    prompt_eval_count: Option<u64>,
+    eval_count: Option<u64>,
+}
+
+impl OllamaResponse {
+    fn usage(&self) -> Option<TokenUsage> {
+        if self.prompt_eval_count.is_none() && self.eval_count.is_none() {
+            return None;
+        }
+        Some(TokenUsage {
+            prompt_tokens: self.prompt_eval_count.unwrap_or(0),
+            candidate_tokens: self.eval_count.unwrap_or(0),
+            thought_tokens: 0,
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct OllamaMessage {
+    #[serde(default)]
+    content: String,
+    /// 开启思考的模型（如 qwen3、deepseek-r1）返回的思考过程
+    thinking: Option<String>,
+}
+
+impl OllamaProvider {
+    pub fn new(base_url: String, models: ModelMap) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            models,
+        }
+    }
+
+    async fn post(&self, request: ChatRequest, stream: bool) -> Result<reqwest::Response, String> {
+        let body = OllamaRequest {
+            model: self.models.resolve(request.model),
+            messages: build_messages(request.prompt),
+            stream,
+        };
+
+        let client = reqwest::Client::new();
+        let response = client
+            .post(format!("{}/api/chat", self.base_url))
+            .json(&body)
+            .send()
+            .await
+            .map_err(|e| format!("发送请求失败: {}", e))?;
+
+        if !response.status().is_success() {
+            let error_text = response
+                .text()
+                .await
+                .unwrap_or_else(|_| "未知错误".to_string());
+            return Err(format!("Ollama API 错误: {}", error_text));
+        }
+
+        Ok(response)
+    }
+}
+
+#[async_trait]
+impl ChatProvider for OllamaProvider {
+    fn name(&self) -> &'static str {
+        "ollama"
+    }
+
+    fn model_name(&self, model: GeminiModel) -> String {
+        self.models.resolve(model)
+    }
+
+    async fn chat(&self, request: ChatRequest) -> Result<ChatResult, String> {
+        let response = self.post(request, false).await?;
+        let body: OllamaResponse = response
+            .json()
+            .await
+            .map_err(|e| format!("解析响应失败: {}", e))?;
+
+        if let Some(error) = body.error {
+            return Err(format!("Ollama API 错误: {}", error));
+        }
+
+        let usage = body.usage();
+        let message = body.message.unwrap_or_default();
+        Ok(ChatResult {
+            response: if message.content.is_empty() {
+                EMPTY_REPLY.to_string()
+            } else {
+                message.content
+            },
+            thinking: message.thinking.filter(|t| !t.is_empty()),
+            usage,
+            tool_calls: Vec::new(),
+        })
+    }
+
+    async fn chat_stream(
+        &self,
+        request: ChatRequest,
+        on_delta: &mut (dyn FnMut(StreamDelta) + Send),
+    ) -> Result<ChatResult, String> {
+        let response = self.post(request, true).await?;
+
+        // Ollama 的流式响应是每行一个 JSON 对象
+        let mut buffer = String::new();
+        let mut result = StreamAccumulator::default();
+
+        read_text_stream(response, |text| {
+            buffer.push_str(text);
+            while let Some(end) = buffer.find('\n') {
+                let line: String = buffer.drain(..=end).collect();
+                result.push_chunk(parse_stream_line(&line)?, on_delta);
+            }
+            Ok(())
+        })
+        .await?;
+
+        result.push_chunk(parse_stream_line(&buffer)?, on_delta);
+
+        Ok(result.finish(EMPTY_REPLY))
+    }
+}
+
+/// 解析流式响应中的一行
+/// 转换为 Ollama 消息格式，图片附件放在最后一条用户消息中，其他附件以文字说明代替
+fn build_messages(prompt: ChatPrompt) -> Vec<OllamaChatMessage> {
+    let mut messages: Vec<OllamaChatMessage> = prompt
+        .system_instruction
+        .map(|content| OllamaChatMessage {
+            role: "system",
+            content,
+            images: Vec::new(),
+        })
+        .into_iter()
+        .chain(prompt.turns.into_iter().map(|turn| OllamaChatMessage {
+            role: match turn.role {
+                ChatRole::User => "user",
+                ChatRole::Model => "assistant",
+            },
+            content: turn.content,
+            images: Vec::new(),
+        }))
+        .collect();
+
+    if let Some(last) = messages.last_mut() {
+        for attachment in prompt.attachments {
+            match attachment.source {
+                AttachmentSource::Inline(data) if attachment.is_image() => last.images.push(data),
+                _ => {
+                    last.content.push_str("\n\n");
+                    last.content.push_str(&attachment.unsupported_notice());
+                }
+            }
+        }
+    }
+    messages
+}
+
+fn parse_stream_line(line: &str) -> Result<StreamChunk, String> {
+    if line.trim().is_empty() {
+        return Ok(StreamChunk::default());
+    }
+
+    let chunk: OllamaResponse =
+        serde_json::from_str(line).map_err(|e| format!("解析流式响应失败: {}", e))?;
+    if let Some(error) = chunk.error {
+        return Err(format!("Ollama API 错误: {}", error));
+    }
+
+    let usage = chunk.usage();
+    let mut deltas = Vec::new();
+    if let Some(message) = chunk.message {
+        if let Some(thinking) = message.thinking.filter(|t| !t.is_empty()) {
+            deltas.push(StreamDelta::Thinking(thinking));
+        }
+        if !message.content.is_empty() {
+            deltas.push(StreamDelta::Response(message.content));
+        }
+    }
+
+    Ok(StreamChunk {
+        deltas,
+        usage,
+        ..StreamChunk::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::provider::{ChatPrompt, test_support::mock_server};
+    use super::*;
+
+    #[tokio::test]
+    async fn test_chat_stream_with_mock_server() {
+        let body = [
+            r#"{"message":{"role":"assistant","content":"","thinking":"想"},"done":false}"#,
+            r#"{"message":{"role":"assistant","content":"你好"},"done":false}"#,
+            r#"{"message":{"role":"assistant","content":"！"},"done":true,"prompt_eval_count":7,"eval_count":4}"#,
+        ]
+        .join("\n");
+        let (base_url, request_body) = mock_server("application/x-ndjson", body).await;
+
+        let provider = OllamaProvider::new(base_url, ModelMap::new("llama3.1", ""));
+        let mut deltas = Vec::new();
+        let result = provider
+            .chat_stream(
+                ChatRequest {
+                    prompt: ChatPrompt::from_history(None, &[], "hi"),
+                    model: GeminiModel::Flash,
+                },
+                &mut |delta| deltas.push(delta),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(result.response, "你好！");
+        assert_eq!(result.thinking.as_deref(), Some("想"));
+        assert_eq!(result.usage.map(|u| u.total()), Some(11));
+        assert_eq!(deltas.len(), 3);
+
+        let sent: serde_json::Value = serde_json::from_str(&request_body.await.unwrap()).unwrap();
+        assert_eq!(sent["model"], "llama3.1");
+        assert_eq!(sent["stream"], true);
+    }
+}
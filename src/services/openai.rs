@@ -0,0 +1,388 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use super::provider::{
+    Attachment, AttachmentSource, ChatPrompt, ChatProvider, ChatRequest, ChatResult, ChatRole,
+    ModelMap, SseBuffer, StreamAccumulator, StreamChunk, StreamDelta, read_text_stream,
+};
+use crate::models::gemini::GeminiModel;
+use crate::models::messages::TokenUsage;
+
+/// 没有收到回复时的占位文本
+const EMPTY_REPLY: &str = "没有收到模型的回复";
+
+/// OpenAI 兼容的 `/v1/chat/completions` 后端（vLLM、llama.cpp server、LM Studio 等）
+pub struct OpenAiProvider {
+    base_url: String,
+    api_key: Option<String>,
+    models: ModelMap,
+}
+
+#[derive(Serialize, Debug)]
+struct CompletionRequest {
+    model: String,
+    messages: Vec<CompletionMessage>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
+}
+
+/// 流式请求时要求在最后一个块中返回用量
+#[derive(Serialize, Debug)]
+struct StreamOptions {
+    include_usage: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct CompletionMessage {
+    role: &'static str,
+    content: MessageContent,
+}
+
+/// 消息内容：纯文本，或带附件时的内容片段数组
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Serialize, Debug)]
+struct ImageUrl {
+    url: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct CompletionResponse {
+    #[serde(default)]
+    choices: Vec<CompletionChoice>,
+    usage: Option<CompletionUsage>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CompletionUsage {
+    #[serde(default)]
+    prompt_tokens: u64,
+    /// 包含推理 token
+    #[serde(default)]
+    completion_tokens: u64,
+    completion_tokens_details: Option<CompletionTokensDetails>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CompletionTokensDetails {
+    #[serde(default)]
+    reasoning_tokens: u64,
+}
+
+impl From<CompletionUsage> for TokenUsage {
+    fn from(usage: CompletionUsage) -> Self {
+        let thought_tokens = usage
+            .completion_tokens_details
+            .map_or(0, |d| d.reasoning_tokens)
+            .min(usage.completion_tokens);
+        TokenUsage {
+            prompt_tokens: usage.prompt_tokens,
+            candidate_tokens: usage.completion_tokens - thought_tokens,
+            thought_tokens,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct CompletionChoice {
+    #[serde(alias = "delta")]
+    message: Option<CompletionContent>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct CompletionContent {
+    content: Option<String>,
+    /// 推理模型（如 DeepSeek-R1、QwQ）通过 vLLM 返回的思考过程
+    reasoning_content: Option<String>,
+}
+
+impl OpenAiProvider {
+    pub fn new(base_url: String, api_key: Option<String>, models: ModelMap) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key,
+            models,
+        }
+    }
+
+    async fn post(&self, request: ChatRequest, stream: bool) -> Result<reqwest::Response, String> {
+        let body = CompletionRequest {
+            model: self.models.resolve(request.model),
+            messages: build_messages(request.prompt),
+            stream,
+            stream_options: stream.then_some(StreamOptions {
+                include_usage: true,
+            }),
+        };
+
+        let client = reqwest::Client::new();
+        let mut builder = client
+            .post(format!("{}/chat/completions", self.base_url))
+            .json(&body);
+        if let Some(ref key) = self.api_key {
+            builder = builder.bearer_auth(key);
+        }
+
+        let response = builder
+            .send()
+            .await
+            .map_err(|e| format!("发送请求失败: {}", e))?;
+
+        if !response.status().is_success() {
+            let error_text = response
+                .text()
+                .await
+                .unwrap_or_else(|_| "未知错误".to_string());
+            return Err(format!("模型 API 错误: {}", error_text));
+        }
+
+        Ok(response)
+    }
+}
+
+#[async_trait]
+impl ChatProvider for OpenAiProvider {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    fn model_name(&self, model: GeminiModel) -> String {
+        self.models.resolve(model)
+    }
+
+    async fn chat(&self, request: ChatRequest) -> Result<ChatResult, String> {
+        let response = self.post(request, false).await?;
+        let completion: CompletionResponse = response
+            .json()
+            .await
+            .map_err(|e| format!("解析响应失败: {}", e))?;
+
+        let usage = completion.usage.map(TokenUsage::from);
+        let content = completion
+            .choices
+            .into_iter()
+            .next()
+            .and_then(|c| c.message)
+            .unwrap_or_default();
+
+        Ok(ChatResult {
+            response: content
+                .content
+                .filter(|c| !c.is_empty())
+                .unwrap_or_else(|| EMPTY_REPLY.to_string()),
+            thinking: content.reasoning_content.filter(|c| !c.is_empty()),
+            usage,
+            tool_calls: Vec::new(),
+        })
+    }
+
+    async fn chat_stream(
+        &self,
+        request: ChatRequest,
+        on_delta: &mut (dyn FnMut(StreamDelta) + Send),
+    ) -> Result<ChatResult, String> {
+        let response = self.post(request, true).await?;
+
+        let mut sse = SseBuffer::default();
+        let mut result = StreamAccumulator::default();
+
+        read_text_stream(response, |text| {
+            for data in sse.push(text) {
+                result.push_chunk(parse_stream_chunk(&data)?, on_delta);
+            }
+            Ok(())
+        })
+        .await?;
+
+        for data in sse.finish() {
+            result.push_chunk(parse_stream_chunk(&data)?, on_delta);
+        }
+
+        Ok(result.finish(EMPTY_REPLY))
+    }
+}
+
+/// 转换为 OpenAI 消息格式，附件放在最后一条用户消息中
+fn build_messages(prompt: ChatPrompt) -> Vec<CompletionMessage> {
+    let last_index = prompt.turns.len().saturating_sub(1);
+    let mut attachments = Some(prompt.attachments).filter(|a| !a.is_empty());
+
+    prompt
+        .system_instruction
+        .map(|content| CompletionMessage {
+            role: "system",
+            content: MessageContent::Text(content),
+        })
+        .into_iter()
+        .chain(
+            prompt
+                .turns
+                .into_iter()
+                .enumerate()
+                .map(|(index, turn)| CompletionMessage {
+                    role: match turn.role {
+                        ChatRole::User => "user",
+                        ChatRole::Model => "assistant",
+                    },
+                    content: match attachments.take_if(|_| index == last_index) {
+                        Some(attachments) => {
+                            MessageContent::Parts(content_parts(turn.content, attachments))
+                        }
+                        None => MessageContent::Text(turn.content),
+                    },
+                }),
+        )
+        .collect()
+}
+
+/// 图片以 data URL 发送，其他附件（PDF、音频等）大多数兼容后端不支持，以文字说明代替
+fn content_parts(text: String, attachments: Vec<Attachment>) -> Vec<ContentPart> {
+    attachments
+        .into_iter()
+        .map(|attachment| match &attachment.source {
+            AttachmentSource::Inline(data) if attachment.is_image() => ContentPart::ImageUrl {
+                image_url: ImageUrl {
+                    url: format!("data:{};base64,{}", attachment.mime_type, data),
+                },
+            },
+            _ => ContentPart::Text {
+                text: attachment.unsupported_notice(),
+            },
+        })
+        .chain(std::iter::once(ContentPart::Text { text }))
+        .collect()
+}
+
+/// 解析单个流式响应块中的增量内容和用量
+fn parse_stream_chunk(data: &str) -> Result<StreamChunk, String> {
+    if data.trim() == "[DONE]" {
+        return Ok(StreamChunk::default());
+    }
+
+    let chunk: CompletionResponse =
+        serde_json::from_str(data).map_err(|e| format!("解析流式响应失败: {}", e))?;
+
+    let mut deltas = Vec::new();
+    if let Some(delta) = chunk.choices.into_iter().next().and_then(|c| c.message) {
+        if let Some(thinking) = delta.reasoning_content.filter(|t| !t.is_empty()) {
+            deltas.push(StreamDelta::Thinking(thinking));
+        }
+        if let Some(content) = delta.content.filter(|c| !c.is_empty()) {
+            deltas.push(StreamDelta::Response(content));
+        }
+    }
+
+    Ok(StreamChunk {
+        deltas,
+        usage: chunk.usage.map(TokenUsage::from),
+        ..StreamChunk::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::provider::test_support::mock_server;
+    use super::*;
+
+    fn request() -> ChatRequest {
+        ChatRequest {
+            prompt: ChatPrompt::from_history(Some("系统".to_string()), &[], "你好"),
+            model: GeminiModel::Flash,
+        }
+    }
+
+    #[test]
+    fn test_build_messages_with_attachments() {
+        let mut prompt = ChatPrompt::from_history(Some("系统".to_string()), &[], "看看这些");
+        prompt.attachments = vec![
+            Attachment {
+                name: "a.png".to_string(),
+                mime_type: "image/png".to_string(),
+                source: AttachmentSource::Inline("iVBORw0=".to_string()),
+            },
+            Attachment {
+                name: "spec.pdf".to_string(),
+                mime_type: "application/pdf".to_string(),
+                source: AttachmentSource::Inline("JVBERi0=".to_string()),
+            },
+        ];
+
+        let json = serde_json::to_value(build_messages(prompt)).unwrap();
+        assert_eq!(json[0]["content"], "系统");
+        let parts = &json[1]["content"];
+        assert_eq!(parts[0]["type"], "image_url");
+        assert_eq!(
+            parts[0]["image_url"]["url"],
+            "data:image/png;base64,iVBORw0="
+        );
+        assert_eq!(parts[1]["type"], "text");
+        assert!(parts[1]["text"].as_str().unwrap().contains("spec.pdf"));
+        assert_eq!(parts[2]["text"], "看看这些");
+    }
+
+    #[tokio::test]
+    async fn test_chat_with_mock_server() {
+        let body = r#"{"choices":[{"message":{"role":"assistant","content":"你好！"}}],"usage":{"prompt_tokens":9,"completion_tokens":5,"completion_tokens_details":{"reasoning_tokens":2}}}"#;
+        let (base_url, request_body) = mock_server("application/json", body.to_string()).await;
+
+        let provider = OpenAiProvider::new(
+            format!("{}/v1", base_url),
+            None,
+            ModelMap::new("local-model", ""),
+        );
+        let result = provider.chat(request()).await.unwrap();
+        assert_eq!(result.response, "你好！");
+        assert_eq!(
+            result.usage,
+            Some(TokenUsage {
+                prompt_tokens: 9,
+                candidate_tokens: 3,
+                thought_tokens: 2,
+            })
+        );
+
+        let sent: serde_json::Value = serde_json::from_str(&request_body.await.unwrap()).unwrap();
+        assert_eq!(sent["model"], "local-model");
+        assert_eq!(sent["messages"][0]["role"], "system");
+        assert_eq!(sent["messages"][1]["content"], "你好");
+        assert!(sent.get("stream_options").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_chat_stream_with_mock_server() {
+        let body = [
+            r#"{"choices":[{"delta":{"reasoning_content":"嗯"}}]}"#,
+            r#"{"choices":[{"delta":{"content":"你"}}]}"#,
+            r#"{"choices":[{"delta":{"content":"好"}}]}"#,
+            "[DONE]",
+        ]
+        .iter()
+        .map(|e| format!("data: {}\n\n", e))
+        .collect();
+        let (base_url, _) = mock_server("text/event-stream", body).await;
+
+        let provider = OpenAiProvider::new(base_url, None, ModelMap::new("local-model", ""));
+        let mut deltas = Vec::new();
+        let result = provider
+            .chat_stream(request(), &mut |delta| deltas.push(delta))
+            .await
+            .unwrap();
+
+        assert_eq!(result.response, "你好");
+        assert_eq!(result.thinking.as_deref(), Some("嗯"));
+        assert_eq!(deltas.len(), 3);
+    }
+}
@@ -0,0 +1,575 @@
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+
+use super::gemini::GeminiProvider;
+use super::memory::ChatRecord;
+use super::ollama::OllamaProvider;
+use super::openai::OpenAiProvider;
+use crate::models::gemini::GeminiModel;
+use crate::models::messages::{FileContext, TokenUsage};
+
+/// 对话角色
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatRole {
+    User,
+    Model,
+}
+
+/// 对话中的一条消息
+#[derive(Debug, Clone)]
+pub struct ChatTurn {
+    pub role: ChatRole,
+    pub content: String,
+}
+
+/// 随用户消息发送的二进制附件（图片、PDF、音频）
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attachment {
+    pub name: String,
+    pub mime_type: String,
+    pub source: AttachmentSource,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttachmentSource {
+    /// base64 编码的数据
+    Inline(String),
+    /// 已上传到后端文件存储（Gemini Files API）的 URI
+    Uri(String),
+}
+
+impl Attachment {
+    /// 由文件上下文创建附件，文本文件返回 None
+    pub fn from_file(file: &FileContext) -> Option<Self> {
+        let source = match (&file.file_uri, &file.data) {
+            (Some(uri), _) => AttachmentSource::Uri(uri.clone()),
+            (None, Some(data)) => AttachmentSource::Inline(data.clone()),
+            (None, None) => return None,
+        };
+        Some(Self {
+            name: file.name.clone(),
+            mime_type: file
+                .mime_type
+                .clone()
+                .unwrap_or_else(|| "application/octet-stream".to_string()),
+            source,
+        })
+    }
+
+    pub fn is_image(&self) -> bool {
+        self.mime_type.starts_with("image/")
+    }
+
+    /// 后端无法接收该附件时代替它放入消息的说明
+    pub fn unsupported_notice(&self) -> String {
+        format!(
+            "（附件 {}（{}）无法发送给当前模型）",
+            self.name, self.mime_type
+        )
+    }
+}
+
+/// 提供给模型的工具说明
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolDeclaration {
+    pub name: String,
+    pub description: String,
+    /// 参数的 JSON Schema
+    pub parameters: serde_json::Value,
+}
+
+/// 模型请求的一次工具调用
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolCall {
+    pub name: String,
+    pub args: serde_json::Value,
+}
+
+/// 工具的执行结果
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolResult {
+    pub name: String,
+    /// 执行失败时为错误说明，同样返回给模型
+    pub output: Result<serde_json::Value, String>,
+}
+
+impl ToolResult {
+    /// 返回给模型的结果对象：非对象的结果包装为 `{"result": ...}`，失败时为 `{"error": ...}`
+    pub fn response(&self) -> serde_json::Value {
+        match &self.output {
+            Ok(value @ serde_json::Value::Object(_)) => value.clone(),
+            Ok(value) => serde_json::json!({ "result": value }),
+            Err(e) => serde_json::json!({ "error": e }),
+        }
+    }
+}
+
+/// 一轮工具调用：模型的调用请求（及同时输出的文本）和对应的执行结果
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolRound {
+    pub text: String,
+    pub calls: Vec<ToolCall>,
+    pub results: Vec<ToolResult>,
+}
+
+/// 发送给模型的完整对话
+#[derive(Debug, Clone)]
+pub struct ChatPrompt {
+    /// 系统指令（检索到的记忆、文件上下文等）
+    pub system_instruction: Option<String>,
+    /// 以 user 开头、user / model 交替排列的对话内容
+    pub turns: Vec<ChatTurn>,
+    /// 随最后一条用户消息发送的附件
+    pub attachments: Vec<Attachment>,
+    /// 模型可以调用的工具（后端不支持时忽略）
+    pub tools: Vec<ToolDeclaration>,
+    /// 最后一条用户消息之后已完成的工具调用
+    pub tool_rounds: Vec<ToolRound>,
+}
+
+impl ChatPrompt {
+    /// 由最近的历史消息和当前用户消息构建对话
+    pub fn from_history(
+        system_instruction: Option<String>,
+        history: &[ChatRecord],
+        user_message: &str,
+    ) -> Self {
+        let messages = history
+            .iter()
+            .map(|record| {
+                let role = if record.role == "model" {
+                    ChatRole::Model
+                } else {
+                    ChatRole::User
+                };
+                (role, record.content.as_str())
+            })
+            .chain(std::iter::once((ChatRole::User, user_message)));
+
+        Self::from_messages(system_instruction, messages)
+    }
+
+    /// 由任意消息序列构建对话，保证以 user 开头且角色交替
+    pub fn from_messages<'a>(
+        system_instruction: Option<String>,
+        messages: impl IntoIterator<Item = (ChatRole, &'a str)>,
+    ) -> Self {
+        let mut turns: Vec<ChatTurn> = Vec::new();
+
+        for (role, text) in messages {
+            // 对话必须以 user 开头
+            if turns.is_empty() && role == ChatRole::Model {
+                continue;
+            }
+
+            // 连续相同角色的消息合并为一条（例如上一次请求失败时）
+            if let Some(last) = turns.last_mut()
+                && last.role == role
+            {
+                last.content.push_str("\n\n");
+                last.content.push_str(text);
+                continue;
+            }
+
+            turns.push(ChatTurn {
+                role,
+                content: text.to_string(),
+            });
+        }
+
+        Self {
+            system_instruction,
+            turns,
+            attachments: Vec::new(),
+            tools: Vec::new(),
+            tool_rounds: Vec::new(),
+        }
+    }
+}
+
+/// 一次对话请求
+#[derive(Debug, Clone)]
+pub struct ChatRequest {
+    pub prompt: ChatPrompt,
+    /// 用户选择的模型档位，由各后端映射为实际的模型名
+    pub model: GeminiModel,
+}
+
+/// 模型调用结果
+#[derive(Debug, Clone)]
+pub struct ChatResult {
+    pub response: String,
+    pub thinking: Option<String>,
+    /// 后端返回的 token 用量（不提供用量的后端为 None）
+    pub usage: Option<TokenUsage>,
+    /// 模型请求的工具调用，不为空时需要执行后继续对话
+    pub tool_calls: Vec<ToolCall>,
+}
+
+/// 流式输出的增量片段
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamDelta {
+    /// 回复文本片段
+    Response(String),
+    /// 思考过程片段
+    Thinking(String),
+    /// 开始执行工具调用（由聊天流程发出，`id` 在一次回复内唯一）
+    ToolCall { id: String, call: ToolCall },
+    /// 工具调用执行完毕
+    ToolResult { id: String, result: ToolResult },
+}
+
+/// 聊天模型后端
+#[async_trait]
+pub trait ChatProvider: Send + Sync {
+    /// 后端名称（用于日志）
+    fn name(&self) -> &'static str;
+
+    /// 该档位实际使用的模型名
+    fn model_name(&self, model: GeminiModel) -> String;
+
+    /// 展示给用户的模型名称
+    fn display_name(&self, model: GeminiModel) -> String {
+        self.model_name(model)
+    }
+
+    /// 一次性返回完整回复
+    async fn chat(&self, request: ChatRequest) -> Result<ChatResult, String>;
+
+    /// 流式返回回复，每收到一个片段就回调 `on_delta`，结束后返回拼接好的完整结果
+    async fn chat_stream(
+        &self,
+        request: ChatRequest,
+        on_delta: &mut (dyn FnMut(StreamDelta) + Send),
+    ) -> Result<ChatResult, String>;
+
+    /// 是否支持工具调用，不支持时对话中的工具被忽略
+    fn supports_tools(&self) -> bool {
+        false
+    }
+
+    /// 用后端的接口计算对话的 token 数，不支持或失败时返回 None
+    async fn count_tokens(&self, _request: &ChatRequest) -> Option<u64> {
+        None
+    }
+
+    /// 把文件上传到后端的文件存储，返回可在对话中引用的 URI；后端没有文件存储时返回 None
+    async fn upload_file(
+        &self,
+        _name: &str,
+        _mime_type: &str,
+        _data: Vec<u8>,
+    ) -> Result<Option<String>, String> {
+        Ok(None)
+    }
+}
+
+/// 非 Gemini 后端的模型名映射
+///
+/// `LLM_MODEL` 为默认模型，`LLM_MODEL_MAP` 可以为各档位单独指定，
+/// 例如 `flash=llama3.1:8b,pro-2.5=qwen2.5:72b`
+#[derive(Debug, Clone, Default)]
+pub struct ModelMap {
+    default: String,
+    overrides: HashMap<String, String>,
+}
+
+impl ModelMap {
+    pub fn new(default: &str, map: &str) -> Self {
+        let overrides = map
+            .split(',')
+            .filter_map(|entry| entry.split_once('='))
+            .map(|(tier, model)| {
+                (
+                    GeminiModel::from_str(tier.trim()).as_str().to_string(),
+                    model.trim().to_string(),
+                )
+            })
+            .collect();
+
+        Self {
+            default: default.to_string(),
+            overrides,
+        }
+    }
+
+    pub fn resolve(&self, model: GeminiModel) -> String {
+        self.overrides
+            .get(model.as_str())
+            .cloned()
+            .unwrap_or_else(|| self.default.clone())
+    }
+}
+
+/// 根据环境变量创建聊天后端
+///
+/// `LLM_PROVIDER` 可选 `gemini`（默认）、`openai`、`ollama`
+pub fn chat_provider_from_env() -> Result<Arc<dyn ChatProvider>, String> {
+    let kind = env::var("LLM_PROVIDER").unwrap_or_else(|_| "gemini".to_string());
+    let base_url = env::var("LLM_BASE_URL").ok();
+    let models = || -> Result<ModelMap, String> {
+        let default = env::var("LLM_MODEL").map_err(|_| "未设置 LLM_MODEL 环境变量".to_string())?;
+        Ok(ModelMap::new(
+            &default,
+            &env::var("LLM_MODEL_MAP").unwrap_or_default(),
+        ))
+    };
+
+    match kind.to_lowercase().as_str() {
+        "gemini" => Ok(Arc::new(GeminiProvider::from_env())),
+        "openai" => Ok(Arc::new(OpenAiProvider::new(
+            base_url.unwrap_or_else(|| "http://localhost:8000/v1".to_string()),
+            env::var("LLM_API_KEY").ok(),
+            models()?,
+        ))),
+        "ollama" => Ok(Arc::new(OllamaProvider::new(
+            base_url.unwrap_or_else(|| "http://localhost:11434".to_string()),
+            models()?,
+        ))),
+        other => Err(format!("不支持的 LLM_PROVIDER: {}", other)),
+    }
+}
+
+/// 逐块读取响应体，只把完整的 UTF-8 文本交给 `on_text`
+///
+/// 多字节字符可能被拆到两个块中，未完整的字节留到下一块再解码
+pub(super) async fn read_text_stream<F>(
+    response: reqwest::Response,
+    mut on_text: F,
+) -> Result<(), String>
+where
+    F: FnMut(&str) -> Result<(), String>,
+{
+    let mut pending: Vec<u8> = Vec::new();
+    let mut stream = response.bytes_stream();
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| format!("读取流式响应失败: {}", e.without_url()))?;
+        pending.extend_from_slice(&chunk);
+        let valid = match std::str::from_utf8(&pending) {
+            Ok(text) => text.len(),
+            Err(e) => e.valid_up_to(),
+        };
+        on_text(&String::from_utf8_lossy(&pending[..valid]))?;
+        pending.drain(..valid);
+    }
+
+    Ok(())
+}
+
+/// SSE 事件缓冲区，取出完整事件的 data 内容
+#[derive(Default)]
+pub(super) struct SseBuffer {
+    buffer: String,
+}
+
+impl SseBuffer {
+    /// 追加文本，返回所有已完整的事件
+    pub fn push(&mut self, text: &str) -> Vec<String> {
+        self.buffer.push_str(text);
+        self.drain()
+    }
+
+    /// 流结束时取出末尾没有空行结尾的事件
+    pub fn finish(&mut self) -> Vec<String> {
+        self.buffer.push_str("\n\n");
+        self.drain()
+    }
+
+    fn drain(&mut self) -> Vec<String> {
+        let normalized = self.buffer.replace("\r\n", "\n");
+        let Some(end) = normalized.rfind("\n\n") else {
+            self.buffer = normalized;
+            return Vec::new();
+        };
+
+        let events = normalized[..end]
+            .split("\n\n")
+            .filter_map(|event| {
+                let data: Vec<&str> = event
+                    .lines()
+                    .filter_map(|line| line.strip_prefix("data:"))
+                    .map(|line| line.strip_prefix(' ').unwrap_or(line))
+                    .collect();
+                if data.is_empty() {
+                    None
+                } else {
+                    Some(data.join("\n"))
+                }
+            })
+            .collect();
+
+        self.buffer = normalized[end + 2..].to_string();
+        events
+    }
+}
+
+/// 单个流式响应块解析出的内容
+#[derive(Debug, Default, PartialEq)]
+pub(super) struct StreamChunk {
+    pub deltas: Vec<StreamDelta>,
+    /// 用量统计，通常只出现在最后一个块中
+    pub usage: Option<TokenUsage>,
+    pub tool_calls: Vec<ToolCall>,
+}
+
+/// 流式结果累加器
+#[derive(Default)]
+pub(super) struct StreamAccumulator {
+    response: String,
+    thinking: String,
+    usage: Option<TokenUsage>,
+    tool_calls: Vec<ToolCall>,
+}
+
+impl StreamAccumulator {
+    /// 记录一个响应块的全部片段，用量以最后出现的为准
+    pub fn push_chunk(
+        &mut self,
+        chunk: StreamChunk,
+        on_delta: &mut (dyn FnMut(StreamDelta) + Send),
+    ) {
+        for delta in chunk.deltas {
+            self.push(delta, on_delta);
+        }
+        if chunk.usage.is_some() {
+            self.usage = chunk.usage;
+        }
+        self.tool_calls.extend(chunk.tool_calls);
+    }
+
+    /// 记录片段并转发给回调
+    fn push(&mut self, delta: StreamDelta, on_delta: &mut (dyn FnMut(StreamDelta) + Send)) {
+        match &delta {
+            StreamDelta::Response(text) => self.response.push_str(text),
+            StreamDelta::Thinking(text) => self.thinking.push_str(text),
+            StreamDelta::ToolCall { .. } | StreamDelta::ToolResult { .. } => {}
+        }
+        on_delta(delta);
+    }
+
+    /// 没有回复文本也没有工具调用时以 `empty_reply` 作为回复
+    pub fn finish(self, empty_reply: &str) -> ChatResult {
+        ChatResult {
+            response: if self.response.is_empty() && self.tool_calls.is_empty() {
+                empty_reply.to_string()
+            } else {
+                self.response
+            },
+            thinking: if self.thinking.is_empty() {
+                None
+            } else {
+                Some(self.thinking)
+            },
+            usage: self.usage,
+            tool_calls: self.tool_calls,
+        }
+    }
+}
+
+#[cfg(test)]
+pub(super) mod test_support {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// 启动一个只返回固定响应的本地服务器，返回 (地址, 收到的请求)
+    pub async fn mock_server(
+        content_type: &'static str,
+        body: String,
+    ) -> (String, tokio::sync::oneshot::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = vec![0u8; 8192];
+            // 读取到请求体结束（简单按 Content-Length 判断）
+            loop {
+                let n = socket.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length = head
+                        .lines()
+                        .find_map(|l| {
+                            l.to_lowercase()
+                                .strip_prefix("content-length:")
+                                .map(|v| v.trim().parse::<usize>().unwrap_or(0))
+                        })
+                        .unwrap_or(0);
+                    if body.len() >= length {
+                        let _ = tx.send(body.to_string());
+                        break;
+                    }
+                }
+                if n == 0 {
+                    break;
+                }
+            }
+
+            let header = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nConnection: close\r\n\r\n",
+                content_type
+            );
+            socket.write_all(header.as_bytes()).await.unwrap();
+            socket.write_all(body.as_bytes()).await.unwrap();
+            socket.flush().await.unwrap();
+        });
+
+        (format!("http://{}", addr), rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(role: &str, content: &str) -> ChatRecord {
+        ChatRecord {
+            id: 0,
+            user_id: "test-user".to_string(),
+            conversation_id: None,
+            role: role.to_string(),
+            content: content.to_string(),
+            summary: None,
+            model: None,
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_prompt_from_history() {
+        let history = vec![
+            record("model", "孤立的回复"),
+            record("user", "第一个问题"),
+            record("model", "第一个回答"),
+            record("user", "没有得到回答的问题"),
+        ];
+        let prompt = ChatPrompt::from_history(Some("系统".to_string()), &history, "第二个问题");
+
+        let roles: Vec<_> = prompt.turns.iter().map(|t| t.role).collect();
+        assert_eq!(roles, vec![ChatRole::User, ChatRole::Model, ChatRole::User]);
+        assert_eq!(prompt.turns[2].content, "没有得到回答的问题\n\n第二个问题");
+    }
+
+    #[test]
+    fn test_sse_buffer() {
+        let mut buffer = SseBuffer::default();
+        let events = buffer.push("data: {\"a\":1}\n\ndata: {\"b\":2}\r\n\r\ndata: {\"c\"");
+        assert_eq!(events, vec!["{\"a\":1}", "{\"b\":2}"]);
+        assert!(buffer.push(":3}").is_empty());
+        assert_eq!(buffer.finish(), vec!["{\"c\":3}"]);
+    }
+
+    #[test]
+    fn test_model_map() {
+        let map = ModelMap::new("llama3.1:8b", "pro-2.5=qwen2.5:72b, bogus");
+        assert_eq!(map.resolve(GeminiModel::Flash), "llama3.1:8b");
+        assert_eq!(map.resolve(GeminiModel::Pro25), "qwen2.5:72b");
+    }
+}
@@ -0,0 +1,368 @@
+use chrono::{DateTime, Days, Utc};
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use super::budget::estimate_tokens;
+use super::memory::ChatMemory;
+use super::provider::ChatResult;
+
+/// 令牌桶数量超过该值时清理已回满的桶
+const MAX_BUCKETS: usize = 10_000;
+
+/// 受限流保护的操作，各自使用独立的令牌桶
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RateAction {
+    Chat,
+    Upload,
+    Auth,
+}
+
+/// 令牌桶参数
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BucketConfig {
+    /// 每分钟补充的令牌数，0 表示不限流
+    pub per_minute: u32,
+    /// 桶容量（允许的突发请求数）
+    pub burst: u32,
+}
+
+/// 限流与每日配额配置
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    pub chat: BucketConfig,
+    pub upload: BucketConfig,
+    /// 登录和注册（未登录，按 IP 限流）
+    pub auth: BucketConfig,
+    /// 每个用户每天的聊天请求上限，0 表示不限
+    pub daily_requests: u64,
+    /// 每个用户每天的 token 上限（提示词 + 回复），0 表示不限
+    pub daily_tokens: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            chat: BucketConfig {
+                per_minute: 20,
+                burst: 10,
+            },
+            upload: BucketConfig {
+                per_minute: 10,
+                burst: 5,
+            },
+            auth: BucketConfig {
+                per_minute: 10,
+                burst: 5,
+            },
+            daily_requests: 0,
+            daily_tokens: 0,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    /// 从环境变量读取配置，未设置的项使用默认值
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let parse = |name: &str, fallback: u64| {
+            env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(fallback)
+        };
+        let bucket = |prefix: &str, fallback: BucketConfig| BucketConfig {
+            per_minute: parse(
+                &format!("{}_PER_MINUTE", prefix),
+                fallback.per_minute as u64,
+            ) as u32,
+            burst: (parse(&format!("{}_BURST", prefix), fallback.burst as u64) as u32).max(1),
+        };
+
+        Self {
+            chat: bucket("RATE_LIMIT_CHAT", default.chat),
+            upload: bucket("RATE_LIMIT_UPLOAD", default.upload),
+            auth: bucket("RATE_LIMIT_AUTH", default.auth),
+            daily_requests: parse("DAILY_REQUEST_QUOTA", default.daily_requests),
+            daily_tokens: parse("DAILY_TOKEN_QUOTA", default.daily_tokens),
+        }
+    }
+
+    fn bucket(&self, action: RateAction) -> BucketConfig {
+        match action {
+            RateAction::Chat => self.chat,
+            RateAction::Upload => self.upload,
+            RateAction::Auth => self.auth,
+        }
+    }
+}
+
+/// 被限流或超出配额时的拒绝信息
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimited {
+    pub message: String,
+    /// 建议等待的秒数
+    pub retry_after: u64,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(config: BucketConfig, now: Instant) -> Self {
+        Self {
+            tokens: config.burst as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, config: BucketConfig, now: Instant) {
+        let rate = config.per_minute as f64 / 60.0;
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(config.burst as f64);
+        self.updated = now;
+    }
+
+    /// 取出一个令牌，不足时返回需要等待的秒数
+    fn try_take(&mut self, config: BucketConfig, now: Instant) -> Result<(), u64> {
+        self.refill(config, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        let rate = config.per_minute as f64 / 60.0;
+        Err(((1.0 - self.tokens) / rate).ceil().max(1.0) as u64)
+    }
+}
+
+/// 按用户（未登录时按 IP）限流，并在 SQLite 中统计每日用量
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    memory: Arc<ChatMemory>,
+    buckets: Mutex<HashMap<(RateAction, String), TokenBucket>>,
+    /// 保证每日配额的检查与计数不被并发请求穿插
+    quota_lock: Mutex<()>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig, memory: Arc<ChatMemory>) -> Self {
+        Self {
+            config,
+            memory,
+            buckets: Mutex::new(HashMap::new()),
+            quota_lock: Mutex::new(()),
+        }
+    }
+
+    /// 检查令牌桶
+    pub fn check(&self, action: RateAction, key: &str) -> Result<(), RateLimited> {
+        self.check_at(action, key, Instant::now())
+    }
+
+    fn check_at(&self, action: RateAction, key: &str, now: Instant) -> Result<(), RateLimited> {
+        let config = self.config.bucket(action);
+        if config.per_minute == 0 {
+            return Ok(());
+        }
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            // 已回满的桶与新建的桶等价，可以安全丢弃
+            buckets.retain(|(action, _), bucket| {
+                let config = self.config.bucket(*action);
+                bucket.refill(config, now);
+                bucket.tokens < config.burst as f64
+            });
+        }
+
+        buckets
+            .entry((action, key.to_string()))
+            .or_insert_with(|| TokenBucket::new(config, now))
+            .try_take(config, now)
+            .map_err(|retry_after| RateLimited {
+                message: format!("请求过于频繁，请 {} 秒后重试", retry_after),
+                retry_after,
+            })
+    }
+
+    /// 检查聊天请求：先检查令牌桶，再检查每日配额并计入一次请求
+    pub fn check_chat(&self, key: &str) -> Result<(), RateLimited> {
+        self.check(RateAction::Chat, key)?;
+        self.consume_daily_request(key, Utc::now())
+    }
+
+    fn consume_daily_request(&self, key: &str, now: DateTime<Utc>) -> Result<(), RateLimited> {
+        let _guard = self.quota_lock.lock().unwrap();
+        let day = day_key(now);
+        let (requests, tokens) = self
+            .memory
+            .get_daily_usage(key, &day)
+            .map_err(|e| eprintln!("读取每日用量失败: {}", e))
+            .unwrap_or_default();
+
+        let exceeded = if self.config.daily_requests > 0 && requests >= self.config.daily_requests {
+            Some(format!(
+                "今日请求次数已达上限（{}），请明天再试",
+                self.config.daily_requests
+            ))
+        } else if self.config.daily_tokens > 0 && tokens >= self.config.daily_tokens {
+            Some(format!(
+                "今日 token 用量已达上限（{}），请明天再试",
+                self.config.daily_tokens
+            ))
+        } else {
+            None
+        };
+        if let Some(message) = exceeded {
+            return Err(RateLimited {
+                message,
+                retry_after: seconds_until_next_day(now),
+            });
+        }
+
+        if let Err(e) = self.memory.add_daily_usage(key, &day, 1, 0) {
+            eprintln!("记录每日用量失败: {}", e);
+        }
+        Ok(())
+    }
+
+    /// 记录一次聊天消耗的 token 数
+    pub fn record_tokens(&self, key: &str, tokens: u64) {
+        if let Err(e) = self
+            .memory
+            .add_daily_usage(key, &day_key(Utc::now()), 0, tokens)
+        {
+            eprintln!("记录每日用量失败: {}", e);
+        }
+    }
+}
+
+/// 已登录用户的限流键
+pub fn user_key(user_id: &str) -> String {
+    format!("user:{}", user_id)
+}
+
+/// 未登录请求按 IP 限流
+pub fn ip_key(ip: &str) -> String {
+    format!("ip:{}", ip)
+}
+
+/// 一次模型调用消耗的 token 数：优先使用后端返回的用量，否则按提示词和回复估算
+pub fn result_tokens(prompt_tokens: u64, result: &ChatResult) -> u64 {
+    match result.usage {
+        Some(usage) => usage.total(),
+        None => {
+            prompt_tokens
+                + estimate_tokens(&result.response)
+                + result.thinking.as_deref().map_or(0, estimate_tokens)
+        }
+    }
+}
+
+/// 每日配额按 UTC 日期统计
+fn day_key(now: DateTime<Utc>) -> String {
+    now.format("%Y-%m-%d").to_string()
+}
+
+fn seconds_until_next_day(now: DateTime<Utc>) -> u64 {
+    let next_day = now
+        .date_naive()
+        .checked_add_days(Days::new(1))
+        .and_then(|d| d.and_hms_opt(0, 0, 0))
+        .map(|d| d.and_utc());
+    next_day
+        .map(|d| (d - now).num_seconds().max(1) as u64)
+        .unwrap_or(86_400)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::time::Duration;
+
+    fn limiter(config: RateLimitConfig) -> RateLimiter {
+        RateLimiter::new(config, Arc::new(ChatMemory::new(":memory:").unwrap()))
+    }
+
+    #[test]
+    fn test_token_bucket_allows_burst_then_refills() {
+        let limiter = limiter(RateLimitConfig {
+            chat: BucketConfig {
+                per_minute: 6,
+                burst: 2,
+            },
+            ..RateLimitConfig::default()
+        });
+        let start = Instant::now();
+
+        assert!(limiter.check_at(RateAction::Chat, "user:a", start).is_ok());
+        assert!(limiter.check_at(RateAction::Chat, "user:a", start).is_ok());
+        let rejected = limiter
+            .check_at(RateAction::Chat, "user:a", start)
+            .unwrap_err();
+        assert_eq!(rejected.retry_after, 10);
+
+        // 其他用户和其他操作使用独立的桶
+        assert!(limiter.check_at(RateAction::Chat, "user:b", start).is_ok());
+        assert!(
+            limiter
+                .check_at(RateAction::Upload, "user:a", start)
+                .is_ok()
+        );
+
+        let later = start + Duration::from_secs(10);
+        assert!(limiter.check_at(RateAction::Chat, "user:a", later).is_ok());
+        assert!(limiter.check_at(RateAction::Chat, "user:a", later).is_err());
+    }
+
+    #[test]
+    fn test_zero_rate_disables_limit() {
+        let limiter = limiter(RateLimitConfig {
+            chat: BucketConfig {
+                per_minute: 0,
+                burst: 1,
+            },
+            ..RateLimitConfig::default()
+        });
+        for _ in 0..100 {
+            assert!(limiter.check(RateAction::Chat, "user:a").is_ok());
+        }
+    }
+
+    #[test]
+    fn test_daily_quotas() {
+        let limiter = limiter(RateLimitConfig {
+            daily_requests: 2,
+            daily_tokens: 100,
+            ..RateLimitConfig::default()
+        });
+        let now = Utc.with_ymd_and_hms(2025, 1, 1, 23, 0, 0).unwrap();
+
+        assert!(limiter.consume_daily_request("user:a", now).is_ok());
+        assert!(limiter.consume_daily_request("user:a", now).is_ok());
+        let rejected = limiter.consume_daily_request("user:a", now).unwrap_err();
+        assert_eq!(rejected.retry_after, 3600);
+
+        // token 配额
+        limiter
+            .memory
+            .add_daily_usage("user:b", &day_key(now), 0, 100)
+            .unwrap();
+        assert!(limiter.consume_daily_request("user:b", now).is_err());
+
+        // 第二天重新计数
+        let tomorrow = now + chrono::Duration::hours(2);
+        assert!(limiter.consume_daily_request("user:a", tomorrow).is_ok());
+        assert_eq!(
+            limiter
+                .memory
+                .get_daily_usage("user:a", &day_key(now))
+                .unwrap(),
+            (2, 0)
+        );
+    }
+}
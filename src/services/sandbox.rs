@@ -0,0 +1,518 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::process::Command;
+
+use super::tools::{Tool, ToolContext};
+
+/// 隐藏目录或设置资源限制失败时脚本的退出码和输出的标记
+const SETUP_FAILED_EXIT: i32 = 97;
+const SETUP_FAILED_MARK: &str = "sandbox setup failed";
+/// 单个文件的大小上限（KB）
+const MAX_FILE_KB: u64 = 10 * 1024;
+/// 共享的临时目录，总是被空的 tmpfs 覆盖，代码看不到其他运行的临时目录
+const SCRATCH_PATHS: [&str; 3] = ["/tmp", "/var/tmp", "/dev/shm"];
+
+/// 代码执行沙箱的配置
+///
+/// 代码在新的 user / net / pid / mount 命名空间中运行：没有网络，看不到宿主进程，
+/// `/tmp`、`/var/tmp`、`/dev/shm`、服务进程的 `$HOME` 和 `hidden_paths` 被空的 tmpfs 覆盖，
+/// 只把本次运行的临时目录挂回原处，根文件系统只读，环境变量只保留 `PATH`。覆盖完成后代码
+/// 再进入一层嵌套的 user 命名空间运行，对外层的 mount 命名空间没有权限，无法卸载 tmpfs
+/// 露出被隐藏的目录。
+#[derive(Debug, Clone)]
+pub struct SandboxConfig {
+    pub timeout: Duration,
+    pub memory_mb: u64,
+    /// stdout / stderr 各自保留的字节数
+    pub max_output_bytes: usize,
+    pub python: String,
+    /// 对代码不可见的目录（如项目目录、数据库目录），不存在的目录会被跳过
+    pub hidden_paths: Vec<PathBuf>,
+}
+
+impl Default for SandboxConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            memory_mb: 256,
+            max_output_bytes: 16 * 1024,
+            python: "python3".to_string(),
+            hidden_paths: Vec::new(),
+        }
+    }
+}
+
+impl SandboxConfig {
+    /// 从环境变量读取配置，默认隐藏当前工作目录和数据库所在目录
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let parse = |name: &str, fallback: u64| {
+            env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(fallback)
+        };
+
+        let hidden_paths = match env::var("SANDBOX_HIDDEN_PATHS") {
+            Ok(list) => list
+                .split(',')
+                .map(str::trim)
+                .filter(|p| !p.is_empty())
+                .map(PathBuf::from)
+                .collect(),
+            Err(_) => {
+                let database =
+                    env::var("DATABASE_URL").unwrap_or_else(|_| "data/web_chat.db".to_string());
+                env::current_dir()
+                    .into_iter()
+                    .chain(Path::new(&database).parent().map(Path::to_path_buf))
+                    .collect()
+            }
+        };
+
+        Self {
+            timeout: Duration::from_secs(parse("SANDBOX_TIMEOUT_SECS", default.timeout.as_secs())),
+            memory_mb: parse("SANDBOX_MEMORY_MB", default.memory_mb),
+            max_output_bytes: parse("SANDBOX_MAX_OUTPUT_BYTES", default.max_output_bytes as u64)
+                as usize,
+            python: env::var("SANDBOX_PYTHON").unwrap_or(default.python),
+            hidden_paths,
+        }
+    }
+}
+
+/// 一次代码执行的结果
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExecutionOutput {
+    /// 被终止（超时）时为 None
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+    pub timed_out: bool,
+    pub duration: Duration,
+}
+
+/// 支持的语言
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Language {
+    Python,
+    Shell,
+}
+
+impl Language {
+    fn file_name(self) -> &'static str {
+        match self {
+            Language::Python => "main.py",
+            Language::Shell => "main.sh",
+        }
+    }
+}
+
+/// 在隔离的临时目录中运行代码，结束后删除目录
+pub async fn run_code(
+    config: &SandboxConfig,
+    language: Language,
+    code: &str,
+) -> Result<ExecutionOutput, String> {
+    let dir = env::temp_dir().join(format!("chat-sandbox-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir(&dir).map_err(|e| format!("创建临时目录失败: {}", e))?;
+    let result = run_in_dir(config, language, code, &dir).await;
+    if let Err(e) = std::fs::remove_dir_all(&dir) {
+        eprintln!("删除沙箱目录 {} 失败: {}", dir.display(), e);
+    }
+    result
+}
+
+async fn run_in_dir(
+    config: &SandboxConfig,
+    language: Language,
+    code: &str,
+    dir: &Path,
+) -> Result<ExecutionOutput, String> {
+    std::fs::write(dir.join(language.file_name()), code)
+        .map_err(|e| format!("写入代码失败: {}", e))?;
+    let interpreter = match language {
+        Language::Python => config.python.as_str(),
+        Language::Shell => "sh",
+    };
+
+    let mut child = Command::new("unshare")
+        .args([
+            "--user",
+            "--map-root-user",
+            "--net",
+            "--pid",
+            "--fork",
+            "--kill-child",
+            "--mount-proc",
+            "--",
+            "sh",
+            "-c",
+            &setup_script(config, dir),
+            "sandbox",
+            interpreter,
+            language.file_name(),
+        ])
+        .current_dir(dir)
+        .env_clear()
+        .env("PATH", env::var("PATH").unwrap_or_default())
+        .env("HOME", dir)
+        .env("TMPDIR", dir)
+        .env("LANG", "C.UTF-8")
+        .env("PYTHONDONTWRITEBYTECODE", "1")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("启动沙箱失败（需要 unshare）: {}", e))?;
+
+    let started = Instant::now();
+    let stdout = tokio::spawn(read_limited(child.stdout.take(), config.max_output_bytes));
+    let stderr = tokio::spawn(read_limited(child.stderr.take(), config.max_output_bytes));
+
+    let (exit_code, timed_out) = match tokio::time::timeout(config.timeout, child.wait()).await {
+        Ok(status) => (
+            status
+                .map_err(|e| format!("等待进程结束失败: {}", e))?
+                .code(),
+            false,
+        ),
+        Err(_) => {
+            // 杀掉 unshare 后，命名空间中的所有进程随之结束
+            let _ = child.kill().await;
+            (None, true)
+        }
+    };
+    let duration = started.elapsed();
+    let stdout = stdout.await.unwrap_or_default();
+    let stderr = stderr.await.unwrap_or_default();
+
+    let setup_failed = (exit_code == Some(SETUP_FAILED_EXIT) && stderr.contains(SETUP_FAILED_MARK))
+        || stderr.starts_with("unshare:");
+    if stdout.is_empty() && setup_failed {
+        return Err(format!("无法创建隔离环境: {}", stderr.trim()));
+    }
+
+    Ok(ExecutionOutput {
+        exit_code,
+        stdout,
+        stderr,
+        timed_out,
+        duration,
+    })
+}
+
+/// 隐藏目录、挂回临时目录、设置资源限制后在嵌套的 user 命名空间中执行 `"$@"`，
+/// 任何一步失败都不运行代码
+///
+/// 进程的工作目录仍指向被覆盖前的临时目录，以它为源把临时目录绑定挂载回原路径
+/// （`--no-canonicalize` 避免 mount 把 `.` 解析成已被覆盖的路径），再切换到挂载后的目录，
+/// 之后 `..` 只能到达空的 tmpfs。嵌套命名空间中的 root 对外层 mount 命名空间没有权限，
+/// 再新建 mount 命名空间时继承的挂载也会被内核锁定，因此无法卸载或移走这些挂载。
+fn setup_script(config: &SandboxConfig, dir: &Path) -> String {
+    let fail = format!(
+        "{{ echo '{}' >&2; exit {}; }}",
+        SETUP_FAILED_MARK, SETUP_FAILED_EXIT
+    );
+    let mut script = String::new();
+    for path in covered_paths(config) {
+        script.push_str(&format!(
+            "mount -t tmpfs -o size=1k tmpfs {} || {}; ",
+            shell_quote(&path.to_string_lossy()),
+            fail
+        ));
+    }
+    let dir = shell_quote(&dir.to_string_lossy());
+    script.push_str(&format!(
+        "mkdir -p {dir} && mount --no-canonicalize --bind . {dir} && cd {dir} || {fail}; \
+         mount -o remount,bind,ro / || {fail}; "
+    ));
+    script.push_str(&format!(
+        "ulimit -v {} && ulimit -t {} && ulimit -f {} || {}; \
+         exec unshare --user --map-root-user -- \"$@\"",
+        config.memory_mb * 1024,
+        config.timeout.as_secs().max(1),
+        MAX_FILE_KB * 2,
+        fail
+    ));
+    script
+}
+
+/// 需要用 tmpfs 覆盖的目录：按层级排序，跳过根目录、不存在的目录和已被上层目录覆盖的目录
+fn covered_paths(config: &SandboxConfig) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = SCRATCH_PATHS
+        .iter()
+        .map(PathBuf::from)
+        .chain(env::var_os("HOME").map(PathBuf::from))
+        .chain(config.hidden_paths.iter().cloned())
+        .filter_map(|path| std::path::absolute(path).ok())
+        .filter(|path| path.parent().is_some() && path.is_dir())
+        .collect();
+    paths.sort_by_key(|path| path.components().count());
+
+    let mut covered: Vec<PathBuf> = Vec::new();
+    for path in paths {
+        if !covered.iter().any(|c| path.starts_with(c)) {
+            covered.push(path);
+        }
+    }
+    covered
+}
+
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// 读取全部输出，只保留前 `limit` 字节（继续读取剩余部分，避免进程因管道写满而阻塞）
+async fn read_limited(pipe: Option<impl AsyncRead + Unpin>, limit: usize) -> String {
+    let Some(mut pipe) = pipe else {
+        return String::new();
+    };
+    let mut kept = Vec::new();
+    let mut total = 0;
+    let mut buf = [0u8; 8192];
+    while let Ok(n) = pipe.read(&mut buf).await {
+        if n == 0 {
+            break;
+        }
+        let keep = n.min(limit.saturating_sub(kept.len()));
+        kept.extend_from_slice(&buf[..keep]);
+        total += n;
+    }
+
+    let mut text = String::from_utf8_lossy(&kept).to_string();
+    if total > kept.len() {
+        text.push_str(&format!("\n…（输出已截断，共 {} 字节）", total));
+    }
+    text
+}
+
+/// 在沙箱中运行 Python 或 shell 代码的工具
+pub struct RunCodeTool {
+    config: SandboxConfig,
+    description: String,
+}
+
+impl RunCodeTool {
+    pub const NAME: &'static str = "run_code";
+
+    pub fn new(config: SandboxConfig) -> Self {
+        let description = format!(
+            "在隔离的沙箱中运行 Python 或 shell 代码，返回 stdout、stderr 和退出码。\
+             用于计算、数据处理或验证代码片段的行为。沙箱没有网络，\
+             最多运行 {} 秒、使用 {} MB 内存，工作目录是空的临时目录，运行结束后删除。\
+             需要输出结果时请打印出来。",
+            config.timeout.as_secs(),
+            config.memory_mb
+        );
+        Self {
+            config,
+            description,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RunCodeArgs {
+    language: Language,
+    code: String,
+}
+
+#[async_trait]
+impl Tool for RunCodeTool {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "language": {
+                    "type": "string",
+                    "enum": ["python", "shell"]
+                },
+                "code": {
+                    "type": "string",
+                    "description": "完整的程序代码"
+                }
+            },
+            "required": ["language", "code"]
+        })
+    }
+
+    async fn execute(&self, args: Value, _context: &ToolContext) -> Result<Value, String> {
+        let args: RunCodeArgs =
+            serde_json::from_value(args).map_err(|e| format!("参数无效: {}", e))?;
+        let output = run_code(&self.config, args.language, &args.code).await?;
+        Ok(json!({
+            "exit_code": output.exit_code,
+            "stdout": output.stdout,
+            "stderr": output.stderr,
+            "timed_out": output.timed_out,
+            "duration_ms": output.duration.as_millis() as u64,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> SandboxConfig {
+        SandboxConfig {
+            timeout: Duration::from_secs(2),
+            max_output_bytes: 64,
+            ..SandboxConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_setup_script() {
+        let temp = env::temp_dir();
+        let config = SandboxConfig {
+            hidden_paths: vec![
+                PathBuf::from("/"),
+                PathBuf::from("/does/not/exist"),
+                temp.clone(),
+                temp.join("chat-sandbox-x"),
+            ],
+            ..config()
+        };
+        // 根目录和不存在的目录跳过，已被上层目录覆盖的目录不重复覆盖
+        let covered = covered_paths(&config);
+        assert!(covered.contains(&PathBuf::from("/tmp")));
+        assert!(covered.iter().all(|p| p != Path::new("/")));
+        assert_eq!(covered.iter().filter(|p| temp.starts_with(p)).count(), 1);
+
+        let script = setup_script(&config, Path::new("/tmp/chat-sandbox-x"));
+        assert!(script.starts_with("mount -t tmpfs -o size=1k tmpfs '/tmp' || "));
+        assert!(script.contains(
+            "mkdir -p '/tmp/chat-sandbox-x' && mount --no-canonicalize --bind . \
+             '/tmp/chat-sandbox-x' && cd '/tmp/chat-sandbox-x' || "
+        ));
+        assert!(script.contains("mount -o remount,bind,ro / || "));
+        assert!(script.contains("ulimit -v 262144 && ulimit -t 2"));
+        assert!(script.ends_with("exec unshare --user --map-root-user -- \"$@\""));
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+    }
+
+    #[tokio::test]
+    async fn test_hidden_paths_cannot_be_unmounted() {
+        let id = uuid::Uuid::new_v4();
+        let target = Path::new(env!("CARGO_MANIFEST_DIR")).join("target");
+        let secret = target.join(format!("sandbox-secret-{}", id));
+        let escape = target.join(format!("sandbox-escape-{}", id));
+        std::fs::create_dir_all(&secret).unwrap();
+        std::fs::write(secret.join("web_chat.db"), "top secret").unwrap();
+        // 另一次运行的临时目录
+        let sibling = env::temp_dir().join(format!("chat-sandbox-{}", id));
+        std::fs::create_dir(&sibling).unwrap();
+        std::fs::write(sibling.join("main.py"), "print('other user')").unwrap();
+        let config = SandboxConfig {
+            hidden_paths: vec![secret.clone()],
+            max_output_bytes: 4096,
+            ..config()
+        };
+
+        // 尝试卸载覆盖隐藏目录的挂载（可能在任一上层目录），包括在嵌套的命名空间中
+        let ancestors: Vec<String> = secret
+            .ancestors()
+            .filter(|p| p.parent().is_some())
+            .map(|p| shell_quote(&p.to_string_lossy()))
+            .collect();
+        let unmount = format!(
+            "for d in {}; do umount $d && echo unmounted; umount -l $d && echo unmounted; done",
+            ancestors.join(" ")
+        );
+        let (secret_dir, sibling_dir, escape_file) = (
+            shell_quote(&secret.to_string_lossy()),
+            shell_quote(&sibling.to_string_lossy()),
+            shell_quote(&escape.to_string_lossy()),
+        );
+        let code = format!(
+            "{unmount}; \
+             unshare --user --map-root-user --mount sh -c {nested}; \
+             cat {secret_dir}/web_chat.db; ls -A {secret_dir} | wc -l; \
+             ls {sibling_dir}; cat {sibling_dir}/main.py; \
+             echo hacked > {sibling_dir}/main.py && echo wrote sibling; \
+             touch {escape_file} && echo wrote outside; \
+             echo ok > out.txt && cat out.txt",
+            nested = shell_quote(&unmount),
+        );
+        let output = run_code(&config, Language::Shell, &code).await;
+        let sibling_code = std::fs::read_to_string(sibling.join("main.py")).unwrap();
+        std::fs::remove_dir_all(&secret).unwrap();
+        std::fs::remove_dir_all(&sibling).unwrap();
+        let escaped = std::fs::remove_file(&escape).is_ok();
+
+        // 系统不允许非特权用户命名空间时无法创建沙箱，跳过
+        let output = match output {
+            Ok(output) => output,
+            Err(e) => {
+                eprintln!("跳过沙箱测试: {}", e);
+                return;
+            }
+        };
+        // 无法卸载，读不到隐藏的文件，看不到也改不了其他运行的目录，只能写自己的临时目录
+        assert_eq!(output.stdout, "0\nok\n");
+        assert!(!output.stderr.contains("top secret"));
+        assert!(!output.stderr.contains("other user"));
+        assert_eq!(sibling_code, "print('other user')");
+        assert!(!escaped);
+    }
+
+    // 需要系统允许非特权用户命名空间
+    #[tokio::test]
+    #[ignore]
+    async fn test_run_code_isolation() {
+        let project = env::current_dir().unwrap();
+        let config = SandboxConfig {
+            hidden_paths: vec![project.clone()],
+            ..config()
+        };
+
+        let output = run_code(
+            &config,
+            Language::Shell,
+            &format!(
+                "echo hello; ls {} 2>/dev/null | wc -l; echo oops >&2; exit 3",
+                project.display()
+            ),
+        )
+        .await
+        .unwrap();
+        assert_eq!(output.stdout, "hello\n0\n");
+        assert_eq!(output.stderr, "oops\n");
+        assert_eq!(output.exit_code, Some(3));
+
+        // 没有网络：只有回环接口
+        let output = run_code(
+            &config,
+            Language::Shell,
+            "tail -n +3 /proc/net/dev | cut -d: -f1",
+        )
+        .await
+        .unwrap();
+        assert_eq!(output.stdout.trim(), "lo");
+
+        let output = run_code(&config, Language::Shell, "yes | head -c 1000; sleep 30")
+            .await
+            .unwrap();
+        assert!(output.timed_out);
+        assert_eq!(output.exit_code, None);
+        assert!(output.stdout.ends_with("（输出已截断，共 1000 字节）"));
+    }
+}
@@ -0,0 +1,305 @@
+use std::env;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::backfill::next_backoff;
+use super::memory::ChatMemory;
+use super::provider::{ChatPrompt, ChatProvider, ChatRequest};
+use crate::models::gemini::GeminiModel;
+use crate::models::messages::TokenUsage;
+
+/// 摘要任务配置
+#[derive(Debug, Clone)]
+pub struct SummarizerConfig {
+    /// 超过该字符数的消息才生成摘要
+    pub min_chars: usize,
+    /// 摘要的目标最大字符数
+    pub max_summary_chars: usize,
+    /// 用于生成摘要的模型（默认使用便宜的 Flash）
+    pub model: GeminiModel,
+    /// 两轮扫描之间的间隔
+    pub interval: Duration,
+    /// 每轮最多处理的消息数
+    pub batch_size: usize,
+    /// 相邻两次请求之间的最小间隔（限速）
+    pub request_interval: Duration,
+    /// 失败后退避的最长时间
+    pub max_backoff: Duration,
+    /// 单条消息最多尝试次数，超过后不再生成摘要
+    pub max_attempts: u32,
+}
+
+impl Default for SummarizerConfig {
+    fn default() -> Self {
+        Self {
+            min_chars: 1200,
+            max_summary_chars: 300,
+            model: GeminiModel::Flash,
+            interval: Duration::from_secs(60),
+            batch_size: 8,
+            request_interval: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(600),
+            max_attempts: 3,
+        }
+    }
+}
+
+impl SummarizerConfig {
+    /// 从环境变量读取配置，未设置的项使用默认值
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let parse = |name: &str, fallback: usize| {
+            env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(fallback)
+        };
+
+        Self {
+            min_chars: parse("SUMMARY_MIN_CHARS", default.min_chars),
+            max_summary_chars: parse("SUMMARY_MAX_CHARS", default.max_summary_chars),
+            model: env::var("SUMMARY_MODEL")
+                .map(|m| GeminiModel::from_str(&m))
+                .unwrap_or(default.model),
+            ..default
+        }
+    }
+}
+
+/// 构建摘要请求
+fn summary_prompt(text: &str, max_chars: usize) -> ChatPrompt {
+    let instruction = format!(
+        "你是一个对话记忆压缩助手。请将用户给出的聊天消息压缩为不超过 {} 字的摘要，\
+         保留关键事实、名称、数字、代码标识符和结论，不要添加原文没有的信息，\
+         直接输出摘要内容。",
+        max_chars
+    );
+    ChatPrompt::from_history(Some(instruction), &[], text)
+}
+
+/// 调用聊天模型为一条消息生成摘要，返回 (摘要, token 用量)
+pub async fn summarize_text(
+    text: &str,
+    provider: &dyn ChatProvider,
+    config: &SummarizerConfig,
+) -> Result<(String, Option<TokenUsage>), String> {
+    let prompt = summary_prompt(text, config.max_summary_chars);
+    let result = provider
+        .chat(ChatRequest {
+            prompt,
+            model: config.model,
+        })
+        .await?;
+    Ok((result.response.trim().to_string(), result.usage))
+}
+
+/// 单轮摘要的结果
+#[derive(Debug, Default, PartialEq)]
+struct BatchOutcome {
+    summarized: usize,
+    failed: usize,
+}
+
+/// 启动后台摘要任务，定期为过长且没有摘要的消息生成摘要
+///
+/// 请求失败时按指数退避，同一条消息失败 `max_attempts` 次后不再尝试。
+/// 摘要调用的 token 用量记入消息所属用户的用量报告，但不占用其每日配额。
+pub fn spawn_summary_worker(
+    memory: Arc<ChatMemory>,
+    provider: Arc<dyn ChatProvider>,
+    config: SummarizerConfig,
+) {
+    actix_web::rt::spawn(async move {
+        let mut backoff = Duration::ZERO;
+
+        loop {
+            tokio::time::sleep(config.interval + backoff).await;
+
+            let outcome = summarize_batch(&memory, &config, |text| {
+                let provider = provider.clone();
+                let config = config.clone();
+                async move { summarize_text(&text, provider.as_ref(), &config).await }
+            })
+            .await;
+
+            // 整轮都失败时退避，有成功则恢复正常间隔
+            backoff = if outcome.failed > 0 && outcome.summarized == 0 {
+                next_backoff(backoff, config.max_backoff)
+            } else {
+                Duration::ZERO
+            };
+        }
+    });
+}
+
+/// 处理一批需要摘要的消息
+async fn summarize_batch<F, Fut>(
+    memory: &ChatMemory,
+    config: &SummarizerConfig,
+    summarize: F,
+) -> BatchOutcome
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<(String, Option<TokenUsage>), String>>,
+{
+    let batch = match memory.get_messages_without_summary(
+        config.min_chars,
+        config.max_attempts,
+        config.batch_size,
+    ) {
+        Ok(records) => records,
+        Err(e) => {
+            eprintln!("⚠️  读取待摘要消息失败: {}", e);
+            return BatchOutcome::default();
+        }
+    };
+
+    let mut outcome = BatchOutcome::default();
+    for (i, record) in batch.into_iter().enumerate() {
+        if i > 0 {
+            tokio::time::sleep(config.request_interval).await;
+        }
+
+        let original_chars = record.content.chars().count();
+        match summarize(record.content).await {
+            Ok((summary, usage)) => {
+                if let Some(usage) = usage
+                    && let Err(e) = memory.add_usage_record(
+                        &record.user_id,
+                        Some(config.model.as_str()),
+                        &usage,
+                    )
+                {
+                    eprintln!("⚠️  保存摘要 token 用量失败: {}", e);
+                }
+
+                // 摘要不比原文短就没有意义，重试也一样，直接放弃这条消息
+                if !summary.is_empty() && summary.chars().count() < original_chars {
+                    match memory.update_summary(record.id, &summary) {
+                        Ok(_) => outcome.summarized += 1,
+                        Err(e) => eprintln!("⚠️  保存消息 {} 的摘要失败: {}", record.id, e),
+                    }
+                } else if let Err(e) = memory.add_summary_attempts(record.id, config.max_attempts) {
+                    eprintln!("⚠️  记录摘要失败次数失败: {}", e);
+                }
+            }
+            Err(e) => {
+                eprintln!("⚠️  生成消息 {} 的摘要失败: {}", record.id, e);
+                if let Err(e) = memory.add_summary_attempts(record.id, 1) {
+                    eprintln!("⚠️  记录摘要失败次数失败: {}", e);
+                }
+                outcome.failed += 1;
+                // 出错时结束本轮，交给退避逻辑
+                break;
+            }
+        }
+    }
+
+    outcome
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_summarize_batch_only_long_messages() {
+        let memory = ChatMemory::new(":memory:").unwrap();
+        let conversation = memory.create_conversation("u", "测试", None).unwrap();
+        let long_text = "很长的消息。".repeat(50);
+        let long_id = memory
+            .add_message("u", conversation.id, "model", &long_text, None)
+            .unwrap();
+        memory
+            .add_message("u", conversation.id, "user", "短消息", None)
+            .unwrap();
+
+        let config = SummarizerConfig {
+            min_chars: 100,
+            request_interval: Duration::ZERO,
+            ..SummarizerConfig::default()
+        };
+        let outcome = summarize_batch(&memory, &config, |_| async {
+            Ok(("摘要".to_string(), None))
+        })
+        .await;
+        assert_eq!(outcome.summarized, 1);
+
+        let messages = memory.get_all_messages("u", conversation.id).unwrap();
+        let long = messages.iter().find(|m| m.id == long_id).unwrap();
+        assert_eq!(long.summary.as_deref(), Some("摘要"));
+        assert!(
+            messages
+                .iter()
+                .all(|m| m.id == long_id || m.summary.is_none())
+        );
+
+        // 已有摘要的消息不会再次处理
+        let outcome = summarize_batch(&memory, &config, |_| async {
+            Ok(("摘要".to_string(), None))
+        })
+        .await;
+        assert_eq!(outcome, BatchOutcome::default());
+    }
+
+    #[tokio::test]
+    async fn test_summarize_batch_retries_and_records_usage() {
+        let memory = ChatMemory::new(":memory:").unwrap();
+        let conversation = memory.create_conversation("u", "测试", None).unwrap();
+        let long_text = "很长的消息。".repeat(50);
+        memory
+            .add_message("u", conversation.id, "model", &long_text, None)
+            .unwrap();
+
+        let config = SummarizerConfig {
+            min_chars: 100,
+            request_interval: Duration::ZERO,
+            max_attempts: 2,
+            ..SummarizerConfig::default()
+        };
+        let fail = |_| async { Err("暂时不可用".to_string()) };
+
+        // 失败的消息在下一轮重试，达到上限后不再处理
+        let outcome = summarize_batch(&memory, &config, fail).await;
+        assert_eq!(outcome.failed, 1);
+        let outcome = summarize_batch(&memory, &config, fail).await;
+        assert_eq!(outcome.failed, 1);
+        let outcome = summarize_batch(&memory, &config, fail).await;
+        assert_eq!(outcome, BatchOutcome::default());
+
+        // 摘要不比原文短时直接放弃，但仍记录用量
+        let id = memory
+            .add_message("u", conversation.id, "model", &long_text, None)
+            .unwrap();
+        let usage = TokenUsage {
+            prompt_tokens: 100,
+            candidate_tokens: 300,
+            thought_tokens: 0,
+        };
+        let useless = |text: String| async move { Ok((text, Some(usage))) };
+        let outcome = summarize_batch(&memory, &config, useless).await;
+        assert_eq!(outcome, BatchOutcome::default());
+        let messages = memory.get_all_messages("u", conversation.id).unwrap();
+        assert!(messages.iter().all(|m| m.summary.is_none()));
+        assert!(
+            memory
+                .get_messages_without_summary(config.min_chars, config.max_attempts, 10)
+                .unwrap()
+                .iter()
+                .all(|m| m.id != id)
+        );
+
+        let rows = memory.usage_summary(Some("u"), "2000-01-01").unwrap();
+        assert_eq!(rows[0].model.as_deref(), Some(config.model.as_str()));
+        assert_eq!(rows[0].usage, usage);
+    }
+
+    #[test]
+    fn test_summary_prompt() {
+        let prompt = summary_prompt("原文", 200);
+        assert!(prompt.system_instruction.unwrap().contains("200"));
+        assert_eq!(prompt.turns.len(), 1);
+        assert_eq!(prompt.turns[0].content, "原文");
+    }
+}
@@ -0,0 +1,510 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::budget::truncate_to_tokens;
+use super::embedding::EmbeddingProvider;
+use super::history;
+use super::memory::ChatMemory;
+use super::provider::{ToolCall, ToolDeclaration, ToolResult};
+use crate::models::auth::Scope;
+use crate::models::messages::SearchHistoryMessage;
+
+/// 单次工具调用的最长执行时间
+const TOOL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// `search_memory` 默认返回的消息条数
+const DEFAULT_SEARCH_RESULTS: usize = 5;
+/// `search_memory` 最多返回的消息条数
+const MAX_SEARCH_RESULTS: usize = 20;
+/// `search_memory` 返回的每条消息的 token 上限
+const MAX_SEARCH_RESULT_TOKENS: u64 = 300;
+
+/// 工具执行时可以访问的上下文
+#[derive(Clone)]
+pub struct ToolContext {
+    pub memory: Arc<ChatMemory>,
+    pub embedder: Arc<dyn EmbeddingProvider>,
+    pub user_id: String,
+    pub conversation_id: i64,
+    /// 正在回复的用户消息（已保存，检索时排除）
+    pub user_message_id: Option<i64>,
+    /// 调用者的权限范围，决定哪些工具可用
+    pub scopes: Vec<Scope>,
+}
+
+/// 可以由模型调用的服务端工具
+#[async_trait]
+pub trait Tool: Send + Sync {
+    /// 工具名，模型以此发起调用（只能包含字母、数字、下划线）
+    fn name(&self) -> &str;
+
+    /// 告诉模型工具的用途和使用时机
+    fn description(&self) -> &str;
+
+    /// 参数的 JSON Schema（OpenAPI 子集，顶层为 object）
+    fn parameters(&self) -> Value;
+
+    /// 使用该工具需要的权限，调用者没有该权限时不提供给模型
+    fn required_scope(&self) -> Option<Scope> {
+        None
+    }
+
+    /// 执行调用，返回的结果或错误说明都会交给模型
+    async fn execute(&self, args: Value, context: &ToolContext) -> Result<Value, String>;
+}
+
+/// 启用的内置工具
+#[derive(Debug, Clone)]
+pub struct ToolConfig {
+    pub enabled: Vec<String>,
+    #[cfg(feature = "code-execution")]
+    pub sandbox: super::sandbox::SandboxConfig,
+}
+
+impl Default for ToolConfig {
+    fn default() -> Self {
+        Self {
+            enabled: vec![SearchMemoryTool::NAME.to_string()],
+            #[cfg(feature = "code-execution")]
+            sandbox: super::sandbox::SandboxConfig::default(),
+        }
+    }
+}
+
+impl ToolConfig {
+    /// 从 `TOOLS` 环境变量读取（逗号分隔的工具名，`none` 表示不启用任何工具）
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            enabled: match env::var("TOOLS") {
+                Ok(list) => list
+                    .split(',')
+                    .map(|name| name.trim().to_lowercase())
+                    .filter(|name| !name.is_empty() && name != "none")
+                    .collect(),
+                Err(_) => default.enabled,
+            },
+            #[cfg(feature = "code-execution")]
+            sandbox: super::sandbox::SandboxConfig::from_env(),
+        }
+    }
+}
+
+/// 已注册的工具
+#[derive(Clone, Default)]
+pub struct ToolRegistry {
+    tools: Vec<Arc<dyn Tool>>,
+}
+
+impl ToolRegistry {
+    /// 按配置注册内置工具
+    pub fn from_config(config: &ToolConfig) -> Self {
+        let mut registry = Self::default();
+        for name in &config.enabled {
+            match name.as_str() {
+                SearchMemoryTool::NAME => registry.register(Arc::new(SearchMemoryTool)),
+                #[cfg(feature = "code-execution")]
+                super::sandbox::RunCodeTool::NAME => registry.register(Arc::new(
+                    super::sandbox::RunCodeTool::new(config.sandbox.clone()),
+                )),
+                #[cfg(not(feature = "code-execution"))]
+                "run_code" => eprintln!("⚠️  run_code 工具需要以 code-execution 特性编译"),
+                other => eprintln!("⚠️  未知的工具: {}", other),
+            }
+        }
+        registry
+    }
+
+    /// 注册工具，同名的工具会被替换
+    pub fn register(&mut self, tool: Arc<dyn Tool>) {
+        self.tools.retain(|t| t.name() != tool.name());
+        self.tools.push(tool);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tools.is_empty()
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.tools.iter().map(|t| t.name()).collect()
+    }
+
+    /// 提供给模型的工具说明，只包含调用者有权使用的工具
+    pub fn declarations(&self, context: &ToolContext) -> Vec<ToolDeclaration> {
+        self.tools
+            .iter()
+            .filter(|tool| allowed(tool.as_ref(), context))
+            .map(|tool| ToolDeclaration {
+                name: tool.name().to_string(),
+                description: tool.description().to_string(),
+                parameters: tool.parameters(),
+            })
+            .collect()
+    }
+
+    /// 执行一次调用，未知的工具、无权使用的工具、执行失败和超时都作为错误结果返回
+    pub async fn execute(&self, call: &ToolCall, context: &ToolContext) -> ToolResult {
+        let output = match self.tools.iter().find(|t| t.name() == call.name) {
+            Some(tool) if !allowed(tool.as_ref(), context) => {
+                Err(format!("没有使用工具 {} 的权限", call.name))
+            }
+            Some(tool) => {
+                match tokio::time::timeout(TOOL_TIMEOUT, tool.execute(call.args.clone(), context))
+                    .await
+                {
+                    Ok(output) => output,
+                    Err(_) => Err(format!("工具执行超时（{} 秒）", TOOL_TIMEOUT.as_secs())),
+                }
+            }
+            None => Err(format!("未知的工具: {}", call.name)),
+        };
+        ToolResult {
+            name: call.name.clone(),
+            output,
+        }
+    }
+}
+
+fn allowed(tool: &dyn Tool, context: &ToolContext) -> bool {
+    tool.required_scope()
+        .is_none_or(|scope| scope.granted_by(&context.scopes))
+}
+
+/// 在用户的聊天历史中按关键词检索消息，可以限定时间范围、角色和会话
+pub struct SearchMemoryTool;
+
+impl SearchMemoryTool {
+    pub const NAME: &'static str = "search_memory";
+}
+
+#[derive(Deserialize)]
+struct SearchMemoryArgs {
+    query: String,
+    from: Option<String>,
+    to: Option<String>,
+    role: Option<String>,
+    scope: Option<String>,
+    /// 模型可能把整数写成 5.0
+    limit: Option<f64>,
+}
+
+#[async_trait]
+impl Tool for SearchMemoryTool {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    fn description(&self) -> &str {
+        "在用户的聊天历史中检索消息。需要回忆用户以前提到的信息、之前讨论过的内容或某段时间内的对话，\
+         而系统指令中的相关历史不够用时调用。结果按相关度排序，包含消息 ID、会话 ID、时间和内容。"
+    }
+
+    fn required_scope(&self) -> Option<Scope> {
+        Some(Scope::ReadHistory)
+    }
+
+    fn parameters(&self) -> Value {
+        let today = chrono::Utc::now().format("%Y-%m-%d");
+        json!({
+            "type": "object",
+            "properties": {
+                "query": {
+                    "type": "string",
+                    "description": "搜索关键词，多个关键词用空格分隔"
+                },
+                "from": {
+                    "type": "string",
+                    "description": format!("起始日期（含），格式 YYYY-MM-DD，今天是 {}", today)
+                },
+                "to": {
+                    "type": "string",
+                    "description": "结束日期（含），格式 YYYY-MM-DD"
+                },
+                "role": {
+                    "type": "string",
+                    "enum": ["user", "model"],
+                    "description": "只搜索用户的消息（user）或模型的回复（model）"
+                },
+                "scope": {
+                    "type": "string",
+                    "enum": ["current", "all"],
+                    "description": "current 只搜索当前会话，all 搜索用户的所有会话（默认）"
+                },
+                "limit": {
+                    "type": "integer",
+                    "description": format!(
+                        "返回条数，默认 {}，最多 {}",
+                        DEFAULT_SEARCH_RESULTS, MAX_SEARCH_RESULTS
+                    )
+                }
+            },
+            "required": ["query"]
+        })
+    }
+
+    async fn execute(&self, args: Value, context: &ToolContext) -> Result<Value, String> {
+        let args: SearchMemoryArgs =
+            serde_json::from_value(args).map_err(|e| format!("参数无效: {}", e))?;
+        let conversation_id = match args.scope.as_deref() {
+            None | Some("all") => None,
+            Some("current") => Some(context.conversation_id),
+            Some(other) => return Err(format!("无效的 scope: {}", other)),
+        };
+        let limit = args
+            .limit
+            .map_or(DEFAULT_SEARCH_RESULTS, |n| n.max(1.0) as usize)
+            .min(MAX_SEARCH_RESULTS);
+
+        // 多取一条，以便排除正在回复的消息后仍有 limit 条
+        let request = SearchHistoryMessage {
+            query: args.query,
+            conversation_id,
+            from: args.from,
+            to: args.to,
+            role: args.role,
+            model: None,
+            offset: 0,
+            limit: Some(limit + 1),
+            rerank: true,
+        };
+        let page = history::search_records(
+            &context.memory,
+            context.embedder.as_ref(),
+            &context.user_id,
+            request,
+        )
+        .await?;
+
+        let messages: Vec<Value> = page
+            .hits
+            .into_iter()
+            .filter(|hit| Some(hit.record.id) != context.user_message_id)
+            .take(limit)
+            .map(|hit| {
+                json!({
+                    "message_id": hit.record.id,
+                    "conversation_id": hit.record.conversation_id,
+                    "role": hit.record.role,
+                    "time": hit.record.created_at.format("%Y-%m-%d %H:%M").to_string(),
+                    "content": truncate_to_tokens(&hit.record.content, MAX_SEARCH_RESULT_TOKENS),
+                })
+            })
+            .collect();
+        Ok(json!({ "total": page.total, "messages": messages }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::embedding::LocalEmbedding;
+
+    struct AddTool;
+
+    #[async_trait]
+    impl Tool for AddTool {
+        fn name(&self) -> &str {
+            "add"
+        }
+
+        fn description(&self) -> &str {
+            "两数相加"
+        }
+
+        fn parameters(&self) -> Value {
+            json!({
+                "type": "object",
+                "properties": {
+                    "a": { "type": "number" },
+                    "b": { "type": "number" }
+                },
+                "required": ["a", "b"]
+            })
+        }
+
+        async fn execute(&self, args: Value, _context: &ToolContext) -> Result<Value, String> {
+            match (args["a"].as_f64(), args["b"].as_f64()) {
+                (Some(a), Some(b)) => Ok(json!(a + b)),
+                _ => Err("参数 a、b 必须是数字".to_string()),
+            }
+        }
+    }
+
+    fn context() -> ToolContext {
+        ToolContext {
+            memory: Arc::new(ChatMemory::new(":memory:").unwrap()),
+            embedder: Arc::new(LocalEmbedding::new(16)),
+            user_id: "u".to_string(),
+            conversation_id: 1,
+            user_message_id: None,
+            scopes: Scope::ALL.to_vec(),
+        }
+    }
+
+    fn call(name: &str, args: Value) -> ToolCall {
+        ToolCall {
+            name: name.to_string(),
+            args,
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_registry_executes_tools() {
+        let mut registry = ToolRegistry::default();
+        registry.register(Arc::new(AddTool));
+        registry.register(Arc::new(AddTool));
+        assert_eq!(registry.names(), vec!["add"]);
+        assert_eq!(
+            registry.declarations(&context())[0].parameters["required"][1],
+            "b"
+        );
+
+        let context = context();
+        let result = registry
+            .execute(&call("add", json!({ "a": 1, "b": 2 })), &context)
+            .await;
+        assert_eq!(result.output, Ok(json!(3.0)));
+        assert_eq!(result.response(), json!({ "result": 3.0 }));
+
+        let result = registry
+            .execute(&call("add", json!({ "a": "x" })), &context)
+            .await;
+        assert_eq!(
+            result.response(),
+            json!({ "error": "参数 a、b 必须是数字" })
+        );
+
+        let result = registry
+            .execute(&call("missing", json!({})), &context)
+            .await;
+        assert_eq!(result.output, Err("未知的工具: missing".to_string()));
+    }
+
+    #[actix_web::test]
+    async fn test_search_memory() {
+        let mut context = context();
+        let memory = context.memory.clone();
+        let old = memory.create_conversation("u", "旧会话", None).unwrap();
+        let current = memory.create_conversation("u", "当前会话", None).unwrap();
+        memory
+            .add_message("u", old.id, "user", "my database is postgres 16", None)
+            .unwrap();
+        memory
+            .add_message("u", old.id, "model", "noted, postgres 16 it is", None)
+            .unwrap();
+        memory
+            .add_message(
+                "other",
+                old.id,
+                "user",
+                "postgres belongs to someone else",
+                None,
+            )
+            .unwrap();
+        let asking = memory
+            .add_message("u", current.id, "user", "which postgres version?", None)
+            .unwrap();
+        context.conversation_id = current.id;
+        context.user_message_id = Some(asking);
+
+        let registry = ToolRegistry::from_config(&ToolConfig::default());
+        let (registry, context) = (&registry, &context);
+        let search = |args: Value| async move {
+            registry
+                .execute(&call("search_memory", args), context)
+                .await
+        };
+
+        // 排除正在回复的消息和其他用户的消息
+        let result = search(json!({ "query": "postgres" })).await.output.unwrap();
+        let messages = result["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().all(|m| m["conversation_id"] == old.id));
+
+        let result = search(json!({ "query": "postgres", "role": "model", "limit": 5.0 }))
+            .await
+            .output
+            .unwrap();
+        assert_eq!(result["messages"][0]["content"], "noted, postgres 16 it is");
+        assert_eq!(result["messages"].as_array().unwrap().len(), 1);
+
+        let result = search(json!({ "query": "postgres", "scope": "current" }))
+            .await
+            .output
+            .unwrap();
+        assert!(result["messages"].as_array().unwrap().is_empty());
+
+        let result = search(json!({ "query": "postgres", "to": "2000-01-01" }))
+            .await
+            .output
+            .unwrap();
+        assert_eq!(result["total"], 0);
+
+        let result = search(json!({ "query": "postgres", "role": "system" })).await;
+        assert_eq!(result.output, Err("无效的角色: system".to_string()));
+        assert!(search(json!({})).await.output.is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_search_memory_requires_read_history() {
+        let memory = Arc::new(ChatMemory::new(":memory:").unwrap());
+        let conversation = memory.create_conversation("u", "会话", None).unwrap();
+        memory
+            .add_message(
+                "u",
+                conversation.id,
+                "user",
+                "my database is postgres 16",
+                None,
+            )
+            .unwrap();
+
+        let mut registry = ToolRegistry::from_config(&ToolConfig::default());
+        registry.register(Arc::new(AddTool));
+        let mut context = context();
+        context.memory = memory;
+        context.scopes = vec![Scope::Chat];
+        let names: Vec<String> = registry
+            .declarations(&context)
+            .into_iter()
+            .map(|d| d.name)
+            .collect();
+        assert_eq!(names, vec!["add"]);
+        let result = registry
+            .execute(
+                &call("search_memory", json!({ "query": "postgres" })),
+                &context,
+            )
+            .await;
+        assert_eq!(
+            result.output,
+            Err("没有使用工具 search_memory 的权限".to_string())
+        );
+
+        context.scopes = vec![Scope::Chat, Scope::ReadHistory];
+        assert_eq!(registry.declarations(&context).len(), 2);
+        let result = registry
+            .execute(
+                &call("search_memory", json!({ "query": "postgres" })),
+                &context,
+            )
+            .await;
+        assert_eq!(result.output.unwrap()["total"], 1);
+    }
+
+    #[test]
+    fn test_tool_config() {
+        let registry = ToolRegistry::from_config(&ToolConfig {
+            enabled: vec!["unknown".to_string()],
+            #[cfg(feature = "code-execution")]
+            sandbox: crate::services::sandbox::SandboxConfig::default(),
+        });
+        assert!(registry.is_empty());
+        let registry = ToolRegistry::from_config(&ToolConfig::default());
+        assert_eq!(registry.names(), vec!["search_memory"]);
+    }
+}
@@ -0,0 +1,239 @@
+use chrono::{Days, Utc};
+use std::collections::HashMap;
+use std::env;
+
+use super::memory::ChatMemory;
+use crate::models::messages::TokenUsage;
+use crate::models::usage::{UsageReport, UsageReportRow, UsageTotals};
+
+/// 默认统计最近 30 天
+pub const DEFAULT_USAGE_DAYS: u32 = 30;
+/// 最多统计一年
+const MAX_USAGE_DAYS: u32 = 366;
+
+/// 模型价格（美元 / 百万 token），思考过程按输出计费
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModelPrice {
+    pub input: f64,
+    pub output: f64,
+}
+
+/// 用量统计配置
+#[derive(Debug, Clone)]
+pub struct UsageConfig {
+    /// 按模型档位（flash / flash-2.5 / pro-2.5）配置的价格
+    pub prices: HashMap<String, ModelPrice>,
+    /// 可以查看所有用户用量的用户名
+    pub admins: Vec<String>,
+}
+
+impl Default for UsageConfig {
+    /// Gemini 官方标准价格
+    fn default() -> Self {
+        let prices = [
+            ("flash", 0.10, 0.40),
+            ("flash-2.5", 0.30, 2.50),
+            ("pro-2.5", 1.25, 10.00),
+        ]
+        .into_iter()
+        .map(|(model, input, output)| (model.to_string(), ModelPrice { input, output }))
+        .collect();
+
+        Self {
+            prices,
+            admins: Vec::new(),
+        }
+    }
+}
+
+impl UsageConfig {
+    /// 从 MODEL_PRICES / USAGE_ADMINS 环境变量读取配置
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        if let Ok(spec) = env::var("MODEL_PRICES") {
+            config.apply_prices(&spec);
+        }
+        if let Ok(admins) = env::var("USAGE_ADMINS") {
+            config.admins = admins
+                .split(',')
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+                .collect();
+        }
+        config
+    }
+
+    /// 解析 `flash=0.1/0.4,pro-2.5=1.25/10` 格式的价格表，覆盖同名模型的默认价格
+    fn apply_prices(&mut self, spec: &str) {
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let price = entry.split_once('=').and_then(|(model, price)| {
+                let (input, output) = price.split_once('/')?;
+                Some((
+                    model.trim().to_string(),
+                    ModelPrice {
+                        input: input.trim().parse().ok()?,
+                        output: output.trim().parse().ok()?,
+                    },
+                ))
+            });
+            match price {
+                Some((model, price)) => {
+                    self.prices.insert(model, price);
+                }
+                None => eprintln!("⚠️  忽略无效的 MODEL_PRICES 项: {}", entry),
+            }
+        }
+    }
+
+    pub fn is_admin(&self, username: &str) -> bool {
+        self.admins.iter().any(|a| a.eq_ignore_ascii_case(username))
+    }
+
+    /// 估算花费，模型未配置价格时返回 None
+    pub fn cost(&self, model: Option<&str>, usage: &TokenUsage) -> Option<f64> {
+        let price = self.prices.get(model?)?;
+        Some(
+            (usage.prompt_tokens as f64 * price.input
+                + usage.output_tokens() as f64 * price.output)
+                / 1_000_000.0,
+        )
+    }
+}
+
+/// 生成最近 `days` 天的用量报告，`user_id` 为 None 时包含所有用户
+pub fn usage_report(
+    memory: &ChatMemory,
+    config: &UsageConfig,
+    user_id: Option<&str>,
+    days: u32,
+) -> Result<UsageReport, String> {
+    let days = days.clamp(1, MAX_USAGE_DAYS);
+    let since = Utc::now()
+        .date_naive()
+        .checked_sub_days(Days::new(days as u64 - 1))
+        .unwrap_or_default()
+        .format("%Y-%m-%d")
+        .to_string();
+
+    let rows = memory
+        .usage_summary(user_id, &since)
+        .map_err(|e| format!("统计用量失败: {}", e))?;
+
+    let mut totals = UsageTotals::default();
+    let rows = rows
+        .into_iter()
+        .map(|row| {
+            let cost = config.cost(row.model.as_deref(), &row.usage);
+            totals.replies += row.replies;
+            totals.usage.prompt_tokens += row.usage.prompt_tokens;
+            totals.usage.candidate_tokens += row.usage.candidate_tokens;
+            totals.usage.thought_tokens += row.usage.thought_tokens;
+            totals.cost += cost.unwrap_or(0.0);
+            UsageReportRow {
+                user_id: row.user_id,
+                username: row.username,
+                day: row.day,
+                model: row.model,
+                replies: row.replies,
+                total_tokens: row.usage.total(),
+                usage: row.usage,
+                cost,
+            }
+        })
+        .collect();
+    totals.total_tokens = totals.usage.total();
+
+    Ok(UsageReport {
+        since,
+        currency: "USD",
+        rows,
+        totals,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(prompt_tokens: u64, candidate_tokens: u64, thought_tokens: u64) -> TokenUsage {
+        TokenUsage {
+            prompt_tokens,
+            candidate_tokens,
+            thought_tokens,
+        }
+    }
+
+    #[test]
+    fn test_apply_prices() {
+        let mut config = UsageConfig::default();
+        config.apply_prices("flash=1/2, local=0/0, broken=abc");
+
+        assert_eq!(
+            config.prices["flash"],
+            ModelPrice {
+                input: 1.0,
+                output: 2.0
+            }
+        );
+        assert!(config.prices.contains_key("local"));
+        assert!(!config.prices.contains_key("broken"));
+        // 未覆盖的模型保留默认价格
+        assert!(config.prices.contains_key("pro-2.5"));
+    }
+
+    #[test]
+    fn test_cost_counts_thoughts_as_output() {
+        let mut config = UsageConfig::default();
+        config.apply_prices("pro-2.5=1/10");
+
+        let cost = config
+            .cost(Some("pro-2.5"), &usage(1_000_000, 200_000, 300_000))
+            .unwrap();
+        assert!((cost - 6.0).abs() < 1e-9);
+        assert_eq!(config.cost(Some("unknown"), &usage(1, 1, 1)), None);
+        assert_eq!(config.cost(None, &usage(1, 1, 1)), None);
+    }
+
+    #[test]
+    fn test_usage_report() {
+        let memory = ChatMemory::new(":memory:").unwrap();
+        let conversation = memory.create_conversation("u1", "会话", None).unwrap();
+        for (user_id, model, tokens) in [
+            ("u1", "flash", usage(100, 20, 0)),
+            ("u1", "flash", usage(50, 10, 0)),
+            ("u1", "pro-2.5", usage(10, 5, 5)),
+            ("u2", "flash", usage(1000, 1000, 0)),
+        ] {
+            let id = memory
+                .add_message(user_id, conversation.id, "model", "回复", Some(model))
+                .unwrap();
+            memory.set_message_usage(id, &tokens).unwrap();
+        }
+        // 没有用量的回复不计入
+        memory
+            .add_message("u1", conversation.id, "model", "旧回复", Some("flash"))
+            .unwrap();
+        // 不保存消息的调用按记录计入
+        memory
+            .add_usage_record("u1", Some("flash"), &usage(1, 2, 0))
+            .unwrap();
+
+        let config = UsageConfig::default();
+        let report = usage_report(&memory, &config, Some("u1"), 7).unwrap();
+        assert_eq!(report.rows.len(), 2);
+        let flash = report
+            .rows
+            .iter()
+            .find(|r| r.model.as_deref() == Some("flash"))
+            .unwrap();
+        assert_eq!(flash.replies, 3);
+        assert_eq!(flash.usage, usage(151, 32, 0));
+        assert_eq!(flash.total_tokens, 183);
+        assert_eq!(report.totals.replies, 4);
+        assert_eq!(report.totals.total_tokens, 203);
+
+        let all = usage_report(&memory, &config, None, 7).unwrap();
+        assert_eq!(all.rows.len(), 3);
+        assert_eq!(all.totals.replies, 5);
+    }
+}